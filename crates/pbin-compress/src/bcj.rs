@@ -5,32 +5,165 @@
 //! improving compression ratios (typically 10-15% better).
 //!
 //! Supported architectures:
-//! - x86/x86_64: CALL (E8) and JMP (E9) instructions
-//! - ARM/AArch64: BL and B instructions
-//! - RISC-V: JAL and AUIPC instructions
+//! - x86/x86_64: CALL (E8) and JMP (E9) instructions, with the xz/7-Zip
+//!   heuristic that skips likely non-instructions, and optionally
+//!   conditional near jumps (0F 80-8F)
+//! - ARM: unconditional BL instructions, as xz filters them
+//! - AArch64: BL and ADRP instructions, as xz filters them
+//! - LoongArch: B, BL and PCADDU18I instructions
+//! - MIPS: BAL and BALC instructions, big- or little-endian
+//! - RISC-V: JAL and AUIPC instructions, walking compressed (RVC)
+//!   instructions by their length
+//! - SPARC: CALL instructions, as xz filters them
+//! - s390x: BRASL and BRCL instructions, walking instructions by their
+//!   length
+//!
+//! Filtering can be limited to ranges of a binary, such as its executable
+//! sections, so data that happens to look like branches is left alone.
 
-use crate::Result;
+use crate::{CompressionError, Result};
+use pbin_core::{Arch, Target};
+use serde::{Serialize, Serializer};
+use std::io::{self, Write};
+use std::ops::Range;
 
 /// Architecture-specific BCJ filter.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BcjArch {
-    /// x86 and x86_64
+    /// x86 and x86_64, as xz and 7-Zip filter them
     X86,
-    /// ARM 32-bit (Thumb and ARM mode)
+    /// [`X86`](Self::X86), also converting conditional near jumps
+    /// (0F 80-8F). Large optimized binaries are full of them, but so is
+    /// some data, so it's opt-in.
+    X86Jcc,
+    /// x86 and x86_64, converting every E8 and E9 byte. Written by earlier
+    /// versions of pbin-pack; kept to read their files.
+    X86Plain,
+    /// ARM 32-bit, as xz filters it
     Arm,
-    /// ARM 64-bit (AArch64)
+    /// ARM 32-bit, converting BL under any condition and BLX. Written by
+    /// earlier versions of pbin-pack; kept to read their files.
+    ArmPlain,
+    /// ARM 64-bit (AArch64), as xz filters it
     Arm64,
-    /// RISC-V 32/64-bit
+    /// AArch64, converting only BL. Written by earlier versions of
+    /// pbin-pack; kept to read their files.
+    Arm64Plain,
+    /// RISC-V 32/64-bit, with or without compressed instructions
     RiscV,
+    /// RISC-V, stepping four bytes at a time whatever the instruction
+    /// lengths and converting only JAL. Written by earlier versions of
+    /// pbin-pack; kept to read their files.
+    RiscVPlain,
     /// PowerPC 64-bit little-endian
     Ppc64Le,
+    /// LoongArch 64-bit
+    LoongArch,
+    /// MIPS 32/64-bit big-endian
+    Mips,
+    /// MIPS 32/64-bit little-endian
+    MipsEl,
+    /// IBM z/Architecture (s390x)
+    S390x,
+    /// SPARC 32/64-bit, as xz filters it
+    Sparc,
     /// No filtering (passthrough)
     None,
 }
 
 impl BcjArch {
-    /// Detect architecture from platform target string.
+    /// Returns the name recorded in PBIN manifests for this filter.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BcjArch::X86 => "x86v2",
+            BcjArch::X86Jcc => "x86v2-jcc",
+            BcjArch::X86Plain => "x86",
+            BcjArch::Arm => "arm-v2",
+            BcjArch::ArmPlain => "arm",
+            BcjArch::Arm64 => "arm64-v2",
+            BcjArch::Arm64Plain => "arm64",
+            BcjArch::RiscV => "riscv-v2",
+            BcjArch::RiscVPlain => "riscv",
+            BcjArch::Ppc64Le => "ppc64le",
+            BcjArch::LoongArch => "loongarch",
+            BcjArch::Mips => "mips",
+            BcjArch::MipsEl => "mipsel",
+            BcjArch::S390x => "s390x",
+            BcjArch::Sparc => "sparc",
+            BcjArch::None => "none",
+        }
+    }
+
+    /// Parses a filter name as recorded in PBIN manifests.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "x86v2" => Some(BcjArch::X86),
+            "x86v2-jcc" => Some(BcjArch::X86Jcc),
+            "x86" => Some(BcjArch::X86Plain),
+            "arm-v2" => Some(BcjArch::Arm),
+            "arm" => Some(BcjArch::ArmPlain),
+            "arm64-v2" => Some(BcjArch::Arm64),
+            "arm64" => Some(BcjArch::Arm64Plain),
+            "riscv-v2" => Some(BcjArch::RiscV),
+            "riscv" => Some(BcjArch::RiscVPlain),
+            "ppc64le" => Some(BcjArch::Ppc64Le),
+            "loongarch" => Some(BcjArch::LoongArch),
+            "mips" => Some(BcjArch::Mips),
+            "mipsel" => Some(BcjArch::MipsEl),
+            "s390x" => Some(BcjArch::S390x),
+            "sparc" => Some(BcjArch::Sparc),
+            "none" => Some(BcjArch::None),
+            _ => None,
+        }
+    }
+
+    /// Returns the filter for code of `arch`.
+    pub fn from_arch(arch: Arch) -> Self {
+        match arch {
+            Arch::X86_64 | Arch::X86 => BcjArch::X86,
+            Arch::Aarch64 => BcjArch::Arm64,
+            Arch::Armv7 | Arch::Armv6 => BcjArch::Arm,
+            Arch::Riscv64 => BcjArch::RiscV,
+            Arch::Ppc64le => BcjArch::Ppc64Le,
+            // The target is big-endian mips64.
+            Arch::Mips64 => BcjArch::Mips,
+            Arch::Loongarch64 => BcjArch::LoongArch,
+            Arch::S390x => BcjArch::S390x,
+            Arch::Wasm32 => BcjArch::None,
+        }
+    }
+
+    /// Returns the filter for code built for `target`.
+    ///
+    /// Prefer this to [`from_target`](Self::from_target) whenever the
+    /// target has been parsed: the filter follows from the target's
+    /// architecture, with no name matching involved.
+    pub fn for_target(target: Target) -> Self {
+        Self::from_arch(target.arch())
+    }
+
+    /// Returns the filter for a target given by name.
+    ///
+    /// Also accepts an entry key ("program/target"), looking only at the
+    /// target so a program's name can't be mistaken for an architecture.
+    /// Target names and the Rust triples of targets go through
+    /// [`for_target`](Self::for_target); only free-form names that are
+    /// neither are matched by the architecture names they contain.
     pub fn from_target(target: &str) -> Self {
+        let target = target.rsplit('/').next().unwrap_or(target);
+        match target
+            .parse()
+            .ok()
+            .or_else(|| Target::from_rust_triple(target))
+        {
+            Some(target) => Self::for_target(target),
+            None => Self::from_free_form(target),
+        }
+    }
+
+    /// Guesses the filter for a name that isn't a target from the
+    /// architecture names it contains.
+    fn from_free_form(target: &str) -> Self {
         if target.contains("x86_64") || target.contains("i686") || target.contains("i586") {
             BcjArch::X86
         } else if target.contains("aarch64") {
@@ -41,19 +174,59 @@ impl BcjArch {
             BcjArch::RiscV
         } else if target.contains("powerpc64le") || target.contains("ppc64le") {
             BcjArch::Ppc64Le
+        } else if target.contains("loongarch") {
+            BcjArch::LoongArch
+        } else if target.contains("mipsel") || target.contains("mips64el") {
+            BcjArch::MipsEl
+        } else if target.contains("mips") {
+            BcjArch::Mips
+        } else if target.contains("s390x") {
+            BcjArch::S390x
+        } else if target.contains("sparc") {
+            BcjArch::Sparc
         } else {
             BcjArch::None
         }
     }
 }
 
+/// Serializes as the name recorded in PBIN manifests.
+impl Serialize for BcjArch {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Adds `by` to `value` when encoding and subtracts it when decoding, modulo
+/// 2^32.
+fn relocate(value: u32, by: u32, encode: bool) -> u32 {
+    if encode {
+        value.wrapping_add(by)
+    } else {
+        value.wrapping_sub(by)
+    }
+}
+
 /// BCJ filter state for streaming processing.
 pub struct BcjFilter {
     arch: BcjArch,
     pos: usize,
-    /// For x86: previous byte state for multi-byte instruction detection
-    #[allow(dead_code)]
+    /// For x86: which of the four bytes before the current one held E8 or
+    /// E9 left unconverted (bits 1-3) and whether the last of those had a
+    /// plausible high byte (bit 4).
     prev_mask: u32,
+    /// For x86: position of the last E8 or E9 byte seen, wrapping like the
+    /// 32-bit positions it is compared with.
+    prev_pos: u32,
+    /// For x86 with conditional jumps: position of the high operand byte
+    /// tested by the last E8 or E9 left unconverted, and by the last 0F 8x
+    /// left unconverted. No conversion may change those bytes, or the
+    /// decoder would see a different candidate.
+    tested: [usize; 2],
+    /// Bytes given to [`encode_chunk`](Self::encode_chunk) or
+    /// [`decode_chunk`](Self::decode_chunk) but held back, as they start an
+    /// instruction the next chunk may complete.
+    pending: Vec<u8>,
 }
 
 impl BcjFilter {
@@ -63,40 +236,259 @@ impl BcjFilter {
             arch,
             pos: 0,
             prev_mask: 0,
+            prev_pos: 0u32.wrapping_sub(5),
+            tested: [0; 2],
+            pending: Vec::new(),
         }
     }
 
     /// Encode (filter) data in-place for compression.
     /// Converts relative addresses to absolute.
+    ///
+    /// `data` is taken to run to the end of the stream, so an instruction
+    /// cut off at its end is left as it is. Data in chunks goes through
+    /// [`encode_chunk`](Self::encode_chunk) instead, which this refuses to
+    /// follow.
     pub fn encode(&mut self, data: &mut [u8]) -> Result<()> {
+        self.check_no_pending()?;
+        let done = self.encode_partial(data);
+        self.pos += data.len() - done;
+        Ok(())
+    }
+
+    /// Encodes the next `chunk` of a stream, returning the bytes that are
+    /// now final. The bytes of an instruction that continues past the chunk
+    /// are held back until the chunk completing it, so the output is the
+    /// same however the stream is split, and matches
+    /// [`encode`](Self::encode) on the whole of it once
+    /// [`finish`](Self::finish) returns the rest.
+    pub fn encode_chunk(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.push(chunk, false)
+    }
+
+    /// Decodes the next `chunk` of a stream, as
+    /// [`encode_chunk`](Self::encode_chunk) encodes.
+    pub fn decode_chunk(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.push(chunk, true)
+    }
+
+    /// Returns the bytes held back at the end of a stream given in chunks,
+    /// which end too soon to hold an instruction and are left as they are.
+    pub fn finish(self) -> Vec<u8> {
+        self.pending
+    }
+
+    /// Filters `chunk` after the bytes held back, holding back again any
+    /// that aren't final.
+    fn push(&mut self, chunk: &[u8], decode: bool) -> Vec<u8> {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(chunk);
+        let done = if decode {
+            self.decode_partial(&mut data)
+        } else {
+            self.encode_partial(&mut data)
+        };
+        self.pending = data.split_off(done);
+        data
+    }
+
+    /// Fails if chunks were given and bytes are still held back, which
+    /// [`encode`](Self::encode) or [`decode`](Self::decode) would skip.
+    fn check_no_pending(&self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        Err(CompressionError::InvalidData(format!(
+            "BCJ filter holds {} bytes of a chunked stream; finish it first",
+            self.pending.len()
+        )))
+    }
+
+    /// Encodes the instructions that start within `data` and fit in it,
+    /// returning how many leading bytes are final. The rest must be passed
+    /// again at the start of the next call.
+    fn encode_partial(&mut self, data: &mut [u8]) -> usize {
         match self.arch {
-            BcjArch::X86 => self.encode_x86(data),
-            BcjArch::Arm64 => self.encode_arm64(data),
-            BcjArch::Arm => self.encode_arm(data),
-            BcjArch::RiscV => self.encode_riscv(data),
-            BcjArch::Ppc64Le => self.encode_ppc64(data),
-            BcjArch::None => Ok(()),
+            BcjArch::X86 | BcjArch::X86Jcc => self.convert_x86(data, true),
+            BcjArch::X86Plain => self.encode_x86_plain(data),
+            BcjArch::Arm64 => self.convert_arm64(data, true),
+            BcjArch::Arm64Plain => self.convert_arm64_plain(data, true),
+            BcjArch::Arm | BcjArch::ArmPlain => self.convert_arm(data, true),
+            BcjArch::RiscV => self.convert_riscv(data, true),
+            BcjArch::RiscVPlain => self.encode_riscv_plain(data),
+            BcjArch::Ppc64Le => self.convert_ppc64(data, true),
+            BcjArch::LoongArch => self.convert_loongarch(data, true),
+            BcjArch::Mips | BcjArch::MipsEl => self.convert_mips(data, true),
+            BcjArch::S390x => self.convert_s390x(data, true),
+            BcjArch::Sparc => self.convert_sparc(data, true),
+            BcjArch::None => {
+                self.pos += data.len();
+                data.len()
+            }
         }
     }
 
     /// Decode (unfilter) data in-place after decompression.
     /// Converts absolute addresses back to relative.
+    ///
+    /// Like [`encode`](Self::encode), `data` is taken to run to the end of
+    /// the stream; data in chunks goes through
+    /// [`decode_chunk`](Self::decode_chunk).
     pub fn decode(&mut self, data: &mut [u8]) -> Result<()> {
+        self.check_no_pending()?;
+        let done = self.decode_partial(data);
+        self.pos += data.len() - done;
+        Ok(())
+    }
+
+    /// Decodes like [`encode_partial`](Self::encode_partial) encodes,
+    /// returning how many leading bytes are final.
+    fn decode_partial(&mut self, data: &mut [u8]) -> usize {
         match self.arch {
-            BcjArch::X86 => self.decode_x86(data),
-            BcjArch::Arm64 => self.decode_arm64(data),
-            BcjArch::Arm => self.decode_arm(data),
-            BcjArch::RiscV => self.decode_riscv(data),
-            BcjArch::Ppc64Le => self.decode_ppc64(data),
-            BcjArch::None => Ok(()),
+            BcjArch::X86 | BcjArch::X86Jcc => self.convert_x86(data, false),
+            BcjArch::X86Plain => self.decode_x86_plain(data),
+            BcjArch::Arm64 => self.convert_arm64(data, false),
+            BcjArch::Arm64Plain => self.convert_arm64_plain(data, false),
+            BcjArch::Arm | BcjArch::ArmPlain => self.convert_arm(data, false),
+            BcjArch::RiscV => self.convert_riscv(data, false),
+            BcjArch::RiscVPlain => self.decode_riscv_plain(data),
+            BcjArch::Ppc64Le => self.convert_ppc64(data, false),
+            BcjArch::LoongArch => self.convert_loongarch(data, false),
+            BcjArch::Mips | BcjArch::MipsEl => self.convert_mips(data, false),
+            BcjArch::S390x => self.convert_s390x(data, false),
+            BcjArch::Sparc => self.convert_sparc(data, false),
+            BcjArch::None => {
+                self.pos += data.len();
+                data.len()
+            }
         }
     }
 
-    /// x86/x86_64 BCJ encoding.
-    /// Filters CALL (E8) and JMP (E9) instructions.
-    fn encode_x86(&mut self, data: &mut [u8]) -> Result<()> {
+    /// x86/x86_64 BCJ encoding (`encode`) or decoding, as in xz's
+    /// `simple/x86.c`.
+    ///
+    /// Only E8/E9 candidates whose operand's high byte is 0x00 or 0xFF are
+    /// converted, since real calls and jumps rarely reach further than
+    /// 16 MiB, and not after an unconverted candidate in the bytes just
+    /// before, which usually means data or the middle of a longer
+    /// instruction. Converted operands have their high byte stored as 0x00
+    /// or 0xFF again, so the decoder sees the same candidates.
+    ///
+    /// With [`BcjArch::X86Jcc`], conditional near jumps (0F 8x rel32) are
+    /// converted the same way at instruction length 6. The E8/E9 masks
+    /// don't cover them, so a conversion that would overwrite the byte an
+    /// unconverted candidate was tested on is skipped instead.
+    fn convert_x86(&mut self, data: &mut [u8], encode: bool) -> usize {
+        /// Whether a mask of unconverted candidates still allows converting.
+        const MASK_TO_ALLOWED: [bool; 8] = [true, true, true, false, true, false, false, false];
+        /// Which operand byte to re-test for a given mask.
+        const MASK_TO_BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+        /// Whether `b` is a plausible high byte of a branch displacement.
+        fn is_high_byte(b: u8) -> bool {
+            b == 0x00 || b == 0xFF
+        }
+        /// Converts the 32-bit operand at `data[0..4]` between relative and
+        /// absolute for an instruction ending at `next`, given its high byte
+        /// is 0x00 or 0xFF, keeping the high byte 0x00 or 0xFF.
+        fn convert_operand(operand: &mut [u8], next: u32, encode: bool) {
+            let src = u32::from_le_bytes([operand[0], operand[1], operand[2], operand[3]]);
+            let dest = relocate(src, next, encode);
+            operand[..3].copy_from_slice(&dest.to_le_bytes()[..3]);
+            operand[3] = if dest & (1 << 24) != 0 { 0xFF } else { 0x00 };
+        }
+
         if data.len() < 5 {
-            return Ok(());
+            return 0;
+        }
+        let now_pos = self.pos as u32;
+        let mut prev_mask = self.prev_mask;
+        let mut prev_pos = self.prev_pos;
+        if now_pos.wrapping_sub(prev_pos) > 5 {
+            prev_pos = now_pos.wrapping_sub(5);
+        }
+
+        let jcc = self.arch == BcjArch::X86Jcc;
+        let limit = data.len() - 5;
+        let mut i = 0;
+        while i <= limit {
+            if jcc && data[i] == 0x0F && data[i + 1] & 0xF0 == 0x80 {
+                // Six bytes, so one starting at `limit` waits for more data.
+                if i == limit {
+                    break;
+                }
+                let pos = self.pos + i;
+                if is_high_byte(data[i + 5]) && self.tested.iter().all(|&t| t < pos + 2) {
+                    let next = (pos as u32).wrapping_add(6);
+                    convert_operand(&mut data[i + 2..i + 6], next, encode);
+                    i += 6;
+                } else {
+                    self.tested[1] = pos + 5;
+                    i += 1;
+                }
+                continue;
+            }
+            if data[i] != 0xE8 && data[i] != 0xE9 {
+                i += 1;
+                continue;
+            }
+
+            let pos = now_pos.wrapping_add(i as u32);
+            let offset = pos.wrapping_sub(prev_pos);
+            prev_pos = pos;
+            if offset > 5 {
+                prev_mask = 0;
+            } else {
+                for _ in 0..offset {
+                    prev_mask &= 0x77;
+                    prev_mask <<= 1;
+                }
+            }
+
+            let high = data[i + 4];
+            if is_high_byte(high)
+                && MASK_TO_ALLOWED[((prev_mask >> 1) & 0x7) as usize]
+                && (prev_mask >> 1) < 0x10
+                && self.tested[1] < self.pos + i + 1
+            {
+                let mut src = u32::from_le_bytes([data[i + 1], data[i + 2], data[i + 3], high]);
+                let next = pos.wrapping_add(5);
+                let dest = loop {
+                    let dest = relocate(src, next, encode);
+                    if prev_mask == 0 {
+                        break dest;
+                    }
+                    let bit = MASK_TO_BIT_NUMBER[(prev_mask >> 1) as usize];
+                    if !is_high_byte((dest >> (24 - bit * 8)) as u8) {
+                        break dest;
+                    }
+                    src = dest ^ ((1u32 << (32 - bit * 8)) - 1);
+                };
+
+                let bytes = dest.to_le_bytes();
+                data[i + 1..i + 4].copy_from_slice(&bytes[..3]);
+                data[i + 4] = if dest & (1 << 24) != 0 { 0xFF } else { 0x00 };
+                i += 5;
+                prev_mask = 0;
+            } else {
+                self.tested[0] = self.pos + i + 4;
+                i += 1;
+                prev_mask |= 1;
+                if is_high_byte(high) {
+                    prev_mask |= 0x10;
+                }
+            }
+        }
+
+        self.prev_mask = prev_mask;
+        self.prev_pos = prev_pos;
+        self.pos += i;
+        i
+    }
+
+    /// x86/x86_64 BCJ encoding of every CALL (E8) and JMP (E9).
+    fn encode_x86_plain(&mut self, data: &mut [u8]) -> usize {
+        if data.len() < 5 {
+            return 0;
         }
 
         let limit = data.len() - 4;
@@ -106,12 +498,7 @@ impl BcjFilter {
             // Look for E8 (CALL) or E9 (JMP near)
             if data[i] == 0xE8 || data[i] == 0xE9 {
                 // Read relative offset (little-endian)
-                let rel = i32::from_le_bytes([
-                    data[i + 1],
-                    data[i + 2],
-                    data[i + 3],
-                    data[i + 4],
-                ]);
+                let rel = i32::from_le_bytes([data[i + 1], data[i + 2], data[i + 3], data[i + 4]]);
 
                 // Convert to absolute: abs = rel + current_pos + 5 (instruction length)
                 let abs = rel.wrapping_add((self.pos + i + 5) as i32);
@@ -129,14 +516,14 @@ impl BcjFilter {
             }
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// x86/x86_64 BCJ decoding.
-    fn decode_x86(&mut self, data: &mut [u8]) -> Result<()> {
+    /// Reverses [`encode_x86_plain`](Self::encode_x86_plain).
+    fn decode_x86_plain(&mut self, data: &mut [u8]) -> usize {
         if data.len() < 5 {
-            return Ok(());
+            return 0;
         }
 
         let limit = data.len() - 4;
@@ -145,12 +532,7 @@ impl BcjFilter {
         while i < limit {
             if data[i] == 0xE8 || data[i] == 0xE9 {
                 // Read absolute address
-                let abs = i32::from_le_bytes([
-                    data[i + 1],
-                    data[i + 2],
-                    data[i + 3],
-                    data[i + 4],
-                ]);
+                let abs = i32::from_le_bytes([data[i + 1], data[i + 2], data[i + 3], data[i + 4]]);
 
                 // Convert back to relative: rel = abs - current_pos - 5
                 let rel = abs.wrapping_sub((self.pos + i + 5) as i32);
@@ -168,171 +550,289 @@ impl BcjFilter {
             }
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// ARM64 (AArch64) BCJ encoding.
-    /// Filters BL (Branch with Link) instructions.
-    fn encode_arm64(&mut self, data: &mut [u8]) -> Result<()> {
-        // ARM64 instructions are 4 bytes, aligned
-        if data.len() < 4 {
-            return Ok(());
-        }
-
-        let mut i = self.pos & 3; // Align to 4-byte boundary
-        if i != 0 {
-            i = 4 - i;
-        }
-
-        while i + 4 <= data.len() {
-            // Read instruction (little-endian)
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-
-            // BL instruction: 100101 followed by 26-bit signed offset
-            // Opcode mask: 0xFC000000, expected: 0x94000000
-            if (inst & 0xFC00_0000) == 0x9400_0000 {
-                // Extract 26-bit signed offset (in units of 4 bytes)
-                let offset = ((inst & 0x03FF_FFFF) as i32) << 6 >> 6; // Sign extend
-
-                // Convert to absolute address
-                let addr = ((self.pos + i) as i32).wrapping_add(offset * 4);
-
-                // Encode as new offset from 0
-                let new_offset = (addr >> 2) as u32 & 0x03FF_FFFF;
-                let new_inst = (inst & 0xFC00_0000) | new_offset;
-
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
+    /// ARM64 (AArch64) BCJ encoding (`encode`) or decoding, as in xz's
+    /// `simple/arm64.c`.
+    ///
+    /// A BL's offset becomes its absolute target, in instructions. Most
+    /// globals are addressed through ADRP and an ADD or LDR, so an ADRP's
+    /// page offset becomes the absolute 4 KiB page too, but only when it's
+    /// within 512 MiB either way: other words matching ADRP's pattern are
+    /// more likely data. A converted ADRP stays within that range, so the
+    /// decoder converts the same ones.
+    fn convert_arm64(&mut self, data: &mut [u8], encode: bool) -> usize {
+        self.convert_words(data, false, |inst, pos| {
+            if inst >> 26 == 0x25 {
+                // BL: 100101 imm26
+                0x9400_0000 | relocate(inst, pos >> 2, encode) & 0x03FF_FFFF
+            } else if inst & 0x9F00_0000 == 0x9000_0000 {
+                // ADRP: 1 immlo(2) 10000 immhi(19) rd(5), with the top two
+                // bits of immhi past the 512 MiB range
+                let page = (inst >> 29) & 3 | (inst >> 3) & 0x001F_FFFC;
+                if page.wrapping_add(0x0002_0000) & 0x001C_0000 != 0 {
+                    return inst;
+                }
+                let page = relocate(page, pos >> 12, encode);
+                inst & 0x9000_001F
+                    | (page & 3) << 29
+                    | (page & 0x0003_FFFC) << 3
+                    | 0u32.wrapping_sub(page & 0x0002_0000) & 0x00E0_0000
+            } else {
+                inst
             }
+        })
+    }
 
-            i += 4;
-        }
-
-        self.pos += data.len();
-        Ok(())
+    /// ARM64 (AArch64) BCJ encoding (`encode`) or decoding of BL (Branch
+    /// with Link) only.
+    fn convert_arm64_plain(&mut self, data: &mut [u8], encode: bool) -> usize {
+        self.convert_words(data, false, |inst, pos| {
+            // BL: 100101 imm26
+            if inst >> 26 == 0x25 {
+                0x9400_0000 | relocate(inst, pos >> 2, encode) & 0x03FF_FFFF
+            } else {
+                inst
+            }
+        })
     }
 
-    /// ARM64 BCJ decoding.
-    fn decode_arm64(&mut self, data: &mut [u8]) -> Result<()> {
-        if data.len() < 4 {
-            return Ok(());
-        }
+    /// ARM 32-bit BCJ encoding (`encode`) or decoding of BL in ARM mode.
+    ///
+    /// Like xz, only BL with the always condition (0xE) is converted. A
+    /// conditional BL is rarer and more often data, and condition 0xF
+    /// encodes BLX, whose bit 24 is the target's halfword offset rather
+    /// than part of the opcode. [`BcjArch::ArmPlain`] converts them all.
+    fn convert_arm(&mut self, data: &mut [u8], encode: bool) -> usize {
+        let any_condition = self.arch == BcjArch::ArmPlain;
+        self.convert_words(data, false, |inst, pos| {
+            // BL: cond 1011 imm24, relative to the instruction two after
+            // it (the ARM pipeline's PC)
+            if inst >> 24 == 0xEB || any_condition && inst & 0x0F00_0000 == 0x0B00_0000 {
+                let pc = pos.wrapping_add(8);
+                inst & 0xFF00_0000 | relocate(inst, pc >> 2, encode) & 0x00FF_FFFF
+            } else {
+                inst
+            }
+        })
+    }
 
-        let mut i = self.pos & 3;
-        if i != 0 {
-            i = 4 - i;
-        }
+    /// PowerPC64 LE BCJ encoding (`encode`) or decoding of B and BL.
+    fn convert_ppc64(&mut self, data: &mut [u8], encode: bool) -> usize {
+        self.convert_words(data, false, |inst, pos| {
+            // B/BL: 010010 LI(24) AA LK, with instructions little-endian
+            if inst >> 26 == 18 {
+                let li = relocate(inst >> 2, pos >> 2, encode) & 0x00FF_FFFF;
+                inst & 0xFC00_0003 | li << 2
+            } else {
+                inst
+            }
+        })
+    }
 
-        while i + 4 <= data.len() {
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+    /// LoongArch BCJ encoding (`encode`) or decoding of B, BL and
+    /// PCADDU18I.
+    ///
+    /// B and BL split their 26-bit offset in instructions, low 16 bits
+    /// above the high 10. PCADDU18I, which starts calls further than BL
+    /// reaches, adds a 20-bit immediate shifted left 18 to its position,
+    /// so it becomes the absolute 256 KiB unit it yields.
+    fn convert_loongarch(&mut self, data: &mut [u8], encode: bool) -> usize {
+        self.convert_words(data, false, |inst, pos| {
+            if inst >> 27 == 0b01010 {
+                // B and BL: 01010 link offs[15:0] offs[25:16]
+                let offs = (inst >> 10) & 0xFFFF | (inst & 0x3FF) << 16;
+                let offs = relocate(offs, pos >> 2, encode);
+                inst & 0xFC00_0000 | (offs & 0xFFFF) << 10 | (offs >> 16) & 0x3FF
+            } else if inst >> 25 == 0b000_1111 {
+                // PCADDU18I: 0001111 si20 rd
+                let si20 = relocate(inst >> 5, pos >> 18, encode) & 0xF_FFFF;
+                inst & 0xFE00_001F | si20 << 5
+            } else {
+                inst
+            }
+        })
+    }
 
-            if (inst & 0xFC00_0000) == 0x9400_0000 {
-                // Extract absolute address
-                let addr = ((inst & 0x03FF_FFFF) as i32) << 6 >> 4; // Sign extend and multiply by 4
+    /// MIPS BCJ encoding (`encode`) or decoding of BAL and BALC, with
+    /// instructions big-endian unless the filter is [`BcjArch::MipsEl`].
+    ///
+    /// J and JAL are left alone: their 26-bit field already holds the
+    /// target's address within its 256 MiB region, so calls to a function
+    /// are the same bytes wherever they are. The calls that are relative
+    /// are BAL (BGEZAL on $zero, 16-bit offset) and, from release 6, BALC
+    /// (26-bit offset), both counted in instructions from the next one.
+    fn convert_mips(&mut self, data: &mut [u8], encode: bool) -> usize {
+        let big_endian = self.arch == BcjArch::Mips;
+        self.convert_words(data, big_endian, |inst, pos| {
+            let next = pos.wrapping_add(4) >> 2;
+            if inst & 0xFFFF_0000 == 0x0411_0000 {
+                // BAL: 000001 00000 10001 offset(16)
+                inst & 0xFFFF_0000 | relocate(inst, next, encode) & 0xFFFF
+            } else if inst >> 26 == 0x3A {
+                // BALC: 111010 offset(26)
+                inst & 0xFC00_0000 | relocate(inst, next, encode) & 0x03FF_FFFF
+            } else {
+                inst
+            }
+        })
+    }
 
-                // Convert back to relative
-                let offset = addr.wrapping_sub((self.pos + i) as i32) >> 2;
-                let new_inst = (inst & 0xFC00_0000) | ((offset as u32) & 0x03FF_FFFF);
+    /// SPARC BCJ encoding (`encode`) or decoding of CALL, as in xz's
+    /// `simple/sparc.c`.
+    ///
+    /// CALL's 30-bit displacement in words reaches anywhere, but only those
+    /// within 8 MiB either way are converted, since other words matching
+    /// its opcode are more likely data. The absolute target is written back
+    /// sign-extended from that range, so the decoder converts the same
+    /// words.
+    fn convert_sparc(&mut self, data: &mut [u8], encode: bool) -> usize {
+        self.convert_words(data, true, |inst, pos| {
+            // CALL: 01 disp30, with the top nine bits of disp30 all equal
+            if inst >> 22 == 0x100 || inst >> 22 == 0x1FF {
+                let disp = relocate(inst << 2, pos, encode) >> 2;
+                0x4000_0000
+                    | 0u32.wrapping_sub(disp >> 22 & 1) << 22 & 0x3FFF_FFFF
+                    | disp & 0x003F_FFFF
+            } else {
+                inst
+            }
+        })
+    }
 
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
+    /// s390x BCJ encoding (`encode`) or decoding of BRASL and BRCL.
+    ///
+    /// Instructions are 2, 4 or 6 bytes long, as the top two bits of their
+    /// first byte say (00, 01 or 10, and 11), and are walked by that length
+    /// so operands aren't mistaken for opcodes. BRASL (call) and BRCL
+    /// (conditional jump, or plain jump under mask 15) are 6-byte RIL
+    /// instructions whose big-endian 32-bit operand counts halfwords from
+    /// the instruction; it becomes the absolute target, in halfwords.
+    /// Only operand bytes change, so the decoder walks the same
+    /// instructions.
+    fn convert_s390x(&mut self, data: &mut [u8], encode: bool) -> usize {
+        let mut i = 0;
+        while i < data.len() {
+            let len = match data[i] >> 6 {
+                0b00 => 2,
+                0b01 | 0b10 => 4,
+                _ => 6,
+            };
+            if i + len > data.len() {
+                break;
             }
 
-            i += 4;
+            // BRCL: C0 m 4 ri(32); BRASL: C0 r 5 ri(32)
+            if data[i] == 0xC0 && matches!(data[i + 1] & 0x0F, 0x4 | 0x5) {
+                let operand = [data[i + 2], data[i + 3], data[i + 4], data[i + 5]];
+                let pos = ((self.pos + i) >> 1) as u32;
+                let converted = relocate(u32::from_be_bytes(operand), pos, encode);
+                data[i + 2..i + 6].copy_from_slice(&converted.to_be_bytes());
+            }
+            i += len;
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// ARM 32-bit BCJ encoding (simplified - handles BL in ARM mode).
-    fn encode_arm(&mut self, data: &mut [u8]) -> Result<()> {
-        // Similar to ARM64 but with different instruction format
-        // BL: cccc 1011 xxxx xxxx xxxx xxxx xxxx xxxx
+    /// Replaces each 4-byte-aligned word of `data`, little-endian unless
+    /// `big_endian`, with `convert(word, position)`.
+    ///
+    /// Positions are taken modulo 2^32, and offsets are only ever added or
+    /// subtracted modulo their field's width, so encoding and decoding are
+    /// exact inverses whatever the offset or position.
+    fn convert_words(
+        &mut self,
+        data: &mut [u8],
+        big_endian: bool,
+        convert: impl Fn(u32, u32) -> u32,
+    ) -> usize {
         if data.len() < 4 {
-            return Ok(());
+            return 0;
         }
 
-        let mut i = self.pos & 3;
+        let mut i = self.pos & 3; // Align to 4-byte boundary
         if i != 0 {
             i = 4 - i;
         }
 
         while i + 4 <= data.len() {
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-
-            // Check for BL instruction (bits 27-24 = 1011)
-            if (inst & 0x0F00_0000) == 0x0B00_0000 {
-                // Extract 24-bit signed offset
-                let offset = ((inst & 0x00FF_FFFF) as i32) << 8 >> 6; // Sign extend, multiply by 4
-
-                // Convert to absolute
-                let addr = ((self.pos + i + 8) as i32).wrapping_add(offset); // +8 for ARM pipeline
-
-                // Store as new offset
-                let new_offset = ((addr >> 2) as u32) & 0x00FF_FFFF;
-                let new_inst = (inst & 0xFF00_0000) | new_offset;
-
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
+            let bytes = [data[i], data[i + 1], data[i + 2], data[i + 3]];
+            if big_endian {
+                let converted = convert(u32::from_be_bytes(bytes), (self.pos + i) as u32);
+                data[i..i + 4].copy_from_slice(&converted.to_be_bytes());
+            } else {
+                let converted = convert(u32::from_le_bytes(bytes), (self.pos + i) as u32);
+                data[i..i + 4].copy_from_slice(&converted.to_le_bytes());
             }
-
             i += 4;
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// ARM 32-bit BCJ decoding.
-    fn decode_arm(&mut self, data: &mut [u8]) -> Result<()> {
-        if data.len() < 4 {
-            return Ok(());
-        }
-
-        let mut i = self.pos & 3;
-        if i != 0 {
-            i = 4 - i;
-        }
-
-        while i + 4 <= data.len() {
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-
-            if (inst & 0x0F00_0000) == 0x0B00_0000 {
-                let addr = ((inst & 0x00FF_FFFF) as i32) << 8 >> 6;
-                let offset = addr.wrapping_sub((self.pos + i + 8) as i32) >> 2;
-                let new_inst = (inst & 0xFF00_0000) | ((offset as u32) & 0x00FF_FFFF);
-
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
+    /// RISC-V BCJ encoding (`encode`) or decoding of JAL and AUIPC.
+    ///
+    /// Instructions are walked by their length, which the low two bits of
+    /// each one's first halfword give (0b11 for 32 bits, anything else for
+    /// a 16-bit compressed instruction), so compressed code doesn't put the
+    /// walk out of step. A JAL's offset becomes its absolute target and an
+    /// AUIPC's immediate the absolute 4 KiB page it yields. Both keep their
+    /// low 12 bits, so the decoder walks the same instructions.
+    fn convert_riscv(&mut self, data: &mut [u8], encode: bool) -> usize {
+        let mut i = 0;
+        while i + 2 <= data.len() {
+            if data[i] & 0b11 != 0b11 {
+                i += 2;
+                continue;
+            }
+            if i + 4 > data.len() {
+                break;
             }
 
+            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+            // Instructions are halfword-aligned, so this only drops the
+            // bit an odd starting position would add.
+            let pos = (self.pos + i) as u32 & !1;
+            let converted = match inst & 0x7F {
+                // JAL: imm[20|10:1|11|19:12] rd opcode
+                0x6F => {
+                    let offset = ((inst >> 31) & 1) << 20
+                        | ((inst >> 12) & 0xFF) << 12
+                        | ((inst >> 20) & 1) << 11
+                        | ((inst >> 21) & 0x3FF) << 1;
+                    let offset = relocate(offset, pos, encode);
+                    (inst & 0xFFF)
+                        | (offset & 0xFF000)
+                        | ((offset >> 11) & 1) << 20
+                        | ((offset >> 1) & 0x3FF) << 21
+                        | ((offset >> 20) & 1) << 31
+                }
+                // AUIPC: imm[31:12] rd opcode
+                0x17 => {
+                    let page = relocate(inst >> 12, pos >> 12, encode);
+                    (inst & 0xFFF) | page << 12
+                }
+                _ => inst,
+            };
+            data[i..i + 4].copy_from_slice(&converted.to_le_bytes());
             i += 4;
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// RISC-V BCJ encoding (JAL and AUIPC instructions).
-    fn encode_riscv(&mut self, data: &mut [u8]) -> Result<()> {
+    /// RISC-V BCJ encoding of JAL, four bytes at a time.
+    fn encode_riscv_plain(&mut self, data: &mut [u8]) -> usize {
         // RISC-V has complex instruction encoding, simplified version
         // JAL: imm[20|10:1|11|19:12] rd opcode (opcode = 1101111)
         if data.len() < 4 {
-            return Ok(());
+            return 0;
         }
 
         let mut i = self.pos & 1; // 2-byte alignment for compressed
@@ -351,10 +851,8 @@ impl BcjFilter {
                 let imm11 = (inst >> 20) & 1;
                 let imm19_12 = (inst >> 12) & 0xFF;
 
-                let offset = ((imm20 << 20)
-                    | (imm19_12 << 12)
-                    | (imm11 << 11)
-                    | (imm10_1 << 1)) as i32;
+                let offset =
+                    ((imm20 << 20) | (imm19_12 << 12) | (imm11 << 11) | (imm10_1 << 1)) as i32;
                 let offset = (offset << 11) >> 11; // Sign extend from bit 20
 
                 // Convert to absolute
@@ -363,10 +861,10 @@ impl BcjFilter {
                 // Re-encode with new address
                 let new_imm = addr as u32;
                 let new_inst = (inst & 0xFFF)
-                    | ((new_imm & 0xFF000) << 0)      // imm[19:12]
+                    | (new_imm & 0xFF000)             // imm[19:12]
                     | (((new_imm >> 11) & 1) << 20)   // imm[11]
                     | (((new_imm >> 1) & 0x3FF) << 21) // imm[10:1]
-                    | (((new_imm >> 20) & 1) << 31);  // imm[20]
+                    | (((new_imm >> 20) & 1) << 31); // imm[20]
 
                 let bytes = new_inst.to_le_bytes();
                 data[i] = bytes[0];
@@ -378,15 +876,15 @@ impl BcjFilter {
             i += 4; // Could be 2 for compressed, but simplified
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
 
-    /// RISC-V BCJ decoding.
-    fn decode_riscv(&mut self, data: &mut [u8]) -> Result<()> {
+    /// RISC-V BCJ decoding of JAL, four bytes at a time.
+    fn decode_riscv_plain(&mut self, data: &mut [u8]) -> usize {
         // Reverse of encode - similar structure
         if data.len() < 4 {
-            return Ok(());
+            return 0;
         }
 
         let mut i = self.pos & 1;
@@ -404,10 +902,8 @@ impl BcjFilter {
                 let imm11 = (inst >> 20) & 1;
                 let imm19_12 = (inst >> 12) & 0xFF;
 
-                let addr = ((imm20 << 20)
-                    | (imm19_12 << 12)
-                    | (imm11 << 11)
-                    | (imm10_1 << 1)) as i32;
+                let addr =
+                    ((imm20 << 20) | (imm19_12 << 12) | (imm11 << 11) | (imm10_1 << 1)) as i32;
                 let addr = (addr << 11) >> 11;
 
                 // Convert back to relative
@@ -416,7 +912,7 @@ impl BcjFilter {
                 // Re-encode
                 let new_imm = offset as u32;
                 let new_inst = (inst & 0xFFF)
-                    | ((new_imm & 0xFF000) << 0)
+                    | (new_imm & 0xFF000)
                     | (((new_imm >> 11) & 1) << 20)
                     | (((new_imm >> 1) & 0x3FF) << 21)
                     | (((new_imm >> 20) & 1) << 31);
@@ -431,88 +927,59 @@ impl BcjFilter {
             i += 4;
         }
 
-        self.pos += data.len();
-        Ok(())
+        self.pos += i;
+        i
     }
+}
 
-    /// PowerPC64 LE BCJ encoding.
-    fn encode_ppc64(&mut self, data: &mut [u8]) -> Result<()> {
-        // PPC64 branch instructions
-        if data.len() < 4 {
-            return Ok(());
-        }
-
-        let mut i = self.pos & 3;
-        if i != 0 {
-            i = 4 - i;
-        }
-
-        while i + 4 <= data.len() {
-            // PPC is big-endian instructions but PPC64LE is little-endian
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-
-            // Branch (b, bl): opcode 18 (bits 0-5 = 010010)
-            let opcode = (inst >> 26) & 0x3F;
-            if opcode == 18 {
-                // LI field: bits 6-29 (24 bits, but only 26-bit signed offset)
-                let li = (inst >> 2) & 0x00FF_FFFF;
-                let offset = ((li as i32) << 8) >> 6; // Sign extend and multiply by 4
-
-                let addr = ((self.pos + i) as i32).wrapping_add(offset);
-
-                let new_li = ((addr >> 2) as u32) & 0x00FF_FFFF;
-                let new_inst = (inst & 0xFC00_0003) | (new_li << 2);
-
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
-            }
+/// Writer that BCJ-encodes (or decodes) data on its way to `inner`, for
+/// filtering data too large to hold in memory.
+///
+/// Output is identical to [`BcjFilter::encode`] (or [`BcjFilter::decode`])
+/// on the whole input at once. Only the few bytes of an instruction split
+/// across writes are held back until the next write, or
+/// [`finish`](Self::finish), as [`BcjFilter::encode_chunk`] holds them.
+pub struct BcjWriter<W: Write> {
+    filter: BcjFilter,
+    inner: W,
+    /// Whether to decode rather than encode.
+    decode: bool,
+}
 
-            i += 4;
+impl<W: Write> BcjWriter<W> {
+    /// Create a writer encoding for `arch` into `inner`.
+    pub fn new(arch: BcjArch, inner: W) -> Self {
+        Self {
+            filter: BcjFilter::new(arch),
+            inner,
+            decode: false,
         }
-
-        self.pos += data.len();
-        Ok(())
     }
 
-    /// PowerPC64 LE BCJ decoding.
-    fn decode_ppc64(&mut self, data: &mut [u8]) -> Result<()> {
-        if data.len() < 4 {
-            return Ok(());
-        }
-
-        let mut i = self.pos & 3;
-        if i != 0 {
-            i = 4 - i;
+    /// Create a writer decoding for `arch` into `inner`.
+    pub fn decoder(arch: BcjArch, inner: W) -> Self {
+        Self {
+            decode: true,
+            ..Self::new(arch, inner)
         }
+    }
 
-        while i + 4 <= data.len() {
-            let inst = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
-
-            let opcode = (inst >> 26) & 0x3F;
-            if opcode == 18 {
-                let li = (inst >> 2) & 0x00FF_FFFF;
-                let addr = ((li as i32) << 8) >> 6;
-
-                let offset = addr.wrapping_sub((self.pos + i) as i32);
-
-                let new_li = ((offset >> 2) as u32) & 0x00FF_FFFF;
-                let new_inst = (inst & 0xFC00_0003) | (new_li << 2);
-
-                let bytes = new_inst.to_le_bytes();
-                data[i] = bytes[0];
-                data[i + 1] = bytes[1];
-                data[i + 2] = bytes[2];
-                data[i + 3] = bytes[3];
-            }
+    /// Write out the bytes held back and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&self.filter.finish())?;
+        Ok(self.inner)
+    }
+}
 
-            i += 4;
-        }
+impl<W: Write> Write for BcjWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let done = self.filter.push(buf, self.decode);
+        self.inner.write_all(&done)?;
+        Ok(buf.len())
+    }
 
-        self.pos += data.len();
-        Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
@@ -528,10 +995,63 @@ pub fn bcj_decode(data: &mut [u8], arch: BcjArch) -> Result<()> {
     filter.decode(data)
 }
 
+/// Encode only `ranges` of `data`, each filtered as it would be within the
+/// whole buffer, leaving the bytes outside them unchanged.
+pub fn bcj_encode_ranges(data: &mut [u8], arch: BcjArch, ranges: &[Range<usize>]) -> Result<()> {
+    for range in ranges {
+        range_filter(data, arch, range)?.encode(&mut data[range.clone()])?;
+    }
+    Ok(())
+}
+
+/// Reverse [`bcj_encode_ranges`] over the same `ranges`.
+pub fn bcj_decode_ranges(data: &mut [u8], arch: BcjArch, ranges: &[Range<usize>]) -> Result<()> {
+    for range in ranges {
+        range_filter(data, arch, range)?.decode(&mut data[range.clone()])?;
+    }
+    Ok(())
+}
+
+/// Returns a filter positioned at the start of `range`, once it's checked
+/// to lie within `data`.
+fn range_filter(data: &[u8], arch: BcjArch, range: &Range<usize>) -> Result<BcjFilter> {
+    if range.start > range.end || range.end > data.len() {
+        return Err(CompressionError::InvalidData(format!(
+            "BCJ range {}..{} is outside the {} bytes filtered",
+            range.start,
+            range.end,
+            data.len()
+        )));
+    }
+    Ok(BcjFilter {
+        pos: range.start,
+        ..BcjFilter::new(arch)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Every filter but [`BcjArch::None`].
+    const FILTERS: [BcjArch; 15] = [
+        BcjArch::X86,
+        BcjArch::X86Jcc,
+        BcjArch::X86Plain,
+        BcjArch::Arm,
+        BcjArch::ArmPlain,
+        BcjArch::Arm64,
+        BcjArch::Arm64Plain,
+        BcjArch::RiscV,
+        BcjArch::RiscVPlain,
+        BcjArch::Ppc64Le,
+        BcjArch::LoongArch,
+        BcjArch::Mips,
+        BcjArch::MipsEl,
+        BcjArch::S390x,
+        BcjArch::Sparc,
+    ];
+
     #[test]
     fn test_x86_roundtrip() {
         // Simulate x86 CALL instruction: E8 followed by relative offset
@@ -556,11 +1076,874 @@ mod tests {
 
     #[test]
     fn test_arch_detection() {
-        assert_eq!(BcjArch::from_target("x86_64-unknown-linux-gnu"), BcjArch::X86);
+        assert_eq!(
+            BcjArch::from_target("x86_64-unknown-linux-gnu"),
+            BcjArch::X86
+        );
         assert_eq!(BcjArch::from_target("aarch64-apple-darwin"), BcjArch::Arm64);
-        assert_eq!(BcjArch::from_target("armv7-unknown-linux-gnueabihf"), BcjArch::Arm);
-        assert_eq!(BcjArch::from_target("riscv64gc-unknown-linux-gnu"), BcjArch::RiscV);
+        assert_eq!(
+            BcjArch::from_target("armv7-unknown-linux-gnueabihf"),
+            BcjArch::Arm
+        );
+        assert_eq!(
+            BcjArch::from_target("riscv64gc-unknown-linux-gnu"),
+            BcjArch::RiscV
+        );
         assert_eq!(BcjArch::from_target("wasm32-wasip1"), BcjArch::None);
+        assert_eq!(BcjArch::from_target("linux-mips64"), BcjArch::Mips);
+        assert_eq!(
+            BcjArch::from_target("loongarch64-unknown-linux-gnu"),
+            BcjArch::LoongArch
+        );
+        assert_eq!(
+            BcjArch::from_target("mips64el-unknown-linux-gnuabi64"),
+            BcjArch::MipsEl
+        );
+        assert_eq!(
+            BcjArch::from_target("mipsel-unknown-linux-gnu"),
+            BcjArch::MipsEl
+        );
+        assert_eq!(BcjArch::from_target("android-i686"), BcjArch::X86);
+        assert_eq!(
+            BcjArch::from_target("s390x-unknown-linux-gnu"),
+            BcjArch::S390x
+        );
+        assert_eq!(
+            BcjArch::from_target("sparc64-unknown-netbsd"),
+            BcjArch::Sparc
+        );
+        assert_eq!(BcjArch::from_target("illumos-sparcv9"), BcjArch::Sparc);
+        assert_eq!(BcjArch::from_target("tool/windows-x86"), BcjArch::X86);
+    }
+
+    #[test]
+    fn test_every_target_has_its_filter() {
+        // No wildcard, so a new target doesn't build until its filter is
+        // chosen here.
+        let expected = |target: Target| match target {
+            Target::LinuxX86_64
+            | Target::LinuxX86_64Musl
+            | Target::LinuxI686
+            | Target::DarwinX86_64
+            | Target::WindowsX86_64
+            | Target::WindowsX86
+            | Target::WindowsX86_64Gnu
+            | Target::FreebsdX86_64
+            | Target::NetbsdX86_64
+            | Target::OpenbsdX86_64
+            | Target::DragonflyX86_64
+            | Target::IllumosX86_64
+            | Target::HaikuX86_64
+            | Target::AndroidX86_64
+            | Target::AndroidI686 => BcjArch::X86,
+            Target::LinuxAarch64
+            | Target::LinuxAarch64Musl
+            | Target::DarwinAarch64
+            | Target::WindowsAarch64
+            | Target::FreebsdAarch64
+            | Target::AndroidAarch64
+            | Target::IosAarch64
+            | Target::TvosAarch64
+            | Target::WatchosAarch64 => BcjArch::Arm64,
+            Target::LinuxArmv7 | Target::LinuxArmv6 | Target::AndroidArmv7 => BcjArch::Arm,
+            Target::LinuxRiscv64 => BcjArch::RiscV,
+            Target::LinuxPpc64le => BcjArch::Ppc64Le,
+            Target::LinuxS390x => BcjArch::S390x,
+            Target::LinuxMips64 => BcjArch::Mips,
+            Target::LinuxLoongarch64 => BcjArch::LoongArch,
+            Target::WasiWasm32 => BcjArch::None,
+        };
+
+        for &target in Target::all() {
+            let arch = expected(target);
+            assert_eq!(BcjArch::for_target(target), arch, "{}", target);
+            // Names, keys and triples of targets all come to the same.
+            for name in [
+                target.as_str().to_string(),
+                format!("tool/{}", target),
+                target.rust_triple().to_string(),
+            ] {
+                assert_eq!(BcjArch::from_target(&name), arch, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_arch_name_roundtrip() {
+        for arch in FILTERS.into_iter().chain([BcjArch::None]) {
+            assert_eq!(BcjArch::from_name(arch.as_str()), Some(arch));
+        }
+        assert_eq!(BcjArch::from_name("ia64"), None);
+        // Files written before the xz-style filters record "x86", "arm"
+        // and "arm64", and before the RISC-V filter walked compressed
+        // instructions "riscv".
+        assert_eq!(BcjArch::from_name("x86"), Some(BcjArch::X86Plain));
+        assert_eq!(BcjArch::from_name("arm"), Some(BcjArch::ArmPlain));
+        assert_eq!(BcjArch::from_name("riscv"), Some(BcjArch::RiscVPlain));
+        assert_eq!(BcjArch::from_name("arm64"), Some(BcjArch::Arm64Plain));
+    }
+
+    /// Up to 512 KiB of this test binary's code.
+    fn compiled_code() -> Vec<u8> {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let ranges = crate::segment::executable_ranges(&exe).unwrap();
+        let text = ranges.iter().max_by_key(|r| r.len()).unwrap().clone();
+        exe[text.start..text.end.min(text.start + 512 * 1024)].to_vec()
+    }
+
+    #[test]
+    fn test_x86_matches_xz() {
+        #[rustfmt::skip]
+        let original = [
+            0x55, 0xE8, 0x10, 0x00, 0x00, 0x00, // call +16
+            0xE8, 0x34, 0x12, 0x00, 0x7F, // high byte 0x7f: not a call
+            0xE9, 0xF0, 0xFF, 0xFF, 0xFF, // jmp -16, just after it
+            0x90, 0x90, 0x90,
+            0xE8, 0xE8, 0x00, 0x00, 0x00, 0x00, // back-to-back candidates
+            0x48, 0x89, 0xC7,
+            0xE8, 0xFB, 0xFF, 0xFF, 0xFF, // call -5
+            0x90, 0x90, 0x90, 0x90, 0x90, 0x90,
+            0xE9, 0x00, 0x00, 0x00, 0x00, // jmp +0
+            0xE8, 0xC3, 0x90, 0x90, // too close to the end
+        ];
+        // `xz --format=raw --x86 --lzma2` output, decompressed by LZMA2
+        // alone.
+        #[rustfmt::skip]
+        let expected = [
+            0x55, 0xE8, 0x16, 0x00, 0x00, 0x00,
+            0xE8, 0x34, 0x12, 0x00, 0x7F,
+            0xE9, 0x00, 0x00, 0x00, 0x00,
+            0x90, 0x90, 0x90,
+            0xE8, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x48, 0x89, 0xC7,
+            0xE8, 0x1C, 0x00, 0x00, 0x00,
+            0x90, 0x90, 0x90, 0x90, 0x90, 0x90,
+            0xE9, 0x2C, 0x00, 0x00, 0x00,
+            0xE8, 0xC3, 0x90, 0x90,
+        ];
+
+        let mut data = original;
+        bcj_encode(&mut data, BcjArch::X86).unwrap();
+        assert_eq!(data, expected);
+        bcj_decode(&mut data, BcjArch::X86).unwrap();
+        assert_eq!(data, original);
+    }
+
+    #[test]
+    fn test_x86_roundtrips_dense_candidates() {
+        // Candidates with every kind of high byte, back to back and a few
+        // bytes apart, to exercise the mask state, and real code.
+        let mut data = Vec::new();
+        for i in 0..4096u32 {
+            let gap = (i % 7) as usize;
+            data.push(if i % 3 == 0 { 0xE8 } else { 0xE9 });
+            data.extend_from_slice(&i.wrapping_mul(2_654_435_761).to_le_bytes()[..gap.min(4)]);
+            data.extend_from_slice(&[[0x00, 0xFF, 0xE8, 0x12][i as usize % 4]; 3][..gap % 4]);
+        }
+
+        for original in [data, compiled_code()] {
+            let mut encoded = original.clone();
+            bcj_encode(&mut encoded, BcjArch::X86).unwrap();
+            assert!(encoded != original);
+            bcj_decode(&mut encoded, BcjArch::X86).unwrap();
+            assert!(encoded == original);
+        }
+    }
+
+    // This test binary's code is only x86 code on an x86 host.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_x86_mask_improves_ratio() {
+        let code = compiled_code();
+        let compressed_size = |arch| {
+            let mut data = code.clone();
+            bcj_encode(&mut data, arch).unwrap();
+            crate::dict::compress(&data, 9).unwrap().len()
+        };
+        let (none, plain, masked) = (
+            compressed_size(BcjArch::None),
+            compressed_size(BcjArch::X86Plain),
+            compressed_size(BcjArch::X86),
+        );
+        // Leaving operands that aren't near branches alone saves about 3%
+        // over converting every E8/E9 on 512 KiB of this binary's code.
+        assert!(
+            masked * 100 < plain * 99 && plain < none,
+            "none {} plain {} masked {}",
+            none,
+            plain,
+            masked
+        );
+    }
+
+    #[test]
+    fn test_x86_jcc() {
+        #[rustfmt::skip]
+        let original = [
+            0x0F, 0x84, 0x10, 0x00, 0x00, 0x00, // je +16
+            0xE8, 0x10, 0x00, 0x00, 0x00, // call +16
+            0x0F, 0x8F, 0xF0, 0xFF, 0xFF, 0xFF, // jg -16
+            0x0F, 0x85, 0x12, 0x34, 0x56, 0x78, // high byte 0x78: not a jump
+            0x0F, 0x85, 0x00, 0x00, 0x00, // too close to the end
+        ];
+        #[rustfmt::skip]
+        let expected = [
+            0x0F, 0x84, 0x16, 0x00, 0x00, 0x00,
+            0xE8, 0x1B, 0x00, 0x00, 0x00,
+            0x0F, 0x8F, 0x01, 0x00, 0x00, 0x00,
+            0x0F, 0x85, 0x12, 0x34, 0x56, 0x78,
+            0x0F, 0x85, 0x00, 0x00, 0x00,
+        ];
+
+        let mut data = original;
+        bcj_encode(&mut data, BcjArch::X86Jcc).unwrap();
+        assert_eq!(data, expected);
+        bcj_decode(&mut data, BcjArch::X86Jcc).unwrap();
+        assert_eq!(data, original);
+
+        let mut data = original;
+        bcj_encode(&mut data, BcjArch::X86).unwrap();
+        assert_eq!(data[..6], original[..6]);
+    }
+
+    #[test]
+    fn test_x86_jcc_roundtrips_overlapping_candidates() {
+        // Short buffers drawn mostly from opcode and high bytes, so Jcc,
+        // CALL and JMP candidates overlap each other's operands every way.
+        const BYTES: [u8; 8] = [0x0F, 0x84, 0x8F, 0xE8, 0xE9, 0x00, 0xFF, 0x12];
+        let mut state = 0x9E37_79B9u32;
+        for _ in 0..20_000 {
+            let len = 5 + state as usize % 28;
+            let original: Vec<u8> = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    BYTES[state as usize % BYTES.len()]
+                })
+                .collect();
+
+            let mut data = original.clone();
+            bcj_encode(&mut data, BcjArch::X86Jcc).unwrap();
+            bcj_decode(&mut data, BcjArch::X86Jcc).unwrap();
+            assert!(data == original, "{:02X?}", original);
+        }
+
+        let mut data = compiled_code();
+        let original = data.clone();
+        bcj_encode(&mut data, BcjArch::X86Jcc).unwrap();
+        bcj_decode(&mut data, BcjArch::X86Jcc).unwrap();
+        assert!(data == original);
+    }
+
+    /// A branch of `arch` to `offset` bytes from its own position (or from
+    /// the PC an ARM or MIPS branch is relative to), in the filter's byte
+    /// order.
+    fn branch(arch: BcjArch, offset: u32) -> [u8; 4] {
+        let word = branch_word(arch, offset);
+        if arch == BcjArch::Sparc {
+            word.to_be_bytes()
+        } else {
+            word.to_le_bytes()
+        }
+    }
+
+    /// The instruction word [`branch`] stores.
+    fn branch_word(arch: BcjArch, offset: u32) -> u32 {
+        match arch {
+            BcjArch::Arm | BcjArch::ArmPlain => 0xEB00_0000 | (offset >> 2) & 0x00FF_FFFF,
+            BcjArch::Arm64 | BcjArch::Arm64Plain => 0x9400_0000 | (offset >> 2) & 0x03FF_FFFF,
+            BcjArch::Ppc64Le => 0x4800_0001 | offset & 0x03FF_FFFC,
+            BcjArch::MipsEl => 0xE800_0000 | (offset >> 2) & 0x03FF_FFFF,
+            BcjArch::Sparc => 0x4000_0000 | (offset >> 2) & 0x3FFF_FFFF,
+            BcjArch::LoongArch => {
+                0x5400_0000 | (offset >> 2 & 0xFFFF) << 10 | (offset >> 18) & 0x3FF
+            }
+            BcjArch::RiscV | BcjArch::RiscVPlain => {
+                0xEF | (offset & 0xFF000)
+                    | ((offset >> 11) & 1) << 20
+                    | ((offset >> 1) & 0x3FF) << 21
+                    | ((offset >> 20) & 1) << 31
+            }
+            _ => unreachable!("{:?} isn't a word-size filter", arch),
+        }
+    }
+
+    #[test]
+    fn test_word_filters_invert_any_offset() {
+        // (filter, bits of branch offset, alignment)
+        let filters = [
+            (BcjArch::Arm, 26, 4),
+            (BcjArch::ArmPlain, 26, 4),
+            (BcjArch::Arm64, 28, 4),
+            (BcjArch::Arm64Plain, 28, 4),
+            (BcjArch::Ppc64Le, 26, 4),
+            (BcjArch::MipsEl, 28, 4),
+            (BcjArch::LoongArch, 28, 4),
+            (BcjArch::Sparc, 24, 4),
+            (BcjArch::RiscV, 21, 2),
+            (BcjArch::RiscVPlain, 21, 2),
+        ];
+        for n in 0..200_000u64 {
+            let (arch, bits, align) = filters[n as usize % filters.len()];
+            let random = crate::delta::mix(n);
+            // Offsets at either end of the range, then anywhere in it.
+            let offset = match n / 6 % 4 {
+                0 => (1u32 << (bits - 1)) - align as u32,
+                1 => 0u32.wrapping_sub(1 << (bits - 1)),
+                _ => (random as u32) << (32 - bits) >> (32 - bits),
+            } & !(align as u32 - 1);
+            // Positions anywhere, including past 4 GiB, and a second one
+            // for a branch to the same target.
+            let pos = (random >> 24) as usize & !(align - 1);
+            let other = pos.wrapping_add((random as usize >> 8) & 0xFFF0);
+            let target = (pos as u32).wrapping_add(offset);
+
+            let word = branch(arch, offset);
+            let mut data = word;
+            BcjFilter {
+                pos,
+                ..BcjFilter::new(arch)
+            }
+            .encode(&mut data)
+            .unwrap();
+            let encoded = data;
+            BcjFilter {
+                pos,
+                ..BcjFilter::new(arch)
+            }
+            .decode(&mut data)
+            .unwrap();
+            assert_eq!(data, word, "{:?} offset {:#x} at {:#x}", arch, offset, pos);
+
+            // Branches to one target become the same bytes from anywhere.
+            let mut data = branch(arch, target.wrapping_sub(other as u32));
+            BcjFilter {
+                pos: other,
+                ..BcjFilter::new(arch)
+            }
+            .encode(&mut data)
+            .unwrap();
+            assert_eq!(
+                data, encoded,
+                "{:?} to {:#x} from {:#x}",
+                arch, target, other
+            );
+        }
+    }
+
+    #[test]
+    fn test_word_filters_match_xz() {
+        // Branches 0x100 bytes in: to +0, to either end of the range, to
+        // just behind them and to an arbitrary offset.
+        #[rustfmt::skip]
+        let vectors = [
+            (
+                BcjArch::Arm, // xz --arm
+                [0xEB00_0000, 0xEB7F_FFFF, 0xEB80_0000, 0xEBFF_FFFE, 0xEB12_3456],
+                [0xEB00_0042, 0xEB80_0042, 0xEB80_0044, 0xEB00_0043, 0xEB12_349C],
+            ),
+            (
+                BcjArch::Arm64Plain, // BL under xz --arm64
+                [0x9400_0000, 0x95FF_FFFF, 0x9600_0000, 0x97FF_FFFF, 0x9412_3456],
+                [0x9400_0040, 0x9600_0040, 0x9600_0042, 0x9400_0042, 0x9412_349A],
+            ),
+            (
+                // xz --powerpc, on each word byte-swapped to big-endian
+                BcjArch::Ppc64Le,
+                [0x4800_0001, 0x49FF_FFFD, 0x4A00_0001, 0x4BFF_FFFD, 0x4812_3455],
+                [0x4800_0101, 0x4A00_0101, 0x4A00_0109, 0x4800_0109, 0x4812_3565],
+            ),
+            (
+                // xz --sparc, whose last word is a CALL past 8 MiB
+                BcjArch::Sparc,
+                [0x4000_0000, 0x403F_FFFF, 0x7FC0_0000, 0x7FFF_FFFF, 0x4040_0000],
+                [0x4000_0040, 0x7FC0_0040, 0x7FC0_0042, 0x4000_0042, 0x4040_0000],
+            ),
+        ];
+        for (arch, words, expected) in vectors {
+            let to_bytes = if arch == BcjArch::Sparc {
+                u32::to_be_bytes
+            } else {
+                u32::to_le_bytes
+            };
+            let bytes = |words: &[u32]| -> Vec<u8> {
+                std::iter::repeat_n(0, 0x40)
+                    .chain(words.iter().copied())
+                    .flat_map(to_bytes)
+                    .collect()
+            };
+            let original = bytes(&words);
+            let mut data = original.clone();
+            bcj_encode(&mut data, arch).unwrap();
+            assert!(data == bytes(&expected), "{:?}", arch);
+            bcj_decode(&mut data, arch).unwrap();
+            assert!(data == original, "{:?}", arch);
+        }
+    }
+
+    #[test]
+    fn test_loongarch_split_immediates() {
+        // (word 0x100 bytes in, its offset or immediate after encoding)
+        #[rustfmt::skip]
+        let cases = [
+            (0x5400_0000u32, 0x40), // bl +0
+            (0x57FF_FFFF, 0x3F), // bl -4
+            (0x57FF_FDFF, 0x0200_003F), // bl +128 MiB - 4, the furthest
+            (0x5400_0200, 0x0200_0040), // bl -128 MiB, the furthest back
+            (0x5000_0400, 0x41), // b +4
+            (0x1E00_0024, 0), // pcaddu18i $a0, 1, still in unit 0
+        ];
+        for (word, field) in cases {
+            let original: Vec<u8> = std::iter::repeat_n(0x0340_0000u32, 0x40) // nop
+                .chain([word])
+                .flat_map(u32::to_le_bytes)
+                .collect();
+            let mut data = original.clone();
+            bcj_encode(&mut data, BcjArch::LoongArch).unwrap();
+            let encoded = u32::from_le_bytes(data[0x100..].try_into().unwrap());
+            let offs = (encoded >> 10) & 0xFFFF | (encoded & 0x3FF) << 16;
+            if word >> 25 == 0b000_1111 {
+                assert_eq!(encoded >> 5 & 0xF_FFFF, (word >> 5 & 0xF_FFFF) + field);
+            } else {
+                assert_eq!(offs, field, "{:#010x}", word);
+                assert_eq!(encoded & 0xFC00_0000, word & 0xFC00_0000);
+            }
+            bcj_decode(&mut data, BcjArch::LoongArch).unwrap();
+            assert!(data == original, "{:#010x}", word);
+        }
+
+        // A PCADDU18I 1 MiB in with immediate -1 yields the same 256 KiB
+        // unit as one at the start with immediate 3, and encodes the same.
+        let far = |pos: usize, si20: u32| {
+            let mut data = (0x1E00_0001 | si20 << 5).to_le_bytes();
+            BcjFilter {
+                pos,
+                ..BcjFilter::new(BcjArch::LoongArch)
+            }
+            .encode(&mut data)
+            .unwrap();
+            data
+        };
+        assert_eq!(far(1 << 20, 0xF_FFFF), far(0, 3));
+    }
+
+    #[test]
+    fn test_mips_converts_relative_calls() {
+        // Eight places calling the function at 0x400 with BAL, BALC, JAL
+        // and J, between NOPs.
+        let mut words = vec![0u32; 0x100];
+        let calls: Vec<usize> = (0..8).map(|n| 0x40 + n * 0x18).collect();
+        for &at in &calls {
+            let next = |at: usize| (0x400u32).wrapping_sub(at as u32 * 4 + 4) >> 2;
+            words[at] = 0x0411_0000 | next(at) & 0xFFFF;
+            words[at + 2] = 0xE800_0000 | next(at + 2) & 0x03FF_FFFF;
+            words[at + 3] = 0x0C00_0000 | 0x400 >> 2;
+            words[at + 5] = 0x0800_0000 | 0x400 >> 2;
+        }
+
+        for arch in [BcjArch::Mips, BcjArch::MipsEl] {
+            let to_bytes = if arch == BcjArch::Mips {
+                u32::to_be_bytes
+            } else {
+                u32::to_le_bytes
+            };
+            let original: Vec<u8> = words.iter().flat_map(|&w| to_bytes(w)).collect();
+            let word = |data: &[u8], at: usize| data[at * 4..at * 4 + 4].to_vec();
+            let mut data = original.clone();
+            bcj_encode(&mut data, arch).unwrap();
+
+            // BAL and BALC to the function become the same bytes from each
+            // place; J and JAL already were, and are left alone.
+            assert_ne!(word(&data, calls[0]), word(&original, calls[0]));
+            for &at in &calls {
+                for call in [at, at + 2] {
+                    assert_eq!(word(&data, call), word(&data, call - at + calls[0]));
+                }
+                for jump in [at + 3, at + 5] {
+                    assert_eq!(word(&data, jump), word(&original, jump));
+                }
+            }
+            bcj_decode(&mut data, arch).unwrap();
+            assert!(data == original, "{:?}", arch);
+
+            // Read with the other byte order, none of them is a call.
+            let other = if arch == BcjArch::Mips {
+                BcjArch::MipsEl
+            } else {
+                BcjArch::Mips
+            };
+            let mut data = original.clone();
+            bcj_encode(&mut data, other).unwrap();
+            assert!(data == original, "{:?} read as {:?}", arch, other);
+        }
+    }
+
+    #[test]
+    fn test_s390x_walks_instruction_lengths() {
+        // 0x100 bytes of NOPR, then the instructions below.
+        #[rustfmt::skip]
+        let code: [&[u8]; 5] = [
+            &[0xC0, 0xE5, 0x00, 0x00, 0x00, 0x10], // brasl %r14, +0x20
+            &[0xC0, 0xF4, 0xFF, 0xFF, 0xFF, 0x7D], // j (brcl 15) back to 0
+            &[0x47, 0xF0, 0xC0, 0xE5], // b 0xe5(%r12)
+            &[0x18, 0x12, 0x18, 0x12], // lr %r1, %r2 twice
+            &[0xC0, 0x10, 0x00, 0x00, 0x00, 0x10], // larl %r1, +0x20
+        ];
+        let original: Vec<u8> = [0x07; 0x100]
+            .iter()
+            .chain(code.concat().iter())
+            .copied()
+            .collect();
+        let mut data = original.clone();
+        bcj_encode(&mut data, BcjArch::S390x).unwrap();
+
+        // Operands become absolute targets in halfwords: 0x120 and 0.
+        assert_eq!(data[0x102..0x106], [0x00, 0x00, 0x00, 0x90]);
+        assert_eq!(data[0x108..0x10C], [0x00, 0x00, 0x00, 0x00]);
+        // The C0 E5 inside the 4-byte branch isn't an instruction, and
+        // LARL isn't a branch.
+        assert_eq!(data[0x10C..], original[0x10C..]);
+        bcj_decode(&mut data, BcjArch::S390x).unwrap();
+        assert!(data == original);
+    }
+
+    #[test]
+    fn test_arm_skips_blx_and_conditional_bl() {
+        #[rustfmt::skip]
+        let words = [
+            0xEB00_0010u32, // bl
+            0xFA00_0010, 0xFB00_0010, // blx, to an even and an odd halfword
+            0x0B00_0010, 0x1B00_0010, 0xDB00_0010, // bleq, blne, blle
+        ];
+        let bytes = |words: &[u32]| -> Vec<u8> {
+            std::iter::repeat_n(0, 0x40)
+                .chain(words.iter().copied())
+                .flat_map(u32::to_le_bytes)
+                .collect()
+        };
+        let original = bytes(&words);
+
+        // `xz --format=raw --arm` converts only the first.
+        let mut expected = words;
+        expected[0] = 0xEB00_0052;
+        let mut data = original.clone();
+        bcj_encode(&mut data, BcjArch::Arm).unwrap();
+        assert!(data == bytes(&expected));
+        bcj_decode(&mut data, BcjArch::Arm).unwrap();
+        assert!(data == original);
+
+        // Earlier files converted all but the BLX with bit 24 clear, and
+        // still decode.
+        let mut data = original.clone();
+        bcj_encode(&mut data, BcjArch::ArmPlain).unwrap();
+        let converted = data.chunks(4).zip(original.chunks(4));
+        assert_eq!(converted.filter(|(a, b)| a != b).count(), words.len() - 1);
+        bcj_decode(&mut data, BcjArch::ArmPlain).unwrap();
+        assert!(data == original);
+    }
+
+    #[test]
+    fn test_arm64_matches_xz() {
+        #[rustfmt::skip]
+        let words = [
+            0x9400_0010, 0x97FF_FFF0, // bl +0x40, bl -0x40
+            0x9000_0010, 0xB000_0210, // adrp x16, +0 and +0x21 pages
+            0x90FF_FFF0, 0xF0FF_FFF0, // adrp x16, -1 and -2 pages
+            0x9040_0000, 0x9020_0000, // adrp x0, past 512 MiB either way
+            0x9100_4210, 0xF940_0210, // add x16, x16, #0x10; ldr x16, [x16]
+            0x9400_0000, 0x1234_5678, // bl +0; data
+        ];
+        // `xz --format=raw --arm64 --lzma2` output for them after 8 KiB of
+        // NOPs, decompressed by LZMA2 alone.
+        #[rustfmt::skip]
+        let expected = [
+            0x9400_0810, 0x9400_07F1,
+            0xD000_0010, 0xF000_0210,
+            0xD0FF_FFF0, 0xB000_0010,
+            0x9040_0000, 0x9020_0000,
+            0x9100_4210, 0xF940_0210,
+            0x9400_080A, 0x1234_5678,
+        ];
+        let bytes = |words: &[u32]| -> Vec<u8> {
+            let nops = std::iter::repeat_n(0xD503_201Fu32, 0x800);
+            nops.chain(words.iter().copied())
+                .flat_map(u32::to_le_bytes)
+                .collect()
+        };
+
+        let original = bytes(&words);
+        let mut data = original.clone();
+        bcj_encode(&mut data, BcjArch::Arm64).unwrap();
+        assert!(data == bytes(&expected));
+        bcj_decode(&mut data, BcjArch::Arm64).unwrap();
+        assert!(data == original);
+    }
+
+    /// The darwin-aarch64 binary stored in `examples/hello.pbin`.
+    fn darwin_aarch64_binary() -> Vec<u8> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../examples/hello.pbin");
+        let file = pbin_core::PbinFile::open(path).unwrap();
+        let entry = file.manifest().find_entry(Target::DarwinAarch64).unwrap();
+        file.entry_data(entry).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_arm64_adrp_improves_ratio() {
+        let binary = darwin_aarch64_binary();
+        let ranges = crate::segment::executable_ranges(&binary).unwrap();
+        let compressed_size = |arch| {
+            let mut data = binary.clone();
+            bcj_encode_ranges(&mut data, arch, &ranges).unwrap();
+            crate::dict::compress(&data, 9).unwrap().len()
+        };
+        let (none, plain, adrp) = (
+            compressed_size(BcjArch::None),
+            compressed_size(BcjArch::Arm64Plain),
+            compressed_size(BcjArch::Arm64),
+        );
+        // On its 200 KiB of code, converting BL saves about 3.1% and ADRP
+        // another 0.7%.
+        assert!(
+            adrp * 1000 < plain * 995 && plain < none,
+            "none {} plain {} adrp {}",
+            none,
+            plain,
+            adrp
+        );
+
+        let mut data = binary.clone();
+        bcj_encode_ranges(&mut data, BcjArch::Arm64, &ranges).unwrap();
+        bcj_decode_ranges(&mut data, BcjArch::Arm64, &ranges).unwrap();
+        assert!(data == binary);
+    }
+
+    /// riscv64gc code as compilers lay it out: functions of compressed and
+    /// full-size instructions, calling a few shared functions with JAL and
+    /// AUIPC+JALR pairs. Returns the code and the offset of each JAL.
+    fn riscv_code() -> (Vec<u8>, Vec<usize>) {
+        let mut code = Vec::new();
+        let mut jals = Vec::new();
+        let halfwords = |code: &mut Vec<u8>, insts: &[u16]| {
+            for inst in insts {
+                code.extend_from_slice(&inst.to_le_bytes());
+            }
+        };
+        for f in 0..200u32 {
+            // addi sp,sp,-16; sd ra,8(sp); sd s0,0(sp); addi s0,sp,16
+            halfwords(&mut code, &[0x1141, 0xE406, 0xE022, 0x0800]);
+            // An odd number of compressed instructions, so the JAL and
+            // AUIPC after them sit two bytes off a four-byte boundary
+            // every other function: li a0,f&31 (and mv a1,a0)
+            halfwords(&mut code, &[0x4501 | ((f as u16 & 31) << 2)]);
+            if f % 2 == 0 {
+                halfwords(&mut code, &[0x85AA]);
+            }
+            // lui a0,0x6f0, whose upper halfword looks like a JAL to a
+            // walk two bytes out of step
+            code.extend_from_slice(&0x006F_0537u32.to_le_bytes());
+            // jal ra, to one of four functions at the start of the code
+            let target = (f % 4) * 64;
+            jals.push(code.len());
+            let offset = target.wrapping_sub(code.len() as u32);
+            let jal = 0xEF
+                | (offset & 0xFF000)
+                | ((offset >> 11) & 1) << 20
+                | ((offset >> 1) & 0x3FF) << 21
+                | ((offset >> 20) & 1) << 31;
+            code.extend_from_slice(&jal.to_le_bytes());
+            // auipc ra,hi; jalr ra,lo(ra), to a function past the end
+            let offset = 0x40_0000 + (f % 3) * 0x100 - code.len() as u32;
+            let hi = offset.wrapping_add(0x800) & 0xFFFF_F000;
+            code.extend_from_slice(&(hi | 0x97).to_le_bytes());
+            code.extend_from_slice(&((offset.wrapping_sub(hi) << 20) | 0x80E7).to_le_bytes());
+            // ld ra,8(sp); ld s0,0(sp); addi sp,sp,16; ret
+            halfwords(&mut code, &[0x60A2, 0x6402, 0x0141, 0x8082]);
+        }
+        (code, jals)
+    }
+
+    #[test]
+    fn test_riscv_walks_compressed_instructions() {
+        let (original, jals) = riscv_code();
+        let mut data = original.clone();
+        bcj_encode(&mut data, BcjArch::RiscV).unwrap();
+
+        // Calls to the same function become the same bytes wherever they
+        // are, and nothing is read out of step.
+        for (f, &jal) in jals.iter().enumerate() {
+            assert_eq!(data[jal..jal + 4], data[jals[f % 4]..jals[f % 4] + 4]);
+            assert_eq!(data[jal - 4..jal], original[jal - 4..jal]);
+        }
+        assert_ne!(data[jals[0]..jals[0] + 4], data[jals[1]..jals[1] + 4]);
+
+        bcj_decode(&mut data, BcjArch::RiscV).unwrap();
+        assert!(data == original);
+
+        // Filtering from an odd offset still round-trips.
+        let mut data = original.clone();
+        let tail = 1..original.len();
+        let tail = std::slice::from_ref(&tail);
+        bcj_encode_ranges(&mut data, BcjArch::RiscV, tail).unwrap();
+        bcj_decode_ranges(&mut data, BcjArch::RiscV, tail).unwrap();
+        assert!(data == original);
+    }
+
+    #[test]
+    fn test_riscv_ratio() {
+        let (code, _) = riscv_code();
+        let compressed_size = |arch| {
+            let mut data = code.clone();
+            bcj_encode(&mut data, arch).unwrap();
+            crate::dict::compress(&data, 9).unwrap().len()
+        };
+        let (none, plain, walked) = (
+            compressed_size(BcjArch::None),
+            compressed_size(BcjArch::RiscVPlain),
+            compressed_size(BcjArch::RiscV),
+        );
+        // Stepping four bytes at a time rewrites halves of instructions and
+        // misses the real calls, so it does worse than not filtering.
+        assert!(
+            walked < none && none < plain,
+            "none {} plain {} walked {}",
+            none,
+            plain,
+            walked
+        );
+    }
+
+    /// Pseudo-random bytes dense with each architecture's branch opcodes.
+    fn branchy_data() -> Vec<u8> {
+        let mut state = 0x2545_F491u32;
+        (0..10_000)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                match i % 16 {
+                    0 => 0xE8,
+                    3 => 0x94,
+                    7 => 0x6F,
+                    10 => 0x0F,
+                    11 => 0x84,
+                    13 => 0xC0,
+                    14 => 0xE5,
+                    _ => state as u8,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_writer_matches_whole_buffer() {
+        let data = branchy_data();
+        for arch in FILTERS {
+            let mut expected = data.clone();
+            bcj_encode(&mut expected, arch).unwrap();
+            for chunk_size in [1, 3, 4, 7, 4096] {
+                let mut writer = BcjWriter::new(arch, Vec::new());
+                for chunk in data.chunks(chunk_size) {
+                    writer.write_all(chunk).unwrap();
+                }
+                let encoded = writer.finish().unwrap();
+                assert!(
+                    encoded == expected,
+                    "{:?} in {}-byte chunks",
+                    arch,
+                    chunk_size
+                );
+
+                let mut writer = BcjWriter::decoder(arch, Vec::new());
+                for chunk in encoded.chunks(chunk_size) {
+                    writer.write_all(chunk).unwrap();
+                }
+                assert!(
+                    writer.finish().unwrap() == data,
+                    "{:?} decoded in {}-byte chunks",
+                    arch,
+                    chunk_size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunks_match_whole_buffer() {
+        let data = branchy_data();
+        let mut state = 0x9E37_79B9u32;
+        // Splits `data` at random, into chunks of 0 to 16 bytes.
+        let mut split = |data: &[u8]| -> Vec<Vec<u8>> {
+            let mut chunks = Vec::new();
+            let mut rest = data;
+            while !rest.is_empty() {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let (chunk, tail) = rest.split_at((state as usize % 17).min(rest.len()));
+                chunks.push(chunk.to_vec());
+                rest = tail;
+            }
+            chunks
+        };
+
+        for arch in FILTERS {
+            let mut encoded = data.clone();
+            bcj_encode(&mut encoded, arch).unwrap();
+
+            // Encoded in chunks and decoded whole.
+            let mut filter = BcjFilter::new(arch);
+            let mut chunked: Vec<u8> = split(&data)
+                .iter()
+                .flat_map(|chunk| filter.encode_chunk(chunk))
+                .collect();
+            chunked.extend(filter.finish());
+            assert!(chunked == encoded, "{:?} encoded in chunks", arch);
+            bcj_decode(&mut chunked, arch).unwrap();
+            assert!(chunked == data, "{:?} decoded whole", arch);
+
+            // Encoded whole and decoded in chunks.
+            let mut filter = BcjFilter::new(arch);
+            let mut decoded: Vec<u8> = split(&encoded)
+                .iter()
+                .flat_map(|chunk| filter.decode_chunk(chunk))
+                .collect();
+            decoded.extend(filter.finish());
+            assert!(decoded == data, "{:?} decoded in chunks", arch);
+        }
+
+        // Whole-buffer filtering after chunks would skip what's held back.
+        let mut filter = BcjFilter::new(BcjArch::X86);
+        assert!(filter.encode_chunk(&[0xE8, 0x10]).is_empty());
+        let err = filter.encode(&mut [0; 8]).unwrap_err();
+        assert!(err.to_string().contains("holds 2 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn test_ranges_filter_only_within() {
+        let mut original = vec![0x90u8; 64];
+        original[4..9].copy_from_slice(&[0xE8, 0x10, 0x00, 0x00, 0x00]);
+        original[40..45].copy_from_slice(&[0xE8, 0x10, 0x00, 0x00, 0x00]);
+        let ranges = [0..20, 30..50];
+
+        let mut data = original.clone();
+        bcj_encode_ranges(&mut data, BcjArch::X86, &ranges).unwrap();
+        // Ranges are filtered at their offsets in the whole buffer, so ranges
+        // covering both calls match filtering everything.
+        let mut whole = original.clone();
+        bcj_encode(&mut whole, BcjArch::X86).unwrap();
+        assert_eq!(data, whole);
+
+        let tail = std::slice::from_ref(&(30..50));
+        let mut data = original.clone();
+        bcj_encode_ranges(&mut data, BcjArch::X86, tail).unwrap();
+        assert_eq!(data[..30], original[..30]);
+        assert_ne!(data, original);
+        bcj_decode_ranges(&mut data, BcjArch::X86, tail).unwrap();
+        assert_eq!(data, original);
+
+        let past_end = std::slice::from_ref(&(60..65));
+        assert!(bcj_encode_ranges(&mut data, BcjArch::X86, past_end).is_err());
     }
 
     #[test]
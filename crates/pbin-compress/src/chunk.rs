@@ -0,0 +1,287 @@
+//! Content-defined chunking and chunk deduplication.
+//!
+//! Binaries for different targets often share long identical runs (string
+//! tables, embedded assets, vendored data) at unrelated offsets. Cutting
+//! every binary at content-defined boundaries (FastCDC's gear hash with
+//! normalized chunking) makes those runs split into the same chunks wherever
+//! they sit, so each distinct chunk is stored once and every binary becomes
+//! a list of chunk indices. Binaries can also be cut at given ranges, such as
+//! the sections [`segment::shared_sections`] finds.
+//!
+//! [`segment::shared_sections`]: crate::segment::shared_sections
+
+use crate::delta::mix;
+use crate::{CompressionError, Result};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Smallest chunk cut, except at the end of the data.
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+
+/// Chunk size the cut masks aim for.
+pub const AVG_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Largest chunk: data without a boundary is cut here regardless.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Mask tested before [`AVG_CHUNK_SIZE`]: two bits stricter than the
+/// average needs, so chunks rarely end early.
+const MASK_SMALL: u64 = !0 << (64 - 15);
+
+/// Mask tested after [`AVG_CHUNK_SIZE`]: two bits looser, so chunks rarely
+/// run long.
+const MASK_LARGE: u64 = !0 << (64 - 11);
+
+/// Random value per byte for the gear hash.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = mix(i as u64);
+        i += 1;
+    }
+    table
+};
+
+/// Returns the length of the first chunk of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits `data` into content-defined chunks.
+pub fn split(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(cut_point(rest));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Splits `data` into the given `ranges`, which must be sorted and not
+/// overlap, and the bytes before, between and after them. Pieces longer than
+/// a chunk size can record are split further.
+pub fn split_at_ranges<'a>(data: &'a [u8], ranges: &[Range<usize>]) -> Vec<&'a [u8]> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for range in ranges
+        .iter()
+        .chain(std::iter::once(&(data.len()..data.len())))
+    {
+        pieces.push(&data[start..range.start]);
+        pieces.push(&data[range.clone()]);
+        start = range.end;
+    }
+    pieces
+        .into_iter()
+        .filter(|piece| !piece.is_empty())
+        .flat_map(|piece| piece.chunks(u32::MAX as usize))
+        .collect()
+}
+
+/// Binaries split into chunks, with each distinct chunk kept once.
+#[derive(Debug, Default)]
+pub struct ChunkedData {
+    /// Every distinct chunk, concatenated in the order first seen.
+    pub store: Vec<u8>,
+    /// Size of each chunk in `store`.
+    pub chunk_sizes: Vec<u32>,
+    /// Each binary's target with the chunks that make it up, in input
+    /// order.
+    pub entries: Vec<(String, Vec<u32>)>,
+}
+
+impl ChunkedData {
+    /// Returns the bytes of `target`, rebuilt from its chunks.
+    pub fn reassemble(&self, target: &str) -> Result<Vec<u8>> {
+        let (_, chunks) = self
+            .entries
+            .iter()
+            .find(|(t, _)| t == target)
+            .ok_or_else(|| CompressionError::InvalidData(format!("Entry {} not found", target)))?;
+        reassemble(&self.store, &self.chunk_sizes, chunks, u64::MAX)
+    }
+}
+
+/// Chunks every binary and keeps each distinct chunk, by BLAKE3 hash, once.
+pub fn dedup(binaries: &[(String, impl AsRef<[u8]>)]) -> ChunkedData {
+    dedup_with(binaries, |_, data| split(data))
+}
+
+/// Like [`dedup`], with `cut` splitting the binary at each index into
+/// chunks, which must not be longer than `u32::MAX` bytes.
+pub fn dedup_with<'a>(
+    binaries: &'a [(String, impl AsRef<[u8]>)],
+    cut: impl Fn(usize, &'a [u8]) -> Vec<&'a [u8]>,
+) -> ChunkedData {
+    let mut chunked = ChunkedData::default();
+    let mut seen: HashMap<blake3::Hash, u32> = HashMap::new();
+    for (i, (target, data)) in binaries.iter().enumerate() {
+        let indices = cut(i, data.as_ref())
+            .into_iter()
+            .map(|chunk| {
+                *seen.entry(blake3::hash(chunk)).or_insert_with(|| {
+                    chunked.store.extend_from_slice(chunk);
+                    chunked.chunk_sizes.push(chunk.len() as u32);
+                    chunked.chunk_sizes.len() as u32 - 1
+                })
+            })
+            .collect();
+        chunked.entries.push((target.clone(), indices));
+    }
+    chunked
+}
+
+/// Concatenates the chunks at `indices` of a decompressed chunk store
+/// holding chunks of `chunk_sizes`, refusing output past `limit` bytes.
+pub fn reassemble(
+    store: &[u8],
+    chunk_sizes: &[u32],
+    indices: &[u32],
+    limit: u64,
+) -> Result<Vec<u8>> {
+    let mut offsets = Vec::with_capacity(chunk_sizes.len() + 1);
+    let mut end = 0usize;
+    offsets.push(end);
+    for &size in chunk_sizes {
+        end += size as usize;
+        offsets.push(end);
+    }
+    if end != store.len() {
+        return Err(CompressionError::InvalidData(format!(
+            "Chunk store holds {} bytes but its chunks add up to {}",
+            store.len(),
+            end
+        )));
+    }
+
+    let mut data = Vec::new();
+    for &index in indices {
+        let index = index as usize;
+        if index >= chunk_sizes.len() {
+            return Err(CompressionError::InvalidData(format!(
+                "Chunk {} is past the end of the store's {}",
+                index,
+                chunk_sizes.len()
+            )));
+        }
+        let chunk = &store[offsets[index]..offsets[index + 1]];
+        if (data.len() + chunk.len()) as u64 > limit {
+            return Err(CompressionError::InvalidData(format!(
+                "Chunked entry exceeds {} bytes",
+                limit
+            )));
+        }
+        data.extend_from_slice(chunk);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        (0..len as u64)
+            .map(|i| mix(i ^ (seed << 40)) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_split_bounds() {
+        let data = noise(1 << 20, 1);
+        let chunks = split(&data);
+
+        assert_eq!(chunks.concat(), data);
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(last.len() <= MAX_CHUNK_SIZE);
+        assert!(rest
+            .iter()
+            .all(|c| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&c.len())));
+        let average = data.len() / chunks.len();
+        assert!(
+            (AVG_CHUNK_SIZE / 2..AVG_CHUNK_SIZE * 2).contains(&average),
+            "{}",
+            average
+        );
+
+        assert!(split(&[]).is_empty());
+        assert_eq!(split(&[7; 100]), vec![&[7; 100][..]]);
+        assert!(split(&vec![0; 200_000])
+            .iter()
+            .all(|c| c.len() <= MAX_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn test_shared_runs_dedup_at_any_offset() {
+        let shared = noise(200_000, 2);
+        let a = [noise(3_000, 3), shared.clone(), noise(50_000, 4)].concat();
+        let b = [noise(41_234, 5), shared.clone()].concat();
+        let binaries = vec![
+            ("linux-x86_64".to_string(), a),
+            ("windows-x86_64".to_string(), b),
+        ];
+
+        let chunked = dedup(&binaries);
+        let total: usize = binaries.iter().map(|(_, d)| d.len()).sum();
+        assert!(
+            chunked.store.len() < total - shared.len() * 3 / 4,
+            "{} of {}",
+            chunked.store.len(),
+            total
+        );
+        assert_eq!(
+            chunked
+                .chunk_sizes
+                .iter()
+                .map(|&s| s as usize)
+                .sum::<usize>(),
+            chunked.store.len()
+        );
+        for (target, data) in &binaries {
+            assert_eq!(&chunked.reassemble(target).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_split_at_ranges() {
+        let data: Vec<u8> = (0..100).collect();
+        let pieces = split_at_ranges(&data, &[0..10, 30..40, 40..60]);
+        let lengths: Vec<usize> = pieces.iter().map(|p| p.len()).collect();
+
+        assert_eq!(lengths, [10, 20, 10, 20, 40]);
+        assert_eq!(pieces.concat(), data);
+        assert_eq!(split_at_ranges(&data, &[]), vec![&data[..]]);
+        assert_eq!(split_at_ranges(&data, &[80..90, 90..100]).len(), 3);
+    }
+
+    #[test]
+    fn test_reassemble_rejects_bad_chunks() {
+        let binaries = vec![("linux-x86_64".to_string(), noise(20_000, 6))];
+        let chunked = dedup(&binaries);
+        let count = chunked.chunk_sizes.len() as u32;
+
+        let err = reassemble(&chunked.store, &chunked.chunk_sizes, &[count], u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{}", err);
+        let err = reassemble(&chunked.store, &chunked.chunk_sizes, &[0, 0], 100).unwrap_err();
+        assert!(err.to_string().contains("exceeds 100 bytes"), "{}", err);
+        let err =
+            reassemble(&chunked.store[1..], &chunked.chunk_sizes, &[0], u64::MAX).unwrap_err();
+        assert!(err.to_string().contains("add up to"), "{}", err);
+    }
+}
@@ -0,0 +1,311 @@
+//! Decompression of in-memory pipeline results.
+//!
+//! [`DecompressionPipeline`] reverses [`CompressionPipeline::compress_all`]
+//! stage by stage: each entry is decompressed (with its dictionary, if
+//! any), a delta entry's patch is applied to its reference, and the BCJ
+//! filter is undone last. Entries read back from a PBIN file go through
+//! [`extract`](crate::extract) instead, which follows the manifest.
+//!
+//! [`CompressionPipeline::compress_all`]: crate::CompressionPipeline::compress_all
+
+use crate::bcj::{self, BcjFilter};
+use crate::delta;
+use crate::extract::{check_size, decompress_entry, decompress_into};
+use crate::pipeline::{CompressedEntry, CompressionResult};
+use crate::{CompressionError, Result};
+use pbin_core::{Checksum, DEFAULT_MAX_ENTRY_SIZE};
+
+/// Reverses a [`CompressionResult`], restoring each binary's original
+/// bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct DecompressionPipeline<'a> {
+    /// Entries, including any delta references.
+    entries: &'a [CompressedEntry],
+    /// Dictionaries, indexed by `CompressedEntry::dict_index`.
+    dictionaries: &'a [(String, Vec<u8>)],
+    /// Most bytes any entry or delta patch may expand to.
+    max_entry_size: u64,
+}
+
+impl<'a> DecompressionPipeline<'a> {
+    /// Decompress the entries of `result` with the dictionaries trained
+    /// alongside them.
+    pub fn new(result: &'a CompressionResult) -> Self {
+        Self::from_parts(&result.entries, &result.dictionaries)
+    }
+
+    /// Decompress `entries` with `dictionaries`, as they'd be found in a
+    /// [`CompressionResult`].
+    pub fn from_parts(
+        entries: &'a [CompressedEntry],
+        dictionaries: &'a [(String, Vec<u8>)],
+    ) -> Self {
+        Self {
+            entries,
+            dictionaries,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+
+    /// Refuse entries and delta patches that expand past `limit` bytes,
+    /// instead of [`DEFAULT_MAX_ENTRY_SIZE`].
+    pub fn with_max_entry_size(mut self, limit: u64) -> Self {
+        self.max_entry_size = limit;
+        self
+    }
+
+    /// Returns the original bytes of the entry for `target`.
+    pub fn decompress(&self, target: &str) -> Result<Vec<u8>> {
+        let entry = self.find(target)?;
+        let mut data = self.filtered_data(entry, &[])?;
+        if let Some(arch) = entry.bcj_arch() {
+            match &entry.bcj_ranges {
+                Some(ranges) => bcj::bcj_decode_ranges(&mut data, arch, ranges)?,
+                None => BcjFilter::new(arch).decode(&mut data)?,
+            }
+        }
+        Ok(data)
+    }
+
+    /// Like [`decompress`](Self::decompress), failing unless the original
+    /// bytes hash to `checksum`.
+    pub fn decompress_verified(&self, target: &str, checksum: &Checksum) -> Result<Vec<u8>> {
+        let data = self.decompress(target)?;
+        if !checksum.matches(&data) {
+            return Err(pbin_core::Error::ChecksumMismatch {
+                expected: checksum.to_string(),
+                actual: Checksum::of(&data).to_string(),
+            }
+            .into());
+        }
+        Ok(data)
+    }
+
+    /// Returns every entry's original bytes, in entry order.
+    pub fn decompress_all(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.entries
+            .iter()
+            .map(|entry| Ok((entry.target.clone(), self.decompress(&entry.target)?)))
+            .collect()
+    }
+
+    /// Returns the entry for `target`.
+    fn find(&self, target: &str) -> Result<&'a CompressedEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.target == target)
+            .ok_or_else(|| CompressionError::InvalidData(format!("Entry {} not found", target)))
+    }
+
+    /// Returns an entry's bytes as they were before compression, still
+    /// BCJ-filtered: patches are made between filtered binaries, so a delta
+    /// entry is rebuilt from its reference's filtered bytes. `chain` holds
+    /// the delta entries already waiting on this one.
+    fn filtered_data(&self, entry: &CompressedEntry, chain: &[&str]) -> Result<Vec<u8>> {
+        let size = entry.original_size as u64;
+        if size > self.max_entry_size {
+            return Err(pbin_core::Error::EntryTooLarge {
+                target: entry.target.clone(),
+                size,
+                limit: self.max_entry_size,
+            }
+            .into());
+        }
+        let dictionary = match entry.dict_index {
+            Some(index) => Some(
+                self.dictionaries
+                    .get(index)
+                    .map(|(_, data)| data.as_slice())
+                    .ok_or_else(|| {
+                        CompressionError::InvalidData(format!(
+                            "Dictionary {} for {} not found",
+                            index, entry.target
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let Some(reference) = entry.delta_reference.as_deref() else {
+            return decompress_entry(
+                &entry.data,
+                entry.compression,
+                dictionary,
+                entry.window_log,
+                size,
+            );
+        };
+        delta::check_chain(&entry.target, reference, chain)?;
+        let reference_entry = self.find(reference).map_err(|_| {
+            CompressionError::InvalidData(format!(
+                "Delta reference {} for {} not found",
+                reference, entry.target
+            ))
+        })?;
+
+        let mut patch = Vec::new();
+        decompress_into(
+            &entry.data,
+            entry.compression,
+            dictionary,
+            entry.window_log,
+            self.max_entry_size,
+            &mut patch,
+        )?;
+        if patch.len() as u64 > self.max_entry_size {
+            return Err(CompressionError::InvalidData(format!(
+                "Delta patch for {} exceeds {} bytes",
+                entry.target, self.max_entry_size
+            )));
+        }
+
+        let chain = [chain, &[entry.target.as_str()]].concat();
+        let reference_data = self.filtered_data(reference_entry, &chain)?;
+        let data = delta::apply_patch_limited(&reference_data, &patch, size)?;
+        check_size(size, &data)?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompressionLevel, CompressionPipeline};
+    use pbin_core::Compression;
+
+    /// Pseudo-random binary-like data: x86 CALLs and ARM64 BLs among
+    /// repeated runs, with `mutation` flipping every 97th byte so related
+    /// binaries differ slightly.
+    fn synthetic_binary(seed: u32, len: usize, mutation: u8) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761).wrapping_add(1);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            match (state >> 24) % 4 {
+                0 => data.extend_from_slice(&[0xe8, state as u8, (state >> 8) as u8, 0, 0]),
+                1 => data.extend_from_slice(&(0x9400_0000 | (state & 0xffff)).to_le_bytes()),
+                2 => data.extend_from_slice(b"\x48\x89\xe5\x48\x83\xec\x10"),
+                _ => data.push((state >> 16) as u8),
+            }
+        }
+        data.truncate(len);
+        for byte in data.iter_mut().step_by(97) {
+            *byte ^= mutation;
+        }
+        data
+    }
+
+    #[test]
+    fn test_round_trip_every_combination() {
+        let algorithms = [
+            Compression::Zstd,
+            Compression::Lz4,
+            Compression::Xz,
+            Compression::Brotli,
+            Compression::None,
+        ];
+        for seed in 0..2u32 {
+            let base = synthetic_binary(seed, 20_000 + seed as usize * 777, 0);
+            let binaries = vec![
+                ("linux-x86_64".to_string(), base.clone()),
+                (
+                    "windows-x86_64".to_string(),
+                    synthetic_binary(seed, base.len(), 1),
+                ),
+                (
+                    "darwin-x86_64".to_string(),
+                    synthetic_binary(seed, base.len() + 5, 2),
+                ),
+                (
+                    "linux-aarch64".to_string(),
+                    synthetic_binary(seed + 10, 12_345, 0),
+                ),
+                (
+                    "freebsd-aarch64".to_string(),
+                    synthetic_binary(seed + 10, 12_345, 3),
+                ),
+            ];
+            for combination in 0..8 {
+                for algorithm in algorithms {
+                    let mut pipeline =
+                        CompressionPipeline::new(CompressionLevel::Fast).with_algorithm(algorithm);
+                    if combination & 1 == 0 {
+                        pipeline = pipeline.without_bcj();
+                    }
+                    if combination & 2 == 0 {
+                        pipeline = pipeline.without_delta();
+                    }
+                    if combination & 4 == 0 {
+                        pipeline = pipeline.without_dict();
+                    }
+                    let result = pipeline.compress_all(&binaries).unwrap();
+                    let decompressor = DecompressionPipeline::new(&result);
+                    let restored = decompressor.decompress_all().unwrap();
+                    assert_eq!(
+                        restored.len(),
+                        binaries.len(),
+                        "{} {}",
+                        combination,
+                        algorithm
+                    );
+                    for (target, data) in &binaries {
+                        let checksum = Checksum::of(data);
+                        let output = decompressor.decompress_verified(target, &checksum);
+                        assert_eq!(
+                            output.as_ref().ok(),
+                            Some(data),
+                            "seed {} combination {} {} {}",
+                            seed,
+                            combination,
+                            algorithm,
+                            target
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_delta_and_dictionary_reversed() {
+        let mut binaries = Vec::new();
+        for program in ["a", "b"] {
+            for os in ["linux", "windows", "darwin", "freebsd"] {
+                let key = format!("{}/{}-x86_64", program, os);
+                binaries.push((key, synthetic_binary(7, 30_000, binaries.len() as u8)));
+            }
+        }
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_delta_threshold(0.1)
+            .compress_all(&binaries)
+            .unwrap();
+        assert!(result.entries.iter().any(|e| e.delta_reference.is_some()));
+        assert!(result.entries.iter().any(|e| e.dict_index.is_some()));
+        assert_eq!(
+            DecompressionPipeline::new(&result)
+                .decompress_all()
+                .unwrap(),
+            binaries
+        );
+    }
+
+    #[test]
+    fn test_verification_failures() {
+        let binaries = vec![("linux-x86_64".to_string(), synthetic_binary(1, 4096, 0))];
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .compress_all(&binaries)
+            .unwrap();
+        let decompressor = DecompressionPipeline::new(&result);
+
+        let err = decompressor
+            .decompress_verified("linux-x86_64", &Checksum::of(b"other"))
+            .unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert!(decompressor.decompress("linux-aarch64").is_err());
+        let err = decompressor
+            .with_max_entry_size(1024)
+            .decompress("linux-x86_64")
+            .unwrap_err();
+        assert!(err.to_string().contains("over the limit"), "{}", err);
+    }
+}
@@ -3,16 +3,93 @@
 //! Uses the bidiff crate for creating delta patches between similar binaries.
 //! This is particularly effective for binaries targeting the same architecture
 //! but different operating systems (e.g., linux-x86_64 vs darwin-x86_64).
+//!
+//! Every patch starts with a [`PatchHeader`] naming the diff format and the
+//! reference it was made against, so a patch is never applied to the wrong
+//! bytes or by a decoder that doesn't understand it.
 
+use crate::pipeline::HashingWriter;
 use crate::{CompressionError, Result};
-use std::io::{Cursor, Read};
+use pbin_core::{Checksum, Target};
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+
+/// Magic bytes opening a framed patch.
+pub const PATCH_MAGIC: [u8; 4] = *b"PBDP";
+
+/// Version of the framed patch format: a bidiff patch behind a
+/// [`PatchHeader`].
+pub const PATCH_VERSION: u8 = 1;
+
+/// The first bytes of an unframed bidiff patch, as written before patches
+/// carried a header. These are still applied, without the header's checks.
+const BIDIFF_MAGIC: [u8; 4] = bipatch::MAGIC.to_le_bytes();
+
+/// Header written ahead of every delta patch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchHeader {
+    /// Format of the patch that follows.
+    pub version: u8,
+    /// BLAKE3 of the reference the patch applies to.
+    pub reference_checksum: Checksum,
+    /// Length of the output the patch produces.
+    pub target_size: u64,
+}
+
+impl PatchHeader {
+    /// Size of the encoded header in bytes.
+    pub const SIZE: usize = 4 + 1 + 32 + 8;
+
+    /// Header for a patch from `reference` to a `target_size`-byte target.
+    pub fn new(reference: &[u8], target_size: u64) -> Self {
+        Self {
+            version: PATCH_VERSION,
+            reference_checksum: Checksum::of(reference),
+            target_size,
+        }
+    }
+
+    /// Encode the header.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..4].copy_from_slice(&PATCH_MAGIC);
+        bytes[4] = self.version;
+        bytes[5..37].copy_from_slice(self.reference_checksum.as_bytes());
+        bytes[37..].copy_from_slice(&self.target_size.to_le_bytes());
+        bytes
+    }
+
+    /// Decode the header at the start of `patch`, returning it and the
+    /// patch body after it. Unknown versions are rejected.
+    pub fn parse(patch: &[u8]) -> Result<(Self, &[u8])> {
+        if patch.len() < Self::SIZE || patch[..4] != PATCH_MAGIC {
+            return Err(CompressionError::Delta(
+                "Patch has no valid header".to_string(),
+            ));
+        }
+        let header = Self {
+            version: patch[4],
+            reference_checksum: Checksum::from(<[u8; 32]>::try_from(&patch[5..37]).unwrap()),
+            target_size: u64::from_le_bytes(patch[37..Self::SIZE].try_into().unwrap()),
+        };
+        if header.version != PATCH_VERSION {
+            return Err(CompressionError::Delta(format!(
+                "Unsupported patch format version {} (this build reads version {})",
+                header.version, PATCH_VERSION
+            )));
+        }
+        Ok((header, &patch[Self::SIZE..]))
+    }
+}
 
 /// Create a delta patch between a reference binary and target binary.
 ///
 /// The patch can be applied to the reference to recreate the target.
 /// Useful for compressing similar binaries by storing only differences.
 pub fn create_patch(reference: &[u8], target: &[u8]) -> Result<Vec<u8>> {
-    let mut patch = Vec::new();
+    let mut patch = PatchHeader::new(reference, target.len() as u64)
+        .to_bytes()
+        .to_vec();
     bidiff::simple_diff(reference, target, &mut patch)
         .map_err(|e| CompressionError::Delta(format!("Failed to create patch: {}", e)))?;
     Ok(patch)
@@ -20,59 +97,188 @@ pub fn create_patch(reference: &[u8], target: &[u8]) -> Result<Vec<u8>> {
 
 /// Apply a delta patch to a reference binary to recreate the target.
 pub fn apply_patch(reference: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    apply_patch_limited(reference, patch, u64::MAX)
+}
+
+/// Apply a delta patch like [`apply_patch`], stopping one byte past `limit`.
+///
+/// A caller comparing the output's length against `limit` can then reject a
+/// patch that expands further without producing all of its output.
+pub fn apply_patch_limited(reference: &[u8], patch: &[u8], limit: u64) -> Result<Vec<u8>> {
     let mut target = Vec::new();
-    let patch_reader = Cursor::new(patch);
-    let old_reader = Cursor::new(reference);
+    apply_patch_to(reference, patch, &mut target, limit)?;
+    Ok(target)
+}
 
-    let mut reader = bipatch::Reader::new(patch_reader, old_reader)
-        .map_err(|e| CompressionError::Delta(format!("Failed to read patch: {}", e)))?;
+/// Apply a delta patch read from `patch`, writing the target to `out` as it
+/// is produced instead of holding it in memory. Stops one byte past `limit`,
+/// like [`apply_patch_limited`].
+///
+/// The header's reference checksum is checked before patching, and its
+/// target size against the output. Returns the number of bytes written and
+/// their BLAKE3, for the caller to verify.
+pub fn apply_patch_to<R: Read, W: Write>(
+    reference: &[u8],
+    mut patch: R,
+    out: W,
+    limit: u64,
+) -> Result<(u64, Checksum)> {
+    let mut header = [0u8; PatchHeader::SIZE];
+    patch
+        .read_exact(&mut header[..4])
+        .map_err(|_| CompressionError::Delta("Patch has no valid header".to_string()))?;
+    let (unframed, header) = if header[..4] == BIDIFF_MAGIC {
+        (&header[..4], None)
+    } else {
+        patch
+            .read_exact(&mut header[4..])
+            .map_err(|_| CompressionError::Delta("Patch has no valid header".to_string()))?;
+        let (header, _) = PatchHeader::parse(&header)?;
+        let actual = Checksum::of(reference);
+        if actual != header.reference_checksum {
+            return Err(CompressionError::Delta(format!(
+                "Patch was made against a different reference (expected {}, got {})",
+                header.reference_checksum, actual
+            )));
+        }
+        (&[][..], Some(header))
+    };
 
-    reader.read_to_end(&mut target)
+    let reader = bipatch::Reader::new(unframed.chain(patch), Cursor::new(reference))
+        .map_err(|e| CompressionError::Delta(format!("Failed to read patch: {}", e)))?;
+    let cap = header.as_ref().map_or(limit, |h| limit.min(h.target_size));
+    let mut out = HashingWriter::new(out);
+    io::copy(&mut reader.take(cap.saturating_add(1)), &mut out)
         .map_err(|e| CompressionError::Delta(format!("Failed to apply patch: {}", e)))?;
-
-    Ok(target)
+    out.flush()?;
+
+    // Past the caller's limit, leave the length for the caller to report.
+    if let Some(header) = header {
+        if out.written != header.target_size && out.written <= limit {
+            return Err(CompressionError::Delta(format!(
+                "Patch produced {} bytes, but its header declares {}",
+                out.written, header.target_size
+            )));
+        }
+    }
+    Ok((out.written, Checksum::from(out.hasher.finalize())))
 }
 
-/// Calculate the similarity ratio between two binaries.
+/// Bytes per shingle hashed into a [`Fingerprint`].
+const SHINGLE_LEN: usize = 8;
+
+/// MinHash slots in a [`Fingerprint`].
+const FINGERPRINT_SLOTS: usize = 256;
+
+/// Slot value of a fingerprint slot no shingle hashed into.
+const EMPTY_SLOT: u64 = u64::MAX;
+
+/// A MinHash sketch of a binary's set of byte shingles.
 ///
-/// Returns a value between 0.0 (completely different) and 1.0 (identical).
-/// Used to decide whether delta compression is beneficial.
-pub fn similarity_ratio(a: &[u8], b: &[u8]) -> f64 {
-    if a.is_empty() && b.is_empty() {
-        return 1.0;
-    }
-    if a.is_empty() || b.is_empty() {
-        return 0.0;
-    }
+/// Every 8-byte window is hashed once; the top bits pick one of 256 slots
+/// and each slot keeps the smallest hash it sees. Two fingerprints agree in
+/// a slot about as often as the binaries' shingle sets overlap, so the
+/// estimate survives code shifted by a relink or an inserted byte, which
+/// would defeat any comparison by position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Smallest shingle hash seen in each slot.
+    slots: Vec<u64>,
+    /// Length of the fingerprinted data.
+    len: usize,
+}
 
-    // Quick length-based heuristic
-    let len_ratio = a.len().min(b.len()) as f64 / a.len().max(b.len()) as f64;
-    if len_ratio < 0.5 {
-        return len_ratio * 0.5; // Very different sizes, low similarity
+impl Fingerprint {
+    /// Fingerprint `data`. Data shorter than a shingle is one shingle.
+    pub fn of(data: &[u8]) -> Self {
+        let mut slots = vec![EMPTY_SLOT; FINGERPRINT_SLOTS];
+        let mut add = |shingle: [u8; SHINGLE_LEN]| {
+            let hash = mix(u64::from_le_bytes(shingle));
+            let slot = (hash >> (64 - FINGERPRINT_SLOTS.trailing_zeros())) as usize;
+            slots[slot] = slots[slot].min(hash);
+        };
+        if data.len() < SHINGLE_LEN {
+            let mut shingle = [0u8; SHINGLE_LEN];
+            shingle[..data.len()].copy_from_slice(data);
+            shingle[SHINGLE_LEN - 1] ^= data.len() as u8;
+            add(shingle);
+        } else {
+            for window in data.windows(SHINGLE_LEN) {
+                add(window.try_into().unwrap());
+            }
+        }
+        Self {
+            slots,
+            len: data.len(),
+        }
     }
 
-    // Sample-based comparison for performance
-    let sample_size = 1024.min(a.len().min(b.len()));
-    let step = a.len().min(b.len()) / sample_size;
-
-    let mut matches = 0;
-    for i in 0..sample_size {
-        let pos = i * step;
-        if a.get(pos) == b.get(pos) {
-            matches += 1;
+    /// Estimated share of shingles the two binaries have in common: 0.0
+    /// for nothing shared, 1.0 for the same set.
+    pub fn similarity(&self, other: &Fingerprint) -> f64 {
+        if self.len == 0 || other.len == 0 {
+            return if self.len == other.len { 1.0 } else { 0.0 };
+        }
+        let mut used = 0;
+        let mut matches = 0;
+        for (&a, &b) in self.slots.iter().zip(&other.slots) {
+            if a == EMPTY_SLOT && b == EMPTY_SLOT {
+                continue;
+            }
+            used += 1;
+            if a == b {
+                matches += 1;
+            }
         }
+        matches as f64 / used as f64
     }
+}
 
-    (matches as f64 / sample_size as f64) * len_ratio
+/// Scrambles a shingle into a well-mixed hash (splitmix64's finalizer).
+pub(crate) const fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
+/// Calculate the similarity ratio between two binaries.
+///
+/// Returns a value between 0.0 (completely different) and 1.0 (identical),
+/// as [`Fingerprint::similarity`] estimates it. Comparing one binary with
+/// many is cheaper with the fingerprints kept.
+pub fn similarity_ratio(a: &[u8], b: &[u8]) -> f64 {
+    Fingerprint::of(a).similarity(&Fingerprint::of(b))
+}
+
+/// Most patches applied in turn to rebuild one entry. Chains are split to
+/// stay within it, and readers reject longer ones.
+pub const MAX_CHAIN_LENGTH: usize = 8;
+
 /// Represents a group of similar binaries for delta compression.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DeltaGroup {
     /// The reference binary (stored in full).
     pub reference_target: String,
     /// Targets that are stored as deltas from the reference.
     pub delta_targets: Vec<String>,
+    /// Whether `delta_targets` form a chain, each patched against the one
+    /// before it, rather than all against the reference (see
+    /// [`DeltaGroup::reference_of`]).
+    pub chained: bool,
+}
+
+impl DeltaGroup {
+    /// Returns the target `delta_targets[index]` is patched against: the
+    /// reference, or in a chain the previous delta target. Every
+    /// [`MAX_CHAIN_LENGTH`] links a chain starts again from the reference.
+    pub fn reference_of(&self, index: usize) -> &str {
+        if self.chained && !index.is_multiple_of(MAX_CHAIN_LENGTH) {
+            &self.delta_targets[index - 1]
+        } else {
+            &self.reference_target
+        }
+    }
 }
 
 /// Group targets by similarity for delta compression.
@@ -80,7 +286,7 @@ pub struct DeltaGroup {
 /// Returns groups where the first target in each group is the reference
 /// and remaining targets can be stored as deltas.
 pub fn group_by_similarity(
-    binaries: &[(String, Vec<u8>)],
+    binaries: &[(String, impl AsRef<[u8]>)],
     threshold: f64,
 ) -> Vec<DeltaGroup> {
     if binaries.is_empty() {
@@ -89,30 +295,35 @@ pub fn group_by_similarity(
 
     let mut groups: Vec<DeltaGroup> = Vec::new();
     let mut assigned: Vec<bool> = vec![false; binaries.len()];
+    let fingerprints: Vec<Fingerprint> = binaries
+        .iter()
+        .map(|(_, d)| Fingerprint::of(d.as_ref()))
+        .collect();
 
     // Group by architecture first (binaries of same arch are most similar)
-    for (i, (target_i, data_i)) in binaries.iter().enumerate() {
+    for (i, (target_i, _)) in binaries.iter().enumerate() {
         if assigned[i] {
             continue;
         }
 
-        let arch_i = extract_arch(target_i);
+        let arch_i = arch_group(target_i);
         let mut group = DeltaGroup {
             reference_target: target_i.clone(),
             delta_targets: Vec::new(),
+            chained: false,
         };
         assigned[i] = true;
 
         // Find similar binaries
-        for (j, (target_j, data_j)) in binaries.iter().enumerate() {
+        for (j, (target_j, _)) in binaries.iter().enumerate() {
             if assigned[j] {
                 continue;
             }
 
             // Same architecture is a strong indicator of similarity
-            let arch_j = extract_arch(target_j);
+            let arch_j = arch_group(target_j);
             if arch_i == arch_j {
-                let sim = similarity_ratio(data_i, data_j);
+                let sim = fingerprints[i].similarity(&fingerprints[j]);
                 if sim >= threshold {
                     group.delta_targets.push(target_j.clone());
                     assigned[j] = true;
@@ -126,15 +337,137 @@ pub fn group_by_similarity(
     groups
 }
 
-/// Extract architecture from target string (e.g., "linux-x86_64" -> "x86_64").
-fn extract_arch(target: &str) -> &str {
-    target.rsplit('-').next().unwrap_or(target)
+/// Re-anchor each group on the member that minimizes the estimated total
+/// size of the other members' patches.
+///
+/// [`group_by_similarity`] anchors a group on its first member, but the
+/// "middle" binary of a group usually patches to the others more cheaply.
+/// A patch against a reference is estimated as the share of the target
+/// [`Fingerprint::similarity`] finds different. Ties keep the current reference.
+/// A replaced reference becomes the group's first delta target, ahead of
+/// the others in their original order.
+pub fn select_references(groups: &mut [DeltaGroup], binaries: &[(String, impl AsRef<[u8]>)]) {
+    let fingerprints = fingerprint_all(binaries);
+    let fingerprint = |target: &str| &fingerprints[target];
+    for group in groups.iter_mut().filter(|g| g.delta_targets.len() > 1) {
+        let members: Vec<&str> = std::iter::once(group.reference_target.as_str())
+            .chain(group.delta_targets.iter().map(String::as_str))
+            .collect();
+        let cost = |reference: &str| -> f64 {
+            members
+                .iter()
+                .filter(|&&target| target != reference)
+                .map(|target| {
+                    let target = fingerprint(target);
+                    (1.0 - fingerprint(reference).similarity(target)) * target.len as f64
+                })
+                .sum()
+        };
+
+        let mut best = (0, cost(members[0]));
+        for (i, candidate) in members.iter().enumerate().skip(1) {
+            let candidate_cost = cost(candidate);
+            if candidate_cost < best.1 {
+                best = (i, candidate_cost);
+            }
+        }
+        if best.0 > 0 {
+            let reference = members[best.0].to_string();
+            let delta_targets = members
+                .iter()
+                .filter(|&&target| target != reference)
+                .map(|target| target.to_string())
+                .collect();
+            *group = DeltaGroup {
+                reference_target: reference,
+                delta_targets,
+                chained: group.chained,
+            };
+        }
+    }
+}
+
+/// Turn each group into a chain: starting from the reference, each next
+/// link is the remaining target most similar to the previous one, so every
+/// patch is between neighbours. Rebuilding a target then applies every
+/// patch before it in the chain, up to [`MAX_CHAIN_LENGTH`].
+pub fn order_chains(groups: &mut [DeltaGroup], binaries: &[(String, impl AsRef<[u8]>)]) {
+    let fingerprints = fingerprint_all(binaries);
+    for group in groups.iter_mut() {
+        let mut remaining = std::mem::take(&mut group.delta_targets);
+        let mut previous = group.reference_target.clone();
+        while !remaining.is_empty() {
+            // Ties go to the earliest target, keeping the order stable.
+            let mut next = 0;
+            let mut best = f64::MIN;
+            for (i, target) in remaining.iter().enumerate() {
+                let similarity =
+                    fingerprints[previous.as_str()].similarity(&fingerprints[target.as_str()]);
+                if similarity > best {
+                    (next, best) = (i, similarity);
+                }
+            }
+            previous = remaining.remove(next);
+            group.delta_targets.push(previous.clone());
+        }
+        group.chained = true;
+    }
+}
+
+/// Fingerprints every binary, by target.
+fn fingerprint_all(binaries: &[(String, impl AsRef<[u8]>)]) -> HashMap<&str, Fingerprint> {
+    binaries
+        .iter()
+        .map(|(target, data)| (target.as_str(), Fingerprint::of(data.as_ref())))
+        .collect()
+}
+
+/// Check that rebuilding `target` from `reference` neither loops nor takes
+/// more than [`MAX_CHAIN_LENGTH`] patches. `chain` holds the delta targets
+/// already waiting on `target`, outermost first.
+pub fn check_chain(target: &str, reference: &str, chain: &[&str]) -> Result<()> {
+    if reference == target || chain.contains(&reference) {
+        return Err(CompressionError::InvalidData(format!(
+            "Delta reference cycle at {}",
+            target
+        )));
+    }
+    if chain.len() >= MAX_CHAIN_LENGTH {
+        return Err(CompressionError::InvalidData(format!(
+            "Delta chain for {} is longer than {} links",
+            chain[0], MAX_CHAIN_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Returns the architecture group of an entry key ("linux-x86_64" or
+/// "program/linux-x86_64"): the name of the target's [`pbin_core::Arch`], or
+/// the target part itself for names that aren't targets.
+pub(crate) fn arch_group(key: &str) -> &str {
+    let target = key.rsplit('/').next().unwrap_or(key);
+    target
+        .parse::<Target>()
+        .map_or(target, |target| target.arch().as_str())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// `len` pseudo-random bytes from `seed`.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2_654_435_761) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
     #[test]
     fn test_patch_roundtrip() {
         let reference = b"Hello, World! This is a test binary with some content.";
@@ -146,6 +479,81 @@ mod tests {
         assert_eq!(recovered, target);
     }
 
+    #[test]
+    fn test_patch_header_checked() {
+        let reference = b"Hello, World! This is a test binary with some content.";
+        let target = b"Hello, World! This is a modified binary with different content.";
+        let patch = create_patch(reference, target).unwrap();
+        let (header, _) = PatchHeader::parse(&patch).unwrap();
+        assert_eq!(header, PatchHeader::new(reference, target.len() as u64));
+
+        let err = apply_patch(b"Some other reference entirely.", &patch).unwrap_err();
+        assert!(err.to_string().contains("different reference"), "{}", err);
+
+        let mut future = patch.clone();
+        future[4] = PATCH_VERSION + 1;
+        let err = apply_patch(reference, &future).unwrap_err();
+        assert!(
+            err.to_string().contains("Unsupported patch format"),
+            "{}",
+            err
+        );
+
+        let mut resized = patch.clone();
+        resized[37..PatchHeader::SIZE].copy_from_slice(&1u64.to_le_bytes());
+        let err = apply_patch(reference, &resized).unwrap_err();
+        assert!(err.to_string().contains("declares 1"), "{}", err);
+
+        assert!(apply_patch(reference, &patch[..PatchHeader::SIZE - 1]).is_err());
+        // A limit below the target size cuts output short for the caller.
+        let limited = apply_patch_limited(reference, &patch, 10).unwrap();
+        assert_eq!(limited.len(), 11);
+    }
+
+    #[test]
+    fn test_apply_patch_to_file() {
+        // Multi-megabyte reference with a scattering of edits.
+        let mut state = 0x2545_f491u32;
+        let reference: Vec<u8> = (0..3 << 20)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                if i % 3 == 0 {
+                    state as u8
+                } else {
+                    (i >> 10) as u8
+                }
+            })
+            .collect();
+        let mut target = reference.clone();
+        for i in (0..target.len()).step_by(65_537) {
+            target[i] ^= 0x5a;
+        }
+        target.extend_from_slice(b"appended section");
+        let patch = create_patch(&reference, &target).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("target");
+        let file = std::fs::File::create(&path).unwrap();
+        let out = std::io::BufWriter::with_capacity(8192, file);
+        let (written, checksum) =
+            apply_patch_to(&reference, &patch[..], out, target.len() as u64).unwrap();
+
+        assert_eq!(written, target.len() as u64);
+        assert_eq!(checksum, Checksum::of(&target));
+        assert!(Checksum::of(&std::fs::read(&path).unwrap()) == checksum);
+    }
+
+    #[test]
+    fn test_unframed_patch_still_applies() {
+        let reference = b"Hello, World! This is a test binary with some content.";
+        let target = b"Hello, World! This is a modified binary with different content.";
+        let mut bare = Vec::new();
+        bidiff::simple_diff(reference, target, &mut bare).unwrap();
+        assert_eq!(apply_patch(reference, &bare).unwrap(), target);
+    }
+
     #[test]
     fn test_identical_patch() {
         // Verify that patching identical data works correctly
@@ -170,15 +578,63 @@ mod tests {
         let b = vec![255u8; 1000];
         let sim = similarity_ratio(&a, &b);
         assert!(sim < 0.1);
+        assert!(similarity_ratio(&noise(5000, 1), &noise(5000, 2)) < 0.05);
+        assert_eq!(similarity_ratio(b"", b""), 1.0);
+        assert_eq!(similarity_ratio(b"", b"a"), 0.0);
+    }
+
+    /// Fraction of positions holding the same byte, as similarity was once
+    /// measured.
+    fn positional_match(a: &[u8], b: &[u8]) -> f64 {
+        let same = a.iter().zip(b).filter(|(x, y)| x == y).count();
+        same as f64 / a.len().max(b.len()) as f64
+    }
+
+    #[test]
+    fn test_similarity_survives_shifts() {
+        let base = noise(50_000, 3);
+        let shifted = [&[0x90][..], &base].concat();
+        let mut inserted = base.clone();
+        for (i, offset) in [7_000, 21_000, 40_000].into_iter().enumerate() {
+            inserted.splice(offset..offset, b"inserted".repeat(i + 1));
+        }
+
+        for variant in [&shifted, &inserted] {
+            assert!(positional_match(&base, variant) < 0.2);
+            let similarity = similarity_ratio(&base, variant);
+            assert!(similarity > 0.9, "{}", similarity);
+        }
+
+        // Half the content replaced shares a third of the shingles.
+        let mut replaced = base.clone();
+        replaced[..25_000].copy_from_slice(&noise(25_000, 4));
+        let similarity = similarity_ratio(&base, &replaced);
+        assert!((0.2..0.45).contains(&similarity), "{}", similarity);
+
+        let binaries = vec![
+            ("linux-x86_64".to_string(), base),
+            ("darwin-x86_64".to_string(), shifted),
+            ("windows-x86_64".to_string(), inserted),
+        ];
+        let groups = group_by_similarity(&binaries, 0.65);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].delta_targets, ["darwin-x86_64", "windows-x86_64"]);
     }
 
     #[test]
     fn test_grouping() {
+        let x86 = noise(2000, 5);
+        let arm = noise(2000, 6);
+        let edited = |data: &[u8]| {
+            let mut data = data.to_vec();
+            data[1000] ^= 1;
+            data
+        };
         let binaries = vec![
-            ("linux-x86_64".to_string(), vec![1, 2, 3, 4]),
-            ("darwin-x86_64".to_string(), vec![1, 2, 3, 5]),
-            ("linux-aarch64".to_string(), vec![10, 20, 30, 40]),
-            ("darwin-aarch64".to_string(), vec![10, 20, 30, 50]),
+            ("linux-x86_64".to_string(), x86.clone()),
+            ("darwin-x86_64".to_string(), edited(&x86)),
+            ("linux-aarch64".to_string(), arm.clone()),
+            ("darwin-aarch64".to_string(), edited(&arm)),
         ];
 
         let groups = group_by_similarity(&binaries, 0.5);
@@ -186,4 +642,111 @@ mod tests {
         // Should group x86_64 together and aarch64 together
         assert_eq!(groups.len(), 2);
     }
+
+    #[test]
+    fn test_select_references_picks_middle() {
+        // `middle` shares two thirds with each outer binary, which share
+        // only a third with each other.
+        let base = noise(3000, 7);
+        let mut first = base.clone();
+        first[..1000].copy_from_slice(&noise(1000, 8));
+        let mut last = base.clone();
+        last[2000..].copy_from_slice(&noise(1000, 9));
+        let binaries = vec![
+            ("linux-x86_64".to_string(), first),
+            ("windows-x86_64".to_string(), last),
+            ("darwin-x86_64".to_string(), base),
+        ];
+
+        let mut groups = group_by_similarity(&binaries, 0.15);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reference_target, "linux-x86_64");
+        select_references(&mut groups, &binaries);
+        assert_eq!(
+            groups,
+            [DeltaGroup {
+                reference_target: "darwin-x86_64".to_string(),
+                delta_targets: vec!["linux-x86_64".to_string(), "windows-x86_64".to_string()],
+                chained: false,
+            }]
+        );
+
+        // Already the best anchor: nothing moves.
+        let before = groups.clone();
+        select_references(&mut groups, &binaries);
+        assert_eq!(groups, before);
+    }
+
+    #[test]
+    fn test_order_chains() {
+        // Each binary changes a further block of the one before it, so the
+        // chain follows that order whatever order they're given in.
+        let mut data = noise(4000, 10);
+        let mut binaries = Vec::new();
+        for (i, target) in [
+            "linux-x86_64",
+            "darwin-x86_64",
+            "windows-x86_64",
+            "freebsd-x86_64",
+        ]
+        .iter()
+        .enumerate()
+        {
+            data[i * 1000..(i + 1) * 1000].copy_from_slice(&noise(1000, 11 + i as u32));
+            binaries.push((target.to_string(), data.clone()));
+        }
+        binaries.swap(1, 3);
+
+        let mut groups = group_by_similarity(&binaries, 0.1);
+        order_chains(&mut groups, &binaries);
+        let group = &groups[0];
+        assert!(group.chained);
+        assert_eq!(
+            group.delta_targets,
+            ["darwin-x86_64", "windows-x86_64", "freebsd-x86_64"]
+        );
+        let references: Vec<&str> = (0..3).map(|i| group.reference_of(i)).collect();
+        assert_eq!(
+            references,
+            ["linux-x86_64", "darwin-x86_64", "windows-x86_64"]
+        );
+
+        // Long chains start again from the reference.
+        let long = DeltaGroup {
+            reference_target: "base".to_string(),
+            delta_targets: (0..MAX_CHAIN_LENGTH + 2).map(|i| i.to_string()).collect(),
+            chained: true,
+        };
+        assert_eq!(
+            long.reference_of(MAX_CHAIN_LENGTH - 1),
+            (MAX_CHAIN_LENGTH - 2).to_string()
+        );
+        assert_eq!(long.reference_of(MAX_CHAIN_LENGTH), "base");
+        assert_eq!(
+            long.reference_of(MAX_CHAIN_LENGTH + 1),
+            MAX_CHAIN_LENGTH.to_string()
+        );
+    }
+
+    #[test]
+    fn test_check_chain() {
+        assert!(check_chain("b", "a", &[]).is_ok());
+        assert!(check_chain("a", "a", &[]).is_err());
+        assert!(check_chain("b", "c", &["c"]).is_err());
+
+        let names: Vec<String> = (0..MAX_CHAIN_LENGTH).map(|i| i.to_string()).collect();
+        let chain: Vec<&str> = names.iter().map(String::as_str).collect();
+        assert!(check_chain("x", "y", &chain[1..]).is_ok());
+        let err = check_chain("x", "y", &chain).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{}", err);
+    }
+
+    #[test]
+    fn test_arch_group() {
+        assert_eq!(arch_group("linux-x86_64"), "x86_64");
+        assert_eq!(arch_group("tool/darwin-aarch64"), "aarch64");
+        assert_eq!(arch_group("linux-i686"), arch_group("windows-x86"));
+        assert_eq!(arch_group("my-tool/linux-armv7"), "armv7");
+        assert_eq!(arch_group("plan9-amd64"), "plan9-amd64");
+    }
 }
@@ -5,21 +5,152 @@
 //! similar binaries (same architecture, similar code patterns).
 
 use crate::{CompressionError, Result};
+use pbin_core::Checksum;
+use std::io::{self, BufReader, Read, Write};
 
 /// Default dictionary size (32KB is a good balance).
 pub const DEFAULT_DICT_SIZE: usize = 32 * 1024;
 
-/// Maximum dictionary size (128KB).
-pub const MAX_DICT_SIZE: usize = 128 * 1024;
+/// Dictionary sizes training accepts: from zstd's smallest to 16 MiB.
+/// zstd itself sets no upper limit, but past that training takes far longer
+/// than a bigger dictionary could save.
+pub const DICT_SIZE_RANGE: std::ops::RangeInclusive<usize> = 256..=16 * 1024 * 1024;
+
+/// Window log used for long-distance matching unless one is configured
+/// (a 128 MiB window, the largest the zstd CLI decodes by default).
+pub const DEFAULT_LONG_WINDOW_LOG: u32 = 27;
+
+/// Window logs zstd accepts on 64-bit systems.
+pub const WINDOW_LOG_RANGE: std::ops::RangeInclusive<u32> = 10..=31;
+
+/// Chain logs zstd accepts on 64-bit systems.
+pub const CHAIN_LOG_RANGE: std::ops::RangeInclusive<u32> = 6..=30;
+
+/// Largest target length zstd accepts (its maximum block size).
+pub const MAX_TARGET_LENGTH: u32 = 128 * 1024;
+
+/// Most worker threads zstd runs at once on 64-bit systems.
+pub const MAX_WORKERS: u32 = 256;
 
 /// Minimum number of samples needed for dictionary training.
 pub const MIN_SAMPLES: usize = 4;
 
+/// Magic bytes opening a saved dictionary (see
+/// [`TrainedDictionary::to_bytes`]).
+pub const DICT_MAGIC: [u8; 4] = *b"PBDT";
+
+/// Magic bytes opening a saved set of dictionaries labelled by group (see
+/// [`dictionaries_to_bytes`]).
+pub const DICT_SET_MAGIC: [u8; 4] = *b"PBDS";
+
+/// Magic bytes opening a dictionary in zstd's own format, as
+/// `zstd --train` writes it.
+pub const ZSTD_DICT_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+/// Version of the saved dictionary formats.
+pub const DICT_FORMAT_VERSION: u8 = 1;
+
+/// Default size of the pieces binaries are cut into when there are too few
+/// of them to train a dictionary on whole.
+pub const DEFAULT_SAMPLE_SIZE: usize = 16 * 1024;
+
+/// How to train a dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrainingParams {
+    /// Largest dictionary to train, within [`DICT_SIZE_RANGE`]. Training
+    /// may return a smaller one.
+    pub dict_size: usize,
+    /// zstd level the dictionary's entropy tables are tuned for, or 0 for
+    /// zstd's default.
+    pub level: i32,
+    /// Pin every training parameter (see
+    /// [`train_dictionary_deterministic`]).
+    pub deterministic: bool,
+}
+
+impl Default for TrainingParams {
+    fn default() -> Self {
+        Self {
+            dict_size: DEFAULT_DICT_SIZE,
+            level: 0,
+            deterministic: false,
+        }
+    }
+}
+
+impl TrainingParams {
+    /// Checks the dictionary size is within [`DICT_SIZE_RANGE`] and the
+    /// level is 0 or one zstd accepts.
+    pub fn validate(&self) -> Result<()> {
+        if !DICT_SIZE_RANGE.contains(&self.dict_size) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "dictionary size {} is outside {}-{}",
+                self.dict_size,
+                DICT_SIZE_RANGE.start(),
+                DICT_SIZE_RANGE.end()
+            )));
+        }
+        let range = level_range();
+        if self.level != 0 && !range.contains(&self.level) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "dictionary training level {} is outside {}-{}",
+                self.level,
+                range.start(),
+                range.end()
+            )));
+        }
+        Ok(())
+    }
+}
+
 /// Train a zstd dictionary from multiple binary samples.
 ///
 /// The dictionary captures common patterns across all samples,
 /// improving compression ratios significantly (often 20-40% better).
 pub fn train_dictionary(samples: &[&[u8]], dict_size: usize) -> Result<Vec<u8>> {
+    train_dictionary_with(
+        samples,
+        &TrainingParams {
+            dict_size,
+            ..Default::default()
+        },
+    )
+}
+
+/// Segment size (`k`) [`train_dictionary_deterministic`] pins, in the
+/// middle of the range zstd's own training searches.
+pub const DETERMINISTIC_SEGMENT_SIZE: u32 = 1024;
+
+/// Dmer size (`d`) [`train_dictionary_deterministic`] pins, as zstd's own
+/// training does.
+pub const DETERMINISTIC_DMER_SIZE: u32 = 8;
+
+/// Like [`train_dictionary`], but with every fastCover parameter pinned
+/// and on one thread, so identical samples always give identical
+/// dictionaries.
+///
+/// [`train_dictionary`] searches segment sizes for the best dictionary,
+/// and that search is free to change between zstd releases. This trains
+/// once with [`DETERMINISTIC_SEGMENT_SIZE`] and [`DETERMINISTIC_DMER_SIZE`]
+/// on every sample, and the dictionary ID comes from its content.
+pub fn train_dictionary_deterministic(samples: &[&[u8]], dict_size: usize) -> Result<Vec<u8>> {
+    train_dictionary_with(
+        samples,
+        &TrainingParams {
+            dict_size,
+            deterministic: true,
+            ..Default::default()
+        },
+    )
+}
+
+/// Train a dictionary from `samples` as `params` asks, with zstd's
+/// fastCover trainer on one thread: searching segment sizes as
+/// [`train_dictionary`] does, or pinned as
+/// [`train_dictionary_deterministic`] does.
+pub fn train_dictionary_with(samples: &[&[u8]], params: &TrainingParams) -> Result<Vec<u8>> {
+    use zstd::zstd_safe::zstd_sys;
+
     if samples.len() < MIN_SAMPLES {
         return Err(CompressionError::InvalidData(format!(
             "Need at least {} samples for dictionary training, got {}",
@@ -27,13 +158,68 @@ pub fn train_dictionary(samples: &[&[u8]], dict_size: usize) -> Result<Vec<u8>>
             samples.len()
         )));
     }
+    params.validate()?;
+
+    let buffer = samples.concat();
+    let sizes: Vec<usize> = samples.iter().map(|s| s.len()).collect();
+    // zstd's own defaults, as its ZDICT_trainFromBuffer sets them, unless
+    // pinned.
+    let mut fast_cover = zstd_sys::ZDICT_fastCover_params_t {
+        k: 0,
+        d: DETERMINISTIC_DMER_SIZE,
+        f: 20,
+        steps: 4,
+        nbThreads: 0,
+        splitPoint: 0.0,
+        accel: 1,
+        shrinkDict: 0,
+        shrinkDictMaxRegression: 0,
+        zParams: zstd_sys::ZDICT_params_t {
+            compressionLevel: params.level,
+            notificationLevel: 0,
+            dictID: 0,
+        },
+    };
+    if params.deterministic {
+        fast_cover.k = DETERMINISTIC_SEGMENT_SIZE.min(params.dict_size as u32);
+        fast_cover.steps = 0;
+        // Train on every sample rather than holding some back to test.
+        fast_cover.splitPoint = 1.0;
+    }
 
-    let dict_size = dict_size.min(MAX_DICT_SIZE);
-
-    // Train dictionary using zstd - it takes a slice of samples
-    let dict = zstd::dict::from_samples(samples, dict_size)
-        .map_err(|e| CompressionError::Zstd(format!("Dictionary training failed: {}", e)))?;
-
+    let mut dict = vec![0u8; params.dict_size];
+    // SAFETY: the pointers and lengths describe `dict`, `buffer`, `sizes`
+    // and `fast_cover`, which outlive the call, and `sizes` adds up to the
+    // length of `buffer`.
+    let written = unsafe {
+        if params.deterministic {
+            zstd_sys::ZDICT_trainFromBuffer_fastCover(
+                dict.as_mut_ptr().cast(),
+                dict.len(),
+                buffer.as_ptr().cast(),
+                sizes.as_ptr(),
+                sizes.len() as u32,
+                fast_cover,
+            )
+        } else {
+            zstd_sys::ZDICT_optimizeTrainFromBuffer_fastCover(
+                dict.as_mut_ptr().cast(),
+                dict.len(),
+                buffer.as_ptr().cast(),
+                sizes.as_ptr(),
+                sizes.len() as u32,
+                &mut fast_cover,
+            )
+        }
+    };
+    // SAFETY: ZDICT_isError only inspects the code.
+    if unsafe { zstd_sys::ZDICT_isError(written) } != 0 {
+        return Err(CompressionError::Zstd(format!(
+            "Dictionary training failed: {}",
+            zstd::zstd_safe::get_error_name(written)
+        )));
+    }
+    dict.truncate(written);
     Ok(dict)
 }
 
@@ -41,38 +227,291 @@ pub fn train_dictionary(samples: &[&[u8]], dict_size: usize) -> Result<Vec<u8>>
 pub fn compress_with_dict(data: &[u8], dict: &[u8], level: i32) -> Result<Vec<u8>> {
     let mut encoder = zstd::bulk::Compressor::with_dictionary(level, dict)
         .map_err(|e| CompressionError::Zstd(format!("Failed to create compressor: {}", e)))?;
+    checksum_frames(&mut encoder)?;
 
     encoder
         .compress(data)
         .map_err(|e| CompressionError::Zstd(format!("Compression failed: {}", e)))
 }
 
-/// Decompress data using a trained dictionary.
-pub fn decompress_with_dict(data: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
-    let mut decoder = zstd::bulk::Decompressor::with_dictionary(dict)
-        .map_err(|e| CompressionError::Zstd(format!("Failed to create decompressor: {}", e)))?;
+/// Have `encoder` end each frame with a checksum of its content, so
+/// decoders catch corruption without hashing the output separately.
+fn checksum_frames(encoder: &mut zstd::bulk::Compressor<'_>) -> Result<()> {
+    encoder
+        .set_parameter(zstd::zstd_safe::CParameter::ChecksumFlag(true))
+        .map_err(|e| CompressionError::Zstd(format!("Failed to configure compressor: {}", e)))
+}
+
+/// zstd's messages for frames whose content is damaged.
+const CORRUPTION_MESSAGES: [&str; 2] = [
+    "Restored data doesn't match checksum",
+    "Data corruption detected",
+];
+
+/// Converts a zstd decoding failure to [`CompressionError::CorruptFrame`]
+/// if the frame is damaged, or [`CompressionError::Decompression`]
+/// otherwise.
+pub(crate) fn decode_error(error: io::Error) -> CompressionError {
+    let message = error.to_string();
+    if CORRUPTION_MESSAGES.contains(&message.as_str()) {
+        CompressionError::CorruptFrame(message)
+    } else {
+        CompressionError::Decompression(format!("Decompression failed: {}", message))
+    }
+}
+
+/// Zstd parameters beyond the compression level. Fields left unset keep
+/// the level's own choice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZstdParams {
+    /// Base-2 log of the window matches are searched in. Decoders need
+    /// this much memory, and refuse windows over 2^27 bytes unless told.
+    pub window_log: Option<u32>,
+    /// Base-2 log of the match chain table higher levels search.
+    pub chain_log: Option<u32>,
+    /// Match length past which the match finder stops looking for longer.
+    pub target_length: Option<u32>,
+    /// Worker threads compressing in parallel, or 0 to compress on the
+    /// calling thread. Output differs from single-threaded output but
+    /// doesn't depend on the number of workers.
+    pub workers: u32,
+    /// Find repeats far further back than the level's search reaches,
+    /// anywhere in the window.
+    pub long_distance_matching: bool,
+}
+
+impl ZstdParams {
+    /// Returns these parameters with the chain log, target length and
+    /// worker count clamped into the ranges zstd accepts.
+    ///
+    /// The window log is left as is: it sets the memory decoders need, so
+    /// [`ZstdParams::validate`] rejects one out of range instead.
+    pub fn clamped(self) -> Self {
+        Self {
+            chain_log: self
+                .chain_log
+                .map(|log| log.clamp(*CHAIN_LOG_RANGE.start(), *CHAIN_LOG_RANGE.end())),
+            target_length: self
+                .target_length
+                .map(|length| length.min(MAX_TARGET_LENGTH)),
+            workers: self.workers.min(MAX_WORKERS),
+            ..self
+        }
+    }
+
+    /// Checks the window log is within [`WINDOW_LOG_RANGE`].
+    pub fn validate(&self) -> Result<()> {
+        match self.window_log {
+            Some(log) if !WINDOW_LOG_RANGE.contains(&log) => {
+                Err(CompressionError::InvalidSetting(format!(
+                    "window log {} is outside {}-{}",
+                    log,
+                    WINDOW_LOG_RANGE.start(),
+                    WINDOW_LOG_RANGE.end()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the zstd parameters to set on a compressor.
+    fn parameters(&self) -> Vec<zstd::zstd_safe::CParameter> {
+        use zstd::zstd_safe::CParameter;
+
+        let params = self.clamped();
+        let mut parameters = vec![CParameter::ChecksumFlag(true)];
+        if let Some(log) = params.window_log {
+            parameters.push(CParameter::WindowLog(log));
+        }
+        if let Some(log) = params.chain_log {
+            parameters.push(CParameter::ChainLog(log));
+        }
+        if let Some(length) = params.target_length {
+            parameters.push(CParameter::TargetLength(length));
+        }
+        if params.workers > 0 {
+            parameters.push(CParameter::NbWorkers(params.workers));
+        }
+        if params.long_distance_matching {
+            parameters.push(CParameter::EnableLongDistanceMatching(true));
+        }
+        parameters
+    }
+}
+
+/// Compress data with `params` on top of `level`, with a trained
+/// dictionary if given.
+///
+/// Streams through zstd's encoder rather than `zstd::bulk`, which only
+/// compresses on the calling thread, so `params.workers` can split large
+/// inputs across threads. Data compressed with a `window_log` over 27 only
+/// decodes with a decompressor told to allow that window.
+pub fn compress_with_params(
+    data: &[u8],
+    dict: Option<&[u8]>,
+    level: i32,
+    params: &ZstdParams,
+) -> Result<Vec<u8>> {
+    let mut encoder = stream_encoder(Vec::new(), dict, level, params, data.len() as u64)?;
+    encoder
+        .write_all(data)
+        .map_err(|e| CompressionError::Zstd(format!("Compression failed: {}", e)))?;
+    encoder
+        .finish()
+        .map_err(|e| CompressionError::Zstd(format!("Compression failed: {}", e)))
+}
+
+/// Returns an encoder writing one zstd frame for `size` bytes of input to
+/// `output`, compressed as [`compress_with_params`] does. The frame is
+/// complete once the encoder is finished.
+pub fn stream_encoder<W: Write>(
+    output: W,
+    dict: Option<&[u8]>,
+    level: i32,
+    params: &ZstdParams,
+    size: u64,
+) -> Result<zstd::stream::write::Encoder<'static, W>> {
+    params.validate()?;
+    let mut encoder =
+        zstd::stream::write::Encoder::with_dictionary(output, level, dict.unwrap_or_default())
+            .map_err(|e| CompressionError::Zstd(format!("Failed to create compressor: {}", e)))?;
+    for parameter in params.parameters() {
+        encoder.set_parameter(parameter).map_err(|e| {
+            CompressionError::Zstd(format!("Failed to configure compressor: {}", e))
+        })?;
+    }
+    // Records the size in the frame header, as `zstd::bulk` does.
+    encoder
+        .set_pledged_src_size(Some(size))
+        .map_err(|e| CompressionError::Zstd(format!("Failed to configure compressor: {}", e)))?;
+    Ok(encoder)
+}
 
-    // Estimate output size (compressed data is typically 2-10x smaller)
-    let estimated_size = data.len() * 10;
+/// Decompress data using a trained dictionary. See [`decompress`] for how
+/// the output is sized.
+pub fn decompress_with_dict(
+    data: &[u8],
+    dict: &[u8],
+    expected_size: Option<usize>,
+) -> Result<Vec<u8>> {
+    decompress_bulk(data, Some(dict), expected_size)
+}
 
-    decoder
-        .decompress(data, estimated_size)
-        .map_err(|e| CompressionError::Decompression(format!("Decompression failed: {}", e)))
+/// Returns the compression levels zstd accepts.
+pub fn level_range() -> std::ops::RangeInclusive<i32> {
+    zstd::compression_level_range()
 }
 
 /// Compress data without a dictionary (standard zstd).
 pub fn compress(data: &[u8], level: i32) -> Result<Vec<u8>> {
-    zstd::bulk::compress(data, level)
+    let mut encoder = zstd::bulk::Compressor::new(level)
+        .map_err(|e| CompressionError::Zstd(format!("Failed to create compressor: {}", e)))?;
+    checksum_frames(&mut encoder)?;
+
+    encoder
+        .compress(data)
         .map_err(|e| CompressionError::Zstd(format!("Compression failed: {}", e)))
 }
 
 /// Decompress data without a dictionary.
-pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
-    // Estimate output size
-    let estimated_size = data.len() * 10;
+///
+/// The output is sized from the content size zstd records in the frame
+/// header, then from `expected_size`. Without either it starts at ten
+/// times the input and doubles until the data fits. Output never grows
+/// past [`pbin_core::DEFAULT_MAX_ENTRY_SIZE`].
+pub fn decompress(data: &[u8], expected_size: Option<usize>) -> Result<Vec<u8>> {
+    decompress_bulk(data, None, expected_size)
+}
+
+/// Decompress `data`, with `dict` if given, sized as [`decompress`]
+/// describes.
+fn decompress_bulk(
+    data: &[u8],
+    dict: Option<&[u8]>,
+    expected_size: Option<usize>,
+) -> Result<Vec<u8>> {
+    use zstd::zstd_safe::{self, zstd_sys, DCtx};
+
+    let mut decoder = DCtx::try_create().ok_or_else(|| {
+        CompressionError::Zstd("Failed to create decompressor: out of memory".to_string())
+    })?;
+    let limit = usize::try_from(pbin_core::DEFAULT_MAX_ENTRY_SIZE).unwrap_or(usize::MAX);
+    let recorded = match zstd_safe::get_frame_content_size(data) {
+        Ok(Some(size)) => Some(usize::try_from(size).unwrap_or(usize::MAX)),
+        Ok(None) | Err(_) => None,
+    };
+    let mut capacity = recorded
+        .or(expected_size)
+        .unwrap_or_else(|| data.len().saturating_mul(10));
+    if capacity > limit {
+        return Err(CompressionError::Decompression(format!(
+            "Decompressed data exceeds {} bytes",
+            limit
+        )));
+    }
 
-    zstd::bulk::decompress(data, estimated_size)
-        .map_err(|e| CompressionError::Decompression(format!("Decompression failed: {}", e)))
+    loop {
+        let mut output = Vec::with_capacity(capacity);
+        let result = match dict {
+            Some(dict) => decoder.decompress_using_dict(&mut output, data, dict),
+            None => decoder.decompress(&mut output, data),
+        };
+        let code = match result {
+            Ok(_) => return Ok(output),
+            Err(code) => code,
+        };
+        // SAFETY: ZSTD_getErrorCode only inspects the code.
+        let error = unsafe { zstd_sys::ZSTD_getErrorCode(code) };
+        if error == zstd_sys::ZSTD_ErrorCode::ZSTD_error_dstSize_tooSmall && capacity < limit {
+            capacity = capacity.saturating_mul(2).clamp(1, limit);
+        } else {
+            return Err(decode_error(io::Error::other(zstd_safe::get_error_name(
+                code,
+            ))));
+        }
+    }
+}
+
+/// Decompress a zstd stream from `reader` into `writer` without buffering
+/// the whole output, returning the number of bytes written.
+///
+/// `expected_len` is a hard cap: decompression fails once the data would
+/// exceed it, without writing the excess. Since the compressor never uses a
+/// window larger than the data, the cap also lets the decoder accept frames
+/// with windows up to its size, beyond the 2^27 bytes zstd allows by default.
+pub fn decompress_stream<R: Read, W: Write>(
+    reader: R,
+    writer: &mut W,
+    dict: Option<&[u8]>,
+    expected_len: Option<u64>,
+) -> Result<u64> {
+    let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+        BufReader::new(reader),
+        dict.unwrap_or_default(),
+    )
+    .map_err(|e| CompressionError::Zstd(format!("Failed to create decompressor: {}", e)))?;
+    let Some(limit) = expected_len else {
+        return io::copy(&mut decoder, writer).map_err(decode_error);
+    };
+
+    let window_log =
+        (u64::BITS - limit.saturating_sub(1).leading_zeros()).min(*WINDOW_LOG_RANGE.end());
+    if window_log > DEFAULT_LONG_WINDOW_LOG {
+        decoder.window_log_max(window_log).map_err(|e| {
+            CompressionError::Zstd(format!("Failed to configure decompressor: {}", e))
+        })?;
+    }
+    let written = io::copy(&mut (&mut decoder).take(limit), writer).map_err(decode_error)?;
+    // Anything left once the cap is reached is over it.
+    let mut extra = [0u8; 1];
+    let over = decoder.read(&mut extra).map_err(decode_error)?;
+    if over > 0 {
+        return Err(CompressionError::Decompression(format!(
+            "Decompressed data exceeds {} bytes",
+            limit
+        )));
+    }
+    Ok(written)
 }
 
 /// Represents a trained dictionary with metadata.
@@ -89,8 +528,32 @@ pub struct TrainedDictionary {
 impl TrainedDictionary {
     /// Train a new dictionary from samples.
     pub fn train(samples: &[&[u8]], dict_size: usize) -> Result<Self> {
+        Self::train_with(
+            samples,
+            &TrainingParams {
+                dict_size,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Like [`train`](Self::train), but always giving the same dictionary
+    /// for the same samples (see [`train_dictionary_deterministic`]).
+    pub fn train_deterministic(samples: &[&[u8]], dict_size: usize) -> Result<Self> {
+        Self::train_with(
+            samples,
+            &TrainingParams {
+                dict_size,
+                deterministic: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Train a new dictionary from samples as `params` asks.
+    pub fn train_with(samples: &[&[u8]], params: &TrainingParams) -> Result<Self> {
         let total_sample_size = samples.iter().map(|s| s.len()).sum();
-        let data = train_dictionary(samples, dict_size)?;
+        let data = train_dictionary_with(samples, params)?;
 
         Ok(Self {
             data,
@@ -99,15 +562,135 @@ impl TrainedDictionary {
         })
     }
 
+    /// ID recorded in the manifest for this dictionary and the entries
+    /// compressed with it.
+    pub fn id(&self) -> String {
+        pbin_core::PbinDictionary::id_for(&self.data)
+    }
+
     /// Compress data using this dictionary.
     pub fn compress(&self, data: &[u8], level: i32) -> Result<Vec<u8>> {
         compress_with_dict(data, &self.data, level)
     }
 
-    /// Decompress data using this dictionary.
-    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
-        decompress_with_dict(data, &self.data)
+    /// Decompress data using this dictionary. See [`decompress`] for how
+    /// the output is sized.
+    pub fn decompress(&self, data: &[u8], expected_size: Option<usize>) -> Result<Vec<u8>> {
+        decompress_with_dict(data, &self.data, expected_size)
+    }
+
+    /// Size of the header [`to_bytes`](Self::to_bytes) writes ahead of the
+    /// dictionary.
+    pub const HEADER_SIZE: usize = 4 + 1 + 8 + 8 + 32;
+
+    /// Encode the dictionary with its training metadata, so a later run can
+    /// reuse it instead of training again: [`DICT_MAGIC`], the format
+    /// version, the sample count and total sample size (little-endian
+    /// u64s), the BLAKE3 of the dictionary, then the dictionary.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(&DICT_MAGIC);
+        bytes.push(DICT_FORMAT_VERSION);
+        bytes.extend_from_slice(&(self.sample_count as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.total_sample_size as u64).to_le_bytes());
+        bytes.extend_from_slice(Checksum::of(&self.data).as_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Decode a dictionary encoded by [`to_bytes`](Self::to_bytes).
+    /// Unknown versions, and dictionaries whose bytes don't match their
+    /// hash, are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < Self::HEADER_SIZE || bytes[..4] != DICT_MAGIC {
+            return Err(CompressionError::InvalidData(
+                "Not a saved dictionary".to_string(),
+            ));
+        }
+        if bytes[4] != DICT_FORMAT_VERSION {
+            return Err(CompressionError::InvalidData(format!(
+                "Unsupported saved dictionary version {} (this build reads version {})",
+                bytes[4], DICT_FORMAT_VERSION
+            )));
+        }
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        let checksum = Checksum::from(<[u8; 32]>::try_from(&bytes[21..53]).unwrap());
+        let data = bytes[Self::HEADER_SIZE..].to_vec();
+        if !checksum.matches(&data) {
+            return Err(CompressionError::InvalidData(
+                "Saved dictionary doesn't match its checksum".to_string(),
+            ));
+        }
+        Ok(Self {
+            data,
+            sample_count: u64_at(5) as usize,
+            total_sample_size: u64_at(13) as usize,
+        })
+    }
+}
+
+/// Encode `dictionaries` with the groups they were trained for:
+/// [`DICT_SET_MAGIC`], the format version and the number of dictionaries
+/// (a little-endian u32), then for each one the group's length (u16) and
+/// name, and the length (u64) and bytes of its
+/// [`TrainedDictionary::to_bytes`] encoding.
+pub fn dictionaries_to_bytes(dictionaries: &[(String, TrainedDictionary)]) -> Vec<u8> {
+    let mut bytes = DICT_SET_MAGIC.to_vec();
+    bytes.push(DICT_FORMAT_VERSION);
+    bytes.extend_from_slice(&(dictionaries.len() as u32).to_le_bytes());
+    for (group, dictionary) in dictionaries {
+        let encoded = dictionary.to_bytes();
+        bytes.extend_from_slice(&(group.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(group.as_bytes());
+        bytes.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&encoded);
     }
+    bytes
+}
+
+/// Decode dictionaries encoded by [`dictionaries_to_bytes`].
+pub fn dictionaries_from_bytes(bytes: &[u8]) -> Result<Vec<(String, TrainedDictionary)>> {
+    let truncated = || CompressionError::InvalidData("Saved dictionaries are truncated".into());
+    let mut rest = bytes;
+    let mut take = |len: usize| -> Result<&[u8]> {
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        let (taken, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(taken)
+    };
+
+    if take(4)? != DICT_SET_MAGIC {
+        return Err(CompressionError::InvalidData(
+            "Not a set of saved dictionaries".to_string(),
+        ));
+    }
+    let version = take(1)?[0];
+    if version != DICT_FORMAT_VERSION {
+        return Err(CompressionError::InvalidData(format!(
+            "Unsupported saved dictionaries version {} (this build reads version {})",
+            version, DICT_FORMAT_VERSION
+        )));
+    }
+    let count = u32::from_le_bytes(take(4)?.try_into().unwrap());
+    let mut dictionaries = Vec::new();
+    for _ in 0..count {
+        let len = u16::from_le_bytes(take(2)?.try_into().unwrap());
+        let group = String::from_utf8(take(len as usize)?.to_vec()).map_err(|_| {
+            CompressionError::InvalidData("Saved dictionary group isn't UTF-8".to_string())
+        })?;
+        let len = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let len = usize::try_from(len).map_err(|_| truncated())?;
+        dictionaries.push((group, TrainedDictionary::from_bytes(take(len)?)?));
+    }
+    if !rest.is_empty() {
+        return Err(CompressionError::InvalidData(format!(
+            "{} bytes follow the saved dictionaries",
+            rest.len()
+        )));
+    }
+    Ok(dictionaries)
 }
 
 #[cfg(test)]
@@ -138,14 +721,80 @@ mod tests {
         let data = b"Hello, World! This is test data for compression.";
 
         let compressed = compress(data, 3).unwrap();
-        let decompressed = decompress(&compressed).unwrap();
+        let decompressed = decompress(&compressed, None).unwrap();
 
         assert_eq!(&decompressed, data);
     }
 
+    #[test]
+    fn test_decompress_beyond_ten_to_one() {
+        let zeros = vec![0u8; 1024 * 1024];
+
+        // Bulk compression records the content size in the frame header.
+        let compressed = compress(&zeros, 3).unwrap();
+        assert!(compressed.len() * 10 < zeros.len());
+        assert_eq!(decompress(&compressed, None).unwrap(), zeros);
+
+        // A streamed frame doesn't, so the expected size is used, or the
+        // output grows until it fits.
+        let streamed = zstd::stream::encode_all(zeros.as_slice(), 3).unwrap();
+        assert_eq!(
+            zstd::zstd_safe::get_frame_content_size(&streamed).unwrap(),
+            None
+        );
+        for expected_size in [Some(zeros.len()), Some(16), None] {
+            assert_eq!(decompress(&streamed, expected_size).unwrap(), zeros);
+        }
+
+        let samples: Vec<Vec<u8>> = (0..10).map(generate_sample).collect();
+        let refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = TrainedDictionary::train(&refs, 4096).unwrap();
+        let compressed = dict.compress(&zeros, 3).unwrap();
+        assert_eq!(dict.decompress(&compressed, None).unwrap(), zeros);
+
+        assert!(decompress(b"not zstd", None).is_err());
+    }
+
+    #[test]
+    fn test_frames_carry_checksums() {
+        let samples: Vec<Vec<u8>> = (0..10).map(generate_sample).collect();
+        let refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = TrainedDictionary::train(&refs, 4096).unwrap();
+        let data = samples.concat();
+        let params = ZstdParams {
+            workers: 2,
+            ..Default::default()
+        };
+
+        let frames = [
+            (compress(&data, 3).unwrap(), None),
+            (dict.compress(&data, 3).unwrap(), Some(dict.data.as_slice())),
+            (compress_with_params(&data, None, 3, &params).unwrap(), None),
+            (
+                compress_with_params(&data, Some(&dict.data), 3, &ZstdParams::default()).unwrap(),
+                Some(dict.data.as_slice()),
+            ),
+        ];
+        for (mut frame, dictionary) in frames {
+            // The frame ends with the low 4 bytes of the content's XXH64.
+            *frame.last_mut().unwrap() ^= 0xFF;
+            let result = match dictionary {
+                Some(dictionary) => decompress_with_dict(&frame, dictionary, None),
+                None => decompress(&frame, None),
+            };
+            assert!(
+                matches!(result, Err(CompressionError::CorruptFrame(_))),
+                "{:?}",
+                result.map(|data| data.len())
+            );
+            let streamed = decompress_stream(frame.as_slice(), &mut Vec::new(), dictionary, None);
+            assert!(matches!(streamed, Err(CompressionError::CorruptFrame(_))));
+        }
+    }
+
     #[test]
     fn test_dictionary_training() {
-        let samples: Vec<Vec<u8>> = (0..8).map(|i| generate_sample(i)).collect();
+        let samples: Vec<Vec<u8>> = (0..8).map(generate_sample).collect();
         let sample_refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
 
         let dict = TrainedDictionary::train(&sample_refs, DEFAULT_DICT_SIZE).unwrap();
@@ -156,7 +805,7 @@ mod tests {
 
     #[test]
     fn test_dictionary_compression() {
-        let samples: Vec<Vec<u8>> = (0..8).map(|i| generate_sample(i)).collect();
+        let samples: Vec<Vec<u8>> = (0..8).map(generate_sample).collect();
         let sample_refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
 
         let dict = TrainedDictionary::train(&sample_refs, DEFAULT_DICT_SIZE).unwrap();
@@ -164,14 +813,14 @@ mod tests {
         // Compress a new sample using the dictionary
         let new_sample = generate_sample(100);
         let compressed = dict.compress(&new_sample, 3).unwrap();
-        let decompressed = dict.decompress(&compressed).unwrap();
+        let decompressed = dict.decompress(&compressed, None).unwrap();
 
         assert_eq!(decompressed, new_sample);
 
         // Dictionary compression should be smaller than without
         let without_dict = compress(&new_sample, 3).unwrap();
         // Note: For small/simple test data, dictionary might not help much
-        assert!(compressed.len() > 0);
+        assert!(!compressed.is_empty());
         println!(
             "With dict: {} bytes, without: {} bytes",
             compressed.len(),
@@ -179,9 +828,160 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_saved_dictionaries_round_trip() {
+        let samples: Vec<Vec<u8>> = (0..8).map(generate_sample).collect();
+        let sample_refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = TrainedDictionary::train(&sample_refs, DEFAULT_DICT_SIZE).unwrap();
+
+        let bytes = dict.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            TrainedDictionary::HEADER_SIZE + dict.data.len()
+        );
+        let decoded = TrainedDictionary::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.data, dict.data);
+        assert_eq!(decoded.sample_count, 8);
+        assert_eq!(decoded.total_sample_size, dict.total_sample_size);
+
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        let err = TrainedDictionary::from_bytes(&damaged).unwrap_err();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+        let mut newer = bytes.clone();
+        newer[4] = DICT_FORMAT_VERSION + 1;
+        let err = TrainedDictionary::from_bytes(&newer).unwrap_err();
+        assert!(err.to_string().contains("Unsupported"), "{}", err);
+
+        let set = vec![
+            ("x86_64".to_string(), dict.clone()),
+            ("shared".to_string(), decoded),
+        ];
+        let bytes = dictionaries_to_bytes(&set);
+        let decoded = dictionaries_from_bytes(&bytes).unwrap();
+        let groups: Vec<&str> = decoded.iter().map(|(g, _)| g.as_str()).collect();
+        assert_eq!(groups, ["x86_64", "shared"]);
+        assert!(decoded.iter().all(|(_, d)| d.data == dict.data));
+        assert!(dictionaries_from_bytes(&dictionaries_to_bytes(&[]))
+            .unwrap()
+            .is_empty());
+        for len in [0, 8, bytes.len() - 1] {
+            assert!(dictionaries_from_bytes(&bytes[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_params_decode_with_standard_decompressor() {
+        let samples: Vec<Vec<u8>> = (0..8).map(generate_sample).collect();
+        let sample_refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = TrainedDictionary::train(&sample_refs, DEFAULT_DICT_SIZE).unwrap();
+        let data: Vec<u8> = (0..64)
+            .flat_map(|seed| generate_sample(seed as u8))
+            .collect();
+
+        let all_params = [
+            ZstdParams::default(),
+            ZstdParams {
+                window_log: Some(WINDOW_LOG_RANGE.start() + 2),
+                ..Default::default()
+            },
+            ZstdParams {
+                chain_log: Some(0),
+                target_length: Some(u32::MAX),
+                ..Default::default()
+            },
+            ZstdParams {
+                workers: 2,
+                ..Default::default()
+            },
+            ZstdParams {
+                window_log: Some(DEFAULT_LONG_WINDOW_LOG),
+                chain_log: Some(24),
+                target_length: Some(256),
+                workers: 4,
+                long_distance_matching: true,
+            },
+        ];
+        for params in all_params {
+            for dictionary in [None, Some(dict.data.as_slice())] {
+                let compressed = compress_with_params(&data, dictionary, 19, &params).unwrap();
+                let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                    compressed.as_slice(),
+                    dictionary.unwrap_or_default(),
+                )
+                .unwrap();
+                let mut decompressed = Vec::new();
+                std::io::Read::read_to_end(&mut decoder, &mut decompressed).unwrap();
+                assert_eq!(decompressed, data, "{:?}", params);
+            }
+        }
+    }
+
+    #[test]
+    fn test_params_clamped_and_validated() {
+        let params = ZstdParams {
+            window_log: Some(40),
+            chain_log: Some(1),
+            target_length: Some(u32::MAX),
+            workers: 10_000,
+            long_distance_matching: false,
+        };
+        let clamped = params.clamped();
+        assert_eq!(clamped.window_log, Some(40));
+        assert_eq!(clamped.chain_log, Some(*CHAIN_LOG_RANGE.start()));
+        assert_eq!(clamped.target_length, Some(MAX_TARGET_LENGTH));
+        assert_eq!(clamped.workers, MAX_WORKERS);
+
+        assert!(matches!(
+            compress_with_params(b"data", None, 3, &params),
+            Err(CompressionError::InvalidSetting(_))
+        ));
+    }
+
+    #[test]
+    fn test_decompress_stream() {
+        let samples: Vec<Vec<u8>> = (0..10).map(generate_sample).collect();
+        let refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
+        let dict = TrainedDictionary::train(&refs, 4096).unwrap();
+        let data = samples.concat();
+        let len = data.len() as u64;
+
+        for dictionary in [None, Some(dict.data.as_slice())] {
+            let params = ZstdParams::default();
+            let compressed = compress_with_params(&data, dictionary, 3, &params).unwrap();
+            for expected_len in [None, Some(len), Some(len + 100)] {
+                let mut output = Vec::new();
+                let written =
+                    decompress_stream(compressed.as_slice(), &mut output, dictionary, expected_len)
+                        .unwrap();
+                assert_eq!((written, &output), (len, &data));
+            }
+
+            let mut output = Vec::new();
+            let err = decompress_stream(
+                compressed.as_slice(),
+                &mut output,
+                dictionary,
+                Some(len - 1),
+            )
+            .unwrap_err();
+            assert!(err.to_string().contains("exceeds"), "{}", err);
+            assert_eq!(output.len() as u64, len - 1);
+        }
+
+        let compressed = compress(&data, 3).unwrap();
+        assert!(decompress_stream(
+            &compressed[..compressed.len() / 2],
+            &mut Vec::new(),
+            None,
+            None
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_insufficient_samples() {
-        let samples: Vec<Vec<u8>> = (0..2).map(|i| generate_sample(i)).collect();
+        let samples: Vec<Vec<u8>> = (0..2).map(generate_sample).collect();
         let sample_refs: Vec<&[u8]> = samples.iter().map(|s| s.as_slice()).collect();
 
         let result = train_dictionary(&sample_refs, DEFAULT_DICT_SIZE);
@@ -1,5 +1,6 @@
 //! Error types for compression operations.
 
+use pbin_core::ErrorCategory;
 use thiserror::Error;
 
 /// Result type for compression operations.
@@ -16,6 +17,22 @@ pub enum CompressionError {
     #[error("Zstd error: {0}")]
     Zstd(String),
 
+    /// LZ4 compression error.
+    #[error("LZ4 error: {0}")]
+    Lz4(String),
+
+    /// XZ compression error.
+    #[error("XZ error: {0}")]
+    Xz(String),
+
+    /// Brotli compression error.
+    #[error("Brotli error: {0}")]
+    Brotli(String),
+
+    /// A pipeline setting is out of range.
+    #[error("Invalid compression setting: {0}")]
+    InvalidSetting(String),
+
     /// Delta compression error.
     #[error("Delta compression error: {0}")]
     Delta(String),
@@ -31,4 +48,81 @@ pub enum CompressionError {
     /// Decompression error.
     #[error("Decompression error: {0}")]
     Decompression(String),
+
+    /// A zstd frame's content is damaged, as caught by its checksum or
+    /// block decoding.
+    #[error("Corrupt zstd frame: {0}")]
+    CorruptFrame(String),
+
+    /// PBIN format error while reading a file.
+    #[error("PBIN format error: {0}")]
+    Format(#[from] pbin_core::Error),
+
+    /// BCJ filtering failed for one binary.
+    #[error("BCJ filter failed for {target}: {source}")]
+    Filter {
+        target: String,
+        source: Box<CompressionError>,
+    },
+
+    /// Creating one binary's delta patch failed.
+    #[error("delta against {reference} failed for {target}: {source}")]
+    DeltaPatch {
+        target: String,
+        reference: String,
+        source: Box<CompressionError>,
+    },
+
+    /// Compressing one binary (or its patch) failed.
+    #[error("compressing {target} failed: {source}")]
+    Compress {
+        target: String,
+        source: Box<CompressionError>,
+    },
+
+    /// One binary's entry, reversed right after compressing it, didn't
+    /// give back the binary.
+    #[error("verifying {target} failed: {source}")]
+    Verify {
+        target: String,
+        source: Box<CompressionError>,
+    },
+}
+
+impl CompressionError {
+    /// Returns the error's category: that of the wrapped format error, or
+    /// [`ErrorCategory::Compression`] for failures of the pipeline itself.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            CompressionError::Io(_) => ErrorCategory::Io,
+            CompressionError::Format(e) => e.category(),
+            CompressionError::InvalidSetting(_) => ErrorCategory::Invalid,
+            CompressionError::CorruptFrame(_) => ErrorCategory::Corrupt,
+            CompressionError::Filter { source, .. }
+            | CompressionError::DeltaPatch { source, .. }
+            | CompressionError::Compress { source, .. } => source.category(),
+            _ => ErrorCategory::Compression,
+        }
+    }
+
+    /// Returns the target of the binary a pipeline stage failed on.
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            CompressionError::Filter { target, .. }
+            | CompressionError::DeltaPatch { target, .. }
+            | CompressionError::Compress { target, .. }
+            | CompressionError::Verify { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+}
+
+impl From<CompressionError> for pbin_core::Error {
+    fn from(error: CompressionError) -> Self {
+        match error {
+            CompressionError::Io(e) => pbin_core::Error::Io(e),
+            CompressionError::Format(e) => e,
+            other => pbin_core::Error::Compression(other.to_string()),
+        }
+    }
 }
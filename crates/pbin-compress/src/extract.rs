@@ -0,0 +1,1223 @@
+//! Entry extraction from PBIN files.
+//!
+//! Reverses the storage of a single entry, dispatching on the compression
+//! each entry records rather than assuming one method for the whole file.
+//! Delta entries are rebuilt from their reference and chunked entries from
+//! the chunk store, then any BCJ filter the manifest records is undone,
+//! over the ranges it records or else the whole entry. Extracting to disk
+//! streams a delta entry's patched output straight into its file.
+//!
+//! Every entry must come out at exactly its recorded `uncompressed_size`,
+//! and no entry may declare more than [`PbinFile::max_entry_size`], so a
+//! crafted stream can't expand past what the manifest promises. Stored bytes
+//! are checked against the entry's `compressed_checksum` before they're
+//! decompressed, and the result against its `checksum` before it's returned
+//! or written.
+
+use crate::bcj::{self, BcjArch, BcjFilter, BcjWriter};
+use crate::chunk;
+use crate::delta;
+use crate::dict;
+use crate::pipeline::HashingWriter;
+use crate::segment;
+use crate::{CompressionError, Result};
+use pbin_core::{Checksum, Compression, PbinEntry, PbinFile, Target};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Extract an entry's original bytes from a PBIN file.
+pub fn extract_entry(file: &PbinFile, entry: &PbinEntry) -> Result<Vec<u8>> {
+    file.manifest().check_checksum_key(file.checksum_key())?;
+    let mut data = filtered_data(file, entry, &[])?;
+    if let Some(arch) = entry_bcj_arch(entry)? {
+        match entry_bcj_ranges(entry) {
+            Some(ranges) => bcj::bcj_decode_ranges(&mut data, arch, &ranges)?,
+            None => BcjFilter::new(arch).decode(&mut data)?,
+        }
+    }
+    file.verify_entry(entry, &data)?;
+    Ok(data)
+}
+
+/// Extract the binary for `target`, and every asset next to it, into `dir`.
+///
+/// Each entry is written under its recorded file name, with binaries falling
+/// back to the application name, and on Unix gets its recorded permission
+/// bits. Returns the binary's path.
+pub fn extract_to_dir(file: &PbinFile, target: Target, dir: &Path) -> Result<PathBuf> {
+    let manifest = file.manifest();
+    let entry = manifest
+        .find_entry(target)
+        .ok_or_else(|| pbin_core::Error::TargetNotFound(target.as_str().to_string()))?;
+    let binary_path = write_entry(file, entry, dir)?;
+
+    for asset in manifest.assets() {
+        write_entry(file, asset, dir)?;
+    }
+
+    Ok(binary_path)
+}
+
+/// Extract the darwin-x86_64 and darwin-aarch64 binaries as one universal
+/// Mach-O binary that runs natively on both.
+pub fn extract_universal_macos(file: &PbinFile) -> Result<Vec<u8>> {
+    let intel = extract_entry(file, find_binary(file, Target::DarwinX86_64)?)?;
+    let arm = extract_entry(file, find_binary(file, Target::DarwinAarch64)?)?;
+    segment::build_universal(&[&intel, &arm])
+}
+
+/// Like [`extract_to_dir`], writing a universal macOS binary (see
+/// [`extract_universal_macos`]) under the darwin-aarch64 entry's name and
+/// mode.
+pub fn extract_universal_macos_to_dir(file: &PbinFile, dir: &Path) -> Result<PathBuf> {
+    let data = extract_universal_macos(file)?;
+    let binary_path = write_data(file, find_binary(file, Target::DarwinAarch64)?, &data, dir)?;
+
+    for asset in file.manifest().assets() {
+        write_entry(file, asset, dir)?;
+    }
+
+    Ok(binary_path)
+}
+
+/// Returns the binary entry for `target`.
+fn find_binary(file: &PbinFile, target: Target) -> Result<&PbinEntry> {
+    file.manifest()
+        .find_entry(target)
+        .ok_or_else(|| pbin_core::Error::TargetNotFound(target.as_str().to_string()).into())
+}
+
+/// Write one entry into `dir` under its output name and mode.
+///
+/// A delta entry is patched straight into the file, so only its reference
+/// and patch are held in memory, not its output as well. It's hashed as it's
+/// written, and the file is removed again if it doesn't match. One
+/// BCJ-filtered over ranges is patched in memory, since [`BcjWriter`]
+/// filters all it writes.
+fn write_entry(file: &PbinFile, entry: &PbinEntry, dir: &Path) -> Result<PathBuf> {
+    let (Some(reference), None) = (entry.delta_reference.as_deref(), &entry.bcj_ranges) else {
+        return write_data(file, entry, &extract_entry(file, entry)?, dir);
+    };
+    file.check_entry_size(entry)?;
+    file.manifest().check_checksum_key(file.checksum_key())?;
+    let (reference_data, patch) = delta_parts(file, entry, reference, &[])?;
+    let path = dir.join(entry.output_name(&file.manifest().name)?);
+    let arch = entry_bcj_arch(entry)?.unwrap_or(BcjArch::None);
+    let written = File::create(&path)
+        .map_err(CompressionError::from)
+        .and_then(|output| {
+            // Hashed under the BCJ decoder, where the bytes are the binary's.
+            let output = HashingWriter::with_hasher(
+                BufWriter::new(output),
+                Checksum::hasher(file.checksum_key()),
+            );
+            let mut output = BcjWriter::decoder(arch, output);
+            delta::apply_patch_to(
+                &reference_data,
+                &patch[..],
+                &mut output,
+                entry.uncompressed_size,
+            )?;
+            let mut output = output.finish()?;
+            output.flush()?;
+            if output.written != entry.uncompressed_size {
+                return Err(pbin_core::Error::SizeMismatch {
+                    declared: entry.uncompressed_size,
+                    actual: output.written,
+                }
+                .into());
+            }
+            let actual = Checksum::from(output.hasher.finalize());
+            file.manifest()
+                .verify_entry_checksum(entry, actual, file.checksum_key())?;
+            Ok(())
+        });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
+    set_mode(entry, &path)?;
+    Ok(path)
+}
+
+/// Write `data` into `dir` under `entry`'s output name and mode.
+fn write_data(file: &PbinFile, entry: &PbinEntry, data: &[u8], dir: &Path) -> Result<PathBuf> {
+    let path = dir.join(entry.output_name(&file.manifest().name)?);
+    std::fs::write(&path, data)?;
+    set_mode(entry, &path)?;
+    Ok(path)
+}
+
+/// Give an extracted file `entry`'s permission bits, on Unix.
+fn set_mode(entry: &PbinEntry, path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.output_mode()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (entry, path);
+    Ok(())
+}
+
+/// Returns an entry's bytes as they were before compression.
+///
+/// Patches are created between BCJ-filtered binaries, so a delta entry is
+/// rebuilt from its reference's filtered bytes and stays filtered itself.
+/// `chain` holds the delta entries already waiting on this one, so a cyclic
+/// or overlong reference chain fails instead of recursing.
+fn filtered_data(file: &PbinFile, entry: &PbinEntry, chain: &[&str]) -> Result<Vec<u8>> {
+    file.check_entry_size(entry)?;
+    if let Some(chunks) = &entry.chunks {
+        return chunked_data(file, entry, chunks);
+    }
+    let Some(reference) = entry.delta_reference.as_deref() else {
+        return decompress_entry(
+            stored_data(file, entry)?,
+            file.entry_compression(entry),
+            file.entry_dictionary(entry)?,
+            entry.window_log,
+            entry.uncompressed_size,
+        );
+    };
+    let (reference_data, patch) = delta_parts(file, entry, reference, chain)?;
+    let data = delta::apply_patch_limited(&reference_data, &patch, entry.uncompressed_size)?;
+    check_size(entry.uncompressed_size, &data)?;
+    Ok(data)
+}
+
+/// Returns an entry's stored bytes, checked against its compressed checksum
+/// if it records one, as files from before the field don't.
+fn stored_data<'a>(file: &'a PbinFile, entry: &PbinEntry) -> Result<&'a [u8]> {
+    let stored = file.entry_data(entry)?;
+    if let Some(expected) = &entry.compressed_checksum {
+        if !expected.matches(stored) {
+            return Err(pbin_core::Error::ChecksumMismatch {
+                expected: expected.to_string(),
+                actual: Checksum::of(stored).to_string(),
+            }
+            .into());
+        }
+    }
+    Ok(stored)
+}
+
+/// Returns a chunked entry's bytes, concatenated from the chunk store.
+///
+/// The store is only inflated up to the combined size of the chunked
+/// entries, since deduplication never makes it larger than them, and must
+/// match its recorded checksum before any chunk is taken from it.
+fn chunked_data(file: &PbinFile, entry: &PbinEntry, chunks: &[u32]) -> Result<Vec<u8>> {
+    if !file.header().has_chunks() {
+        return Err(CompressionError::InvalidData(format!(
+            "Entry {} is chunked but the header doesn't allow chunks",
+            entry.key()
+        )));
+    }
+    let manifest = file.manifest();
+    let (Some(store), Some(stored)) = (&manifest.chunk_store, file.chunk_store_data()?) else {
+        return Err(CompressionError::InvalidData(format!(
+            "Chunk store for {} not found",
+            entry.key()
+        )));
+    };
+    let limit: u64 = manifest
+        .entries
+        .iter()
+        .filter(|e| e.is_chunked())
+        .map(|e| e.uncompressed_size)
+        .sum();
+    if store.uncompressed_size > limit {
+        return Err(CompressionError::InvalidData(format!(
+            "Chunk store declares {} bytes, more than the {} its entries hold",
+            store.uncompressed_size, limit
+        )));
+    }
+
+    let data = decompress_entry(
+        stored,
+        store.effective_compression(file.header().compression),
+        None,
+        store.window_log,
+        store.uncompressed_size,
+    )?;
+    if !store.checksum.matches(&data) {
+        return Err(pbin_core::Error::ChecksumMismatch {
+            expected: store.checksum.to_string(),
+            actual: pbin_core::Checksum::of(&data).to_string(),
+        }
+        .into());
+    }
+    let data = chunk::reassemble(&data, &store.chunk_sizes, chunks, entry.uncompressed_size)?;
+    check_size(entry.uncompressed_size, &data)?;
+    Ok(data)
+}
+
+/// Returns the filtered bytes of a delta entry's `reference`, and the
+/// entry's decompressed patch.
+fn delta_parts(
+    file: &PbinFile,
+    entry: &PbinEntry,
+    reference: &str,
+    chain: &[&str],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let stored = stored_data(file, entry)?;
+    let dictionary = file.entry_dictionary(entry)?;
+    let compression = file.entry_compression(entry);
+    let key = entry.key();
+    delta::check_chain(&key, reference, chain)?;
+    let entries = &file.manifest().entries;
+    let reference_entry = entries
+        .iter()
+        .find(|e| !e.is_asset() && e.key() == reference)
+        .ok_or_else(|| {
+            CompressionError::InvalidData(format!(
+                "Delta reference {} for {} not found",
+                reference,
+                entry.key()
+            ))
+        })?;
+
+    // A patch's own size isn't recorded, so only the file's limit bounds it.
+    let limit = file.max_entry_size();
+    let mut patch = Vec::new();
+    decompress_into(
+        stored,
+        compression,
+        dictionary,
+        entry.window_log,
+        limit,
+        &mut patch,
+    )?;
+    if patch.len() as u64 > limit {
+        return Err(CompressionError::InvalidData(format!(
+            "Delta patch for {} exceeds {} bytes",
+            entry.key(),
+            limit
+        )));
+    }
+
+    let chain = [chain, &[key.as_str()]].concat();
+    let reference_data = filtered_data(file, reference_entry, &chain)?;
+    Ok((reference_data, patch))
+}
+
+/// Returns the BCJ filter recorded for an entry, if any.
+pub fn entry_bcj_arch(entry: &PbinEntry) -> Result<Option<BcjArch>> {
+    match entry.bcj.as_deref() {
+        None => Ok(None),
+        Some(name) => BcjArch::from_name(name).map(Some).ok_or_else(|| {
+            CompressionError::InvalidData(format!(
+                "Unknown BCJ filter '{}' for {}",
+                name, entry.target
+            ))
+        }),
+    }
+}
+
+/// Returns the ranges an entry's BCJ filter covers, if it records any
+/// rather than covering the whole entry.
+pub fn entry_bcj_ranges(entry: &PbinEntry) -> Option<Vec<Range<usize>>> {
+    let ranges = entry.bcj_ranges.as_ref()?;
+    Some(
+        ranges
+            .iter()
+            .map(|&[start, end]| start as usize..end as usize)
+            .collect(),
+    )
+}
+
+/// Decompress stored entry bytes with the given method and dictionary into
+/// exactly `size` bytes, the entry's recorded `uncompressed_size`.
+/// `window_log` is the entry's recorded zstd window log, which zstd data
+/// compressed with a window over 2^27 bytes needs.
+///
+/// The output buffer is allocated at `size` up front and decompression stops
+/// one byte past it, so data of any other size fails with
+/// [`pbin_core::Error::SizeMismatch`] without being fully inflated. Callers
+/// reading untrusted files should bound `size` first (see
+/// [`PbinFile::check_entry_size`]).
+pub fn decompress_entry(
+    stored: &[u8],
+    compression: Compression,
+    dictionary: Option<&[u8]>,
+    window_log: Option<u32>,
+    size: u64,
+) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    data.try_reserve_exact(size as usize)
+        .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+    decompress_into(stored, compression, dictionary, window_log, size, &mut data)?;
+    check_size(size, &data)?;
+    Ok(data)
+}
+
+/// Decompress stored bytes into `data`, stopping one byte past `limit`.
+pub(crate) fn decompress_into(
+    stored: &[u8],
+    compression: Compression,
+    dictionary: Option<&[u8]>,
+    window_log: Option<u32>,
+    limit: u64,
+    data: &mut Vec<u8>,
+) -> Result<()> {
+    if window_log.is_some() && compression != Compression::Zstd {
+        return Err(CompressionError::InvalidData(format!(
+            "{} entries can't have a window log",
+            compression
+        )));
+    }
+    match compression {
+        Compression::None => data.extend_from_slice(stored),
+        // Streamed rather than `dict::decompress`, so output stops one
+        // byte past `limit` whatever size the frame header claims.
+        Compression::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_dictionary(
+                std::io::BufReader::new(stored),
+                dictionary.unwrap_or_default(),
+            )
+            .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+            if let Some(window_log) = window_log {
+                decoder
+                    .window_log_max(window_log)
+                    .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+            }
+            decoder
+                .take(limit.saturating_add(1))
+                .read_to_end(data)
+                .map_err(dict::decode_error)?;
+        }
+        Compression::Lz4 => {
+            if dictionary.is_some() {
+                return Err(CompressionError::InvalidData(
+                    "LZ4 entries can't use a dictionary".into(),
+                ));
+            }
+            lz4_flex::frame::FrameDecoder::new(stored)
+                .take(limit.saturating_add(1))
+                .read_to_end(data)
+                .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+        }
+        Compression::Xz => {
+            if dictionary.is_some() {
+                return Err(CompressionError::InvalidData(
+                    "XZ entries can't use a dictionary".into(),
+                ));
+            }
+            xz2::read::XzDecoder::new(stored)
+                .take(limit.saturating_add(1))
+                .read_to_end(data)
+                .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+        }
+        Compression::Brotli => {
+            if dictionary.is_some() {
+                return Err(CompressionError::InvalidData(
+                    "Brotli entries can't use a dictionary".into(),
+                ));
+            }
+            ::brotli::Decompressor::new(stored, 4096)
+                .take(limit.saturating_add(1))
+                .read_to_end(data)
+                .map_err(|e| CompressionError::Decompression(e.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that decompressed `data` is the `declared` size.
+pub(crate) fn check_size(declared: u64, data: &[u8]) -> Result<()> {
+    if data.len() as u64 != declared {
+        return Err(pbin_core::Error::SizeMismatch {
+            declared,
+            actual: data.len() as u64,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{dict, CompressionLevel, CompressionPipeline};
+    use pbin_core::{
+        Checksum, PbinChunkStore, PbinDictionary, PbinHeader, PbinManifest, Target, PAYLOAD_MARKER,
+    };
+
+    /// Assemble a PBIN file from already-stored entry bytes.
+    pub(crate) fn build_file(
+        header_compression: Compression,
+        entries: Vec<(PbinEntry, Vec<u8>)>,
+    ) -> PbinFile {
+        build_file_with_dictionaries(header_compression, &[], entries)
+    }
+
+    /// Assemble a PBIN file, storing `dictionaries` ahead of the entries.
+    pub(crate) fn build_file_with_dictionaries(
+        header_compression: Compression,
+        dictionaries: &[&[u8]],
+        entries: Vec<(PbinEntry, Vec<u8>)>,
+    ) -> PbinFile {
+        assemble(header_compression, dictionaries, entries, true)
+    }
+
+    /// Assemble a PBIN file with relative or absolute offsets.
+    ///
+    /// Each entry's offset is filled in here. With absolute offsets the
+    /// manifest is re-serialized until its length (and therefore every
+    /// offset) is stable.
+    fn assemble(
+        header_compression: Compression,
+        dictionaries: &[&[u8]],
+        entries: Vec<(PbinEntry, Vec<u8>)>,
+        relative: bool,
+    ) -> PbinFile {
+        let mut manifest = PbinManifest::new("test".into(), "1.0.0".into());
+        manifest.dictionaries = dictionaries
+            .iter()
+            .map(|data| PbinDictionary::new("test", data, 0))
+            .collect();
+        for (entry, _) in &entries {
+            manifest.add_entry(entry.clone());
+        }
+
+        let payload_start = |manifest: &PbinManifest| {
+            if relative {
+                0
+            } else {
+                PAYLOAD_MARKER.len() + 64 + manifest.to_json().unwrap().len()
+            }
+        };
+        let mut start = usize::MAX;
+        while start != payload_start(&manifest) {
+            start = payload_start(&manifest);
+            let mut offset = start;
+            for dictionary in &mut manifest.dictionaries {
+                dictionary.offset = offset as u64;
+                offset += dictionary.size as usize;
+            }
+            for (entry, (_, data)) in manifest.entries.iter_mut().zip(&entries) {
+                entry.offset = offset as u64;
+                offset += data.len();
+            }
+        }
+
+        let json = manifest.to_json().unwrap();
+        let mut header = PbinHeader::new(
+            header_compression,
+            manifest.entries.len() as u8,
+            json.len() as u32,
+        );
+        if relative {
+            header = header.with_relative_offsets();
+        }
+        let mut bytes = PAYLOAD_MARKER.to_vec();
+        bytes.extend_from_slice(&header.to_bytes());
+        bytes.extend_from_slice(json.as_bytes());
+        for dictionary in dictionaries {
+            bytes.extend_from_slice(dictionary);
+        }
+        for (_, data) in &entries {
+            bytes.extend_from_slice(data);
+        }
+        PbinFile::from_bytes(bytes).unwrap()
+    }
+
+    /// Build an entry for `original` stored as `stored`.
+    pub(crate) fn entry_for(target: Target, original: &[u8], stored: &[u8]) -> PbinEntry {
+        PbinEntry::new(
+            target,
+            0,
+            stored.len() as u64,
+            original.len() as u64,
+            Checksum::of(original),
+            Checksum::of(stored),
+        )
+    }
+
+    #[test]
+    fn test_mixed_compression() {
+        let zstd_original = b"zstd entry data, zstd entry data, zstd entry data".to_vec();
+        let zstd_stored = dict::compress(&zstd_original, 3).unwrap();
+        let raw_original = b"raw entry data".to_vec();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &zstd_original, &zstd_stored)
+                        .with_compression(Compression::Zstd),
+                    zstd_stored.clone(),
+                ),
+                (
+                    entry_for(Target::DarwinAarch64, &raw_original, &raw_original)
+                        .with_compression(Compression::None),
+                    raw_original.clone(),
+                ),
+            ],
+        );
+
+        let manifest = file.manifest();
+        let zstd_entry = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let raw_entry = manifest.find_entry(Target::DarwinAarch64).unwrap();
+
+        assert_eq!(extract_entry(&file, zstd_entry).unwrap(), zstd_original);
+        assert_eq!(extract_entry(&file, raw_entry).unwrap(), raw_original);
+    }
+
+    #[test]
+    fn test_every_algorithm_round_trips() {
+        let inputs: [&[u8]; 3] = [
+            b"",
+            b"short",
+            &b"repetitive entry data, repetitive entry data".repeat(100),
+        ];
+        for &compression in Compression::all() {
+            for original in inputs {
+                let stored = CompressionPipeline::new(CompressionLevel::Fast)
+                    .with_algorithm(compression)
+                    .compress(original)
+                    .unwrap();
+                let decompressed =
+                    decompress_entry(&stored, compression, None, None, original.len() as u64)
+                        .unwrap();
+                assert_eq!(decompressed, original, "{}", compression);
+
+                // Entries without their own compression use the header's.
+                let file = build_file(
+                    compression,
+                    vec![(entry_for(Target::LinuxX86_64, original, &stored), stored)],
+                );
+                let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+                assert_eq!(extract_entry(&file, entry).unwrap(), original);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bcj_decoded_on_extract() {
+        let mut original = Vec::new();
+        for i in 0..200u32 {
+            original.push(0xE8);
+            original.extend_from_slice(&i.to_le_bytes());
+        }
+        let mut filtered = original.clone();
+        BcjFilter::new(BcjArch::X86).encode(&mut filtered).unwrap();
+        let stored = dict::compress(&filtered, 3).unwrap();
+        // Filtered only over 100..600, as for an executable section.
+        let mut partly_filtered = original.clone();
+        let ranges = std::slice::from_ref(&(100..600));
+        bcj::bcj_encode_ranges(&mut partly_filtered, BcjArch::X86, ranges).unwrap();
+        let partly_stored = dict::compress(&partly_filtered, 3).unwrap();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &original, &stored)
+                        .with_bcj(BcjArch::X86.as_str()),
+                    stored,
+                ),
+                (
+                    entry_for(Target::WindowsX86_64, &original, &partly_stored)
+                        .with_bcj(BcjArch::X86.as_str())
+                        .with_bcj_ranges(vec![[100, 600]]),
+                    partly_stored,
+                ),
+            ],
+        );
+
+        for target in [Target::LinuxX86_64, Target::WindowsX86_64] {
+            let entry = file.manifest().find_entry(target).unwrap();
+            assert_eq!(extract_entry(&file, entry).unwrap(), original);
+        }
+    }
+
+    #[test]
+    fn test_delta_entry_reconstructed() {
+        let linux: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut darwin = linux.clone();
+        darwin[100..108].copy_from_slice(b"__darwin");
+        darwin.extend_from_slice(b"trailing mach-o bits");
+
+        let linux_stored = dict::compress(&linux, 3).unwrap();
+        let patch = delta::create_patch(&linux, &darwin).unwrap();
+        let darwin_stored = dict::compress(&patch, 3).unwrap();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &linux, &linux_stored),
+                    linux_stored,
+                ),
+                (
+                    entry_for(Target::DarwinX86_64, &darwin, &darwin_stored)
+                        .with_delta_reference("linux-x86_64"),
+                    darwin_stored,
+                ),
+            ],
+        );
+        let manifest = file.manifest();
+        let linux_entry = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let darwin_entry = manifest.find_entry(Target::DarwinX86_64).unwrap();
+
+        assert_eq!(extract_entry(&file, linux_entry).unwrap(), linux);
+        assert_eq!(extract_entry(&file, darwin_entry).unwrap(), darwin);
+    }
+
+    #[test]
+    fn test_delta_entry_streamed_to_dir() {
+        let mut linux = Vec::new();
+        for i in 0..5000u32 {
+            linux.push(0xE8);
+            linux.extend_from_slice(&(i % 300).to_le_bytes());
+            linux.extend_from_slice(b"\x48\x89\xe5");
+        }
+        let mut darwin = linux.clone();
+        darwin[1000..1008].copy_from_slice(b"__darwin");
+        let filter = |data: &[u8]| {
+            let mut data = data.to_vec();
+            BcjFilter::new(BcjArch::X86).encode(&mut data).unwrap();
+            data
+        };
+        let linux_stored = dict::compress(&filter(&linux), 3).unwrap();
+        let patch = delta::create_patch(&filter(&linux), &filter(&darwin)).unwrap();
+        let darwin_stored = dict::compress(&patch, 3).unwrap();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &linux, &linux_stored)
+                        .with_bcj(BcjArch::X86.as_str()),
+                    linux_stored,
+                ),
+                (
+                    entry_for(Target::DarwinX86_64, &darwin, &darwin_stored)
+                        .with_bcj(BcjArch::X86.as_str())
+                        .with_delta_reference("linux-x86_64")
+                        .with_file_name("app"),
+                    darwin_stored,
+                ),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = extract_to_dir(&file, Target::DarwinX86_64, dir.path()).unwrap();
+        assert!(std::fs::read(path).unwrap() == darwin);
+    }
+
+    #[test]
+    fn test_corrupt_delta_entry_not_written() {
+        let mut linux = Vec::new();
+        for i in 0..5000u32 {
+            linux.push(0xE8);
+            linux.extend_from_slice(&(i % 300).to_le_bytes());
+        }
+        let filter = |data: &[u8]| {
+            let mut data = data.to_vec();
+            BcjFilter::new(BcjArch::X86).encode(&mut data).unwrap();
+            data
+        };
+        // The patch rebuilds a binary other than the one recorded, though
+        // the same size and stored intact.
+        let mut darwin = linux.clone();
+        darwin[1000..1008].copy_from_slice(b"__darwin");
+        let mut corrupt = darwin.clone();
+        corrupt[2000] ^= 0x01;
+        let linux_stored = dict::compress(&filter(&linux), 3).unwrap();
+        let patch = delta::create_patch(&filter(&linux), &filter(&corrupt)).unwrap();
+        let darwin_stored = dict::compress(&patch, 3).unwrap();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &linux, &linux_stored)
+                        .with_bcj(BcjArch::X86.as_str()),
+                    linux_stored,
+                ),
+                (
+                    entry_for(Target::DarwinX86_64, &darwin, &darwin_stored)
+                        .with_bcj(BcjArch::X86.as_str())
+                        .with_delta_reference("linux-x86_64")
+                        .with_file_name("app"),
+                    darwin_stored,
+                ),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = extract_to_dir(&file, Target::DarwinX86_64, dir.path()).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                CompressionError::Format(pbin_core::Error::ChecksumMismatch { .. })
+            ),
+            "{}",
+            err
+        );
+        assert!(!dir.path().join("app").exists());
+    }
+
+    /// Assemble a PBIN file whose binaries are all chunked, with the chunk
+    /// store as its only payload.
+    fn chunked_file(binaries: Vec<(String, Vec<u8>)>, flagged: bool) -> PbinFile {
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .compress_chunked(&binaries)
+            .unwrap();
+        let mut manifest = PbinManifest::new("test".into(), "1.0.0".into());
+        manifest.chunk_store = Some(PbinChunkStore {
+            offset: 0,
+            compressed_size: result.store.len() as u64,
+            uncompressed_size: result.store_size as u64,
+            checksum: result.store_checksum,
+            compression: Some(result.compression),
+            window_log: result.window_log,
+            chunk_sizes: result.chunk_sizes.clone(),
+        });
+        for ((target, data), chunked) in binaries.iter().zip(result.entries) {
+            let mut entry = entry_for(target.parse().unwrap(), data, &[]);
+            if let Some(arch) = chunked.bcj_arch() {
+                entry = entry.with_bcj(arch.as_str());
+            }
+            let entry = entry.with_chunks(chunked.chunks);
+            manifest.add_entry(entry);
+        }
+
+        let json = manifest.to_json().unwrap();
+        let mut header = PbinHeader::new(
+            Compression::Zstd,
+            manifest.entries.len() as u8,
+            json.len() as u32,
+        )
+        .with_relative_offsets();
+        if flagged {
+            header = header.with_chunks();
+        }
+        let mut bytes = PAYLOAD_MARKER.to_vec();
+        bytes.extend_from_slice(&header.to_bytes());
+        bytes.extend_from_slice(json.as_bytes());
+        bytes.extend_from_slice(&result.store);
+        PbinFile::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_chunked_entries_extract() {
+        // ASCII holds no x86 CALLs or ARM64 BLs, so the shared run looks the
+        // same after either target's BCJ filter.
+        let mut state = 1u32;
+        let shared: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b'a' + (state >> 16) as u8 % 26
+            })
+            .collect();
+        let linux = [b"\xe8\x10\0\0\0linux header".to_vec(), shared.clone()].concat();
+        let darwin = [vec![7; 5_000], shared, b"darwin footer".to_vec()].concat();
+        let binaries = vec![
+            ("linux-x86_64".to_string(), linux.clone()),
+            ("darwin-aarch64".to_string(), darwin.clone()),
+        ];
+        let file = chunked_file(binaries.clone(), true);
+        assert!(
+            file.manifest()
+                .chunk_store
+                .as_ref()
+                .unwrap()
+                .uncompressed_size
+                < 150_000
+        );
+
+        for (target, data) in &binaries {
+            let entry = file.manifest().find_entry(target.parse().unwrap()).unwrap();
+            assert!(entry.is_chunked());
+            assert!(&extract_entry(&file, entry).unwrap() == data);
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let path = extract_to_dir(&file, Target::LinuxX86_64, dir.path()).unwrap();
+        assert!(std::fs::read(path).unwrap() == linux);
+
+        let file = chunked_file(binaries, false);
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let err = extract_entry(&file, entry).unwrap_err();
+        assert!(err.to_string().contains("doesn't allow chunks"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_delta_reference_rejected() {
+        let stored = dict::compress(b"patch", 3).unwrap();
+        let file = build_file(
+            Compression::Zstd,
+            vec![(
+                entry_for(Target::DarwinX86_64, b"patch", &stored)
+                    .with_delta_reference("linux-x86_64"),
+                stored,
+            )],
+        );
+        let entry = file.manifest().find_entry(Target::DarwinX86_64).unwrap();
+
+        assert!(extract_entry(&file, entry).is_err());
+    }
+
+    #[test]
+    fn test_delta_reference_cycle_rejected() {
+        let stored = dict::compress(b"patch", 3).unwrap();
+        let file = build_file(
+            Compression::Zstd,
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, b"patch", &stored)
+                        .with_delta_reference("darwin-x86_64"),
+                    stored.clone(),
+                ),
+                (
+                    entry_for(Target::DarwinX86_64, b"patch", &stored)
+                        .with_delta_reference("linux-x86_64"),
+                    stored,
+                ),
+            ],
+        );
+        let entry = file.manifest().find_entry(Target::DarwinX86_64).unwrap();
+
+        let err = extract_entry(&file, entry).unwrap_err();
+        assert!(err.to_string().contains("cycle"), "{}", err);
+    }
+
+    /// A file with one entry compressed against a raw-content dictionary.
+    fn dictionary_file(original: &[u8], dictionary: &[u8]) -> PbinFile {
+        let stored = dict::compress_with_dict(original, dictionary, 3).unwrap();
+        build_file_with_dictionaries(
+            Compression::Zstd,
+            &[dictionary],
+            vec![(
+                entry_for(Target::LinuxX86_64, original, &stored)
+                    .with_dictionary(0, PbinDictionary::id_for(dictionary)),
+                stored,
+            )],
+        )
+    }
+
+    #[test]
+    fn test_dictionary_entry_extracts() {
+        let dictionary = b"shared runtime code, shared runtime code".repeat(8);
+        let original = [dictionary.as_slice(), b"app specific tail"].concat();
+        let file = dictionary_file(&original, &dictionary);
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+
+        assert_eq!(extract_entry(&file, entry).unwrap(), original);
+    }
+
+    #[test]
+    fn test_entry_uses_its_own_dictionary() {
+        let x86_dictionary = b"x86_64 runtime code, x86_64 runtime code".repeat(8);
+        let arm_dictionary = b"aarch64 runtime code, aarch64 runtime code".repeat(8);
+        let x86 = [x86_dictionary.as_slice(), b"x86 tail"].concat();
+        let arm = [arm_dictionary.as_slice(), b"arm tail"].concat();
+        let x86_stored = dict::compress_with_dict(&x86, &x86_dictionary, 3).unwrap();
+        let arm_stored = dict::compress_with_dict(&arm, &arm_dictionary, 3).unwrap();
+
+        let file = build_file_with_dictionaries(
+            Compression::Zstd,
+            &[&x86_dictionary, &arm_dictionary],
+            vec![
+                (
+                    entry_for(Target::LinuxX86_64, &x86, &x86_stored)
+                        .with_dictionary(0, PbinDictionary::id_for(&x86_dictionary)),
+                    x86_stored,
+                ),
+                (
+                    entry_for(Target::LinuxAarch64, &arm, &arm_stored)
+                        .with_dictionary(1, PbinDictionary::id_for(&arm_dictionary)),
+                    arm_stored,
+                ),
+            ],
+        );
+        let manifest = file.manifest();
+        let x86_entry = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let arm_entry = manifest.find_entry(Target::LinuxAarch64).unwrap();
+
+        assert_eq!(extract_entry(&file, x86_entry).unwrap(), x86);
+        assert_eq!(extract_entry(&file, arm_entry).unwrap(), arm);
+    }
+
+    #[test]
+    fn test_corrupt_dictionary_rejected() {
+        let dictionary = b"shared runtime code, shared runtime code".repeat(8);
+        let original = [dictionary.as_slice(), b"app specific tail"].concat();
+        let file = dictionary_file(&original, &dictionary);
+
+        let mut bytes = file.as_bytes().to_vec();
+        bytes[file.payload_offset() + file.manifest().dictionaries[0].offset as usize] ^= 0xFF;
+        let file = PbinFile::from_bytes(bytes).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+
+        assert!(matches!(
+            extract_entry(&file, entry),
+            Err(CompressionError::Format(
+                pbin_core::Error::DictionaryMismatch { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_frame_detected() {
+        let original: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8 | (i % 7) as u8)
+            .collect();
+        let mut stored = dict::compress(&original, 3).unwrap();
+        let middle = stored.len() / 2;
+        stored[middle] ^= 0x01;
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![(
+                entry_for(Target::LinuxX86_64, &original, &stored),
+                stored.clone(),
+            )],
+        );
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let err = extract_entry(&file, entry).unwrap_err();
+        assert!(matches!(err, CompressionError::CorruptFrame(_)), "{}", err);
+        assert_eq!(err.category(), pbin_core::ErrorCategory::Corrupt);
+        assert!(matches!(
+            dict::decompress(&stored, None),
+            Err(CompressionError::CorruptFrame(_))
+        ));
+    }
+
+    #[test]
+    fn test_checksums_checked() {
+        let original = b"checked binary contents ".repeat(256);
+        let stored = dict::compress(&original, 3).unwrap();
+        let mut corrupt = stored.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0x01;
+        let mut other = original.clone();
+        other[0] ^= 0x01;
+        let is_mismatch = |err| {
+            matches!(
+                err,
+                CompressionError::Format(pbin_core::Error::ChecksumMismatch { .. })
+            )
+        };
+
+        // Stored bytes that don't match are rejected before decompressing.
+        let entry = entry_for(Target::LinuxX86_64, &original, &stored);
+        let file = build_file(Compression::Zstd, vec![(entry, corrupt)]);
+        let err = extract_entry(&file, &file.manifest().entries[0]).unwrap_err();
+        assert!(is_mismatch(err));
+
+        // Neither is output that doesn't match, nor written to disk.
+        let entry = entry_for(Target::LinuxX86_64, &other, &stored).with_file_name("app");
+        let file = build_file(Compression::Zstd, vec![(entry, stored)]);
+        let err = extract_entry(&file, &file.manifest().entries[0]).unwrap_err();
+        assert!(is_mismatch(err));
+        let dir = tempfile::tempdir().unwrap();
+        let err = extract_to_dir(&file, Target::LinuxX86_64, dir.path()).unwrap_err();
+        assert!(is_mismatch(err));
+        assert!(!dir.path().join("app").exists());
+    }
+
+    #[test]
+    fn test_swapped_dictionary_rejected() {
+        let dictionary = b"shared runtime code, shared runtime code".repeat(8);
+        let original = [dictionary.as_slice(), b"app specific tail"].concat();
+        let file = dictionary_file(&original, &dictionary);
+        let mut entry = file.manifest().entries[0].clone();
+        entry.dict_id = Some(PbinDictionary::id_for(b"some other dictionary"));
+
+        assert!(matches!(
+            extract_entry(&file, &entry),
+            Err(CompressionError::Format(
+                pbin_core::Error::DictionaryMismatch { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_extract_to_dir_uses_name_and_mode() {
+        let original = b"#!/bin/sh\necho hi\n".to_vec();
+        let file = build_file(
+            Compression::None,
+            vec![(
+                entry_for(Target::LinuxX86_64, &original, &original)
+                    .with_file_name("hello")
+                    .with_mode(0o700),
+                original.clone(),
+            )],
+        );
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = extract_to_dir(&file, Target::LinuxX86_64, dir.path()).unwrap();
+        assert_eq!(path, dir.path().join("hello"));
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+
+    #[test]
+    fn test_extract_universal_macos() {
+        let thin = |cputype: u32| {
+            let mut data = Vec::new();
+            for field in [0xfeed_facf, cputype, 0, 2, 0, 0, 0, 0] {
+                data.extend_from_slice(&u32::to_le_bytes(field));
+            }
+            data
+        };
+        let (intel, arm) = (thin(0x0100_0007), thin(0x0100_000c));
+        let file = build_file(
+            Compression::None,
+            vec![
+                (
+                    entry_for(Target::DarwinX86_64, &intel, &intel),
+                    intel.clone(),
+                ),
+                (
+                    entry_for(Target::DarwinAarch64, &arm, &arm).with_file_name("app"),
+                    arm.clone(),
+                ),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+
+        let path = extract_universal_macos_to_dir(&file, dir.path()).unwrap();
+        assert_eq!(path, dir.path().join("app"));
+        let fat = std::fs::read(&path).unwrap();
+        let slices = segment::split_universal(&fat).unwrap().unwrap();
+        assert_eq!(slices.len(), 2);
+        assert_eq!(
+            (slices[0].arch.as_str(), slices[0].data),
+            ("x86_64", intel.as_slice())
+        );
+        assert_eq!(
+            (slices[1].arch.as_str(), slices[1].data),
+            ("aarch64", arm.as_slice())
+        );
+
+        let file = build_file(
+            Compression::None,
+            vec![(entry_for(Target::DarwinAarch64, &arm, &arm), arm.clone())],
+        );
+        assert!(extract_universal_macos(&file).is_err());
+    }
+
+    #[test]
+    fn test_extract_to_dir_rejects_escaping_name() {
+        let file = build_file(
+            Compression::None,
+            vec![(
+                entry_for(Target::LinuxX86_64, b"evil", b"evil").with_file_name("../evil"),
+                b"evil".to_vec(),
+            )],
+        );
+        let parent = tempfile::tempdir().unwrap();
+        let dir = parent.path().join("out");
+        std::fs::create_dir(&dir).unwrap();
+
+        assert!(matches!(
+            extract_to_dir(&file, Target::LinuxX86_64, &dir),
+            Err(CompressionError::Format(pbin_core::Error::UnsafeFileName(
+                _
+            )))
+        ));
+        assert!(!parent.path().join("evil").exists());
+    }
+
+    #[test]
+    fn test_absolute_offsets_still_extract() {
+        let x86 = b"x86 binary".to_vec();
+        let arm = b"arm binary, a little longer".to_vec();
+        let file = assemble(
+            Compression::None,
+            &[],
+            vec![
+                (entry_for(Target::LinuxX86_64, &x86, &x86), x86.clone()),
+                (entry_for(Target::LinuxAarch64, &arm, &arm), arm.clone()),
+            ],
+            false,
+        );
+
+        assert_eq!(file.payload_offset(), 0);
+        let manifest = file.manifest();
+        let arm_entry = manifest.find_entry(Target::LinuxAarch64).unwrap();
+        assert!(arm_entry.offset as usize > file.header_offset());
+        assert_eq!(extract_entry(&file, arm_entry).unwrap(), arm);
+    }
+
+    #[test]
+    fn test_wrong_uncompressed_size_rejected() {
+        let original = vec![0u8; 1 << 20];
+        let stored = dict::compress(&original, 3).unwrap();
+
+        // Oversized data stops one byte past the declared size.
+        let len = original.len() as u64;
+        for (declared, actual) in [(100, 101), (len + 1, len)] {
+            let mut entry = entry_for(Target::LinuxX86_64, &original, &stored);
+            entry.uncompressed_size = declared;
+            let file = build_file(Compression::Zstd, vec![(entry, stored.clone())]);
+            let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+
+            assert!(matches!(
+                extract_entry(&file, entry),
+                Err(CompressionError::Format(pbin_core::Error::SizeMismatch {
+                    declared: d,
+                    actual: a,
+                })) if d == declared && a == actual
+            ));
+        }
+    }
+
+    #[test]
+    fn test_entry_over_size_limit_rejected() {
+        let original = b"small but over the limit".to_vec();
+        let file = build_file(
+            Compression::None,
+            vec![(
+                entry_for(Target::LinuxX86_64, &original, &original),
+                original.clone(),
+            )],
+        );
+        let file = file.with_max_entry_size(8);
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+
+        assert!(matches!(
+            extract_entry(&file, entry),
+            Err(CompressionError::Format(pbin_core::Error::EntryTooLarge {
+                size: 24,
+                limit: 8,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_bcj_rejected() {
+        let entry = entry_for(Target::LinuxX86_64, b"a", b"a").with_bcj("z80");
+        assert!(entry_bcj_arch(&entry).is_err());
+    }
+
+    #[test]
+    fn test_entry_without_compression_uses_header() {
+        let original = b"header-level zstd, header-level zstd".to_vec();
+        let stored = dict::compress(&original, 3).unwrap();
+
+        let file = build_file(
+            Compression::Zstd,
+            vec![(entry_for(Target::LinuxX86_64, &original, &stored), stored)],
+        );
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+
+        assert_eq!(file.entry_compression(entry), Compression::Zstd);
+        assert_eq!(extract_entry(&file, entry).unwrap(), original);
+    }
+}
@@ -4,15 +4,26 @@
 //! - BCJ filters for x86/ARM code preprocessing
 //! - Delta compression for similar binaries
 //! - Zstd dictionary training
+//! - LZ4 as a faster-decompressing alternative to zstd
+//! - XZ as a denser alternative to zstd
+//! - Brotli, for files served alongside other web content
 //! - Segment deduplication
+//! - Content-defined chunk deduplication across binaries
 
 pub mod bcj;
+pub mod brotli;
+pub mod chunk;
+pub mod decompress;
 pub mod delta;
 pub mod dict;
+pub mod extract;
+pub mod lz4;
 pub mod pipeline;
 pub mod segment;
+pub mod xz;
 
 mod error;
 
+pub use decompress::DecompressionPipeline;
 pub use error::{CompressionError, Result};
 pub use pipeline::{CompressionLevel, CompressionPipeline, PlatformTier};
@@ -0,0 +1,53 @@
+//! LZ4 compression.
+//!
+//! Compresses less than zstd but decompresses several times faster, which
+//! shortens a packed program's startup. Data is stored as an LZ4 frame, the
+//! format the `lz4` command-line tool reads, so the stub can decompress it.
+
+use crate::{CompressionError, Result};
+use std::io::{Read, Write};
+
+/// Compress data into an LZ4 frame.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+    encoder
+        .write_all(data)
+        .map_err(|e| CompressionError::Lz4(format!("Compression failed: {}", e)))?;
+    encoder
+        .finish()
+        .map_err(|e| CompressionError::Lz4(format!("Compression failed: {}", e)))
+}
+
+/// Decompress an LZ4 frame.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    lz4_flex::frame::FrameDecoder::new(data)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| CompressionError::Decompression(format!("Decompression failed: {}", e)))?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_decompress() {
+        let data = b"Hello, World! This is test data for compression.".repeat(20);
+
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        // LZ4 frame magic number, which the lz4 tool checks for.
+        assert_eq!(compressed[..4], [0x04, 0x22, 0x4d, 0x18]);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_corrupt_frame_rejected() {
+        let mut compressed = compress(b"some data").unwrap();
+        compressed[0] ^= 0xFF;
+
+        assert!(decompress(&compressed).is_err());
+        assert!(decompress(b"not lz4").is_err());
+    }
+}
@@ -1,73 +1,24 @@
 //! Compression pipeline orchestration.
 //!
 //! Coordinates BCJ filtering, delta compression, dictionary training,
-//! and final zstd compression for optimal results.
+//! and final zstd (or LZ4, XZ or Brotli) compression for optimal results.
 
-use crate::bcj::{BcjArch, BcjFilter};
+use crate::bcj::{self, BcjArch, BcjFilter, BcjWriter};
+use crate::chunk::{self, ChunkedData};
+use crate::decompress::DecompressionPipeline;
 use crate::delta::{self, DeltaGroup};
-use crate::dict::{self, TrainedDictionary, DEFAULT_DICT_SIZE};
-use crate::{CompressionError, Result};
+use crate::dict::{self, TrainedDictionary, TrainingParams, ZstdParams, DEFAULT_SAMPLE_SIZE};
+use crate::segment::{self, ParsedBinary};
+use crate::{brotli, lz4, xz, CompressionError, Result};
+pub use pbin_core::PlatformTier;
+use pbin_core::{Checksum, Compression};
+use serde::Serialize;
+use std::borrow::Cow;
 use std::collections::HashMap;
-
-/// Platform tier classification.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum PlatformTier {
-    /// Core platforms (most common, highest priority).
-    /// Linux/macOS/Windows on x86_64 and ARM64.
-    Core,
-    /// Standard platforms (common, good support).
-    /// Adds more Linux architectures, musl variants.
-    Standard,
-    /// Extended platforms (specialized, full support).
-    /// Adds BSDs, Android, iOS, embedded targets.
-    Extended,
-}
-
-impl PlatformTier {
-    /// Get targets for this tier.
-    pub fn targets(&self) -> Vec<&'static str> {
-        match self {
-            PlatformTier::Core => vec![
-                "linux-x86_64",
-                "linux-aarch64",
-                "darwin-x86_64",
-                "darwin-aarch64",
-                "windows-x86_64",
-                "windows-aarch64",
-            ],
-            PlatformTier::Standard => {
-                let mut targets = PlatformTier::Core.targets();
-                targets.extend(vec![
-                    "linux-x86_64-musl",
-                    "linux-aarch64-musl",
-                    "linux-armv7",
-                    "linux-riscv64",
-                    "linux-ppc64le",
-                    "linux-s390x",
-                    "windows-x86",
-                ]);
-                targets
-            }
-            PlatformTier::Extended => {
-                let mut targets = PlatformTier::Standard.targets();
-                targets.extend(vec![
-                    "freebsd-x86_64",
-                    "freebsd-aarch64",
-                    "netbsd-x86_64",
-                    "openbsd-x86_64",
-                    "android-aarch64",
-                    "android-armv7",
-                    "android-x86_64",
-                    "ios-aarch64",
-                    "linux-mips64",
-                    "linux-loongarch64",
-                    "wasi-wasm32",
-                ]);
-                targets
-            }
-        }
-    }
-}
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 /// Compression level presets.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -78,6 +29,13 @@ pub enum CompressionLevel {
     Balanced,
     /// Maximum compression, slower.
     Maximum,
+    /// Zstd level 22 with long-distance matching, for very large binaries
+    /// whose repeats lie further apart than a normal window reaches.
+    ///
+    /// Compressing needs several times the window size in memory and
+    /// decompressing needs the window itself: 128 MiB at the default
+    /// window log of 27 (see [`CompressionPipeline::with_zstd_params`]).
+    Ultra,
 }
 
 impl CompressionLevel {
@@ -87,15 +45,39 @@ impl CompressionLevel {
             CompressionLevel::Fast => 3,
             CompressionLevel::Balanced => 12,
             CompressionLevel::Maximum => 19,
+            CompressionLevel::Ultra => 22,
+        }
+    }
+
+    /// Get xz preset.
+    pub fn xz_preset(&self) -> u32 {
+        match self {
+            CompressionLevel::Fast => 3,
+            CompressionLevel::Balanced => 6,
+            CompressionLevel::Maximum | CompressionLevel::Ultra => 9,
+        }
+    }
+
+    /// Get brotli quality.
+    pub fn brotli_quality(&self) -> u32 {
+        match self {
+            CompressionLevel::Fast => 5,
+            CompressionLevel::Balanced => 9,
+            CompressionLevel::Maximum | CompressionLevel::Ultra => 11,
         }
     }
 
     /// Get similarity threshold for delta compression.
+    ///
+    /// Similarity is the share of shingles two binaries have in common, so
+    /// binaries differing in a fraction `d` of their content score about
+    /// `(1 - d) / (1 + d)`: 0.65 is roughly a fifth changed.
     pub fn delta_threshold(&self) -> f64 {
         match self {
-            CompressionLevel::Fast => 0.8,     // Only very similar binaries
-            CompressionLevel::Balanced => 0.6, // Moderately similar
-            CompressionLevel::Maximum => 0.4,  // More aggressive grouping
+            CompressionLevel::Fast => 0.65,     // Only very similar binaries
+            CompressionLevel::Balanced => 0.45, // Moderately similar
+            CompressionLevel::Maximum => 0.25,  // More aggressive grouping
+            CompressionLevel::Ultra => 0.25,
         }
     }
 }
@@ -103,30 +85,213 @@ impl CompressionLevel {
 /// Compressed binary entry.
 #[derive(Debug)]
 pub struct CompressedEntry {
-    /// Target platform.
+    /// Target platform, or entry key ("program/target") when several
+    /// programs are packed.
     pub target: String,
     /// Compressed data.
     pub data: Vec<u8>,
-    /// Whether BCJ filter was applied.
-    pub bcj_filtered: bool,
-    /// If stored as delta, reference target.
+    /// BCJ filter applied, if any.
+    pub bcj: Option<BcjArch>,
+    /// Ranges of the binary the BCJ filter covered, when it was applied
+    /// only to its executable sections rather than the whole binary.
+    pub bcj_ranges: Option<Vec<Range<usize>>>,
+    /// If stored as delta, reference target (or entry key).
     pub delta_reference: Option<String>,
+    /// Index into `CompressionResult::dictionaries` of the dictionary used
+    /// to compress `data`, if any.
+    pub dict_index: Option<usize>,
     /// Original uncompressed size.
     pub original_size: usize,
+    /// Algorithm `data` was compressed with.
+    pub compression: Compression,
+    /// Zstd window log, when `data` was compressed with long-distance
+    /// matching.
+    pub window_log: Option<u32>,
+}
+
+impl CompressedEntry {
+    /// BCJ filter that must be reversed after decompression, if any.
+    pub fn bcj_arch(&self) -> Option<BcjArch> {
+        self.bcj
+    }
+}
+
+/// Ranges each binary filtered section by section was BCJ-filtered over, by
+/// target.
+type BcjRanges = HashMap<String, Vec<Range<usize>>>;
+
+/// Time each BCJ-filtered binary's filter took, by target.
+type BcjTimes = HashMap<String, Duration>;
+
+/// Runs `f`, returning its result with the time it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = f();
+    (result, started.elapsed())
+}
+
+/// Zstd level delta comparisons are probed at before the final level.
+pub const DELTA_PROBE_LEVEL: i32 = 1;
+
+/// Group label of a dictionary given with
+/// [`CompressionPipeline::with_dictionary`].
+pub const SUPPLIED_DICT_GROUP: &str = "supplied";
+
+/// A saved dictionary (see [`CompressionPipeline::with_saved_dictionaries`])
+/// is reused only if its group's binaries compress at least this fraction
+/// smaller with it than without at [`DELTA_PROBE_LEVEL`]. Otherwise it's
+/// stale, and trained again.
+pub const STALE_DICT_MARGIN: f64 = 0.01;
+
+/// Default [`CompressionPipeline::with_dict_margin`]: a trained dictionary
+/// is kept whenever it saves more than its own size.
+pub const DEFAULT_DICT_MARGIN: f64 = 0.0;
+
+/// Default [`CompressionPipeline::with_delta_probe_margin`]: a probe wins
+/// outright when it's more than 10% smaller than the other.
+pub const DEFAULT_DELTA_PROBE_MARGIN: f64 = 0.1;
+
+/// Memory zstd's dictionary trainer needs besides its samples, mostly the
+/// frequency tables its fastCover search builds.
+pub const DICT_TRAINING_OVERHEAD: usize = 6 << 20;
+
+/// Bytes a delta patch takes to build per byte of its reference: bidiff
+/// sorts the reference's suffixes into an index four times its size.
+pub const DELTA_MEMORY_PER_BYTE: usize = 4;
+
+/// Bytes of a streamed binary read between
+/// [`ProgressEvent::BytesProcessed`] events.
+pub const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// Stage of compression a [`ProgressEvent`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Applying a binary's BCJ filter.
+    Bcj,
+    /// Training dictionaries, or checking saved ones still help.
+    DictTraining,
+    /// Grouping binaries by similarity for delta compression.
+    DeltaGrouping,
+    /// Compressing a binary, including its delta patch and probes, or a
+    /// chunk store.
+    Compression,
+    /// Checking a compressed entry reverses to its binary.
+    Verify,
+}
+
+impl Stage {
+    /// Returns a short description of the stage, for progress output.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Bcj => "BCJ filtering",
+            Stage::DictTraining => "training dictionaries",
+            Stage::DeltaGrouping => "grouping for delta",
+            Stage::Compression => "compressing",
+            Stage::Verify => "verifying",
+        }
+    }
 }
 
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Progress of a compression run, given to the callback set with
+/// [`CompressionPipeline::with_progress`].
+///
+/// Stages about a single binary name its target; the others, which work
+/// on every binary at once, have none. With several jobs, events for
+/// different targets interleave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent<'a> {
+    /// `stage` started.
+    StageStarted {
+        target: Option<&'a str>,
+        stage: Stage,
+    },
+    /// `done` of the `total` bytes of `target` have been compressed. Sent
+    /// once a binary is compressed, and every [`PROGRESS_INTERVAL`] bytes
+    /// as it's streamed.
+    BytesProcessed {
+        target: &'a str,
+        done: u64,
+        total: u64,
+    },
+    /// `stage` finished, or failed.
+    StageFinished {
+        target: Option<&'a str>,
+        stage: Stage,
+    },
+}
+
+/// Callback given [`ProgressEvent`]s.
+type ProgressFn = Box<dyn Fn(ProgressEvent<'_>) + Send + Sync>;
+
 /// Compression pipeline for PBIN.
 pub struct CompressionPipeline {
     /// Compression level.
     level: CompressionLevel,
+    /// Algorithm entries are compressed with.
+    algorithm: Compression,
+    /// Brotli window size, as log2 bytes.
+    brotli_window: u32,
+    /// Zstd level overriding the preset's.
+    zstd_level: Option<i32>,
+    /// Delta similarity threshold overriding the preset's.
+    delta_threshold: Option<f64>,
+    /// Fraction of an entry's size compression must save for the entry
+    /// not to be stored raw.
+    min_savings: f64,
+    /// How much smaller one side of a delta probe must be to win outright.
+    delta_probe_margin: f64,
+    /// Fraction a trained dictionary must save, counting its own size, to
+    /// be kept.
+    dict_margin: f64,
+    /// Zstd parameters on top of the level.
+    zstd_params: ZstdParams,
+    /// Number of binaries compressed at once.
+    jobs: usize,
     /// Whether to use BCJ filters.
     use_bcj: bool,
+    /// Whether x86 BCJ filters also convert conditional near jumps.
+    x86_jcc: bool,
     /// Whether to use delta compression.
     use_delta: bool,
+    /// Whether to re-anchor delta groups on their cheapest reference.
+    select_references: bool,
+    /// Whether delta targets are chained, each patched against the last.
+    delta_chains: bool,
     /// Whether to train dictionaries.
     use_dict: bool,
-    /// Trained dictionary (if any).
-    dictionary: Option<TrainedDictionary>,
+    /// Fewest binaries a dictionary is trained on whole.
+    dict_min_samples: usize,
+    /// Size of the pieces binaries are cut into when there are fewer.
+    dict_sample_size: usize,
+    /// Trained dictionaries, labelled by architecture group.
+    dictionaries: Vec<(String, TrainedDictionary)>,
+    /// Dictionaries saved by an earlier run, to reuse instead of training
+    /// while they still help.
+    saved_dictionaries: Vec<(String, TrainedDictionary)>,
+    /// Dictionary to use for every binary instead of training any.
+    supplied_dictionary: Option<TrainedDictionary>,
+    /// Index into `dictionaries` for each target compressed with one.
+    dict_assignments: HashMap<String, usize>,
+    /// Whether to decompress every entry again and check it gives back
+    /// its binary.
+    verify: bool,
+    /// Dictionary size, level and whether training is pinned so identical
+    /// inputs always give identical output.
+    dict_training: TrainingParams,
+    /// Called with each stage's progress.
+    progress: Option<ProgressFn>,
+    /// Memory the pipeline may use on top of the binaries it's given.
+    memory_budget: Option<usize>,
+    /// Rewrites BCJ-filtered binaries, standing in for a filter whose
+    /// decoding doesn't invert its encoding.
+    #[cfg(test)]
+    break_filter: Option<fn(&mut Vec<u8>)>,
 }
 
 impl Default for CompressionPipeline {
@@ -140,11 +305,194 @@ impl CompressionPipeline {
     pub fn new(level: CompressionLevel) -> Self {
         Self {
             level,
+            algorithm: Compression::Zstd,
+            brotli_window: brotli::DEFAULT_WINDOW,
+            zstd_level: None,
+            delta_threshold: None,
+            min_savings: 0.0,
+            delta_probe_margin: DEFAULT_DELTA_PROBE_MARGIN,
+            dict_margin: DEFAULT_DICT_MARGIN,
+            zstd_params: ZstdParams::default(),
+            jobs: 1,
             use_bcj: true,
+            x86_jcc: false,
             use_delta: true,
+            select_references: false,
+            delta_chains: false,
             use_dict: true,
-            dictionary: None,
+            dict_min_samples: dict::MIN_SAMPLES,
+            dict_sample_size: DEFAULT_SAMPLE_SIZE,
+            dictionaries: Vec::new(),
+            saved_dictionaries: Vec::new(),
+            supplied_dictionary: None,
+            dict_assignments: HashMap::new(),
+            verify: level == CompressionLevel::Maximum,
+            dict_training: TrainingParams::default(),
+            progress: None,
+            memory_budget: None,
+            #[cfg(test)]
+            break_filter: None,
+        }
+    }
+
+    /// Compress with `algorithm` instead of zstd. BCJ, delta and
+    /// deduplication work the same whatever the final coder.
+    ///
+    /// Dictionaries are a zstd feature, so other algorithms train none.
+    pub fn with_algorithm(mut self, algorithm: Compression) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set the Brotli window size, as log2 bytes (see
+    /// [`brotli::WINDOW_RANGE`]). Only used with [`Compression::Brotli`].
+    pub fn with_brotli_window(mut self, window: u32) -> Self {
+        self.brotli_window = window;
+        self
+    }
+
+    /// Use zstd `level` instead of the preset's, keeping its other
+    /// settings. Must be within [`dict::level_range`].
+    pub fn with_zstd_level(mut self, level: i32) -> Self {
+        self.zstd_level = Some(level);
+        self
+    }
+
+    /// Use `threshold` (0.0 to 1.0) as the similarity above which binaries
+    /// are delta compressed, instead of the preset's.
+    pub fn with_delta_threshold(mut self, threshold: f64) -> Self {
+        self.delta_threshold = Some(threshold);
+        self
+    }
+
+    /// Decide between a delta target's patch and its binary by compressing
+    /// both at [`DELTA_PROBE_LEVEL`], keeping the final level for the one
+    /// whose probe is smaller by more than `margin` (0.0 to 1.0) of the
+    /// other's. Closer calls compress both at the final level and compare,
+    /// as does every call at 1.0. Defaults to
+    /// [`DEFAULT_DELTA_PROBE_MARGIN`].
+    pub fn with_delta_probe_margin(mut self, margin: f64) -> Self {
+        self.delta_probe_margin = margin;
+        self
+    }
+
+    /// Keep a trained dictionary only if its group's binaries, compressed
+    /// with it at [`DELTA_PROBE_LEVEL`] and counting the dictionary itself,
+    /// come out more than `margin` (0.0 to 1.0) smaller than compressed
+    /// without it. Binaries whose architecture's dictionary is dropped
+    /// join the shared one. Defaults to [`DEFAULT_DICT_MARGIN`].
+    pub fn with_dict_margin(mut self, margin: f64) -> Self {
+        self.dict_margin = margin;
+        self
+    }
+
+    /// Store an entry raw unless compressing it saves at least `fraction`
+    /// (0.0 to 1.0) of its size. At the default of 0.0, entries are stored
+    /// raw only when compression doesn't shrink them at all.
+    pub fn with_min_savings(mut self, fraction: f64) -> Self {
+        self.min_savings = fraction;
+        self
+    }
+
+    /// Returns true if `compressed_size` bytes save too little over
+    /// `original_size` to be worth decompressing, so the original bytes
+    /// should be stored instead (see
+    /// [`with_min_savings`](Self::with_min_savings)).
+    pub fn stores_raw(&self, original_size: usize, compressed_size: usize) -> bool {
+        let saved = original_size.saturating_sub(compressed_size);
+        saved == 0 || (saved as f64) < original_size as f64 * self.min_savings
+    }
+
+    /// Tune zstd beyond its level with `params`, clamped into the ranges
+    /// zstd accepts. The window log isn't clamped and must be within
+    /// [`dict::WINDOW_LOG_RANGE`]. Only used with [`Compression::Zstd`].
+    pub fn with_zstd_params(mut self, params: ZstdParams) -> Self {
+        self.zstd_params = params;
+        self
+    }
+
+    /// Compress up to `jobs` binaries at once, on that many threads (plus
+    /// one more per delta target, compressing it directly while its patch
+    /// is built). Entries come out the same whatever the number of jobs;
+    /// 0 is treated as 1.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Returns the zstd parameters in effect: those set with
+    /// [`with_zstd_params`](Self::with_zstd_params), plus long-distance
+    /// matching over a [`dict::DEFAULT_LONG_WINDOW_LOG`] window unless
+    /// another is set at the Ultra level.
+    pub fn zstd_params(&self) -> ZstdParams {
+        let mut params = self.zstd_params.clamped();
+        if self.level == CompressionLevel::Ultra {
+            params.long_distance_matching = true;
+            params
+                .window_log
+                .get_or_insert(dict::DEFAULT_LONG_WINDOW_LOG);
+        }
+        params
+    }
+
+    /// Returns the zstd window log entries record, when compressing with
+    /// zstd and one is set explicitly or by the Ultra level.
+    pub fn window_log(&self) -> Option<u32> {
+        if self.algorithm == Compression::Zstd {
+            self.zstd_params().window_log
+        } else {
+            None
+        }
+    }
+
+    /// Returns the zstd level in effect.
+    pub fn zstd_level(&self) -> i32 {
+        self.zstd_level.unwrap_or(self.level.zstd_level())
+    }
+
+    /// Returns the delta similarity threshold in effect.
+    pub fn delta_threshold(&self) -> f64 {
+        self.delta_threshold.unwrap_or(self.level.delta_threshold())
+    }
+
+    /// Checks the overridden settings are in range.
+    fn check_settings(&self) -> Result<()> {
+        let range = dict::level_range();
+        if !range.contains(&self.zstd_level()) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "zstd level {} is outside {}-{}",
+                self.zstd_level(),
+                range.start(),
+                range.end()
+            )));
+        }
+        self.zstd_params.validate()?;
+        self.dict_training.validate()?;
+        if !(0.0..=1.0).contains(&self.delta_threshold()) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "delta threshold {} is outside 0-1",
+                self.delta_threshold()
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.delta_probe_margin) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "delta probe margin {} is outside 0-1",
+                self.delta_probe_margin
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.dict_margin) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "dictionary margin {} is outside 0-1",
+                self.dict_margin
+            )));
+        }
+        if !(0.0..=1.0).contains(&self.min_savings) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "minimum savings {} is outside 0-1",
+                self.min_savings
+            )));
         }
+        Ok(())
     }
 
     /// Disable BCJ filtering.
@@ -153,67 +501,286 @@ impl CompressionPipeline {
         self
     }
 
+    /// Filter x86 binaries with [`BcjArch::X86Jcc`], also converting
+    /// conditional near jumps. Optimized code has plenty, but data and
+    /// unoptimized code mistaken for them can compress worse.
+    pub fn with_x86_jcc(mut self) -> Self {
+        self.x86_jcc = true;
+        self
+    }
+
+    /// Returns the BCJ filter for `target`'s binary, if one applies.
+    fn bcj_filter(&self, target: &str) -> Option<BcjArch> {
+        match BcjArch::from_target(target) {
+            _ if !self.use_bcj => None,
+            BcjArch::None => None,
+            BcjArch::X86 if self.x86_jcc => Some(BcjArch::X86Jcc),
+            arch => Some(arch),
+        }
+    }
+
     /// Disable delta compression.
     pub fn without_delta(mut self) -> Self {
         self.use_delta = false;
         self
     }
 
-    /// Disable dictionary training.
+    /// Anchor each delta group on the binary whose patches to the rest are
+    /// estimated smallest, rather than the group's first binary (see
+    /// [`delta::select_references`]). Costs a similarity comparison per
+    /// pair of binaries in a group.
+    pub fn with_reference_selection(mut self) -> Self {
+        self.select_references = true;
+        self
+    }
+
+    /// Chain each group's delta targets, patching every target against the
+    /// most similar one before it rather than the reference (see
+    /// [`delta::order_chains`]). Suits families that drift a little per
+    /// build; rebuilding a target applies every patch before it.
+    pub fn with_delta_chains(mut self) -> Self {
+        self.delta_chains = true;
+        self
+    }
+
+    /// Disable dictionary training, and compress without the dictionary
+    /// given with [`with_dictionary`](Self::with_dictionary), if any.
     pub fn without_dict(mut self) -> Self {
         self.use_dict = false;
         self
     }
 
+    /// Train a group's dictionary on its binaries whole only when it has at
+    /// least `count` of them (never fewer than [`dict::MIN_SAMPLES`]).
+    /// Defaults to [`dict::MIN_SAMPLES`]; see
+    /// [`with_dict_sample_size`](Self::with_dict_sample_size) for what
+    /// happens below it.
+    pub fn with_dict_min_samples(mut self, count: usize) -> Self {
+        self.dict_min_samples = count.max(dict::MIN_SAMPLES);
+        self
+    }
+
+    /// Cut binaries into pieces of `size` bytes to train on when too few
+    /// are packed to train on whole, instead of [`DEFAULT_SAMPLE_SIZE`].
+    /// 0 is treated as 1.
+    pub fn with_dict_sample_size(mut self, size: usize) -> Self {
+        self.dict_sample_size = size.max(1);
+        self
+    }
+
+    /// Reuse `dictionaries`, labelled by group as
+    /// [`dictionaries`](Self::dictionaries) returns them after an earlier
+    /// run, instead of training a group's dictionary again. A saved
+    /// dictionary that no longer makes its group's binaries at least
+    /// [`STALE_DICT_MARGIN`] smaller is stale, and trained again; stats
+    /// list the groups whose dictionaries were reused or stale.
+    pub fn with_saved_dictionaries(
+        mut self,
+        dictionaries: Vec<(String, TrainedDictionary)>,
+    ) -> Self {
+        self.saved_dictionaries = dictionaries;
+        self
+    }
+
+    /// Compress every binary with `dictionary`, trained offline, instead of
+    /// training dictionaries per architecture. It's kept as long as it
+    /// makes the binaries at least [`STALE_DICT_MARGIN`] smaller, under
+    /// [`SUPPLIED_DICT_GROUP`], and stats record its ID; otherwise it's
+    /// listed as stale and dictionaries are trained as usual. Saved
+    /// dictionaries are only considered then. Ignored with
+    /// [`without_dict`](Self::without_dict) or an algorithm other than
+    /// zstd.
+    pub fn with_dictionary(mut self, dictionary: TrainedDictionary) -> Self {
+        self.supplied_dictionary = Some(dictionary);
+        self
+    }
+
+    /// Returns the dictionaries the last [`compress_all`](Self::compress_all)
+    /// trained or reused, labelled by group, for saving with
+    /// [`dict::dictionaries_to_bytes`].
+    pub fn dictionaries(&self) -> &[(String, TrainedDictionary)] {
+        &self.dictionaries
+    }
+
+    /// Check every entry [`compress_all`](Self::compress_all) produces by
+    /// reversing it (decompressing, applying its delta patch and undoing
+    /// its BCJ filter) and comparing the BLAKE3 of the result with the
+    /// binary's, failing with [`CompressionError::Verify`] for the first
+    /// entry that differs. On by default at [`CompressionLevel::Maximum`].
+    pub fn with_verify(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    /// Don't verify entries, even at [`CompressionLevel::Maximum`].
+    pub fn without_verify(mut self) -> Self {
+        self.verify = false;
+        self
+    }
+
+    /// Call `progress` as each stage starts and finishes, and as binaries
+    /// compress (see [`ProgressEvent`]). It's called from whichever thread
+    /// does the work, so must return quickly.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(ProgressEvent<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Keep the memory [`compress_all`](Self::compress_all) uses on top of
+    /// the binaries it's given to about `bytes`, at some cost in size and
+    /// speed. To fit, it:
+    ///
+    /// - trains dictionaries on evenly spread pieces of their binaries, or
+    ///   not at all if even [`dict::MIN_SAMPLES`] pieces and
+    ///   [`DICT_TRAINING_OVERHEAD`] don't fit;
+    /// - compresses delta targets directly when building their patch
+    ///   would need more than the budget (see [`DELTA_MEMORY_PER_BYTE`]),
+    ///   and runs fewer jobs at once when they wouldn't all fit, never
+    ///   building a patch alongside the direct compression it's compared
+    ///   with;
+    /// - with zstd, BCJ-filters the biggest binaries as they stream into
+    ///   the encoder rather than filtering copies of them, until the copies
+    ///   left fit in half the budget. Those skip delta compression and
+    ///   dictionaries, and are filtered whole rather than by section.
+    ///
+    /// What it gave up is listed in [`CompressionStats::degradations`].
+    /// The output is always a correct pack; only its size suffers.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Sends `event` to the progress callback, if there is one.
+    fn report(&self, event: ProgressEvent<'_>) {
+        if let Some(progress) = &self.progress {
+            progress(event);
+        }
+    }
+
+    /// Runs `f` as `stage`, for `target` if it's about a single binary,
+    /// reporting when it starts and finishes.
+    fn stage<T>(&self, target: Option<&str>, stage: Stage, f: impl FnOnce() -> T) -> T {
+        self.report(ProgressEvent::StageStarted { target, stage });
+        let result = f();
+        self.report(ProgressEvent::StageFinished { target, stage });
+        result
+    }
+
+    /// Guarantee identical output for identical inputs, whatever the
+    /// number of jobs, by training dictionaries with
+    /// pinned parameters on one thread (see
+    /// [`dict::train_dictionary_deterministic`]). The rest of the pipeline
+    /// already gives the same output every run; only zstd's search for the
+    /// best training parameters may not.
+    pub fn with_deterministic(mut self) -> Self {
+        self.dict_training.deterministic = true;
+        self
+    }
+
+    /// Train dictionaries of up to `size` bytes instead of
+    /// [`dict::DEFAULT_DICT_SIZE`]. Big packs of similar binaries can make
+    /// a much bigger dictionary pay; tiny ones need far less. Must be
+    /// within [`dict::DICT_SIZE_RANGE`].
+    pub fn with_dict_size(mut self, size: usize) -> Self {
+        self.dict_training.dict_size = size;
+        self
+    }
+
+    /// Tune dictionaries' entropy tables for zstd `level`, ideally the
+    /// level entries are compressed at, instead of zstd's default. Must be
+    /// 0 (the default) or a level zstd accepts.
+    pub fn with_dict_level(mut self, level: i32) -> Self {
+        self.dict_training.level = level;
+        self
+    }
+
     /// Compress multiple binaries with the pipeline.
+    ///
+    /// The binaries are only borrowed: the pipeline copies just those it
+    /// BCJ-filters, so packing holds little more than the inputs and their
+    /// compressed entries.
     pub fn compress_all(
         &mut self,
-        binaries: Vec<(String, Vec<u8>)>,
+        binaries: &[(String, impl AsRef<[u8]>)],
     ) -> Result<CompressionResult> {
+        self.check_settings()?;
         if binaries.is_empty() {
             return Ok(CompressionResult {
                 entries: Vec::new(),
-                dictionary: None,
+                dictionaries: Vec::new(),
+                delta_groups: Vec::new(),
                 stats: CompressionStats::default(),
             });
         }
 
         let mut stats = CompressionStats {
-            original_size: binaries.iter().map(|(_, d)| d.len()).sum(),
+            original_size: binaries.iter().map(|(_, d)| d.as_ref().len()).sum(),
             ..Default::default()
         };
+        let checksums: Option<Vec<(String, Checksum)>> = self.verify.then(|| {
+            binaries
+                .iter()
+                .map(|(target, data)| (target.clone(), Checksum::of(data.as_ref())))
+                .collect()
+        });
 
-        // Step 1: Parse binaries and apply BCJ filters
-        let mut processed: Vec<(String, Vec<u8>)> = Vec::new();
-        for (target, mut data) in binaries {
-            if self.use_bcj {
-                let arch = BcjArch::from_target(&target);
-                if arch != BcjArch::None {
-                    let mut filter = BcjFilter::new(arch);
-                    filter.encode(&mut data)?;
-                    stats.bcj_filtered += 1;
-                }
-            }
-            processed.push((target, data));
+        // Step 1: Parse binaries and apply BCJ filters. Binaries streamed
+        // to fit the memory budget are filtered as they compress instead,
+        // and sit out the dictionary and delta steps.
+        let streamed = self.streamed_targets(binaries);
+        for target in &streamed {
+            stats.degradations.push(Degradation::Streamed {
+                target: target.clone(),
+            });
         }
+        let mut bcj_ranges = BcjRanges::new();
+        let mut bcj_times = BcjTimes::new();
+        let processed = self.apply_bcj(
+            binaries,
+            &streamed,
+            &mut bcj_ranges,
+            &mut bcj_times,
+            &mut stats,
+        )?;
+        let (streamed_binaries, processed): (Vec<_>, Vec<_>) = processed
+            .into_iter()
+            .partition(|(target, _)| streamed.contains(target));
 
-        // Step 2: Train dictionary if enabled
-        if self.use_dict && processed.len() >= 4 {
-            let samples: Vec<&[u8]> = processed.iter().map(|(_, d)| d.as_slice()).collect();
-            match TrainedDictionary::train(&samples, DEFAULT_DICT_SIZE) {
-                Ok(dict) => {
-                    self.dictionary = Some(dict);
-                    stats.dict_trained = true;
-                }
-                Err(_) => {
-                    // Dictionary training failed, continue without
-                }
-            }
+        // Step 2: Train dictionaries if enabled
+        self.dictionaries.clear();
+        self.dict_assignments.clear();
+        if self.use_dict && self.algorithm == Compression::Zstd {
+            let started = Instant::now();
+            self.report(ProgressEvent::StageStarted {
+                target: None,
+                stage: Stage::DictTraining,
+            });
+            self.train_dictionaries(&processed, &mut stats);
+            self.report(ProgressEvent::StageFinished {
+                target: None,
+                stage: Stage::DictTraining,
+            });
+            stats.dict_trained = !self.dictionaries.is_empty();
+            stats.dict_size_limit = Some(self.dict_training.dict_size);
+            stats.stage_times.dict_training = started.elapsed();
         }
 
         // Step 3: Group binaries for delta compression
-        let groups = if self.use_delta {
-            delta::group_by_similarity(&processed, self.level.delta_threshold())
+        let started = Instant::now();
+        let mut groups = if self.use_delta {
+            self.stage(None, Stage::DeltaGrouping, || {
+                let mut groups = delta::group_by_similarity(&processed, self.delta_threshold());
+                if self.select_references {
+                    delta::select_references(&mut groups, &processed);
+                }
+                if self.delta_chains {
+                    delta::order_chains(&mut groups, &processed);
+                }
+                groups
+            })
         } else {
             // No grouping, each binary is its own group
             processed
@@ -221,207 +788,2933 @@ impl CompressionPipeline {
                 .map(|(target, _)| DeltaGroup {
                     reference_target: target.clone(),
                     delta_targets: Vec::new(),
+                    chained: false,
                 })
                 .collect()
         };
+        stats.stage_times.delta_grouping = started.elapsed();
+        groups.extend(streamed_binaries.iter().map(|(target, _)| DeltaGroup {
+            reference_target: target.clone(),
+            delta_targets: Vec::new(),
+            chained: false,
+        }));
 
-        // Step 4: Compress each group
-        let zstd_level = self.level.zstd_level();
-        let mut entries: Vec<CompressedEntry> = Vec::new();
-
-        // Build lookup for processed binaries
-        let binary_map: HashMap<String, Vec<u8>> = processed.into_iter().collect();
-
-        for group in groups {
-            // Compress reference binary
-            let ref_data = binary_map
-                .get(&group.reference_target)
-                .ok_or_else(|| CompressionError::InvalidData("Missing reference binary".into()))?;
-
-            let compressed_ref = self.compress_single(ref_data, zstd_level)?;
-            entries.push(CompressedEntry {
-                target: group.reference_target.clone(),
-                data: compressed_ref,
-                bcj_filtered: self.use_bcj && BcjArch::from_target(&group.reference_target) != BcjArch::None,
-                delta_reference: None,
-                original_size: ref_data.len(),
-            });
-
-            // Compress delta targets
-            for delta_target in &group.delta_targets {
-                let target_data = binary_map
-                    .get(delta_target)
-                    .ok_or_else(|| CompressionError::InvalidData("Missing delta target".into()))?;
-
-                // Create delta patch
-                let patch = delta::create_patch(ref_data, target_data)?;
-
-                // Compress the patch
-                let compressed_patch = self.compress_single(&patch, zstd_level)?;
-
-                // Only use delta if it's smaller than direct compression
-                let direct_compressed = self.compress_single(target_data, zstd_level)?;
-
-                if compressed_patch.len() < direct_compressed.len() {
-                    stats.delta_used += 1;
-                    entries.push(CompressedEntry {
-                        target: delta_target.clone(),
-                        data: compressed_patch,
-                        bcj_filtered: self.use_bcj && BcjArch::from_target(delta_target) != BcjArch::None,
-                        delta_reference: Some(group.reference_target.clone()),
-                        original_size: target_data.len(),
-                    });
+        // Step 4: Compress each group, one job per binary. Delta targets
+        // only need their reference's uncompressed data, even in a chain, so
+        // every binary compresses independently.
+        let binary_map: HashMap<&str, &[u8]> = processed
+            .iter()
+            .chain(&streamed_binaries)
+            .map(|(target, data)| (target.as_str(), data.as_ref()))
+            .collect();
+        let mut jobs: Vec<(&str, Option<&str>)> = groups
+            .iter()
+            .flat_map(|group| {
+                std::iter::once((group.reference_target.as_str(), None)).chain(
+                    group
+                        .delta_targets
+                        .iter()
+                        .enumerate()
+                        .map(|(i, target)| (target.as_str(), Some(group.reference_of(i)))),
+                )
+            })
+            .collect();
+        let threads = self.fit_jobs(&mut jobs, &binary_map, &mut stats);
+        let started = Instant::now();
+        let results = self.run_jobs_on(threads, &jobs, |(target, reference)| {
+            let started = Instant::now();
+            let result = self.stage(Some(target), Stage::Compression, || {
+                let result = if streamed.iter().any(|t| t == target) {
+                    self.compress_streamed(target, binary_map[target])
+                        .map(|entry| (entry, false, EntryTimes::default()))
                 } else {
-                    entries.push(CompressedEntry {
-                        target: delta_target.clone(),
-                        data: direct_compressed,
-                        bcj_filtered: self.use_bcj && BcjArch::from_target(delta_target) != BcjArch::None,
-                        delta_reference: None,
-                        original_size: target_data.len(),
+                    self.compress_target(target, *reference, &binary_map, &bcj_ranges)
+                };
+                if let Ok((entry, ..)) = &result {
+                    let total = entry.original_size as u64;
+                    self.report(ProgressEvent::BytesProcessed {
+                        target,
+                        done: total,
+                        total,
                     });
                 }
+                result
+            });
+            result.map(|(entry, probed, times)| (entry, probed, times, started.elapsed()))
+        });
+        stats.stage_times.compression = started.elapsed();
+
+        // Results come back in job order, so entries (and the first error)
+        // don't depend on which thread finished first.
+        let mut entries: Vec<CompressedEntry> = Vec::new();
+        for result in results {
+            let (entry, probed, mut times, time) = result?;
+            if entry.delta_reference.is_some() {
+                stats.delta_used += 1;
             }
+            if probed {
+                stats.delta_comparisons_skipped += 1;
+            }
+            if entry.compression != self.algorithm {
+                stats.stored_raw += 1;
+            }
+            stats.compress_times.push((entry.target.clone(), time));
+            times.bcj = bcj_times.get(&entry.target).copied().unwrap_or_default();
+            times.compress = time;
+            stats.entries.push(EntryStats {
+                target: entry.target.clone(),
+                original_size: entry.original_size,
+                compressed_size: entry.data.len(),
+                compression: entry.compression,
+                bcj: entry.bcj,
+                delta_reference: entry.delta_reference.clone(),
+                dictionary: entry
+                    .dict_index
+                    .map(|index| self.dictionaries[index].0.clone()),
+                times,
+            });
+            entries.push(entry);
         }
 
         stats.compressed_size = entries.iter().map(|e| e.data.len()).sum();
-        if let Some(ref dict) = self.dictionary {
-            stats.compressed_size += dict.data.len();
+        stats.compressed_size += self
+            .dictionaries
+            .iter()
+            .map(|(_, dict)| dict.data.len())
+            .sum::<usize>();
+        stats.dictionaries = self
+            .dictionaries
+            .iter()
+            .enumerate()
+            .map(|(index, (group, dict))| {
+                let members = entries.iter().filter(|e| e.dict_index == Some(index));
+                DictionaryStats {
+                    group: group.clone(),
+                    dict_size: dict.data.len(),
+                    entries: members.clone().count(),
+                    original_size: members.clone().map(|e| e.original_size).sum(),
+                    compressed_size: dict.data.len() + members.map(|e| e.data.len()).sum::<usize>(),
+                }
+            })
+            .collect();
+
+        let dictionaries: Vec<(String, Vec<u8>)> = self
+            .dictionaries
+            .iter()
+            .map(|(group, dict)| (group.clone(), dict.data.clone()))
+            .collect();
+
+        // Step 5: Reverse every entry and check it gives back its binary
+        if let Some(checksums) = checksums {
+            let started = Instant::now();
+            let decompressor = DecompressionPipeline::from_parts(&entries, &dictionaries);
+            let results = self.run_jobs_on(threads, &checksums, |(target, checksum)| {
+                self.stage(Some(target), Stage::Verify, || {
+                    decompressor.decompress_verified(target, checksum)
+                })
+                .map_err(|e| CompressionError::Verify {
+                    target: target.clone(),
+                    source: Box::new(e),
+                })
+            });
+            for result in results {
+                result?;
+            }
+            stats.verify_time = Some(started.elapsed());
         }
 
         Ok(CompressionResult {
             entries,
-            dictionary: self.dictionary.as_ref().map(|d| d.data.clone()),
+            dictionaries,
+            delta_groups: groups,
             stats,
         })
     }
 
-    /// Compress a single binary.
-    fn compress_single(&self, data: &[u8], level: i32) -> Result<Vec<u8>> {
-        if let Some(ref dict) = self.dictionary {
-            dict.compress(data, level)
-        } else {
-            dict::compress(data, level)
-        }
-    }
-}
-
-/// Result of compression pipeline.
-#[derive(Debug)]
-pub struct CompressionResult {
-    /// Compressed entries.
-    pub entries: Vec<CompressedEntry>,
-    /// Trained dictionary (if any).
-    pub dictionary: Option<Vec<u8>>,
-    /// Compression statistics.
-    pub stats: CompressionStats,
-}
-
-/// Compression statistics.
-#[derive(Debug, Default)]
-pub struct CompressionStats {
-    /// Total original size.
-    pub original_size: usize,
-    /// Total compressed size (including dictionary).
-    pub compressed_size: usize,
-    /// Number of binaries with BCJ filter applied.
-    pub bcj_filtered: usize,
-    /// Number of binaries using delta compression.
-    pub delta_used: usize,
-    /// Whether dictionary was trained.
-    pub dict_trained: bool,
-}
-
-impl CompressionStats {
-    /// Calculate compression ratio.
-    pub fn ratio(&self) -> f64 {
-        if self.original_size == 0 {
-            0.0
-        } else {
-            self.compressed_size as f64 / self.original_size as f64
-        }
+    /// Compress binaries by content-defined chunk deduplication instead of
+    /// per-binary compression (see [`chunk`]).
+    ///
+    /// Each binary is BCJ-filtered as in [`compress_all`](Self::compress_all)
+    /// and split into chunks; every distinct chunk goes once into a chunk
+    /// store compressed as a single payload, and each binary becomes the
+    /// list of chunks that make it up. Delta compression and dictionaries
+    /// don't apply to chunks, so they are skipped.
+    pub fn compress_chunked(
+        &self,
+        binaries: &[(String, impl AsRef<[u8]>)],
+    ) -> Result<ChunkedResult> {
+        self.store_chunks(binaries, |processed| chunk::dedup(processed))
     }
 
-    /// Calculate space savings percentage.
-    pub fn savings_percent(&self) -> f64 {
-        (1.0 - self.ratio()) * 100.0
+    /// Compress binaries by storing the read-only data sections they share
+    /// once, like [`compress_chunked`](Self::compress_chunked) with chunks
+    /// cut at section boundaries instead of content-defined ones.
+    ///
+    /// Each section [`segment::shared_sections`] finds becomes one chunk,
+    /// and the bytes around it, headers, code, alignment padding and all,
+    /// become chunks of their own, so every binary is rebuilt byte for byte.
+    /// Sections are located in the original binaries, before any BCJ filter.
+    /// Data that isn't an ELF, Mach-O or PE binary is kept as a single chunk.
+    /// The binaries are parsed where they are, so this takes them rather
+    /// than borrowing them.
+    pub fn compress_segments(&self, binaries: Vec<(String, Vec<u8>)>) -> Result<ChunkedResult> {
+        let parsed: Vec<ParsedBinary> = binaries
+            .into_iter()
+            .map(|(target, data)| ParsedBinary::parse_or_opaque(&target, data))
+            .collect();
+        let sections = segment::shared_sections(&parsed);
+        let binaries: Vec<_> = parsed.into_iter().map(|p| (p.target, p.data)).collect();
+        self.store_chunks(&binaries, |processed| {
+            chunk::dedup_with(processed, |i, data| {
+                chunk::split_at_ranges(data, &sections[i])
+            })
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// BCJ-filter `binaries`, split them into deduplicated chunks with
+    /// `dedup` and compress the chunk store.
+    fn store_chunks(
+        &self,
+        binaries: &[(String, impl AsRef<[u8]>)],
+        dedup: impl FnOnce(&[(String, Cow<'_, [u8]>)]) -> ChunkedData,
+    ) -> Result<ChunkedResult> {
+        self.check_settings()?;
+        let mut stats = CompressionStats {
+            original_size: binaries.iter().map(|(_, d)| d.as_ref().len()).sum(),
+            ..Default::default()
+        };
+        let mut bcj_ranges = BcjRanges::new();
+        let processed = self.apply_bcj(
+            binaries,
+            &[],
+            &mut bcj_ranges,
+            &mut BcjTimes::new(),
+            &mut stats,
+        )?;
+        let chunked = dedup(&processed);
 
-    fn make_binary(target: &str, seed: u8) -> (String, Vec<u8>) {
-        let mut data = Vec::with_capacity(4096);
+        let started = Instant::now();
+        let compressed = self.stage(None, Stage::Compression, || self.compress(&chunked.store))?;
+        let (store, compression, window_log) =
+            if self.stores_raw(chunked.store.len(), compressed.len()) {
+                stats.stored_raw += 1;
+                (chunked.store.clone(), Compression::None, None)
+            } else {
+                (compressed, self.algorithm, self.window_log())
+            };
+        stats.stage_times.compression = started.elapsed();
+        stats
+            .compress_times
+            .push(("chunk store".to_string(), stats.stage_times.compression));
+        stats.compressed_size = store.len();
 
-        // Simulate ELF header
-        data.extend_from_slice(b"\x7FELF\x02\x01\x01\x00");
-        data.extend_from_slice(&[0; 8]);
+        let entries = processed
+            .iter()
+            .zip(chunked.entries)
+            .map(|((target, data), (_, chunks))| ChunkedEntry {
+                bcj: self.bcj_filter(target),
+                bcj_ranges: bcj_ranges.remove(target),
+                target: target.clone(),
+                chunks,
+                original_size: data.len(),
+            })
+            .collect();
+        Ok(ChunkedResult {
+            store,
+            store_size: chunked.store.len(),
+            store_checksum: Checksum::of(&chunked.store),
+            compression,
+            window_log,
+            chunk_sizes: chunked.chunk_sizes,
+            entries,
+            stats,
+        })
+    }
 
-        // Add some x86-like instructions with CALL patterns
-        for i in 0..500 {
-            if i % 20 == 0 {
-                // CALL instruction pattern
-                data.push(0xE8);
-                data.extend_from_slice(&[
-                    (i as u8).wrapping_add(seed),
-                    0x00,
-                    0x00,
-                    0x00,
-                ]);
-            } else {
-                data.push((i as u8).wrapping_mul(seed.wrapping_add(1)));
+    /// Targets to stream through the BCJ filter rather than filter copies
+    /// of, to fit the memory budget: the biggest binaries BCJ would copy,
+    /// until the rest fit in half the budget. None without a budget, or
+    /// unless compressing with zstd, the one algorithm pbin streams.
+    fn streamed_targets(&self, binaries: &[(String, impl AsRef<[u8]>)]) -> Vec<String> {
+        let Some(budget) = self.memory_budget else {
+            return Vec::new();
+        };
+        if self.algorithm != Compression::Zstd {
+            return Vec::new();
+        }
+        let mut filtered: Vec<(&String, usize)> = binaries
+            .iter()
+            .filter(|(target, _)| self.bcj_filter(target).is_some())
+            .map(|(target, data)| (target, data.as_ref().len()))
+            .collect();
+        filtered.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+        let mut copied: usize = filtered.iter().map(|&(_, len)| len).sum();
+        let mut streamed = Vec::new();
+        for (target, len) in filtered {
+            if copied <= budget / 2 {
+                break;
             }
+            copied -= len;
+            streamed.push(target.clone());
         }
-
-        (target.to_string(), data)
+        streamed
     }
 
-    #[test]
-    fn test_compression_pipeline() {
-        let binaries = vec![
-            make_binary("linux-x86_64", 1),
-            make_binary("darwin-x86_64", 2),
-            make_binary("linux-aarch64", 3),
+    /// Fits compression `jobs` to the memory budget, returning how many to
+    /// run at once. Delta targets whose patch wouldn't fit are compressed
+    /// directly instead.
+    fn fit_jobs(
+        &self,
+        jobs: &mut [(&str, Option<&str>)],
+        binaries: &HashMap<&str, &[u8]>,
+        stats: &mut CompressionStats,
+    ) -> usize {
+        let Some(budget) = self.memory_budget else {
+            return self.jobs;
+        };
+        let len = |target: &str| binaries.get(target).map_or(0, |data| data.len());
+        let mut largest = 0;
+        for (target, reference) in jobs.iter_mut() {
+            // The patch, the suffix index it's built with, and the output.
+            let mut needed = len(target);
+            if let Some(r) = *reference {
+                let patch = DELTA_MEMORY_PER_BYTE * len(r) + len(target);
+                if patch > budget {
+                    stats.degradations.push(Degradation::DeltaSkipped {
+                        target: target.to_string(),
+                        reference: r.to_string(),
+                    });
+                    *reference = None;
+                } else {
+                    needed += patch;
+                }
+            }
+            largest = largest.max(needed);
+        }
+        let threads = (budget / largest.max(1)).clamp(1, self.jobs);
+        if threads < self.jobs.min(jobs.len()) {
+            stats.degradations.push(Degradation::Jobs { jobs: threads });
+        }
+        threads
+    }
+
+    /// Apply each binary's BCJ filter, if BCJ filtering is enabled.
+    ///
+    /// Only the executable sections of binaries goblin can parse are
+    /// filtered, since data the filter mistakes for branches compresses
+    /// worse once rewritten; the ranges filtered go in `bcj_ranges`. Other
+    /// binaries are filtered whole. The time each filter took goes in
+    /// `bcj_times`. `streamed` binaries are left as they are, to be
+    /// filtered as they compress.
+    fn apply_bcj<'a>(
+        &self,
+        binaries: &'a [(String, impl AsRef<[u8]>)],
+        streamed: &[String],
+        bcj_ranges: &mut BcjRanges,
+        bcj_times: &mut BcjTimes,
+        stats: &mut CompressionStats,
+    ) -> Result<Vec<(String, Cow<'a, [u8]>)>> {
+        let mut processed = Vec::with_capacity(binaries.len());
+        for (target, data) in binaries {
+            let Some(arch) = self.bcj_filter(target) else {
+                processed.push((target.clone(), Cow::Borrowed(data.as_ref())));
+                continue;
+            };
+            if streamed.contains(target) {
+                stats.bcj_filtered += 1;
+                stats.bcj_filters.push((target.clone(), arch));
+                processed.push((target.clone(), Cow::Borrowed(data.as_ref())));
+                continue;
+            }
+            let started = Instant::now();
+            let mut data = data.as_ref().to_vec();
+            let ranges = self
+                .stage(Some(target), Stage::Bcj, || {
+                    let ranges = segment::executable_ranges(&data);
+                    match &ranges {
+                        Some(ranges) => bcj::bcj_encode_ranges(&mut data, arch, ranges),
+                        None => BcjFilter::new(arch).encode(&mut data),
+                    }
+                    .map(|()| ranges)
+                })
+                .map_err(|e| CompressionError::Filter {
+                    target: target.clone(),
+                    source: Box::new(e),
+                })?;
+            if let Some(ranges) = ranges {
+                bcj_ranges.insert(target.clone(), ranges);
+            }
+            #[cfg(test)]
+            if let Some(break_filter) = self.break_filter {
+                break_filter(&mut data);
+            }
+            stats.bcj_filtered += 1;
+            stats.bcj_filters.push((target.clone(), arch));
+            let time = started.elapsed();
+            stats.stage_times.bcj += time;
+            bcj_times.insert(target.clone(), time);
+            processed.push((target.clone(), Cow::Owned(data)));
+        }
+        Ok(processed)
+    }
+
+    /// Compress binaries given as `(target, reader, len)`, writing each
+    /// compressed entry to `output` in turn.
+    ///
+    /// Unlike [`compress_all`](Self::compress_all), no binary is ever held
+    /// in memory whole: each is read, BCJ-filtered and compressed a few KiB
+    /// at a time, so memory use stays at the zstd window (plus a job buffer
+    /// per worker) whatever the binaries' sizes. Delta compression and
+    /// dictionaries need whole binaries, so this skips them, and binaries
+    /// are compressed one after another whatever [`with_jobs`](Self::with_jobs)
+    /// says. BCJ filters cover whole binaries, whose sections aren't known
+    /// until they've been read. Only zstd and [`Compression::None`] stream. Entries are written
+    /// as they compress, so none falls back to being stored raw.
+    pub fn compress_streaming<W: Write>(
+        &self,
+        inputs: Vec<(String, Box<dyn Read>, u64)>,
+        output: &mut W,
+    ) -> Result<StreamingResult> {
+        self.check_settings()?;
+        if !matches!(self.algorithm, Compression::None | Compression::Zstd) {
+            return Err(CompressionError::InvalidSetting(format!(
+                "{} can't compress streamed input",
+                self.algorithm
+            )));
+        }
+
+        let mut stats = CompressionStats::default();
+        let mut entries = Vec::new();
+        for (target, reader, len) in inputs {
+            let started = Instant::now();
+            let entry = self
+                .stage(Some(&target), Stage::Compression, || {
+                    self.compress_stream(&target, reader, len, &mut *output)
+                })
+                .map_err(|e| CompressionError::Compress {
+                    target: target.clone(),
+                    source: Box::new(e),
+                })?;
+            let time = started.elapsed();
+            stats.compress_times.push((target, time));
+            stats.stage_times.compression += time;
+            stats.entries.push(EntryStats {
+                target: entry.target.clone(),
+                original_size: entry.original_size as usize,
+                compressed_size: entry.compressed_size as usize,
+                compression: entry.compression,
+                bcj: entry.bcj,
+                delta_reference: None,
+                dictionary: None,
+                times: EntryTimes {
+                    compress: time,
+                    ..Default::default()
+                },
+            });
+            stats.original_size += entry.original_size as usize;
+            stats.compressed_size += entry.compressed_size as usize;
+            if let Some(arch) = entry.bcj {
+                stats.bcj_filtered += 1;
+                stats.bcj_filters.push((entry.target.clone(), arch));
+            }
+            entries.push(entry);
+        }
+        Ok(StreamingResult { entries, stats })
+    }
+
+    /// Compress the `len` bytes `reader` yields for `target` to `output`.
+    fn compress_stream<W: Write>(
+        &self,
+        target: &str,
+        reader: Box<dyn Read>,
+        len: u64,
+        output: W,
+    ) -> Result<StreamedEntry> {
+        let bcj = self.bcj_filter(target);
+        let output = HashingWriter::new(output);
+        let coder = match self.algorithm {
+            Compression::Zstd => StreamCoder::Zstd(dict::stream_encoder(
+                output,
+                None,
+                self.zstd_level(),
+                &self.zstd_params(),
+                len,
+            )?),
+            _ => StreamCoder::Raw(output),
+        };
+
+        let mut writer = BcjWriter::new(bcj.unwrap_or(BcjArch::None), coder);
+        // Reads one byte past `len`, to tell a longer input from an exact one.
+        let mut reader = ProgressReader {
+            inner: reader.take(len.saturating_add(1)),
+            pipeline: self,
+            target,
+            done: 0,
+            reported: 0,
+            total: len,
+        };
+        let read = io::copy(&mut reader, &mut writer)?;
+        if read != len {
+            return Err(CompressionError::InvalidData(format!(
+                "Read {} bytes, expected {}",
+                if read > len {
+                    format!("over {}", len)
+                } else {
+                    read.to_string()
+                },
+                len
+            )));
+        }
+        let output = writer.finish()?.finish()?;
+        self.report(ProgressEvent::BytesProcessed {
+            target,
+            done: len,
+            total: len,
+        });
+
+        Ok(StreamedEntry {
+            target: target.to_string(),
+            original_size: len,
+            compressed_size: output.written,
+            compressed_checksum: Checksum::from(output.hasher.finalize()),
+            bcj,
+            compression: self.algorithm,
+            window_log: self.window_log(),
+        })
+    }
+
+    /// Runs `job` on each item on up to [`with_jobs`](Self::with_jobs)
+    /// threads, returning the results in item order.
+    fn run_jobs<T: Sync, R: Send>(&self, items: &[T], job: impl Fn(&T) -> R + Sync) -> Vec<R> {
+        self.run_jobs_on(self.jobs, items, job)
+    }
+
+    /// Runs `job` on each item like [`run_jobs`](Self::run_jobs), on up to
+    /// `threads` threads.
+    fn run_jobs_on<T: Sync, R: Send>(
+        &self,
+        threads: usize,
+        items: &[T],
+        job: impl Fn(&T) -> R + Sync,
+    ) -> Vec<R> {
+        let threads = threads.min(items.len());
+        if threads <= 1 {
+            return items.iter().map(job).collect();
+        }
+
+        let next = AtomicUsize::new(0);
+        let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(item) = items.get(index) else {
+                                return done;
+                            };
+                            done.push((index, job(item)));
+                        }
+                    })
+                })
+                .collect();
+            for worker in workers {
+                let done = worker
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                for (index, result) in done {
+                    results[index] = Some(result);
+                }
+            }
+        });
+        results
+            .into_iter()
+            .map(|result| result.expect("every job ran"))
+            .collect()
+    }
+
+    /// Train one dictionary per architecture group.
+    ///
+    /// Groups with too few binaries to train on (or whose training fails) are
+    /// pooled into one shared dictionary, so small mixed-architecture sets
+    /// still get one. When even the pool is too small, as when packing two
+    /// or three binaries, the shared dictionary is trained on pieces of
+    /// them. Each dictionary is kept only if it saves enough. A supplied
+    /// dictionary that helps replaces them all.
+    fn train_dictionaries(
+        &mut self,
+        binaries: &[(String, Cow<'_, [u8]>)],
+        stats: &mut CompressionStats,
+    ) {
+        if let Some(dict) = self.supplied_dictionary.clone() {
+            let members: Vec<usize> = (0..binaries.len()).collect();
+            if self.dictionary_helps(&dict.data, &members, binaries) {
+                stats.supplied_dictionary = Some(dict.id());
+                self.assign_dictionary(SUPPLIED_DICT_GROUP, &members, binaries, dict);
+                return;
+            }
+            stats
+                .stale_dictionaries
+                .push(SUPPLIED_DICT_GROUP.to_string());
+        }
+
+        let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+        for (i, (target, _)) in binaries.iter().enumerate() {
+            let arch = delta::arch_group(target);
+            match groups.iter_mut().find(|(group, _)| *group == arch) {
+                Some((_, members)) => members.push(i),
+                None => groups.push((arch, vec![i])),
+            }
+        }
+
+        let mut shared = Vec::new();
+        // Whether each group pooled had already been trained on alone.
+        let mut pooled_tried = Vec::new();
+        for (arch, members) in groups {
+            let tried = members.len() >= self.dict_min_samples;
+            if !(tried && self.train_group(arch, &members, binaries, false, stats)) {
+                shared.extend(members);
+                pooled_tried.push(tried);
+            }
+        }
+        if pooled_tried == [true] {
+            // Training the pool would only repeat its one group's failure.
+            return;
+        }
+        if shared.len() >= self.dict_min_samples {
+            self.train_group("shared", &shared, binaries, false, stats);
+        } else if shared.len() >= 2 {
+            // A lone binary has nothing to share through a dictionary.
+            self.train_group("shared", &shared, binaries, true, stats);
+        }
+    }
+
+    /// Train a dictionary on `members`, or on pieces of them if `pieces`,
+    /// and assign it to their targets. A saved dictionary for `group` is
+    /// reused instead unless it's stale. Returns false if training failed,
+    /// or the dictionary didn't save enough (see
+    /// [`with_dict_margin`](Self::with_dict_margin)).
+    fn train_group(
+        &mut self,
+        group: &str,
+        members: &[usize],
+        binaries: &[(String, Cow<'_, [u8]>)],
+        pieces: bool,
+        stats: &mut CompressionStats,
+    ) -> bool {
+        let saved = self.saved_dictionaries.iter().find(|(g, _)| g == group);
+        if let Some((_, dict)) = saved {
+            if self.dictionary_helps(&dict.data, members, binaries) {
+                let dict = dict.clone();
+                self.assign_dictionary(group, members, binaries, dict);
+                stats.reused_dictionaries.push(group.to_string());
+                return true;
+            }
+            stats.stale_dictionaries.push(group.to_string());
+        }
+
+        let mut samples: Vec<&[u8]> = if pieces {
+            members
+                .iter()
+                .flat_map(|&i| binaries[i].1.chunks(self.dict_sample_size))
+                .collect()
+        } else {
+            members.iter().map(|&i| binaries[i].1.as_ref()).collect()
+        };
+        if let Some(budget) = self.memory_budget {
+            let total: usize = samples.iter().map(|s| s.len()).sum();
+            let allowed = budget.saturating_sub(DICT_TRAINING_OVERHEAD);
+            if total > allowed {
+                // Train on pieces spread evenly over the binaries.
+                let pieces: Vec<&[u8]> = samples
+                    .iter()
+                    .flat_map(|s| s.chunks(self.dict_sample_size))
+                    .collect();
+                let keep = allowed / self.dict_sample_size.max(1);
+                if keep < dict::MIN_SAMPLES {
+                    stats.degradations.push(Degradation::DictSkipped {
+                        group: group.to_string(),
+                    });
+                    return false;
+                }
+                let step = pieces.len().div_ceil(keep);
+                samples = pieces.into_iter().step_by(step).collect();
+                stats.degradations.push(Degradation::DictSampled {
+                    group: group.to_string(),
+                    sampled: samples.iter().map(|s| s.len()).sum(),
+                    total,
+                });
+            }
+        }
+        let Ok(dict) = TrainedDictionary::train_with(&samples, &self.dict_training) else {
+            return false;
+        };
+
+        // Training succeeds even when there's little to share, as with few
+        // large binaries, so the dictionary has to pay for itself.
+        let Some((with, without)) = self.probe_dictionary(&dict.data, members, binaries) else {
+            return false;
+        };
+        let decision = DictDecision {
+            group: group.to_string(),
+            dict_size: dict.data.len(),
+            with_dict: with + dict.data.len(),
+            without_dict: without,
+            kept: ((with + dict.data.len()) as f64) < without as f64 * (1.0 - self.dict_margin),
+        };
+        let kept = decision.kept;
+        stats.dict_decisions.push(decision);
+        if kept {
+            self.assign_dictionary(group, members, binaries, dict);
+        }
+        kept
+    }
+
+    /// Add `dict` as `group`'s dictionary and assign it to `members`.
+    fn assign_dictionary(
+        &mut self,
+        group: &str,
+        members: &[usize],
+        binaries: &[(String, Cow<'_, [u8]>)],
+        dict: TrainedDictionary,
+    ) {
+        let index = self.dictionaries.len();
+        for &i in members {
+            self.dict_assignments.insert(binaries[i].0.clone(), index);
+        }
+        self.dictionaries.push((group.to_string(), dict));
+    }
+
+    /// Returns true if `members` compress at least [`STALE_DICT_MARGIN`]
+    /// smaller with `dictionary`, not counting its own size, at
+    /// [`DELTA_PROBE_LEVEL`].
+    fn dictionary_helps(
+        &self,
+        dictionary: &[u8],
+        members: &[usize],
+        binaries: &[(String, Cow<'_, [u8]>)],
+    ) -> bool {
+        let sizes = self.probe_dictionary(dictionary, members, binaries);
+        sizes.is_some_and(|(with, without)| {
+            (with as f64) < without as f64 * (1.0 - STALE_DICT_MARGIN)
+        })
+    }
+
+    /// Returns the total size of `members` compressed with `dictionary` at
+    /// [`DELTA_PROBE_LEVEL`], and without it, or None if compressing failed.
+    fn probe_dictionary(
+        &self,
+        dictionary: &[u8],
+        members: &[usize],
+        binaries: &[(String, Cow<'_, [u8]>)],
+    ) -> Option<(usize, usize)> {
+        let sizes = self.run_jobs(members, |&i| {
+            let data = &binaries[i].1;
+            let with = dict::compress_with_dict(data, dictionary, DELTA_PROBE_LEVEL)?;
+            let without = dict::compress(data, DELTA_PROBE_LEVEL)?;
+            Ok((with.len(), without.len()))
+        });
+        let sizes: Result<Vec<(usize, usize)>> = sizes.into_iter().collect();
+        sizes.ok().map(|sizes| {
+            sizes
+                .iter()
+                .fold((0, 0), |(with, without), (w, wo)| (with + w, without + wo))
+        })
+    }
+
+    /// Index of the dictionary `compress_single` uses for `target`, if any.
+    fn dict_index(&self, target: &str) -> Option<usize> {
+        self.dict_assignments.get(target).copied()
+    }
+
+    /// Compress `data` on its own, without a dictionary, with the
+    /// pipeline's algorithm and level. For data that skips the other
+    /// stages, such as assets. LZ4 has no levels, so it ignores the level.
+    /// Callers decide with [`stores_raw`](Self::stores_raw) whether to keep
+    /// the result.
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.check_settings()?;
+        match self.algorithm {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => self.compress_zstd(data, None),
+            Compression::Lz4 => lz4::compress(data),
+            Compression::Xz => xz::compress(data, self.level.xz_preset()),
+            Compression::Brotli => {
+                brotli::compress(data, self.level.brotli_quality(), self.brotli_window)
+            }
+        }
+    }
+
+    /// Compress with zstd at the level and parameters in effect.
+    fn compress_zstd(&self, data: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<u8>> {
+        dict::compress_with_params(data, dictionary, self.zstd_level(), &self.zstd_params())
+    }
+
+    /// Compress `target`'s binary, as a delta patch against `reference`'s
+    /// if that comes out smaller than compressing it directly. Also returns
+    /// whether a quick probe settled the comparison, so only the winner was
+    /// compressed at the final level (see
+    /// [`with_delta_probe_margin`](Self::with_delta_probe_margin)).
+    fn compress_target(
+        &self,
+        target: &str,
+        reference: Option<&str>,
+        binaries: &HashMap<&str, &[u8]>,
+        bcj_ranges: &BcjRanges,
+    ) -> Result<(CompressedEntry, bool, EntryTimes)> {
+        let data = binaries
+            .get(target)
+            .ok_or_else(|| CompressionError::InvalidData(format!("Missing binary {}", target)))?;
+        let entry = |stored, delta_reference: Option<&str>| CompressedEntry {
+            target: target.to_string(),
+            data: stored,
+            bcj: self.bcj_filter(target),
+            bcj_ranges: bcj_ranges.get(target).cloned(),
+            delta_reference: delta_reference.map(str::to_string),
+            dict_index: self.dict_index(target),
+            original_size: data.len(),
+            compression: self.algorithm,
+            window_log: self.window_log(),
+        };
+
+        // Data compression can't shrink is stored as is. It keeps its BCJ
+        // filter, since delta targets are patched against the filtered bytes.
+        let raw = || CompressedEntry {
+            data: data.to_vec(),
+            delta_reference: None,
+            dict_index: None,
+            compression: Compression::None,
+            window_log: None,
+            ..entry(Vec::new(), None)
+        };
+        let choose = |stored: Vec<u8>, delta_reference| {
+            if self.stores_raw(data.len(), stored.len()) {
+                raw()
+            } else {
+                entry(stored, delta_reference)
+            }
+        };
+
+        let mut times = EntryTimes::default();
+        let Some(reference) = reference else {
+            return Ok((
+                choose(self.compress_single(target, data)?, None),
+                false,
+                times,
+            ));
+        };
+        let ref_data = binaries.get(reference).ok_or_else(|| {
+            CompressionError::InvalidData(format!("Missing reference binary {}", reference))
+        })?;
+        let create_patch = || {
+            timed(|| {
+                delta::create_patch(ref_data, data).map_err(|e| CompressionError::DeltaPatch {
+                    target: target.to_string(),
+                    reference: reference.to_string(),
+                    source: Box::new(e),
+                })
+            })
+        };
+        let probe =
+            |data: &[u8]| timed(|| dict::compress(data, DELTA_PROBE_LEVEL).map(|c| c.len()));
+
+        // Settle the comparison with a quick probe when the final level is
+        // slower, so only the winner is compressed at it. The direct probe
+        // runs alongside the patch, which it doesn't need.
+        let patch = if self.probes_delta() {
+            let ((patch, patch_time), (direct_probe, direct_time)) =
+                self.join(create_patch, || probe(data));
+            let patch = patch?;
+            let (patch_probe, probe_time) = probe(&patch);
+            let (patch_probe, direct_probe) = (patch_probe?, direct_probe?);
+            times.delta_patch = patch_time;
+            times.delta_probe = direct_time + probe_time;
+            let decisive = 1.0 - self.delta_probe_margin;
+            if (patch_probe as f64) < direct_probe as f64 * decisive {
+                let compressed = self.compress_single(target, &patch)?;
+                return Ok((choose(compressed, Some(reference)), true, times));
+            }
+            if (direct_probe as f64) < patch_probe as f64 * decisive {
+                let compressed = self.compress_single(target, data)?;
+                return Ok((choose(compressed, None), true, times));
+            }
+            Some(patch)
+        } else {
+            None
+        };
+
+        // Only use delta if it's smaller than direct compression. With
+        // jobs to spare, the direct compression runs alongside the patch.
+        let ((compressed_patch, patch_time), direct_compressed) = self.join(
+            // The patch is dropped as soon as it's compressed.
+            || match patch {
+                Some(patch) => (self.compress_single(target, &patch), None),
+                None => {
+                    let (patch, time) = create_patch();
+                    (
+                        patch.and_then(|p| self.compress_single(target, &p)),
+                        Some(time),
+                    )
+                }
+            },
+            || self.compress_single(target, data),
+        );
+        let (compressed_patch, direct_compressed) = (compressed_patch?, direct_compressed?);
+        if let Some(time) = patch_time {
+            times.delta_patch = time;
+        }
+
+        if compressed_patch.len() < direct_compressed.len() {
+            Ok((choose(compressed_patch, Some(reference)), false, times))
+        } else {
+            Ok((choose(direct_compressed, None), false, times))
+        }
+    }
+
+    /// Returns true if delta comparisons are probed at
+    /// [`DELTA_PROBE_LEVEL`] first: when the final compression is slower,
+    /// and a probe can ever be decisive.
+    fn probes_delta(&self) -> bool {
+        let slower = match self.algorithm {
+            Compression::Zstd => self.zstd_level() > DELTA_PROBE_LEVEL,
+            Compression::None => false,
+            Compression::Lz4 | Compression::Xz | Compression::Brotli => true,
+        };
+        slower && self.delta_probe_margin < 1.0
+    }
+
+    /// Compress `data`, a binary left unfiltered to fit the memory budget,
+    /// BCJ-filtering it as it streams into a zstd encoder.
+    fn compress_streamed(&self, target: &str, data: &[u8]) -> Result<CompressedEntry> {
+        let bcj = self.bcj_filter(target);
+        let compressed = (|| {
+            let encoder = dict::stream_encoder(
+                Vec::new(),
+                None,
+                self.zstd_level(),
+                &self.zstd_params(),
+                data.len() as u64,
+            )?;
+            let mut writer =
+                BcjWriter::new(bcj.unwrap_or(BcjArch::None), StreamCoder::Zstd(encoder));
+            writer.write_all(data)?;
+            Ok(writer.finish()?.finish()?)
+        })()
+        .map_err(|e: CompressionError| CompressionError::Compress {
+            target: target.to_string(),
+            source: Box::new(e),
+        })?;
+        let entry = CompressedEntry {
+            target: target.to_string(),
+            data: compressed,
+            bcj,
+            bcj_ranges: None,
+            delta_reference: None,
+            dict_index: None,
+            original_size: data.len(),
+            compression: self.algorithm,
+            window_log: self.window_log(),
+        };
+        if !self.stores_raw(data.len(), entry.data.len()) {
+            return Ok(entry);
+        }
+        // Raw entries are stored filtered, like any other.
+        let mut filtered = data.to_vec();
+        if let Some(arch) = bcj {
+            bcj::bcj_encode(&mut filtered, arch)?;
+        }
+        Ok(CompressedEntry {
+            data: filtered,
+            compression: Compression::None,
+            window_log: None,
+            ..entry
+        })
+    }
+
+    /// Runs `a` and `b`, on two threads when there are jobs to spare and no
+    /// memory budget.
+    fn join<A: Send, B: Send>(
+        &self,
+        a: impl FnOnce() -> A + Send,
+        b: impl FnOnce() -> B + Send,
+    ) -> (A, B) {
+        if self.jobs > 1 && self.memory_budget.is_none() {
+            std::thread::scope(|scope| {
+                let b = scope.spawn(b);
+                let a = a();
+                (
+                    a,
+                    b.join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+                )
+            })
+        } else {
+            (a(), b())
+        }
+    }
+
+    /// Compress a single binary (or patch) for `target`.
+    fn compress_single(&self, target: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = if let Some(index) = self.dict_index(target) {
+            self.compress_zstd(data, Some(&self.dictionaries[index].1.data))
+        } else {
+            self.compress(data)
+        };
+        compressed.map_err(|e| CompressionError::Compress {
+            target: target.to_string(),
+            source: Box::new(e),
+        })
+    }
+}
+
+/// Result of compression pipeline.
+#[derive(Debug)]
+pub struct CompressionResult {
+    /// Compressed entries.
+    pub entries: Vec<CompressedEntry>,
+    /// Trained dictionaries, labelled by architecture group (or "shared",
+    /// or [`SUPPLIED_DICT_GROUP`] for a supplied one), indexed by `CompressedEntry::dict_index`.
+    pub dictionaries: Vec<(String, Vec<u8>)>,
+    /// Delta groups binaries were compressed in, with the reference each
+    /// group's targets were patched against if that beat compressing them
+    /// directly. Every binary is its own group without delta compression.
+    pub delta_groups: Vec<DeltaGroup>,
+    /// Compression statistics.
+    pub stats: CompressionStats,
+}
+
+/// A binary [`CompressionPipeline::compress_chunked`] split into chunks.
+#[derive(Debug, Clone)]
+pub struct ChunkedEntry {
+    /// Target key the binary was given with.
+    pub target: String,
+    /// Indices into the chunk store of the chunks making up the binary,
+    /// after any BCJ filter.
+    pub chunks: Vec<u32>,
+    /// BCJ filter applied, if any.
+    pub bcj: Option<BcjArch>,
+    /// Ranges of the binary the BCJ filter covered, when it was applied
+    /// only to its executable sections rather than the whole binary.
+    pub bcj_ranges: Option<Vec<Range<usize>>>,
+    /// Size of the original binary.
+    pub original_size: usize,
+}
+
+impl ChunkedEntry {
+    /// BCJ filter that must be reversed after reassembly, if any.
+    pub fn bcj_arch(&self) -> Option<BcjArch> {
+        self.bcj
+    }
+}
+
+/// Result of chunked compression.
+#[derive(Debug)]
+pub struct ChunkedResult {
+    /// Chunk store as stored: every distinct chunk, concatenated and
+    /// compressed.
+    pub store: Vec<u8>,
+    /// Size of the chunk store before compression.
+    pub store_size: usize,
+    /// BLAKE3 of the chunk store before compression.
+    pub store_checksum: Checksum,
+    /// Algorithm the chunk store was compressed with.
+    pub compression: Compression,
+    /// Zstd window log the chunk store was compressed with, if one was set.
+    pub window_log: Option<u32>,
+    /// Size of each chunk in the store.
+    pub chunk_sizes: Vec<u32>,
+    /// Entries, in the order the binaries were given.
+    pub entries: Vec<ChunkedEntry>,
+    /// Compression statistics.
+    pub stats: CompressionStats,
+}
+
+/// A binary [`CompressionPipeline::compress_streaming`] wrote.
+#[derive(Debug, Clone)]
+pub struct StreamedEntry {
+    /// Target key the binary was given with.
+    pub target: String,
+    /// Size of the original binary.
+    pub original_size: u64,
+    /// Bytes written to the output.
+    pub compressed_size: u64,
+    /// BLAKE3 of the bytes written.
+    pub compressed_checksum: Checksum,
+    /// BCJ filter applied, if any.
+    pub bcj: Option<BcjArch>,
+    /// Algorithm the data was compressed with.
+    pub compression: Compression,
+    /// Zstd window log the data was compressed with, if one was set.
+    pub window_log: Option<u32>,
+}
+
+impl StreamedEntry {
+    /// BCJ filter that must be reversed after decompression, if any.
+    pub fn bcj_arch(&self) -> Option<BcjArch> {
+        self.bcj
+    }
+}
+
+/// Result of streaming compression.
+#[derive(Debug)]
+pub struct StreamingResult {
+    /// Entries in the order they were written.
+    pub entries: Vec<StreamedEntry>,
+    /// Compression statistics.
+    pub stats: CompressionStats,
+}
+
+/// Reader reporting how much of a streamed binary has been read, every
+/// [`PROGRESS_INTERVAL`] bytes.
+struct ProgressReader<'a, R> {
+    inner: R,
+    pipeline: &'a CompressionPipeline,
+    target: &'a str,
+    /// Bytes read so far.
+    done: u64,
+    /// Bytes read when progress was last reported.
+    reported: u64,
+    /// Length of the binary.
+    total: u64,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        if self.done - self.reported >= PROGRESS_INTERVAL && self.done < self.total {
+            self.reported = self.done;
+            self.pipeline.report(ProgressEvent::BytesProcessed {
+                target: self.target,
+                done: self.done,
+                total: self.total,
+            });
+        }
+        Ok(n)
+    }
+}
+
+/// Final coder for streamed entries.
+enum StreamCoder<W: Write> {
+    Raw(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> StreamCoder<W> {
+    /// Ends the compressed data and returns the output.
+    fn finish(self) -> io::Result<W> {
+        match self {
+            StreamCoder::Raw(output) => Ok(output),
+            StreamCoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for StreamCoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StreamCoder::Raw(output) => output.write(buf),
+            StreamCoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StreamCoder::Raw(output) => output.flush(),
+            StreamCoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writer that counts and hashes what passes through it.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    pub(crate) hasher: blake3::Hasher,
+    pub(crate) written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self::with_hasher(inner, blake3::Hasher::new())
+    }
+
+    /// Hashes with `hasher`, such as a keyed one.
+    pub(crate) fn with_hasher(inner: W, hasher: blake3::Hasher) -> Self {
+        Self {
+            inner,
+            hasher,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compression statistics.
+#[derive(Debug, Default, Serialize)]
+pub struct CompressionStats {
+    /// Total original size.
+    pub original_size: usize,
+    /// Total compressed size (including dictionary).
+    pub compressed_size: usize,
+    /// Number of binaries with BCJ filter applied.
+    pub bcj_filtered: usize,
+    /// Filter applied to each BCJ-filtered binary, by target, in input
+    /// order.
+    pub bcj_filters: Vec<(String, BcjArch)>,
+    /// Number of binaries using delta compression.
+    pub delta_used: usize,
+    /// Number of binaries stored raw because compression saved too little.
+    pub stored_raw: usize,
+    /// Number of delta targets whose probe settled the comparison, so
+    /// either the patch or the binary skipped compression at the final
+    /// level.
+    pub delta_comparisons_skipped: usize,
+    /// Whether dictionary was trained.
+    pub dict_trained: bool,
+    /// Largest dictionary training aimed for (see
+    /// [`CompressionPipeline::with_dict_size`]), when it ran. Each
+    /// dictionary's actual size is in `dictionaries`.
+    pub dict_size_limit: Option<usize>,
+    /// Whether each dictionary trained was kept, with the sizes that
+    /// decided it, in training order.
+    pub dict_decisions: Vec<DictDecision>,
+    /// What each trained dictionary did, in the order of
+    /// `CompressionResult::dictionaries`.
+    pub dictionaries: Vec<DictionaryStats>,
+    /// ID of the dictionary given with
+    /// [`CompressionPipeline::with_dictionary`], when binaries were
+    /// compressed with it.
+    pub supplied_dictionary: Option<String>,
+    /// Groups whose saved dictionary was reused instead of trained.
+    pub reused_dictionaries: Vec<String>,
+    /// Groups whose saved dictionary had gone stale and was trained again,
+    /// and [`SUPPLIED_DICT_GROUP`] if the supplied dictionary didn't help.
+    pub stale_dictionaries: Vec<String>,
+    /// Time spent compressing each entry, by target, in the order entries
+    /// were compressed. A delta target's time covers both the patch and the
+    /// direct compression it was compared against.
+    pub compress_times: Vec<(String, Duration)>,
+    /// Time spent checking every entry reverses to its binary, if entries
+    /// were verified.
+    pub verify_time: Option<Duration>,
+    /// Sizes, method and stage timings of each entry, in entry order.
+    /// Chunk stores don't compress entries one by one, so they leave this
+    /// empty.
+    pub entries: Vec<EntryStats>,
+    /// Time spent in each stage, over all entries.
+    pub stage_times: StageTimes,
+    /// What compression gave up to fit its memory budget (see
+    /// [`CompressionPipeline::with_memory_budget`]), in the order it did.
+    pub degradations: Vec<Degradation>,
+}
+
+impl CompressionStats {
+    /// Calculate compression ratio.
+    pub fn ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            0.0
+        } else {
+            self.compressed_size as f64 / self.original_size as f64
+        }
+    }
+
+    /// Calculate space savings percentage.
+    pub fn savings_percent(&self) -> f64 {
+        (1.0 - self.ratio()) * 100.0
+    }
+}
+
+/// Whether a freshly trained dictionary was kept (see
+/// [`CompressionPipeline::with_dict_margin`]), from its group's binaries
+/// compressed with and without it at [`DELTA_PROBE_LEVEL`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DictDecision {
+    /// Architecture group, or "shared", the dictionary was trained for.
+    pub group: String,
+    /// Size of the dictionary.
+    pub dict_size: usize,
+    /// Total size of the group's binaries compressed with the dictionary,
+    /// plus the dictionary.
+    pub with_dict: usize,
+    /// Total size of the group's binaries compressed without it.
+    pub without_dict: usize,
+    /// Whether the dictionary was kept.
+    pub kept: bool,
+}
+
+impl DictDecision {
+    /// Bytes the dictionary saves, counting itself; negative if it costs
+    /// more than it saves.
+    pub fn savings(&self) -> i64 {
+        self.without_dict as i64 - self.with_dict as i64
+    }
+}
+
+/// Something [`CompressionPipeline::with_memory_budget`] gave up to stay
+/// within its budget.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Degradation {
+    /// A group's dictionary was trained on `sampled` of the `total` bytes
+    /// of its binaries.
+    DictSampled {
+        group: String,
+        sampled: usize,
+        total: usize,
+    },
+    /// A group's dictionary wasn't trained, as too few samples fit.
+    DictSkipped { group: String },
+    /// A delta target was compressed directly, as patching it against
+    /// `reference` would have needed more than the budget.
+    DeltaSkipped { target: String, reference: String },
+    /// Only `jobs` entries were compressed at once.
+    Jobs { jobs: usize },
+    /// A binary was BCJ-filtered as it streamed into the encoder, whole
+    /// and without delta compression or a dictionary.
+    Streamed { target: String },
+}
+
+impl std::fmt::Display for Degradation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Degradation::DictSampled {
+                group,
+                sampled,
+                total,
+            } => write!(
+                f,
+                "{} dictionary trained on {} of {} bytes",
+                group, sampled, total
+            ),
+            Degradation::DictSkipped { group } => {
+                write!(f, "{} dictionary not trained", group)
+            }
+            Degradation::DeltaSkipped { target, reference } => {
+                write!(f, "{} not patched against {}", target, reference)
+            }
+            Degradation::Jobs { jobs } => write!(f, "{} jobs at once", jobs),
+            Degradation::Streamed { target } => write!(f, "{} streamed", target),
+        }
+    }
+}
+
+/// Sizes of one trained dictionary and the entries compressed with it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DictionaryStats {
+    /// Architecture group the dictionary was trained on, or "shared" for
+    /// the one pooling groups too small for their own.
+    pub group: String,
+    /// Size of the dictionary.
+    pub dict_size: usize,
+    /// Number of entries compressed with the dictionary.
+    pub entries: usize,
+    /// Total original size of those entries.
+    pub original_size: usize,
+    /// Total compressed size of those entries, plus the dictionary.
+    pub compressed_size: usize,
+}
+
+impl DictionaryStats {
+    /// Compression ratio of the dictionary's entries, counting the
+    /// dictionary itself.
+    pub fn ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            0.0
+        } else {
+            self.compressed_size as f64 / self.original_size as f64
+        }
+    }
+}
+
+/// Sizes, method and stage timings of one compressed entry.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EntryStats {
+    /// Target platform, or entry key ("program/target").
+    pub target: String,
+    /// Original uncompressed size.
+    pub original_size: usize,
+    /// Size of the entry's compressed data, not counting its dictionary.
+    pub compressed_size: usize,
+    /// Algorithm the entry was compressed with; [`Compression::None`] if
+    /// it was stored raw.
+    pub compression: Compression,
+    /// BCJ filter applied, if any.
+    pub bcj: Option<BcjArch>,
+    /// Reference target the entry is a delta against, if any.
+    pub delta_reference: Option<String>,
+    /// Group of the dictionary the entry was compressed with, if any.
+    pub dictionary: Option<String>,
+    /// Time the entry spent in each stage.
+    pub times: EntryTimes,
+}
+
+impl EntryStats {
+    /// Describes how the entry was stored, such as "zstd+delta+dict" or
+    /// "none" for an entry stored raw.
+    pub fn method(&self) -> String {
+        let mut method = self.compression.to_string();
+        if self.delta_reference.is_some() {
+            method.push_str("+delta");
+        }
+        if self.dictionary.is_some() {
+            method.push_str("+dict");
+        }
+        method
+    }
+
+    /// Calculate compression ratio.
+    pub fn ratio(&self) -> f64 {
+        if self.original_size == 0 {
+            0.0
+        } else {
+            self.compressed_size as f64 / self.original_size as f64
+        }
+    }
+}
+
+/// Time one entry spent in each stage of compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct EntryTimes {
+    /// Applying its BCJ filter. Streamed entries are filtered as they
+    /// compress, so theirs is part of `compress`.
+    pub bcj: Duration,
+    /// Creating its delta patch against its reference, if it has one.
+    pub delta_patch: Duration,
+    /// Compressing its patch and itself at [`DELTA_PROBE_LEVEL`] to settle
+    /// the delta comparison, if it was probed.
+    pub delta_probe: Duration,
+    /// Its whole compression job, patch and probes included. With jobs to
+    /// spare those run alongside the direct compression, so this can be
+    /// less than the stages added up.
+    pub compress: Duration,
+}
+
+/// Time spent in each stage of compression, over all entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct StageTimes {
+    /// Applying BCJ filters.
+    pub bcj: Duration,
+    /// Training dictionaries, and probing whether they pay.
+    pub dict_training: Duration,
+    /// Grouping binaries by similarity and choosing delta references.
+    pub delta_grouping: Duration,
+    /// Compressing every entry. Entries compress on up to
+    /// [`CompressionPipeline::with_jobs`] threads, so this is wall-clock
+    /// time, not the entries' times added up.
+    pub compression: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pbin_core::{Arch, Target};
+
+    fn make_binary(target: &str, seed: u8) -> (String, Vec<u8>) {
+        let mut data = Vec::with_capacity(4096);
+
+        // Simulate ELF header
+        data.extend_from_slice(b"\x7FELF\x02\x01\x01\x00");
+        data.extend_from_slice(&[0; 8]);
+
+        // Add some x86-like instructions with CALL patterns
+        for i in 0..500 {
+            if i % 20 == 0 {
+                // CALL instruction pattern
+                data.push(0xE8);
+                data.extend_from_slice(&[(i as u8).wrapping_add(seed), 0x00, 0x00, 0x00]);
+            } else {
+                data.push((i as u8).wrapping_mul(seed.wrapping_add(1)));
+            }
+        }
+
+        (target.to_string(), data)
+    }
+
+    #[test]
+    fn test_compression_pipeline() {
+        let binaries = vec![
+            make_binary("linux-x86_64", 1),
+            make_binary("darwin-x86_64", 2),
+            make_binary("linux-aarch64", 3),
             make_binary("darwin-aarch64", 4),
         ];
 
-        let original_size: usize = binaries.iter().map(|(_, d)| d.len()).sum();
+        let original_size: usize = binaries.iter().map(|(_, d)| d.len()).sum();
+
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Balanced);
+        let result = pipeline.compress_all(&binaries).unwrap();
+
+        assert_eq!(result.entries.len(), 4);
+        assert!(result.stats.compressed_size < original_size);
+
+        println!("Original: {} bytes", result.stats.original_size);
+        println!("Compressed: {} bytes", result.stats.compressed_size);
+        println!("Ratio: {:.2}%", result.stats.ratio() * 100.0);
+        println!("Savings: {:.2}%", result.stats.savings_percent());
+    }
+
+    #[test]
+    fn test_verify_catches_broken_filter() {
+        let binaries = vec![
+            make_binary("linux-x86_64", 1),
+            make_binary("darwin-x86_64", 2),
+            make_binary("wasi-wasm32", 3),
+        ];
+
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_verify()
+            .compress_all(&binaries)
+            .unwrap();
+        assert!(result.stats.verify_time.is_some());
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .compress_all(&binaries)
+            .unwrap();
+        assert!(result.stats.verify_time.is_none());
+        assert!(CompressionPipeline::new(CompressionLevel::Maximum).verify);
+
+        // A filter that changes a byte its decoder doesn't restore packs
+        // without complaint unless entries are verified.
+        let broken = |pipeline: CompressionPipeline| CompressionPipeline {
+            break_filter: Some(|data| data[100] ^= 1),
+            ..pipeline
+        };
+        assert!(broken(CompressionPipeline::new(CompressionLevel::Fast))
+            .compress_all(&binaries)
+            .is_ok());
+        let err = broken(CompressionPipeline::new(CompressionLevel::Fast).with_verify())
+            .compress_all(&binaries)
+            .unwrap_err();
+        assert_eq!(err.target(), Some("linux-x86_64"));
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+    }
+
+    /// Text-like binary whose content depends on its architecture, so
+    /// binaries of one architecture share far more with each other than with
+    /// the rest. A table of noise shared by the architecture, which only a
+    /// dictionary can compress, makes dictionaries pay for themselves.
+    fn make_symbol_table(target: &str, seed: usize) -> (String, Vec<u8>) {
+        let arch = target.rsplit('-').next().unwrap();
+        let symbols: Vec<u8> = (0..256)
+            .flat_map(|i| format!("{}_symbol_{}() -> {}\n", arch, i, i * (seed + 1)).into_bytes())
+            .collect();
+        let arch_seed = arch.bytes().fold(0, |h, b| delta::mix(h ^ b as u64));
+        let table = random_bytes(12_000, arch_seed);
+        (target.to_string(), [symbols, table].concat())
+    }
+
+    #[test]
+    fn test_dictionary_per_arch() {
+        let mut binaries = Vec::new();
+        // zstd's trainer needs several samples per dictionary, so each
+        // target gets binaries for two programs.
+        for program in ["a", "b"] {
+            for target in Target::all() {
+                if matches!(target.arch(), Arch::X86_64 | Arch::Aarch64) {
+                    let key = format!("{}/{}", program, target);
+                    binaries.push(make_symbol_table(&key, binaries.len()));
+                }
+            }
+        }
+
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+        let result = pipeline.compress_all(&binaries).unwrap();
+
+        let groups: Vec<&str> = result
+            .dictionaries
+            .iter()
+            .map(|(g, _)| g.as_str())
+            .collect();
+        assert_eq!(groups, ["x86_64", "aarch64"]);
+        for (index, dict_stats) in result.stats.dictionaries.iter().enumerate() {
+            assert_eq!(dict_stats.group, groups[index]);
+            assert_eq!(dict_stats.dict_size, result.dictionaries[index].1.len());
+            let members: Vec<&CompressedEntry> = result
+                .entries
+                .iter()
+                .filter(|e| e.dict_index == Some(index))
+                .collect();
+            assert_eq!(dict_stats.entries, members.len());
+            assert_eq!(
+                dict_stats.compressed_size,
+                dict_stats.dict_size + members.iter().map(|e| e.data.len()).sum::<usize>()
+            );
+            assert!(dict_stats.ratio() < 1.0, "{:?}", dict_stats);
+        }
+        for entry in &result.entries {
+            let index = entry.dict_index.unwrap();
+            assert_eq!(delta::arch_group(&entry.target), groups[index]);
+            let dictionary = &result.dictionaries[index].1;
+            let decompressed = crate::extract::decompress_entry(
+                &entry.data,
+                pbin_core::Compression::Zstd,
+                Some(dictionary),
+                None,
+                entry.original_size as u64,
+            )
+            .unwrap();
+            assert_eq!(decompressed.len(), entry.original_size);
+        }
+    }
+
+    /// Binaries for two programs on every x86_64 and aarch64 target, each
+    /// architecture's sharing a table of its own.
+    fn arch_shared_tables() -> Vec<(String, Vec<u8>)> {
+        let mut binaries = Vec::new();
+        for program in ["a", "b"] {
+            for target in Target::all() {
+                if matches!(target.arch(), Arch::X86_64 | Arch::Aarch64) {
+                    let table = random_bytes(8_000, delta::mix(1000 + target.arch() as u64));
+                    let code = random_bytes(4_000, delta::mix(binaries.len() as u64));
+                    let key = format!("{}/{}", program, target);
+                    binaries.push((key, [code, table].concat()));
+                }
+            }
+        }
+        binaries
+    }
+
+    #[test]
+    fn test_deterministic_training() {
+        let binaries = arch_shared_tables();
+        let pack = |jobs| {
+            CompressionPipeline::new(CompressionLevel::Fast)
+                .without_delta()
+                .with_deterministic()
+                .with_jobs(jobs)
+                .compress_all(&binaries)
+                .unwrap()
+        };
+
+        let first = pack(1);
+        let second = pack(4);
+        assert_eq!(first.dictionaries.len(), 2);
+        assert_eq!(first.dictionaries, second.dictionaries);
+        for (a, b) in first.entries.iter().zip(&second.entries) {
+            assert_eq!((&a.target, &a.data), (&b.target, &b.data));
+        }
+        assert_eq!(first.entries.len(), second.entries.len());
+
+        // Pinned parameters still learn the shared strings.
+        let without = CompressionPipeline::new(CompressionLevel::Fast)
+            .without_delta()
+            .without_dict()
+            .compress_all(&binaries)
+            .unwrap();
+        assert!(
+            first.stats.compressed_size < without.stats.compressed_size,
+            "{} with dictionaries, {} without",
+            first.stats.compressed_size,
+            without.stats.compressed_size
+        );
+        assert_eq!(
+            DecompressionPipeline::new(&first).decompress_all().unwrap(),
+            binaries
+        );
+    }
+
+    #[test]
+    fn test_dict_size() {
+        let binaries = arch_shared_tables();
+        let pack = |size| {
+            CompressionPipeline::new(CompressionLevel::Fast)
+                .without_delta()
+                .with_dict_size(size)
+                .with_dict_level(3)
+                .compress_all(&binaries)
+                .unwrap()
+        };
+
+        let small = pack(8 * 1024);
+        assert_eq!(small.stats.dict_size_limit, Some(8 * 1024));
+        assert!(!small.dictionaries.is_empty());
+        assert!(small.dictionaries.iter().all(|(_, d)| d.len() <= 8 * 1024));
+
+        // Readers take dictionaries of any size, here one past the old
+        // 128 KiB cap learning a big shared table.
+        let table = random_bytes(200_000, 100);
+        let mut binaries = Vec::new();
+        for program in ["a", "b"] {
+            let targets = Target::all().iter().filter(|t| t.arch() == Arch::X86_64);
+            for target in targets.take(4) {
+                let code = random_bytes(4_000, delta::mix(101 + binaries.len() as u64));
+                let key = format!("{}/{}", program, target);
+                binaries.push((key, [code, table.clone()].concat()));
+            }
+        }
+        let large = CompressionPipeline::new(CompressionLevel::Fast)
+            .without_delta()
+            .with_dict_size(512 * 1024)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(large.stats.dict_size_limit, Some(512 * 1024));
+        assert!(
+            large.dictionaries.iter().any(|(_, d)| d.len() > 128 * 1024),
+            "{:?}",
+            large.stats.dictionaries
+        );
+        assert_eq!(
+            DecompressionPipeline::new(&large).decompress_all().unwrap(),
+            binaries
+        );
+
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .without_dict()
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.dict_size_limit, None);
+    }
+
+    #[test]
+    fn test_supplied_dictionary() {
+        let binaries = arch_shared_tables();
+        let samples: Vec<&[u8]> = binaries.iter().map(|(_, d)| d.as_slice()).collect();
+        let curated = TrainedDictionary::train(&samples, dict::DEFAULT_DICT_SIZE).unwrap();
+        let pipeline = || CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+
+        // One dictionary for every architecture, in place of training.
+        let result = pipeline()
+            .with_dictionary(curated.clone())
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(
+            result.dictionaries,
+            [(SUPPLIED_DICT_GROUP.to_string(), curated.data.clone())]
+        );
+        assert_eq!(result.stats.supplied_dictionary, Some(curated.id()));
+        assert!(result.entries.iter().all(|e| e.dict_index == Some(0)));
+        assert_eq!(
+            DecompressionPipeline::new(&result)
+                .decompress_all()
+                .unwrap(),
+            binaries
+        );
+
+        // Turning dictionaries off drops it too.
+        let result = pipeline()
+            .with_dictionary(curated)
+            .without_dict()
+            .compress_all(&binaries)
+            .unwrap();
+        assert!(result.dictionaries.is_empty());
+        assert_eq!(result.stats.supplied_dictionary, None);
+
+        // One that doesn't help falls back to training per architecture.
+        let noise = TrainedDictionary {
+            data: random_bytes(dict::DEFAULT_DICT_SIZE, 7777),
+            sample_count: 0,
+            total_sample_size: 0,
+        };
+        let result = pipeline()
+            .with_dictionary(noise)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.stale_dictionaries, [SUPPLIED_DICT_GROUP]);
+        assert_eq!(result.stats.supplied_dictionary, None);
+        let groups: Vec<&str> = result
+            .dictionaries
+            .iter()
+            .map(|(g, _)| g.as_str())
+            .collect();
+        assert_eq!(groups, ["x86_64", "aarch64"]);
+    }
+
+    #[test]
+    fn test_saved_dictionaries_reused_until_stale() {
+        let binaries = arch_shared_tables();
+        let pipeline = || CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+
+        let mut first = pipeline();
+        let trained = first.compress_all(&binaries).unwrap();
+        assert!(trained.stats.reused_dictionaries.is_empty());
+        let saved =
+            dict::dictionaries_from_bytes(&dict::dictionaries_to_bytes(first.dictionaries()))
+                .unwrap();
+
+        let result = pipeline()
+            .with_saved_dictionaries(saved.clone())
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.reused_dictionaries, ["x86_64", "aarch64"]);
+        assert!(result.stats.stale_dictionaries.is_empty());
+        assert_eq!(result.dictionaries, trained.dictionaries);
+        assert_eq!(
+            DecompressionPipeline::new(&result)
+                .decompress_all()
+                .unwrap(),
+            binaries
+        );
+
+        // A dictionary of noise doesn't help, so it's trained again.
+        let mut stale = saved;
+        stale[0].1 = TrainedDictionary {
+            data: random_bytes(stale[0].1.data.len(), 7777),
+            sample_count: 0,
+            total_sample_size: 0,
+        };
+        let result = pipeline()
+            .with_saved_dictionaries(stale)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.reused_dictionaries, ["aarch64"]);
+        assert_eq!(result.stats.stale_dictionaries, ["x86_64"]);
+        assert_eq!(result.dictionaries, trained.dictionaries);
+    }
+
+    #[test]
+    fn test_dictionary_kept_only_if_it_pays() {
+        let pipeline = || CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+
+        // Each architecture shares a table only a dictionary compresses.
+        let similar = pipeline().compress_all(&arch_shared_tables()).unwrap();
+        let decisions = &similar.stats.dict_decisions;
+        assert_eq!(decisions.len(), 2);
+        assert!(
+            decisions.iter().all(|d| d.kept && d.savings() > 0),
+            "{:?}",
+            decisions
+        );
+        assert_eq!(similar.dictionaries.len(), 2);
+
+        // Nothing in common: training still gives a dictionary, but it
+        // only adds its own size.
+        let dissimilar: Vec<(String, Vec<u8>)> = Target::all()
+            .iter()
+            .take(8)
+            .enumerate()
+            .map(|(i, target)| {
+                (
+                    target.to_string(),
+                    random_bytes(30_000, delta::mix(i as u64)),
+                )
+            })
+            .collect();
+        let result = pipeline().compress_all(&dissimilar).unwrap();
+        let decisions = &result.stats.dict_decisions;
+        assert!(!decisions.is_empty());
+        assert!(
+            decisions.iter().all(|d| !d.kept && d.savings() <= 0),
+            "{:?}",
+            decisions
+        );
+        assert!(result.dictionaries.is_empty());
+        assert!(result.entries.iter().all(|e| e.dict_index.is_none()));
+        assert_eq!(
+            result.stats.compressed_size,
+            result.entries.iter().map(|e| e.data.len()).sum::<usize>()
+        );
+
+        // A margin can ask for more than the similar tables save.
+        let result = pipeline()
+            .with_dict_margin(0.9)
+            .compress_all(&arch_shared_tables())
+            .unwrap();
+        assert!(result.dictionaries.is_empty());
+        assert!(result.stats.dict_decisions.iter().all(|d| !d.kept));
+    }
+
+    #[test]
+    fn test_two_binaries_train_on_pieces() {
+        // Two targets sharing a string table, each with code of its own.
+        let strings: Vec<u8> = (0..1500)
+            .flat_map(|i| {
+                format!("failed to open {} ({})\n", i * 7919 % 10007, i % 13).into_bytes()
+            })
+            .collect();
+        let binaries: Vec<(String, Vec<u8>)> = ["linux-x86_64", "darwin-aarch64"]
+            .iter()
+            .enumerate()
+            .map(|(i, target)| {
+                let code = random_bytes(20_000, i as u64);
+                (target.to_string(), [code, strings.clone()].concat())
+            })
+            .collect();
+        let pipeline = || CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+
+        let result = pipeline().compress_all(&binaries).unwrap();
+        let without = pipeline().without_dict().compress_all(&binaries).unwrap();
+        assert_eq!(result.dictionaries.len(), 1);
+        assert_eq!(result.dictionaries[0].0, "shared");
+        assert!(result.entries.iter().all(|e| e.dict_index == Some(0)));
+        assert!(
+            result.stats.compressed_size < without.stats.compressed_size,
+            "{} with the dictionary, {} without",
+            result.stats.compressed_size,
+            without.stats.compressed_size
+        );
+        assert_eq!(
+            DecompressionPipeline::new(&result)
+                .decompress_all()
+                .unwrap(),
+            binaries
+        );
+
+        // Requiring more binaries doesn't stop training on pieces.
+        let result = pipeline()
+            .with_dict_min_samples(8)
+            .with_dict_sample_size(4096)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.dictionaries.len(), 1);
+
+        // A dictionary that can't pay for itself isn't kept.
+        let noise = vec![
+            ("linux-x86_64".to_string(), random_bytes(100_000, 1)),
+            ("darwin-aarch64".to_string(), random_bytes(100_000, 2)),
+        ];
+        let result = pipeline().compress_all(&noise).unwrap();
+        assert!(result.dictionaries.is_empty());
+        assert!(!result.stats.dict_trained);
+    }
+
+    #[test]
+    fn test_small_arch_groups_share_dictionary() {
+        let mut binaries = Vec::new();
+        for os in ["linux", "darwin"] {
+            for arch in ["x86_64", "aarch64", "riscv64", "ppc64le"] {
+                let target = format!("{}-{}", os, arch);
+                binaries.push(make_symbol_table(&target, binaries.len()));
+            }
+        }
 
-        let mut pipeline = CompressionPipeline::new(CompressionLevel::Balanced);
-        let result = pipeline.compress_all(binaries).unwrap();
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast).without_delta();
+        let result = pipeline.compress_all(&binaries).unwrap();
 
-        assert_eq!(result.entries.len(), 4);
-        assert!(result.stats.compressed_size < original_size);
+        assert_eq!(result.dictionaries.len(), 1);
+        assert_eq!(result.dictionaries[0].0, "shared");
+        assert!(result.entries.iter().all(|e| e.dict_index == Some(0)));
+    }
 
-        println!("Original: {} bytes", result.stats.original_size);
-        println!("Compressed: {} bytes", result.stats.compressed_size);
-        println!("Ratio: {:.2}%", result.stats.ratio() * 100.0);
-        println!("Savings: {:.2}%", result.stats.savings_percent());
+    #[test]
+    fn test_lz4_pipeline() {
+        let mut binaries = Vec::new();
+        for arch in ["x86_64", "aarch64"] {
+            for program in ["a", "b", "c", "d"] {
+                let key = format!("{}/linux-{}", program, arch);
+                binaries.push(make_symbol_table(&key, binaries.len()));
+            }
+        }
+        let originals: HashMap<String, Vec<u8>> = binaries.iter().cloned().collect();
+
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Balanced)
+            .with_algorithm(Compression::Lz4)
+            .without_bcj();
+        let result = pipeline.compress_all(&binaries).unwrap();
+
+        assert!(result.dictionaries.is_empty());
+        for entry in &result.entries {
+            assert_eq!(entry.compression, Compression::Lz4);
+            assert!(entry.dict_index.is_none());
+            let mut data = lz4::decompress(&entry.data).unwrap();
+            if let Some(reference) = &entry.delta_reference {
+                data = delta::apply_patch(&originals[reference], &data).unwrap();
+            }
+            assert_eq!(data, originals[&entry.target]);
+        }
+    }
+
+    #[test]
+    fn test_xz_pipeline_keeps_bcj() {
+        let binaries: Vec<(String, Vec<u8>)> = [
+            "linux-x86_64",
+            "darwin-x86_64",
+            "linux-aarch64",
+            "linux-s390x",
+            // Not a target, but filtered by the architecture it names.
+            "sparc64-unknown-netbsd",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, target)| make_symbol_table(target, i))
+        .collect();
+        let originals: HashMap<String, Vec<u8>> = binaries.iter().cloned().collect();
+
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_algorithm(Compression::Xz)
+            .without_delta();
+        let result = pipeline.compress_all(&binaries).unwrap();
+
+        assert!(result.dictionaries.is_empty());
+        assert_eq!(result.stats.bcj_filtered, 5);
+        assert_eq!(
+            result.stats.bcj_filters[3..],
+            [
+                ("linux-s390x".to_string(), BcjArch::S390x),
+                ("sparc64-unknown-netbsd".to_string(), BcjArch::Sparc)
+            ]
+        );
+        for entry in &result.entries {
+            assert_eq!(entry.compression, Compression::Xz);
+            assert!(entry.bcj.is_some());
+            let mut data = xz::decompress(&entry.data).unwrap();
+            BcjFilter::new(entry.bcj_arch().unwrap())
+                .decode(&mut data)
+                .unwrap();
+            assert_eq!(data, originals[&entry.target]);
+        }
+    }
+
+    #[test]
+    fn test_brotli_window() {
+        // A block repeated 8 KiB later, past a 1 KiB window's reach.
+        let block: Vec<u8> = (0..8192u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let data = [block.as_slice(), &block].concat();
+        let compress = |window| {
+            CompressionPipeline::new(CompressionLevel::Fast)
+                .with_algorithm(Compression::Brotli)
+                .with_brotli_window(window)
+                .compress(&data)
+        };
+
+        let small = compress(10).unwrap();
+        let large = compress(brotli::DEFAULT_WINDOW).unwrap();
+        assert!(large.len() < small.len());
+        assert_eq!(brotli::decompress(&small).unwrap(), data);
+        assert_eq!(brotli::decompress(&large).unwrap(), data);
+        assert!(compress(30).is_err());
+    }
+
+    #[test]
+    fn test_setting_overrides() {
+        let preset = CompressionPipeline::new(CompressionLevel::Balanced);
+        assert_eq!(preset.zstd_level(), 12);
+        assert_eq!(preset.delta_threshold(), 0.45);
+
+        let pipeline = CompressionPipeline::new(CompressionLevel::Balanced).with_zstd_level(15);
+        assert_eq!(pipeline.zstd_level(), 15);
+        assert_eq!(pipeline.delta_threshold(), 0.45);
+        let pipeline = CompressionPipeline::new(CompressionLevel::Fast).with_delta_threshold(0.5);
+        assert_eq!(pipeline.zstd_level(), 3);
+        assert_eq!(pipeline.delta_threshold(), 0.5);
+
+        let data = b"zstd level override, zstd level override".repeat(50);
+        let stored = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_zstd_level(-5)
+            .compress(&data)
+            .unwrap();
+        let size = data.len() as u64;
+        assert_eq!(
+            crate::extract::decompress_entry(&stored, Compression::Zstd, None, None, size).unwrap(),
+            data
+        );
+
+        let binaries = vec![("linux-x86_64".to_string(), data.clone())];
+        for mut pipeline in [
+            CompressionPipeline::new(CompressionLevel::Fast).with_zstd_level(23),
+            CompressionPipeline::new(CompressionLevel::Fast).with_delta_threshold(1.5),
+            CompressionPipeline::new(CompressionLevel::Fast).with_min_savings(-0.1),
+            CompressionPipeline::new(CompressionLevel::Fast).with_delta_probe_margin(2.0),
+            CompressionPipeline::new(CompressionLevel::Fast).with_dict_size(100),
+            CompressionPipeline::new(CompressionLevel::Fast).with_dict_level(23),
+            CompressionPipeline::new(CompressionLevel::Fast).with_dict_margin(1.5),
+        ] {
+            assert!(matches!(
+                pipeline.compress_all(&binaries),
+                Err(CompressionError::InvalidSetting(_))
+            ));
+        }
+    }
+
+    /// Binaries sharing long runs at unrelated offsets, as vendored data
+    /// and string tables are shared between targets.
+    fn shared_run_binaries() -> Vec<(String, Vec<u8>)> {
+        let run = |seed| random_bytes(150_000, seed);
+        let own = |len, seed| random_bytes(len, seed);
+        let (first, second, third) = (run(20), run(22), run(24));
+        vec![
+            (
+                "linux-x86_64".to_string(),
+                [
+                    own(21_001, 30),
+                    first.clone(),
+                    own(3_333, 32),
+                    second.clone(),
+                ]
+                .concat(),
+            ),
+            (
+                "windows-x86_64".to_string(),
+                [second.clone(), own(40_123, 34), first.clone()].concat(),
+            ),
+            (
+                "darwin-aarch64".to_string(),
+                [own(7_777, 36), first, third.clone()].concat(),
+            ),
+            (
+                "linux-riscv64".to_string(),
+                [third, own(12_345, 38), second].concat(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_chunked_beats_per_binary_on_shared_runs() {
+        let binaries = shared_run_binaries();
+        let chunked = CompressionPipeline::new(CompressionLevel::Balanced)
+            .without_bcj()
+            .compress_chunked(&binaries)
+            .unwrap();
+        let standard = CompressionPipeline::new(CompressionLevel::Balanced)
+            .without_bcj()
+            .compress_all(&binaries)
+            .unwrap();
+        // Unique bytes make up 45% of the input, which the chunk store gets
+        // close to; delta patches against one reference each leave 77%.
+        assert!(chunked.stats.ratio() < 0.47, "{}", chunked.stats.ratio());
+        assert!(
+            chunked.stats.ratio() < standard.stats.ratio() * 0.7,
+            "{} vs {}",
+            chunked.stats.ratio(),
+            standard.stats.ratio()
+        );
+
+        let store = crate::extract::decompress_entry(
+            &chunked.store,
+            chunked.compression,
+            None,
+            chunked.window_log,
+            chunked.store_size as u64,
+        )
+        .unwrap();
+        assert!(chunked.store_checksum.matches(&store));
+        for ((target, data), entry) in binaries.iter().zip(&chunked.entries) {
+            assert_eq!(&entry.target, target);
+            let restored =
+                chunk::reassemble(&store, &chunked.chunk_sizes, &entry.chunks, u64::MAX).unwrap();
+            assert_eq!(&restored, data);
+        }
+    }
+
+    #[test]
+    fn test_bcj_filters_only_code() {
+        use crate::bcj::bcj_encode;
+        use crate::decompress::DecompressionPipeline;
+        use crate::segment::tests::elf_with_sections;
+        use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR};
+
+        let alloc = SHF_ALLOC as u64;
+        let exec = alloc | SHF_EXECINSTR as u64;
+        // Calls to a handful of functions, and a table of small integers:
+        // 1000 is e8 03 00 00, which the x86 filter takes for a call.
+        let text: Vec<u8> = (0..4000u32)
+            .flat_map(|i| {
+                let rel = (i % 7) as i32 * 4096 - (i as i32) * 8;
+                [&[0x48, 0x89, 0xc7][..], &[0xe8], &rel.to_le_bytes()].concat()
+            })
+            .collect();
+        let rodata: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| [1000u32, i % 16].map(u32::to_le_bytes).concat())
+            .collect();
+        let binary = elf_with_sections(b"", &[(".text", exec, &text), (".rodata", alloc, &rodata)]);
+        let target = "linux-x86_64".to_string();
+
+        let compress = |pipeline: CompressionPipeline, data: &[u8]| {
+            pipeline
+                .without_delta()
+                .without_dict()
+                .compress_all(&[(target.clone(), data)])
+                .unwrap()
+        };
+        let level = CompressionLevel::Balanced;
+        let result = compress(CompressionPipeline::new(level), &binary);
+        let mut filtered = binary.clone();
+        bcj_encode(&mut filtered, BcjArch::X86).unwrap();
+        let whole = compress(CompressionPipeline::new(level).without_bcj(), &filtered);
+        let unfiltered = compress(CompressionPipeline::new(level).without_bcj(), &binary);
+
+        // Filtering the table as well turns many of its 1000s into different
+        // addresses, leaving it bigger than not filtering at all.
+        let (sections, whole, unfiltered) = (
+            result.stats.ratio(),
+            whole.stats.ratio(),
+            unfiltered.stats.ratio(),
+        );
+        assert!(
+            sections * 5.0 < whole && sections < unfiltered,
+            "sections {} whole {} unfiltered {}",
+            sections,
+            whole,
+            unfiltered
+        );
+
+        let ranges = result.entries[0].bcj_ranges.clone().unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(&binary[ranges[0].clone()], &text[..]);
+        let restored = DecompressionPipeline::new(&result)
+            .decompress(&target)
+            .unwrap();
+        assert!(restored == binary);
+    }
+
+    #[test]
+    fn test_segments_round_trip() {
+        use crate::segment::tests::elf_with_sections;
+        use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE};
+
+        let alloc = SHF_ALLOC as u64;
+        let (exec, write) = (alloc | SHF_EXECINSTR as u64, alloc | SHF_WRITE as u64);
+        // BCJ filters only .text, so the shared section still matches after
+        // filtering, bytes that look like calls and all.
+        let rodata = b"usage: app [OPTIONS] <FILE>\xe8\x03\0\0\n".repeat(400);
+        let linux = elf_with_sections(
+            b"",
+            &[
+                (".text", exec, &b"\xe8\x10\0\0\0\xc3".repeat(50)),
+                (".rodata", alloc, &rodata),
+                (".data", write, &[7; 77]),
+            ],
+        );
+        let freebsd = elf_with_sections(
+            b"FreeBSD",
+            &[
+                (".text", exec, &b"\x55\xe8\x20\0\0\0\xc3".repeat(61)),
+                (".data", write, &[9; 13]),
+                (".rodata", alloc, &rodata),
+            ],
+        );
+        let binaries = vec![
+            ("linux-x86_64".to_string(), linux),
+            ("freebsd-x86_64".to_string(), freebsd),
+            ("wasi-wasm32".to_string(), b"\0asm module".to_vec()),
+        ];
+
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .compress_segments(binaries.clone())
+            .unwrap();
+        assert_eq!(result.stats.bcj_filtered, 2);
+        assert!(result.entries[..2].iter().all(|e| e.bcj_ranges.is_some()));
+        let total: usize = binaries.iter().map(|(_, d)| d.len()).sum();
+        assert!(result.store_size <= total - rodata.len());
+        // The section is one chunk, stored once and used by both binaries.
+        let section = result
+            .chunk_sizes
+            .iter()
+            .position(|&size| size as usize == rodata.len())
+            .unwrap() as u32;
+        assert!(result.entries[..2]
+            .iter()
+            .all(|e| e.chunks.contains(&section)));
+        assert_eq!(result.entries[2].chunks.len(), 1);
+
+        let store = crate::extract::decompress_entry(
+            &result.store,
+            result.compression,
+            None,
+            result.window_log,
+            result.store_size as u64,
+        )
+        .unwrap();
+        for ((target, data), entry) in binaries.iter().zip(&result.entries) {
+            let mut restored =
+                chunk::reassemble(&store, &result.chunk_sizes, &entry.chunks, u64::MAX).unwrap();
+            if let (Some(arch), Some(ranges)) = (entry.bcj_arch(), &entry.bcj_ranges) {
+                bcj::bcj_decode_ranges(&mut restored, arch, ranges).unwrap();
+            }
+            assert!(&restored == data, "{} differs", target);
+        }
+    }
+
+    /// Bytes no compressor can shrink.
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
     }
 
     #[test]
-    fn test_tier_targets() {
-        let core = PlatformTier::Core.targets();
-        let standard = PlatformTier::Standard.targets();
-        let extended = PlatformTier::Extended.targets();
+    fn test_incompressible_entry_stored_raw() {
+        let random = random_bytes(64 * 1024, 7);
+        let (_, compressible) = make_binary("linux-aarch64", 3);
+        let binaries = vec![
+            ("linux-x86_64".to_string(), random.clone()),
+            ("linux-aarch64".to_string(), compressible.clone()),
+        ];
+
+        for algorithm in [
+            Compression::Zstd,
+            Compression::Lz4,
+            Compression::Xz,
+            Compression::Brotli,
+        ] {
+            let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast)
+                .with_algorithm(algorithm)
+                .without_delta();
+            let result = pipeline.compress_all(&binaries).unwrap();
+            assert_eq!(result.stats.stored_raw, 1, "{}", algorithm);
+
+            let raw = &result.entries[0];
+            assert_eq!(raw.compression, Compression::None);
+            assert_eq!(raw.data.len(), random.len());
+            assert_eq!((raw.dict_index, raw.window_log), (None, None));
+            assert_eq!(result.entries[1].compression, algorithm);
+
+            let decompressor = crate::DecompressionPipeline::new(&result);
+            assert_eq!(decompressor.decompress_all().unwrap(), binaries);
+        }
+
+        // Demanding more savings than compression gives stores everything raw.
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast).with_min_savings(1.0);
+        let result = pipeline.compress_all(&binaries).unwrap();
+        assert_eq!(result.stats.stored_raw, 2);
+        assert!(result
+            .entries
+            .iter()
+            .all(|e| e.compression == Compression::None));
+        assert!(pipeline.stores_raw(100, 100) && pipeline.stores_raw(0, 0));
+        let pipeline = CompressionPipeline::new(CompressionLevel::Fast).with_min_savings(0.5);
+        assert!(pipeline.stores_raw(100, 51) && !pipeline.stores_raw(100, 50));
+    }
+
+    #[test]
+    fn test_delta_probe_skips_final_comparison() {
+        // Related binaries patch far smaller than they compress, and one
+        // unrelated binary grouped with them patches far larger.
+        let base = random_bytes(64 * 1024, 3)
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| if i % 3 == 0 { *byte } else { (i % 61) as u8 })
+            .collect::<Vec<u8>>();
+        let mut binaries = vec![("linux-x86_64".to_string(), base.clone())];
+        for (k, target) in ["darwin-x86_64", "windows-x86_64"].iter().enumerate() {
+            let mut data = base.clone();
+            for i in (k..data.len()).step_by(503 + k) {
+                data[i] ^= 0x33;
+            }
+            binaries.push((target.to_string(), data));
+        }
+        binaries.push(("freebsd-x86_64".to_string(), vec![0x90; base.len()]));
+
+        let compress = |margin| {
+            CompressionPipeline::new(CompressionLevel::Maximum)
+                .without_dict()
+                .with_delta_threshold(0.0)
+                .with_delta_probe_margin(margin)
+                .compress_all(&binaries)
+                .unwrap()
+        };
+        let (probed, compared) = (compress(DEFAULT_DELTA_PROBE_MARGIN), compress(1.0));
+        assert_eq!(probed.stats.delta_comparisons_skipped, 3);
+        assert_eq!(compared.stats.delta_comparisons_skipped, 0);
+        assert_eq!(probed.stats.delta_used, 2);
+        for (probed, compared) in probed.entries.iter().zip(&compared.entries) {
+            assert_eq!(probed.delta_reference, compared.delta_reference);
+            assert_eq!(probed.data, compared.data);
+        }
+
+        // Compressing at the probe level already never probes.
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_zstd_level(DELTA_PROBE_LEVEL)
+            .without_dict()
+            .with_delta_threshold(0.0)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.delta_comparisons_skipped, 0);
+        assert_eq!(
+            crate::DecompressionPipeline::new(&probed)
+                .decompress_all()
+                .unwrap(),
+            binaries
+        );
+    }
+
+    #[test]
+    fn test_delta_chain_round_trip() {
+        // Each build rewrites one more block of the one before it.
+        let targets = [
+            "linux-x86_64",
+            "windows-x86_64",
+            "darwin-x86_64",
+            "freebsd-x86_64",
+        ];
+        let mut data = random_bytes(30_000, 20);
+        let mut binaries = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            if i > 0 {
+                data[i * 3000..(i + 1) * 3000]
+                    .copy_from_slice(&random_bytes(3000, 40 + 2 * i as u64));
+            }
+            binaries.push((target.to_string(), data.clone()));
+        }
+        binaries.swap(1, 3);
+
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .without_dict()
+            .with_delta_threshold(0.3)
+            .with_delta_chains()
+            .compress_all(&binaries)
+            .unwrap();
+        let references: Vec<(&str, Option<&str>)> = result
+            .entries
+            .iter()
+            .map(|e| (e.target.as_str(), e.delta_reference.as_deref()))
+            .collect();
+        assert_eq!(
+            references,
+            [
+                ("linux-x86_64", None),
+                ("windows-x86_64", Some("linux-x86_64")),
+                ("darwin-x86_64", Some("windows-x86_64")),
+                ("freebsd-x86_64", Some("darwin-x86_64")),
+            ]
+        );
+        let decompressor = crate::DecompressionPipeline::new(&result);
+        for (target, data) in &binaries {
+            assert!(
+                decompressor.decompress(target).unwrap() == *data,
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_reference_selection() {
+        // The last binary shares two thirds with each of the others, which
+        // share only a third with each other.
+        let base = random_bytes(30_000, 9);
+        let mut first = base.clone();
+        first[..10_000].copy_from_slice(&random_bytes(10_000, 10));
+        let mut second = base.clone();
+        second[20_000..].copy_from_slice(&random_bytes(10_000, 12));
+        let binaries = vec![
+            ("linux-x86_64".to_string(), first),
+            ("windows-x86_64".to_string(), second),
+            ("darwin-x86_64".to_string(), base),
+        ];
+
+        let compress = |pipeline: CompressionPipeline| {
+            pipeline
+                .without_dict()
+                .with_delta_threshold(0.15)
+                .compress_all(&binaries)
+                .unwrap()
+        };
+        let first_anchor = compress(CompressionPipeline::new(CompressionLevel::Fast));
+        assert_eq!(
+            first_anchor.delta_groups[0].reference_target,
+            "linux-x86_64"
+        );
+
+        let selected =
+            compress(CompressionPipeline::new(CompressionLevel::Fast).with_reference_selection());
+        assert_eq!(
+            selected.delta_groups,
+            [DeltaGroup {
+                reference_target: "darwin-x86_64".to_string(),
+                delta_targets: vec!["linux-x86_64".to_string(), "windows-x86_64".to_string()],
+                chained: false,
+            }]
+        );
+        for entry in &selected.entries {
+            assert_ne!(entry.delta_reference.as_deref(), Some("linux-x86_64"));
+        }
+        assert!(selected.stats.compressed_size < first_anchor.stats.compressed_size);
+        let decompressor = crate::DecompressionPipeline::new(&selected);
+        for (target, data) in &binaries {
+            assert!(
+                decompressor.decompress(target).unwrap() == *data,
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn test_ultra_long_distance_ratio() {
+        // 4 MiB of noise repeated right after itself: further back than the
+        // 2 MiB window zstd level 3 uses for this input.
+        let block = random_bytes(4 << 20, 0x9e37_79b9);
+        let data = [block.as_slice(), &block].concat();
+
+        // Same zstd level for both, so only long-distance matching differs.
+        let fast = CompressionPipeline::new(CompressionLevel::Fast);
+        let ultra = CompressionPipeline::new(CompressionLevel::Ultra).with_zstd_level(3);
+        assert_eq!(fast.window_log(), None);
+        assert_eq!(ultra.window_log(), Some(dict::DEFAULT_LONG_WINDOW_LOG));
+        let fast_size = fast.compress(&data).unwrap().len();
+        let stored = ultra.compress(&data).unwrap();
+        println!("Fast: {} bytes, Ultra: {} bytes", fast_size, stored.len());
+        assert!(fast_size > data.len() * 9 / 10);
+        assert!(stored.len() < data.len() * 6 / 10);
+
+        let size = data.len() as u64;
+        let window_log = ultra.window_log();
+        let decompressed =
+            crate::extract::decompress_entry(&stored, Compression::Zstd, None, window_log, size)
+                .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_ultra_window_log() {
+        let binaries = vec![make_symbol_table("linux-x86_64", 0)];
+        let params = |window_log| ZstdParams {
+            window_log: Some(window_log),
+            ..Default::default()
+        };
+        let mut pipeline =
+            CompressionPipeline::new(CompressionLevel::Ultra).with_zstd_params(params(24));
+        let result = pipeline.compress_all(&binaries).unwrap();
+        assert_eq!(result.entries[0].window_log, Some(24));
+
+        // Other levels record a window log only when one is set.
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast);
+        let result = pipeline.compress_all(&binaries).unwrap();
+        assert_eq!(result.entries[0].window_log, None);
+        let mut pipeline =
+            CompressionPipeline::new(CompressionLevel::Fast).with_zstd_params(params(20));
+        let result = pipeline.compress_all(&binaries).unwrap();
+        assert_eq!(result.entries[0].window_log, Some(20));
+
+        // Only zstd has long-distance matching.
+        let mut pipeline =
+            CompressionPipeline::new(CompressionLevel::Ultra).with_algorithm(Compression::Xz);
+        let result = pipeline.compress_all(&binaries).unwrap();
+        assert_eq!(result.entries[0].window_log, None);
+
+        let mut pipeline =
+            CompressionPipeline::new(CompressionLevel::Ultra).with_zstd_params(params(32));
+        assert!(matches!(
+            pipeline.compress_all(&binaries),
+            Err(CompressionError::InvalidSetting(_))
+        ));
+    }
+
+    #[test]
+    fn test_zstd_workers() {
+        let binaries = vec![
+            make_symbol_table("linux-x86_64", 0),
+            make_symbol_table("linux-aarch64", 1),
+            make_symbol_table("macos-aarch64", 2),
+        ];
+        let compress = |workers| {
+            let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast)
+                .without_bcj()
+                .without_delta()
+                .without_dict()
+                .with_zstd_params(ZstdParams {
+                    workers,
+                    ..Default::default()
+                });
+            pipeline.compress_all(&binaries).unwrap()
+        };
+
+        let result = compress(2);
+        let targets: Vec<&str> = result
+            .stats
+            .compress_times
+            .iter()
+            .map(|(target, _)| target.as_str())
+            .collect();
+        assert_eq!(targets, ["linux-x86_64", "linux-aarch64", "macos-aarch64"]);
+        // Output is deterministic for a given worker count.
+        for (entry, again) in result.entries.iter().zip(&compress(2).entries) {
+            assert_eq!(entry.data, again.data);
+        }
+        for (entry, (_, original)) in result.entries.iter().zip(&binaries) {
+            let size = original.len() as u64;
+            let decompressed =
+                crate::extract::decompress_entry(&entry.data, Compression::Zstd, None, None, size)
+                    .unwrap();
+            assert_eq!(&decompressed, original);
+        }
+    }
+
+    #[test]
+    fn test_jobs_output_identical() {
+        // Two programs per target, so there are dictionaries to train and
+        // similar binaries to delta compress.
+        let mut binaries = Vec::new();
+        for program in ["a", "b"] {
+            for target in Target::all() {
+                let key = format!("{}/{}", program, target);
+                binaries.push(make_symbol_table(&key, binaries.len() % 3));
+            }
+        }
+
+        let compress = |jobs| {
+            let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast).with_jobs(jobs);
+            pipeline.compress_all(&binaries).unwrap()
+        };
+        let serial = compress(1);
+        let parallel = compress(4);
+        assert!(serial.stats.delta_used > 0);
+        assert_eq!(parallel.stats.delta_used, serial.stats.delta_used);
+        assert_eq!(parallel.dictionaries, serial.dictionaries);
+        assert_eq!(parallel.entries.len(), serial.entries.len());
+        for (entry, expected) in parallel.entries.iter().zip(&serial.entries) {
+            assert_eq!(entry.target, expected.target);
+            assert_eq!(entry.data, expected.data, "{}", entry.target);
+            assert_eq!(entry.delta_reference, expected.delta_reference);
+            assert_eq!(entry.dict_index, expected.dict_index);
+        }
+        let order = |result: &CompressionResult| -> Vec<String> {
+            let times = &result.stats.compress_times;
+            times.iter().map(|(target, _)| target.clone()).collect()
+        };
+        assert_eq!(order(&parallel), order(&serial));
+    }
+
+    #[test]
+    fn test_entry_stats() {
+        let binaries = arch_shared_tables();
+        let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast);
+        let result = pipeline.compress_all(&binaries).unwrap();
+        let stats = &result.stats;
+
+        assert_eq!(stats.entries.len(), result.entries.len());
+        for (entry, compressed) in stats.entries.iter().zip(&result.entries) {
+            assert_eq!(entry.target, compressed.target);
+            assert_eq!(entry.compressed_size, compressed.data.len());
+            assert_eq!(entry.delta_reference, compressed.delta_reference);
+            let group = compressed.dict_index.map(|i| &result.dictionaries[i].0);
+            assert_eq!(entry.dictionary.as_ref(), group);
+            assert!(entry.times.compress > Duration::ZERO, "{}", entry.target);
+            assert_eq!(entry.bcj.is_some(), entry.times.bcj > Duration::ZERO);
+            if entry.delta_reference.is_some() {
+                assert!(entry.times.delta_patch > Duration::ZERO);
+            }
+        }
+        let original: usize = stats.entries.iter().map(|e| e.original_size).sum();
+        assert_eq!(original, stats.original_size);
+        let dictionaries: usize = result.dictionaries.iter().map(|(_, d)| d.len()).sum();
+        let compressed: usize = stats.entries.iter().map(|e| e.compressed_size).sum();
+        assert_eq!(compressed + dictionaries, stats.compressed_size);
+
+        assert!(stats.bcj_filtered > 0);
+        assert!(stats.stage_times.bcj > Duration::ZERO);
+        assert!(stats.stage_times.dict_training > Duration::ZERO);
+        assert!(stats.stage_times.delta_grouping > Duration::ZERO);
+        assert!(stats.stage_times.compression > Duration::ZERO);
+        assert!(stats.entries.iter().any(|e| e.method().starts_with("zstd")));
+    }
+
+    /// Events a progress callback was given, with targets owned.
+    type Events = std::sync::Arc<
+        std::sync::Mutex<Vec<(&'static str, Option<String>, Option<Stage>, u64, u64)>>,
+    >;
+
+    /// Returns a pipeline recording its progress events in the returned log.
+    fn record_progress(pipeline: CompressionPipeline) -> (CompressionPipeline, Events) {
+        let events = Events::default();
+        let log = events.clone();
+        let pipeline = pipeline.with_progress(move |event| {
+            let record = match event {
+                ProgressEvent::StageStarted { target, stage } => {
+                    ("started", target.map(str::to_string), Some(stage), 0, 0)
+                }
+                ProgressEvent::StageFinished { target, stage } => {
+                    ("finished", target.map(str::to_string), Some(stage), 0, 0)
+                }
+                ProgressEvent::BytesProcessed {
+                    target,
+                    done,
+                    total,
+                } => ("bytes", Some(target.to_string()), None, done, total),
+            };
+            log.lock().unwrap().push(record);
+        });
+        (pipeline, events)
+    }
+
+    #[test]
+    fn test_progress_events() {
+        let binaries = arch_shared_tables();
+        let (pipeline, events) =
+            record_progress(CompressionPipeline::new(CompressionLevel::Fast).with_jobs(4));
+        let mut pipeline = pipeline.with_verify();
+        pipeline.compress_all(&binaries).unwrap();
+        let events = events.lock().unwrap();
+
+        let count = |kind: &str, target: Option<&str>, stage: Stage| {
+            events
+                .iter()
+                .filter(|e| e.0 == kind && e.1.as_deref() == target && e.2 == Some(stage))
+                .count()
+        };
+        for stage in [Stage::DictTraining, Stage::DeltaGrouping] {
+            assert_eq!(count("started", None, stage), 1, "{}", stage);
+            assert_eq!(count("finished", None, stage), 1, "{}", stage);
+        }
+        for (target, data) in &binaries {
+            let target = Some(target.as_str());
+            for stage in [Stage::Bcj, Stage::Compression, Stage::Verify] {
+                assert_eq!(count("started", target, stage), 1, "{:?} {}", target, stage);
+                assert_eq!(
+                    count("finished", target, stage),
+                    1,
+                    "{:?} {}",
+                    target,
+                    stage
+                );
+            }
+            let bytes: Vec<_> = events
+                .iter()
+                .filter(|e| e.0 == "bytes" && e.1.as_deref() == target)
+                .map(|e| (e.3, e.4))
+                .collect();
+            let len = data.len() as u64;
+            assert_eq!(bytes, [(len, len)]);
+        }
+        let position = |kind: &str, stage: Stage| {
+            events
+                .iter()
+                .position(|e| e.0 == kind && e.2 == Some(stage))
+                .unwrap()
+        };
+        assert!(
+            position("finished", Stage::DictTraining) < position("started", Stage::Compression)
+        );
+    }
+
+    /// Reader yielding `remaining` bytes of x86-like code, generated as it
+    /// is read so no test holds the whole input.
+    struct SyntheticReader {
+        remaining: u64,
+        state: u32,
+    }
+
+    impl SyntheticReader {
+        fn new(len: u64, seed: u32) -> Self {
+            Self {
+                remaining: len,
+                state: seed | 1,
+            }
+        }
+    }
+
+    impl Read for SyntheticReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.remaining as usize);
+            for byte in &mut buf[..n] {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 17;
+                self.state ^= self.state << 5;
+                // Mostly a small alphabet with frequent CALL opcodes.
+                *byte = match self.state % 8 {
+                    0 => 0xE8,
+                    n => (self.state >> 8) as u8 & (n as u8 * 3),
+                };
+            }
+            self.remaining -= n as u64;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_streaming_large_inputs() {
+        const LEN: u64 = 16 << 20;
+        let targets = ["linux-x86_64", "linux-aarch64"];
+        let inputs: Vec<(String, Box<dyn Read>, u64)> = targets
+            .iter()
+            .enumerate()
+            .map(|(seed, target)| {
+                let reader: Box<dyn Read> = Box::new(SyntheticReader::new(LEN, seed as u32));
+                (target.to_string(), reader, LEN)
+            })
+            .collect();
+
+        let pipeline = CompressionPipeline::new(CompressionLevel::Fast);
+        let mut output = Vec::new();
+        let result = pipeline.compress_streaming(inputs, &mut output).unwrap();
+        assert_eq!(result.entries.len(), 2);
+        assert_eq!(result.stats.original_size as u64, 2 * LEN);
+        assert_eq!(result.stats.compressed_size, output.len());
+        assert_eq!(result.stats.bcj_filtered, 2);
+
+        let mut offset = 0;
+        for (seed, entry) in result.entries.iter().enumerate() {
+            let stored = &output[offset..offset + entry.compressed_size as usize];
+            offset += stored.len();
+            assert_eq!(entry.compressed_checksum, Checksum::of(stored));
+            let mut data = crate::extract::decompress_entry(
+                stored,
+                entry.compression,
+                None,
+                entry.window_log,
+                entry.original_size,
+            )
+            .unwrap();
+            BcjFilter::new(entry.bcj_arch().unwrap())
+                .decode(&mut data)
+                .unwrap();
+            let expected = Checksum::of_reader(SyntheticReader::new(LEN, seed as u32)).unwrap();
+            assert!(expected.matches(&data), "{}", entry.target);
+        }
+    }
+
+    #[test]
+    fn test_streaming_progress() {
+        const LEN: u64 = 3 * PROGRESS_INTERVAL + 1000;
+        let reader: Box<dyn Read> = Box::new(SyntheticReader::new(LEN, 0));
+        let inputs = vec![("linux-x86_64".to_string(), reader, LEN)];
+        let (pipeline, events) = record_progress(CompressionPipeline::new(CompressionLevel::Fast));
+        pipeline
+            .compress_streaming(inputs, &mut io::sink())
+            .unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.first().unwrap().0, "started");
+        assert_eq!(events.last().unwrap().0, "finished");
+        let done: Vec<u64> = events
+            .iter()
+            .filter(|e| e.0 == "bytes")
+            .inspect(|e| assert_eq!(e.4, LEN))
+            .map(|e| e.3)
+            .collect();
+        assert_eq!(done.len(), 4, "{:?}", done);
+        assert!(done.windows(2).all(|w| w[0] < w[1]), "{:?}", done);
+        assert!(done[0] >= PROGRESS_INTERVAL);
+        assert_eq!(done.last(), Some(&LEN));
+    }
 
-        assert!(core.len() < standard.len());
-        assert!(standard.len() < extended.len());
+    #[test]
+    fn test_memory_budget() {
+        // Builds that patch far smaller than they compress.
+        let base = random_bytes(256 * 1024, delta::mix(621));
+        let binaries: Vec<(String, Vec<u8>)> = [
+            "linux-x86_64",
+            "darwin-x86_64",
+            "windows-x86_64",
+            "freebsd-x86_64",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(k, target)| {
+            let mut data = base.clone();
+            for i in (k..data.len()).step_by(4093) {
+                data[i] ^= 0x5A;
+            }
+            (target.to_string(), data)
+        })
+        .collect();
+        let pipeline = || {
+            CompressionPipeline::new(CompressionLevel::Fast)
+                .with_delta_threshold(0.0)
+                .with_jobs(4)
+                .with_memory_budget(64 * 1024)
+        };
+        let round_trips = |result: &CompressionResult| {
+            let decompressor = crate::DecompressionPipeline::new(result);
+            assert_eq!(decompressor.decompress_all().unwrap(), binaries);
+        };
+
+        // Too little for any patch, dictionary or second job.
+        let result = pipeline().without_bcj().compress_all(&binaries).unwrap();
+        let degradations = &result.stats.degradations;
+        assert!(degradations.contains(&Degradation::Jobs { jobs: 1 }));
+        let skipped = degradations
+            .iter()
+            .filter(|d| matches!(d, Degradation::DeltaSkipped { .. }))
+            .count();
+        assert_eq!(skipped, 3);
+        assert!(degradations
+            .iter()
+            .any(|d| matches!(d, Degradation::DictSkipped { .. })));
+        assert_eq!((result.stats.delta_used, result.dictionaries.len()), (0, 0));
+        round_trips(&result);
+
+        // Filtered copies don't fit either, so every binary streams.
+        let result = pipeline().compress_all(&binaries).unwrap();
+        assert_eq!(result.stats.bcj_filtered, 4);
+        for (target, _) in &binaries {
+            let streamed = Degradation::Streamed {
+                target: target.clone(),
+            };
+            assert!(result.stats.degradations.contains(&streamed), "{}", target);
+        }
+        assert!(result.entries.iter().all(|e| e.bcj_ranges.is_none()));
+        round_trips(&result);
 
-        // Core targets should be in standard and extended
-        for target in &core {
-            assert!(standard.contains(target));
-            assert!(extended.contains(target));
+        // A budget with room for patches degrades nothing.
+        let result = pipeline()
+            .without_bcj()
+            .without_dict()
+            .with_memory_budget(64 << 20)
+            .compress_all(&binaries)
+            .unwrap();
+        assert_eq!(result.stats.degradations, []);
+        assert_eq!(result.stats.delta_used, 3);
+    }
+
+    #[test]
+    fn test_memory_budget_samples_dictionaries() {
+        let binaries = arch_shared_tables();
+        let result = CompressionPipeline::new(CompressionLevel::Fast)
+            .without_delta()
+            .without_bcj()
+            .with_dict_sample_size(4096)
+            .with_memory_budget(DICT_TRAINING_OVERHEAD + 48 * 1024)
+            .compress_all(&binaries)
+            .unwrap();
+        let sampled: Vec<(usize, usize)> = result
+            .stats
+            .degradations
+            .iter()
+            .filter_map(|d| match d {
+                Degradation::DictSampled { sampled, total, .. } => Some((*sampled, *total)),
+                _ => None,
+            })
+            .collect();
+        assert!(!sampled.is_empty(), "{:?}", result.stats.degradations);
+        for (sampled, total) in sampled {
+            assert!(sampled <= 48 * 1024 && sampled < total);
+        }
+        let decompressor = crate::DecompressionPipeline::new(&result);
+        assert_eq!(decompressor.decompress_all().unwrap(), binaries);
+    }
+
+    #[test]
+    fn test_streaming_checks_length() {
+        let pipeline = CompressionPipeline::new(CompressionLevel::Fast);
+        for (actual, given) in [(100, 101), (101, 100)] {
+            let reader: Box<dyn Read> = Box::new(SyntheticReader::new(actual, 1));
+            let inputs = vec![("linux-x86_64".to_string(), reader, given)];
+            let err = pipeline
+                .compress_streaming(inputs, &mut io::sink())
+                .unwrap_err();
+            assert!(matches!(err, CompressionError::Compress { .. }), "{}", err);
         }
+
+        let reader: Box<dyn Read> = Box::new(SyntheticReader::new(10, 1));
+        let err = CompressionPipeline::new(CompressionLevel::Fast)
+            .with_algorithm(Compression::Xz)
+            .compress_streaming(
+                vec![("linux-x86_64".to_string(), reader, 10)],
+                &mut io::sink(),
+            )
+            .unwrap_err();
+        assert!(matches!(err, CompressionError::InvalidSetting(_)));
     }
 
     #[test]
     fn test_empty_input() {
         let mut pipeline = CompressionPipeline::new(CompressionLevel::Fast);
-        let result = pipeline.compress_all(Vec::new()).unwrap();
+        let result = pipeline
+            .compress_all(&Vec::<(String, Vec<u8>)>::new())
+            .unwrap();
 
         assert!(result.entries.is_empty());
-        assert!(result.dictionary.is_none());
+        assert!(result.dictionaries.is_empty());
     }
 }
@@ -2,10 +2,19 @@
 //!
 //! Parses ELF, Mach-O, and PE binaries to identify segments that can be
 //! deduplicated across multiple binaries (e.g., identical data sections).
+//! [`shared_sections`] picks the read-only data sections the pipeline stores
+//! once (see [`CompressionPipeline::compress_segments`]).
+//!
+//! [`CompressionPipeline::compress_segments`]: crate::CompressionPipeline::compress_segments
 
 use crate::{CompressionError, Result};
 use goblin::Object;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+/// Smallest section [`shared_sections`] stores on its own. Smaller ones
+/// save less than the chunk that records them costs.
+pub const MIN_SHARED_SECTION_SIZE: usize = 256;
 
 /// Represents a segment from a binary.
 #[derive(Debug, Clone)]
@@ -18,6 +27,8 @@ pub struct Segment {
     pub size: usize,
     /// Whether this segment contains executable code.
     pub executable: bool,
+    /// Whether this segment is mapped writable.
+    pub writable: bool,
     /// Hash of segment contents for deduplication.
     pub hash: [u8; 32],
 }
@@ -31,32 +42,57 @@ pub struct ParsedBinary {
     pub arch: String,
     /// List of segments.
     pub segments: Vec<Segment>,
+    /// Oldest OS version the binary declares support for, from Mach-O
+    /// `LC_BUILD_VERSION`/`LC_VERSION_MIN_*` or the PE optional header.
+    pub min_os_version: Option<String>,
+    /// Shared libraries the binary loads at startup.
+    pub dynamic_deps: Vec<String>,
     /// Raw binary data.
     pub data: Vec<u8>,
 }
 
+/// What [`ParsedBinary`] reads from an object: its segments, architecture,
+/// minimum OS version and dynamic dependencies.
+type ObjectInfo = (Vec<Segment>, String, Option<String>, Vec<String>);
+
+impl Segment {
+    /// Returns true if the segment holds data that is neither code nor
+    /// written at run time, such as string tables and constants.
+    pub fn is_read_only_data(&self) -> bool {
+        !self.executable && !self.writable
+    }
+
+    /// Returns the bytes of the file the segment occupies.
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.size
+    }
+}
+
 impl ParsedBinary {
     /// Parse a binary and extract segment information.
     pub fn parse(target: &str, data: Vec<u8>) -> Result<Self> {
-        let (segments, arch) = match Object::parse(&data) {
-            Ok(Object::Elf(elf)) => parse_elf(&data, &elf),
-            Ok(Object::Mach(mach)) => parse_mach(&data, &mach),
-            Ok(Object::PE(pe)) => parse_pe(&data, &pe),
-            Ok(_) => (Vec::new(), "unknown".to_string()),
-            Err(e) => {
-                return Err(CompressionError::Parse(format!(
-                    "Failed to parse binary: {}",
-                    e
-                )))
-            }
-        };
+        let info = object_info(&data)?;
+        Ok(Self::from_info(target, info, data))
+    }
 
-        Ok(Self {
+    /// Like [`parse`](Self::parse), but data goblin can't parse is kept as a
+    /// binary with no segments instead of failing.
+    pub fn parse_or_opaque(target: &str, data: Vec<u8>) -> Self {
+        let info = object_info(&data)
+            .unwrap_or_else(|_| (Vec::new(), "unknown".to_string(), None, Vec::new()));
+        Self::from_info(target, info, data)
+    }
+
+    fn from_info(target: &str, info: ObjectInfo, data: Vec<u8>) -> Self {
+        let (segments, arch, min_os_version, dynamic_deps) = info;
+        Self {
             target: target.to_string(),
             arch,
             segments,
+            min_os_version,
+            dynamic_deps,
             data,
-        })
+        }
     }
 
     /// Get executable segments (for BCJ filtering).
@@ -71,6 +107,280 @@ impl ParsedBinary {
     }
 }
 
+/// Reads the segments and other details of the object in `data`.
+fn object_info(data: &[u8]) -> Result<ObjectInfo> {
+    let object = Object::parse(data)
+        .map_err(|e| CompressionError::Parse(format!("Failed to parse binary: {}", e)))?;
+    let (segments, arch) = match &object {
+        Object::Elf(elf) => parse_elf(data, elf),
+        Object::Mach(mach) => parse_mach(data, mach),
+        Object::PE(pe) => parse_pe(data, pe),
+        _ => (Vec::new(), "unknown".to_string()),
+    };
+    let min_os_version = object_min_os_version(data, &object);
+    let dynamic_deps = object_dynamic_deps(&object);
+    Ok((segments, arch, min_os_version, dynamic_deps))
+}
+
+/// Detect the oldest OS version a Mach-O or PE binary declares support for.
+///
+/// Returns `None` for other formats, unparseable data, or binaries that
+/// don't record one.
+pub fn detect_min_os_version(data: &[u8]) -> Option<String> {
+    let object = Object::parse(data).ok()?;
+    object_min_os_version(data, &object)
+}
+
+fn object_min_os_version(data: &[u8], object: &Object) -> Option<String> {
+    match object {
+        Object::Mach(goblin::mach::Mach::Binary(macho)) => macho_min_os_version(macho),
+        Object::Mach(goblin::mach::Mach::Fat(fat)) => {
+            // Like segment parsing, use the first architecture.
+            let arch = fat.iter_arches().next()?.ok()?;
+            let start = arch.offset as usize;
+            let slice = data.get(start..start.checked_add(arch.size as usize)?)?;
+            match Object::parse(slice).ok()? {
+                Object::Mach(goblin::mach::Mach::Binary(macho)) => macho_min_os_version(&macho),
+                _ => None,
+            }
+        }
+        Object::PE(pe) => {
+            let fields = &pe.header.optional_header.as_ref()?.windows_fields;
+            Some(format!(
+                "{}.{}",
+                fields.major_operating_system_version, fields.minor_operating_system_version
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn macho_min_os_version(macho: &goblin::mach::MachO) -> Option<String> {
+    use goblin::mach::load_command::CommandVariant;
+
+    macho
+        .load_commands
+        .iter()
+        .find_map(|command| match &command.command {
+            CommandVariant::BuildVersion(build) => Some(build.minos),
+            CommandVariant::VersionMinMacosx(min)
+            | CommandVariant::VersionMinIphoneos(min)
+            | CommandVariant::VersionMinTvos(min)
+            | CommandVariant::VersionMinWatchos(min) => Some(min.version),
+            _ => None,
+        })
+        .map(format_macho_version)
+}
+
+/// Format a Mach-O version, encoded in nibbles as xxxx.yy.zz.
+fn format_macho_version(version: u32) -> String {
+    let (major, minor, patch) = (version >> 16, (version >> 8) & 0xff, version & 0xff);
+    if patch == 0 {
+        format!("{}.{}", major, minor)
+    } else {
+        format!("{}.{}.{}", major, minor, patch)
+    }
+}
+
+/// Detect the shared libraries a binary loads at startup: ELF `DT_NEEDED`
+/// entries, Mach-O `LC_LOAD_DYLIB` commands or PE imports.
+///
+/// An ELF binary with a program interpreter but no `DT_NEEDED` entries
+/// lists the interpreter, so any dynamically linked ELF binary reports at
+/// least one dependency. Returns an empty list for static binaries and data
+/// that isn't a recognized binary.
+pub fn detect_dynamic_deps(data: &[u8]) -> Vec<String> {
+    Object::parse(data)
+        .map(|object| object_dynamic_deps(&object))
+        .unwrap_or_default()
+}
+
+fn object_dynamic_deps(object: &Object) -> Vec<String> {
+    let libraries: Vec<&str> = match object {
+        Object::Elf(elf) if elf.libraries.is_empty() => elf.interpreter.into_iter().collect(),
+        Object::Elf(elf) => elf.libraries.clone(),
+        // goblin lists the binary itself as "self" ahead of its dylibs.
+        Object::Mach(goblin::mach::Mach::Binary(macho)) => macho
+            .libs
+            .iter()
+            .copied()
+            .filter(|lib| *lib != "self")
+            .collect(),
+        Object::PE(pe) => pe.libraries.clone(),
+        _ => Vec::new(),
+    };
+    libraries.into_iter().map(str::to_string).collect()
+}
+
+/// Toolchain ABI a Windows binary was built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowsAbi {
+    /// Microsoft's toolchain (`*-pc-windows-msvc`).
+    Msvc,
+    /// MinGW-w64 (`*-pc-windows-gnu`).
+    Gnu,
+}
+
+impl WindowsAbi {
+    /// Returns the ABI's name as used in Rust target triples.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WindowsAbi::Msvc => "msvc",
+            WindowsAbi::Gnu => "gnu",
+        }
+    }
+}
+
+impl std::fmt::Display for WindowsAbi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Detect which toolchain built a PE binary.
+///
+/// Microsoft's linker writes a Rich header after the DOS stub; MinGW's
+/// doesn't, and its binaries keep a separate `.CRT` section and import the
+/// MinGW runtime DLLs. Returns `None` for other formats, and for PE
+/// binaries showing neither sign (e.g., linked with `lld-link`).
+pub fn detect_windows_abi(data: &[u8]) -> Option<WindowsAbi> {
+    let Ok(Object::PE(pe)) = Object::parse(data) else {
+        return None;
+    };
+    let sections: Vec<&str> = pe
+        .sections
+        .iter()
+        .filter_map(|section| section.name().ok())
+        .collect();
+    windows_abi(pe.header.rich_header.is_some(), &sections, &pe.libraries)
+}
+
+fn windows_abi(rich_header: bool, sections: &[&str], libraries: &[&str]) -> Option<WindowsAbi> {
+    const MINGW_LIBRARIES: [&str; 4] = ["msvcrt", "libgcc_s", "libwinpthread", "libstdc++"];
+
+    if rich_header {
+        return Some(WindowsAbi::Msvc);
+    }
+    let mingw_library = libraries.iter().any(|library| {
+        let library = library.to_ascii_lowercase();
+        MINGW_LIBRARIES.iter().any(|name| library.starts_with(name))
+    });
+    if mingw_library || sections.contains(&".CRT") {
+        Some(WindowsAbi::Gnu)
+    } else {
+        None
+    }
+}
+
+/// One architecture of a universal (fat) Mach-O binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniversalSlice<'a> {
+    /// Architecture, named as in [`ParsedBinary::arch`] for the ones BCJ
+    /// knows and by Apple's name otherwise (e.g., "i386").
+    pub arch: String,
+    /// Offset of the slice within the universal binary.
+    pub offset: u64,
+    /// The slice's bytes, a thin Mach-O binary.
+    pub data: &'a [u8],
+}
+
+/// Split a universal (fat) Mach-O binary into its architectures, in the
+/// order the fat header lists them.
+///
+/// Returns `None` if `data` isn't a fat Mach-O.
+pub fn split_universal(data: &[u8]) -> Result<Option<Vec<UniversalSlice<'_>>>> {
+    let Ok(Object::Mach(goblin::mach::Mach::Fat(fat))) = Object::parse(data) else {
+        return Ok(None);
+    };
+    let mut slices = Vec::new();
+    for arch in fat.iter_arches() {
+        let arch = arch.map_err(|e| {
+            CompressionError::Parse(format!("Failed to parse universal binary: {}", e))
+        })?;
+        let start = arch.offset as usize;
+        let slice = start
+            .checked_add(arch.size as usize)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| {
+                CompressionError::Parse(format!(
+                    "Universal binary slice at offset {} runs past the end of the file",
+                    start
+                ))
+            })?;
+        let name = match macho_arch(arch.cputype()) {
+            "unknown" | "arm" => {
+                goblin::mach::cputype::get_arch_name_from_types(arch.cputype(), arch.cpusubtype())
+                    .unwrap_or("unknown")
+            }
+            name => name,
+        };
+        slices.push(UniversalSlice {
+            arch: name.to_string(),
+            offset: start as u64,
+            data: slice,
+        });
+    }
+    Ok(Some(slices))
+}
+
+/// Build a universal (fat) Mach-O binary from thin Mach-O `slices`.
+///
+/// Slices keep their order and start at the alignment `lipo` uses: 16 KiB,
+/// the arm64 page size, for ARM slices and 4 KiB for the rest.
+pub fn build_universal(slices: &[&[u8]]) -> Result<Vec<u8>> {
+    const FAT_MAGIC: u32 = 0xcafe_babe;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&FAT_MAGIC.to_be_bytes());
+    header.extend_from_slice(&(slices.len() as u32).to_be_bytes());
+    let mut offset = 8 + 20 * slices.len();
+    let mut placed = Vec::new();
+    for slice in slices {
+        let Ok(Object::Mach(goblin::mach::Mach::Binary(macho))) = Object::parse(slice) else {
+            return Err(CompressionError::Parse(
+                "Universal binary slices must be thin Mach-O binaries".into(),
+            ));
+        };
+        let (cputype, cpusubtype) = (macho.header.cputype(), macho.header.cpusubtype());
+        let align: u32 = match macho_arch(cputype) {
+            "aarch64" | "arm" => 14,
+            _ => 12,
+        };
+        offset = offset.next_multiple_of(1 << align);
+        let (Ok(start), Ok(size)) = (u32::try_from(offset), u32::try_from(slice.len())) else {
+            return Err(CompressionError::InvalidData(
+                "Universal binary exceeds the 4 GiB fat header limit".into(),
+            ));
+        };
+        for field in [cputype, cpusubtype, start, size, align] {
+            header.extend_from_slice(&field.to_be_bytes());
+        }
+        placed.push((offset, slice));
+        offset += slice.len();
+    }
+
+    let mut data = header;
+    for (start, slice) in placed {
+        data.resize(start, 0);
+        data.extend_from_slice(slice);
+    }
+    Ok(data)
+}
+
+/// Name a Mach-O CPU type as [`ParsedBinary::arch`] does.
+fn macho_arch(cputype: goblin::mach::cputype::CpuType) -> &'static str {
+    match cputype {
+        goblin::mach::cputype::CPU_TYPE_X86_64 => "x86_64",
+        // arm64_32 (ILP32 on 64-bit ARM, used by watchOS) runs the same
+        // instruction set.
+        goblin::mach::cputype::CPU_TYPE_ARM64 | goblin::mach::cputype::CPU_TYPE_ARM64_32 => {
+            "aarch64"
+        }
+        goblin::mach::cputype::CPU_TYPE_ARM => "arm",
+        _ => "unknown",
+    }
+}
+
 /// Parse ELF binary segments.
 fn parse_elf(data: &[u8], elf: &goblin::elf::Elf) -> (Vec<Segment>, String) {
     let arch = match elf.header.e_machine {
@@ -87,7 +397,8 @@ fn parse_elf(data: &[u8], elf: &goblin::elf::Elf) -> (Vec<Segment>, String) {
     let mut segments = Vec::new();
 
     for section in &elf.section_headers {
-        if section.sh_size == 0 {
+        // NOBITS sections (.bss) occupy no bytes of the file.
+        if section.sh_size == 0 || section.sh_type == goblin::elf::section_header::SHT_NOBITS {
             continue;
         }
 
@@ -105,6 +416,7 @@ fn parse_elf(data: &[u8], elf: &goblin::elf::Elf) -> (Vec<Segment>, String) {
         }
 
         let executable = section.sh_flags & goblin::elf::section_header::SHF_EXECINSTR as u64 != 0;
+        let writable = section.sh_flags & goblin::elf::section_header::SHF_WRITE as u64 != 0;
         let hash = blake3::hash(&data[offset..offset + size]).into();
 
         segments.push(Segment {
@@ -112,6 +424,7 @@ fn parse_elf(data: &[u8], elf: &goblin::elf::Elf) -> (Vec<Segment>, String) {
             offset,
             size,
             executable,
+            writable,
             hash,
         });
     }
@@ -125,17 +438,15 @@ fn parse_mach(data: &[u8], mach: &goblin::mach::Mach) -> (Vec<Segment>, String)
         goblin::mach::Mach::Binary(macho) => parse_macho_binary(data, macho),
         goblin::mach::Mach::Fat(fat) => {
             // For fat binaries, parse the first architecture
-            if let Some(arch) = fat.iter_arches().next() {
-                if let Ok(arch) = arch {
-                    let start = arch.offset as usize;
-                    let end = start + arch.size as usize;
-                    if end <= data.len() {
-                        let slice = &data[start..end];
-                        if let Ok(Object::Mach(goblin::mach::Mach::Binary(macho))) =
-                            Object::parse(slice)
-                        {
-                            return parse_macho_binary(slice, &macho);
-                        }
+            if let Some(Ok(arch)) = fat.iter_arches().next() {
+                let start = arch.offset as usize;
+                let end = start + arch.size as usize;
+                if end <= data.len() {
+                    let slice = &data[start..end];
+                    if let Ok(Object::Mach(goblin::mach::Mach::Binary(macho))) =
+                        Object::parse(slice)
+                    {
+                        return parse_macho_binary(slice, &macho);
                     }
                 }
             }
@@ -144,27 +455,23 @@ fn parse_mach(data: &[u8], mach: &goblin::mach::Mach) -> (Vec<Segment>, String)
     }
 }
 
-fn parse_macho_binary(
-    data: &[u8],
-    macho: &goblin::mach::MachO,
-) -> (Vec<Segment>, String) {
-    let arch = match macho.header.cputype() {
-        goblin::mach::cputype::CPU_TYPE_X86_64 => "x86_64",
-        goblin::mach::cputype::CPU_TYPE_ARM64 => "aarch64",
-        goblin::mach::cputype::CPU_TYPE_ARM => "arm",
-        _ => "unknown",
-    }
-    .to_string();
+fn parse_macho_binary(data: &[u8], macho: &goblin::mach::MachO) -> (Vec<Segment>, String) {
+    let arch = macho_arch(macho.header.cputype()).to_string();
 
     let mut segments = Vec::new();
 
     for segment in &macho.segments {
+        // VM_PROT_WRITE
+        let writable = segment.initprot & 0x2 != 0;
         for (section, _) in segment.sections().unwrap_or_default() {
             let name = section.name().unwrap_or("").to_string();
             let offset = section.offset as usize;
             let size = section.size as usize;
 
-            if offset + size > data.len() || size == 0 {
+            // Zerofill sections (S_ZEROFILL, S_GB_ZEROFILL and
+            // S_THREAD_LOCAL_ZEROFILL) occupy no bytes of the file.
+            let zerofill = matches!(section.flags & 0xff, 0x1 | 0xc | 0x12);
+            if offset + size > data.len() || size == 0 || zerofill {
                 continue;
             }
 
@@ -177,6 +484,7 @@ fn parse_macho_binary(
                 offset,
                 size,
                 executable,
+                writable,
                 hash,
             });
         }
@@ -200,8 +508,9 @@ fn parse_pe(data: &[u8], pe: &goblin::pe::PE) -> (Vec<Segment>, String) {
             continue;
         }
 
-        // IMAGE_SCN_MEM_EXECUTE
+        // IMAGE_SCN_MEM_EXECUTE and IMAGE_SCN_MEM_WRITE
         let executable = section.characteristics & 0x20000000 != 0;
+        let writable = section.characteristics & 0x80000000 != 0;
         let hash = blake3::hash(&data[offset..offset + size]).into();
 
         segments.push(Segment {
@@ -209,6 +518,7 @@ fn parse_pe(data: &[u8], pe: &goblin::pe::PE) -> (Vec<Segment>, String) {
             offset,
             size,
             executable,
+            writable,
             hash,
         });
     }
@@ -244,7 +554,9 @@ pub fn estimate_savings(binaries: &[ParsedBinary]) -> usize {
         if locations.len() > 1 {
             // First occurrence is kept, rest are deduplicated
             for (bin_idx, seg_idx) in locations.iter().skip(1) {
-                if let Some(segment) = binaries.get(*bin_idx).and_then(|b| b.segments.get(*seg_idx))
+                if let Some(segment) = binaries
+                    .get(*bin_idx)
+                    .and_then(|b| b.segments.get(*seg_idx))
                 {
                     savings += segment.size;
                 }
@@ -255,10 +567,213 @@ pub fn estimate_savings(binaries: &[ParsedBinary]) -> usize {
     savings
 }
 
+/// Returns, for each binary, the byte ranges of the read-only data sections
+/// (see [`Segment::is_read_only_data`]) whose contents also appear in
+/// another section, in any of the binaries, sorted by offset.
+///
+/// Sections under [`MIN_SHARED_SECTION_SIZE`] are left out, and so is any
+/// section overlapping one earlier in the file, so the ranges can cut each
+/// binary into pieces.
+pub fn shared_sections(binaries: &[ParsedBinary]) -> Vec<Vec<Range<usize>>> {
+    let duplicates: HashSet<[u8; 32]> = find_duplicates(binaries).into_keys().collect();
+    binaries
+        .iter()
+        .map(|binary| {
+            let mut sections: Vec<&Segment> = binary
+                .segments
+                .iter()
+                .filter(|s| {
+                    s.is_read_only_data()
+                        && s.size >= MIN_SHARED_SECTION_SIZE
+                        && duplicates.contains(&s.hash)
+                })
+                .collect();
+            sections.sort_by_key(|s| (s.offset, s.size));
+
+            let mut ranges: Vec<Range<usize>> = Vec::new();
+            for section in sections {
+                if ranges.last().is_none_or(|last| last.end <= section.offset) {
+                    ranges.push(section.range());
+                }
+            }
+            ranges
+        })
+        .collect()
+}
+
+/// Returns the byte ranges of the executable sections of the binary in
+/// `data`, sorted, with overlapping or adjacent ones merged, for BCJ
+/// filtering only code. `None` if goblin can't parse the binary or it has
+/// no executable sections.
+pub fn executable_ranges(data: &[u8]) -> Option<Vec<Range<usize>>> {
+    let (segments, ..) = object_info(data).ok()?;
+    let mut sections: Vec<Range<usize>> = segments
+        .iter()
+        .filter(|s| s.executable && s.size > 0)
+        .map(Segment::range)
+        .collect();
+    sections.sort_by_key(|r| (r.start, r.end));
+
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for section in sections {
+        match ranges.last_mut() {
+            Some(last) if section.start <= last.end => last.end = last.end.max(section.end),
+            _ => ranges.push(section),
+        }
+    }
+    (!ranges.is_empty()).then_some(ranges)
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
+    /// An x86_64 ELF executable holding `sections` (name, flags, contents)
+    /// after `prefix`, each aligned to 16 bytes with 0xcc padding, then the
+    /// section name table and section headers.
+    pub(crate) fn elf_with_sections(prefix: &[u8], sections: &[(&str, u64, &[u8])]) -> Vec<u8> {
+        use goblin::elf::section_header::{SHT_PROGBITS, SHT_STRTAB};
+
+        let mut data = vec![0; 64];
+        data.extend_from_slice(prefix);
+        let mut names = vec![0u8];
+        // (name offset, type, flags, offset, size) of each section after the
+        // null one.
+        let mut headers = Vec::new();
+        for (name, flags, contents) in sections {
+            while !data.len().is_multiple_of(16) {
+                data.push(0xcc);
+            }
+            headers.push((
+                names.len(),
+                SHT_PROGBITS,
+                *flags,
+                data.len(),
+                contents.len(),
+            ));
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+            data.extend_from_slice(contents);
+        }
+        let strtab_name = names.len();
+        names.extend_from_slice(b".shstrtab\0");
+        headers.push((strtab_name, SHT_STRTAB, 0, data.len(), names.len()));
+        data.extend_from_slice(&names);
+        while !data.len().is_multiple_of(8) {
+            data.push(0);
+        }
+
+        let shoff = data.len() as u64;
+        data.extend_from_slice(&[0; 64]);
+        for (name, kind, flags, offset, size) in &headers {
+            data.extend_from_slice(&(*name as u32).to_le_bytes());
+            data.extend_from_slice(&kind.to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            data.extend_from_slice(&0u64.to_le_bytes());
+            data.extend_from_slice(&(*offset as u64).to_le_bytes());
+            data.extend_from_slice(&(*size as u64).to_le_bytes());
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&1u64.to_le_bytes());
+            data.extend_from_slice(&0u64.to_le_bytes());
+        }
+
+        let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+        header.resize(16, 0);
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&goblin::elf::header::EM_X86_64.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&shoff.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        for field in [
+            64u16,
+            56,
+            0,
+            64,
+            headers.len() as u16 + 1,
+            headers.len() as u16,
+        ] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        data[..64].copy_from_slice(&header);
+        data
+    }
+
+    #[test]
+    fn test_shared_sections() {
+        use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE};
+
+        let alloc = SHF_ALLOC as u64;
+        let (exec, write) = (alloc | SHF_EXECINSTR as u64, alloc | SHF_WRITE as u64);
+        let rodata = vec![0x42; 1000];
+        let data = vec![0x17; 1000];
+        let linux = elf_with_sections(
+            b"linux",
+            &[
+                (".text", exec, &[0x90; 300]),
+                (".rodata", alloc, &rodata),
+                (".data", write, &data),
+                (".tiny", alloc, &[1; 16]),
+            ],
+        );
+        let freebsd = elf_with_sections(
+            b"freebsd prefix",
+            &[
+                (".text", exec, &[0xc3; 555]),
+                (".data", write, &data),
+                (".rodata", alloc, &rodata),
+                (".tiny", alloc, &[1; 16]),
+            ],
+        );
+        let binaries = vec![
+            ParsedBinary::parse("linux-x86_64", linux).unwrap(),
+            ParsedBinary::parse("freebsd-x86_64", freebsd).unwrap(),
+            ParsedBinary::parse_or_opaque("wasi-wasm32", b"\0asm?".to_vec()),
+        ];
+
+        // Only .rodata: .data is writable and .tiny too small to pay off.
+        let sections = shared_sections(&binaries);
+        assert_eq!(sections.len(), 3);
+        for (binary, ranges) in binaries.iter().zip(&sections) {
+            if binary.segments.is_empty() {
+                assert!(ranges.is_empty());
+                continue;
+            }
+            assert_eq!(ranges.len(), 1, "{}", binary.target);
+            assert_eq!(&binary.data[ranges[0].clone()], &rodata[..]);
+        }
+        assert_ne!(sections[0][0].start, sections[1][0].start);
+    }
+
+    #[test]
+    fn test_executable_ranges() {
+        use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR};
+
+        let alloc = SHF_ALLOC as u64;
+        let exec = alloc | SHF_EXECINSTR as u64;
+        let binary = elf_with_sections(
+            b"",
+            &[
+                (".init", exec, &[0x90; 32]),
+                (".text", exec, &[0xc3; 300]),
+                (".rodata", alloc, &[0xe8; 500]),
+                (".fini", exec, &[0x90; 16]),
+            ],
+        );
+
+        // .init and .text are back to back, so they merge.
+        let ranges = executable_ranges(&binary).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(ranges[0].len(), 332);
+        assert!(binary[ranges[0].clone()].iter().all(|&b| b != 0xe8));
+        assert_eq!(&binary[ranges[1].clone()], &[0x90; 16]);
+
+        let data_only = elf_with_sections(b"", &[(".rodata", alloc, &[1; 64])]);
+        assert_eq!(executable_ranges(&data_only), None);
+        assert_eq!(executable_ranges(b"not a binary"), None);
+    }
+
     #[test]
     fn test_segment_hash() {
         let data1 = vec![1, 2, 3, 4, 5];
@@ -273,6 +788,170 @@ mod tests {
         assert_ne!(hash1, hash3);
     }
 
+    #[test]
+    fn test_macho_min_os_version() {
+        // A 64-bit arm64 executable header with a single LC_BUILD_VERSION
+        // command: platform macOS, minos 13.0, sdk 14.2, no tools.
+        let mut data = Vec::new();
+        for word in [0xfeed_facf_u32, 0x0100_000c, 0, 2, 1, 24, 0, 0] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        for word in [0x32_u32, 24, 1, 0x000d_0000, 0x000e_0200, 0] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+
+        assert_eq!(detect_min_os_version(&data).as_deref(), Some("13.0"));
+
+        // The same header with LC_VERSION_MIN_WATCHOS 9.1 instead.
+        data.truncate(32);
+        for word in [0x30_u32, 16, 0x0009_0100, 0x000a_0000] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data[20..24].copy_from_slice(&16_u32.to_le_bytes());
+        assert_eq!(detect_min_os_version(&data).as_deref(), Some("9.1"));
+        assert_eq!(format_macho_version(0x000b_0601), "11.6.1");
+        assert_eq!(detect_min_os_version(b"not a binary"), None);
+    }
+
+    #[test]
+    fn test_windows_abi() {
+        let msvc_sections = [".text", ".rdata", ".data", ".pdata", ".reloc"];
+        let msvc_libraries = ["KERNEL32.dll", "VCRUNTIME140.dll"];
+        assert_eq!(
+            windows_abi(true, &msvc_sections, &msvc_libraries),
+            Some(WindowsAbi::Msvc)
+        );
+        assert_eq!(windows_abi(false, &msvc_sections, &msvc_libraries), None);
+
+        let gnu_sections = [".text", ".data", ".rdata", ".pdata", ".CRT", ".tls"];
+        assert_eq!(
+            windows_abi(false, &gnu_sections, &["KERNEL32.dll"]),
+            Some(WindowsAbi::Gnu)
+        );
+        assert_eq!(
+            windows_abi(false, &msvc_sections, &["KERNEL32.dll", "msvcrt.dll"]),
+            Some(WindowsAbi::Gnu)
+        );
+        assert_eq!(
+            windows_abi(false, &msvc_sections, &["libgcc_s_seh-1.dll"]),
+            Some(WindowsAbi::Gnu)
+        );
+        assert_eq!(detect_windows_abi(b"not a binary"), None);
+    }
+
+    /// Builds a fat Mach-O header listing `arches` (CPU type, subtype and
+    /// contents), followed by their contents.
+    fn fat_macho(arches: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&0xcafe_babe_u32.to_be_bytes());
+        data.extend_from_slice(&(arches.len() as u32).to_be_bytes());
+        let mut offset = 8 + 20 * arches.len();
+        for (cputype, cpusubtype, contents) in arches {
+            for field in [
+                *cputype,
+                *cpusubtype,
+                offset as u32,
+                contents.len() as u32,
+                0,
+            ] {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            offset += contents.len();
+        }
+        for (_, _, contents) in arches {
+            data.extend_from_slice(contents);
+        }
+        data
+    }
+
+    #[test]
+    fn test_split_universal() {
+        use goblin::mach::cputype::{CPU_TYPE_ARM64, CPU_TYPE_X86, CPU_TYPE_X86_64};
+
+        let fat = fat_macho(&[
+            (CPU_TYPE_X86_64, 3, b"intel slice"),
+            (CPU_TYPE_ARM64, 0, b"arm slice"),
+            (CPU_TYPE_X86, 3, b"i386"),
+        ]);
+        let slices = split_universal(&fat).unwrap().unwrap();
+        for slice in &slices {
+            let start = slice.offset as usize;
+            assert_eq!(&fat[start..start + slice.data.len()], slice.data);
+        }
+        let slices: Vec<(&str, &[u8])> = slices
+            .iter()
+            .map(|slice| (slice.arch.as_str(), slice.data))
+            .collect();
+        assert_eq!(
+            slices,
+            [
+                ("x86_64", &b"intel slice"[..]),
+                ("aarch64", &b"arm slice"[..]),
+                ("i386", &b"i386"[..]),
+            ]
+        );
+
+        assert!(split_universal(b"\x7FELF\x02\x01\x01\x00")
+            .unwrap()
+            .is_none());
+
+        let mut truncated = fat.clone();
+        truncated.truncate(fat.len() - 1);
+        assert!(split_universal(&truncated).is_err());
+    }
+
+    /// A thin 64-bit Mach-O executable with no load commands.
+    fn thin_macho(cputype: u32, cpusubtype: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in [0xfeed_facf, cputype, cpusubtype, 2, 0, 0, 0, 0] {
+            data.extend_from_slice(&u32::to_le_bytes(field));
+        }
+        data
+    }
+
+    #[test]
+    fn test_build_universal() {
+        use goblin::mach::cputype::{CPU_TYPE_ARM64, CPU_TYPE_X86_64};
+
+        let intel = thin_macho(CPU_TYPE_X86_64, 3);
+        let arm = thin_macho(CPU_TYPE_ARM64, 0);
+        let fat = build_universal(&[&intel, &arm]).unwrap();
+
+        let Ok(Object::Mach(goblin::mach::Mach::Fat(multi))) = Object::parse(&fat) else {
+            panic!("not a fat binary");
+        };
+        let arches = multi.arches().unwrap();
+        assert_eq!(arches.len(), 2);
+        assert_eq!(
+            (arches[0].cputype(), arches[0].offset, arches[0].align),
+            (CPU_TYPE_X86_64, 4096, 12)
+        );
+        assert_eq!(
+            (arches[1].cputype(), arches[1].offset, arches[1].align),
+            (CPU_TYPE_ARM64, 16384, 14)
+        );
+
+        let slices = split_universal(&fat).unwrap().unwrap();
+        assert_eq!(slices[0].data, intel.as_slice());
+        assert_eq!(slices[1].data, arm.as_slice());
+
+        assert!(build_universal(&[b"not a mach-o binary"]).is_err());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn test_dynamic_deps_detected() {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let deps = detect_dynamic_deps(&exe);
+
+        assert!(
+            deps.iter().any(|dep| dep.starts_with("libc.so")),
+            "{:?}",
+            deps
+        );
+        assert!(detect_dynamic_deps(b"not a binary").is_empty());
+    }
+
     #[test]
     fn test_find_duplicates() {
         // Create mock binaries with some duplicate segments
@@ -286,6 +965,7 @@ mod tests {
                         offset: 0,
                         size: 100,
                         executable: true,
+                        writable: false,
                         hash: [1; 32],
                     },
                     Segment {
@@ -293,9 +973,12 @@ mod tests {
                         offset: 100,
                         size: 50,
                         executable: false,
+                        writable: true,
                         hash: [2; 32], // Same as darwin
                     },
                 ],
+                min_os_version: None,
+                dynamic_deps: Vec::new(),
                 data: vec![0; 150],
             },
             ParsedBinary {
@@ -307,6 +990,7 @@ mod tests {
                         offset: 0,
                         size: 100,
                         executable: true,
+                        writable: false,
                         hash: [3; 32], // Different
                     },
                     Segment {
@@ -314,9 +998,12 @@ mod tests {
                         offset: 100,
                         size: 50,
                         executable: false,
+                        writable: true,
                         hash: [2; 32], // Same as linux
                     },
                 ],
+                min_os_version: None,
+                dynamic_deps: Vec::new(),
                 data: vec![0; 150],
             },
         ];
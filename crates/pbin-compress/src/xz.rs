@@ -0,0 +1,57 @@
+//! XZ compression.
+//!
+//! Slower than zstd but usually several percent smaller on executables,
+//! for packs where size matters more than startup time. Data is stored as
+//! a single XZ stream, which the `xz` command-line tool reads, so the stub
+//! can decompress it.
+
+use crate::{CompressionError, Result};
+use std::io::{Read, Write};
+
+/// Compress data into an XZ stream with the given preset (0-9).
+pub fn compress(data: &[u8], preset: u32) -> Result<Vec<u8>> {
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), preset);
+    encoder
+        .write_all(data)
+        .map_err(|e| CompressionError::Xz(format!("Compression failed: {}", e)))?;
+    encoder
+        .finish()
+        .map_err(|e| CompressionError::Xz(format!("Compression failed: {}", e)))
+}
+
+/// Decompress an XZ stream.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    xz2::read::XzDecoder::new(data)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| CompressionError::Decompression(format!("Decompression failed: {}", e)))?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_decompress() {
+        let data = b"Hello, World! This is test data for compression.".repeat(20);
+
+        for preset in [0, 6, 9] {
+            let compressed = compress(&data, preset).unwrap();
+            assert!(compressed.len() < data.len());
+            // XZ stream magic, which the xz tool checks for.
+            assert_eq!(compressed[..6], [0xFD, b'7', b'z', b'X', b'Z', 0x00]);
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_stream_rejected() {
+        let mut compressed = compress(b"some data", 6).unwrap();
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0xFF;
+
+        assert!(decompress(&compressed).is_err());
+        assert!(decompress(b"not xz").is_err());
+    }
+}
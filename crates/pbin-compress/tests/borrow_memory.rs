@@ -0,0 +1,137 @@
+//! Measures the memory `compress_all` holds beyond its inputs, with an
+//! allocator that counts each thread's allocations. It replaces the global
+//! allocator, so it runs as its own test binary rather than with the unit
+//! tests.
+
+use pbin_compress::{CompressionLevel, CompressionPipeline};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Allocator counting the bytes each thread holds, so a test can
+/// measure the memory work on its own thread peaks at. Only Rust
+/// allocations count; zstd allocates its own buffers.
+struct CountingAllocator;
+
+thread_local! {
+    /// Bytes this thread has allocated and not freed, and the most it
+    /// has held since the last [`peak_memory`].
+    static HELD: Cell<(isize, isize)> = const { Cell::new((0, 0)) };
+}
+
+fn track(change: isize) {
+    // Allocations while the thread is torn down go uncounted.
+    let _ = HELD.try_with(|held| {
+        let (now, peak) = held.get();
+        held.set((now + change, peak.max(now + change)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // SAFETY: the caller's guarantees for `layout` carry over.
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            track(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `ptr` was allocated by `System` with `layout`.
+        unsafe { System.dealloc(ptr, layout) };
+        track(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // SAFETY: `ptr` was allocated by `System` with `layout`.
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            track(new_size as isize - layout.size() as isize);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f`, returning its result with the most memory the thread held
+/// meanwhile beyond what it held before.
+fn peak_memory<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = HELD.with(|held| {
+        let (now, _) = held.get();
+        held.set((now, now));
+        now
+    });
+    let result = f();
+    let peak = HELD.with(|held| held.get().1);
+    (result, (peak - start) as usize)
+}
+
+fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 32) as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_inputs_borrowed() {
+    const LEN: usize = 4 << 20;
+    // Bytes below 0x40 hold no x86 or AArch64 branch, so the filters
+    // leave them be and the repeats compress to almost nothing.
+    let block: Vec<u8> = random_bytes(64 * 1024, 620)
+        .iter()
+        .map(|b| b & 0x3F)
+        .collect();
+    let binaries: Vec<(String, Vec<u8>)> = [
+        "linux-x86_64",
+        "linux-aarch64",
+        "darwin-x86_64",
+        "wasi-wasm32",
+    ]
+    .iter()
+    .enumerate()
+    .map(|(i, target)| {
+        let mut data = block.repeat(LEN / block.len());
+        data[..8].copy_from_slice(&(i as u64).to_le_bytes());
+        (target.to_string(), data)
+    })
+    .collect();
+    let total = binaries.len() * LEN;
+    let pipeline = || {
+        CompressionPipeline::new(CompressionLevel::Fast)
+            .without_delta()
+            .without_dict()
+    };
+
+    // Nothing is filtered, so nothing is copied: the pipeline holds
+    // little more than the compressed entries.
+    let (unfiltered, peak) =
+        peak_memory(|| pipeline().without_bcj().compress_all(&binaries).unwrap());
+    assert!(peak < total / 8, "{} of {}", peak, total);
+    assert_eq!(unfiltered.stats.original_size, total);
+
+    // Only the three filtered binaries are copied.
+    let (filtered, peak) = peak_memory(|| pipeline().compress_all(&binaries).unwrap());
+    assert_eq!(filtered.stats.bcj_filtered, 3);
+    assert!(peak < 3 * LEN + total / 8, "{} of {}", peak, total);
+
+    // Borrowed slices give the same entries as owned binaries.
+    let borrowed: Vec<(String, &[u8])> = binaries
+        .iter()
+        .map(|(target, data)| (target.clone(), data.as_slice()))
+        .collect();
+    let result = pipeline().compress_all(&borrowed).unwrap();
+    assert_eq!(result.entries.len(), filtered.entries.len());
+    for (entry, expected) in result.entries.iter().zip(&filtered.entries) {
+        assert_eq!(entry.target, expected.target);
+        assert_eq!(entry.data, expected.data, "{}", entry.target);
+        assert_eq!(entry.bcj, expected.bcj);
+    }
+}
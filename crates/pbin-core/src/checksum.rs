@@ -0,0 +1,223 @@
+//! BLAKE3 checksums of entry data, plain or keyed.
+
+use crate::{Error, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read};
+
+/// A BLAKE3 checksum.
+///
+/// Displayed and serialized as 64 lowercase hex characters, the form the
+/// manifest has always stored. Comparisons run in constant time.
+#[derive(Clone, Copy, Eq)]
+pub struct Checksum([u8; 32]);
+
+impl Checksum {
+    /// Hashes `data`.
+    pub fn of(data: &[u8]) -> Self {
+        Self(*blake3::hash(data).as_bytes())
+    }
+
+    /// Hashes everything read from `reader`.
+    pub fn of_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut hasher = blake3::Hasher::new();
+        io::copy(&mut reader, &mut hasher)?;
+        Ok(Self(*hasher.finalize().as_bytes()))
+    }
+
+    /// Hashes `data` with BLAKE3's keyed mode, which needs `key` to
+    /// reproduce.
+    pub fn keyed(key: &ChecksumKey, data: &[u8]) -> Self {
+        Self(*blake3::keyed_hash(&key.0, data).as_bytes())
+    }
+
+    /// Returns a hasher that gives [`of`](Self::of) what it's fed, or
+    /// [`keyed`](Self::keyed) with `key`, for data too large to hold at
+    /// once.
+    pub fn hasher(key: Option<&ChecksumKey>) -> blake3::Hasher {
+        match key {
+            Some(key) => blake3::Hasher::new_keyed(&key.0),
+            None => blake3::Hasher::new(),
+        }
+    }
+
+    /// Returns the raw checksum bytes.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Returns true if `data` hashes to this checksum.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        *self == Self::of(data)
+    }
+}
+
+/// Secret key for keyed entry checksums.
+///
+/// A file packed with a key stores keyed BLAKE3 hashes as its entries'
+/// `checksum`, so rewriting an entry along with its manifest isn't enough to
+/// pass verification without the key. The manifest records only the key's
+/// [`fingerprint`](Self::fingerprint).
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChecksumKey([u8; 32]);
+
+impl ChecksumKey {
+    /// Derives a key from key material of any length, such as the contents
+    /// of a key file.
+    pub fn derive(material: &[u8]) -> Self {
+        Self(blake3::derive_key("pbin 2024 entry checksum key", material))
+    }
+
+    /// Returns the value recorded in manifests to identify this key.
+    pub fn fingerprint(&self) -> Checksum {
+        Checksum(blake3::derive_key(
+            "pbin 2024 checksum key fingerprint",
+            &self.0,
+        ))
+    }
+}
+
+impl From<[u8; 32]> for ChecksumKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl fmt::Debug for ChecksumKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChecksumKey(fingerprint {})", self.fingerprint())
+    }
+}
+
+impl From<[u8; 32]> for Checksum {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<blake3::Hash> for Checksum {
+    fn from(hash: blake3::Hash) -> Self {
+        Self(*hash.as_bytes())
+    }
+}
+
+impl PartialEq for Checksum {
+    fn eq(&self, other: &Self) -> bool {
+        // blake3::Hash compares in constant time.
+        blake3::Hash::from(self.0) == blake3::Hash::from(other.0)
+    }
+}
+
+impl Hash for Checksum {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(blake3::Hash::from(self.0).to_hex().as_str())
+    }
+}
+
+impl fmt::Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum({})", self)
+    }
+}
+
+impl std::str::FromStr for Checksum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        blake3::Hash::from_hex(s)
+            .map(Self::from)
+            .map_err(|_| Error::InvalidChecksum(s.to_string()))
+    }
+}
+
+impl Serialize for Checksum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let checksum = Checksum::of(b"hello");
+        let hex = checksum.to_string();
+
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex, blake3::hash(b"hello").to_hex().as_str());
+        assert_eq!(hex.parse::<Checksum>().unwrap(), checksum);
+    }
+
+    #[test]
+    fn test_invalid_hex_rejected() {
+        for hex in ["", "abc", &"zz".repeat(32), &"00".repeat(33)] {
+            assert!(matches!(
+                hex.parse::<Checksum>(),
+                Err(Error::InvalidChecksum(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_serializes_as_hex_string() {
+        let checksum = Checksum::from([0xab; 32]);
+        let json = serde_json::to_string(&checksum).unwrap();
+
+        assert_eq!(json, format!("\"{}\"", "ab".repeat(32)));
+        assert_eq!(serde_json::from_str::<Checksum>(&json).unwrap(), checksum);
+        assert!(serde_json::from_str::<Checksum>("\"nope\"").is_err());
+    }
+
+    #[test]
+    fn test_keyed_checksum() {
+        let key = ChecksumKey::derive(b"key file contents");
+        let checksum = Checksum::keyed(&key, b"data");
+
+        assert_eq!(
+            checksum.as_bytes(),
+            blake3::keyed_hash(&key.0, b"data").as_bytes()
+        );
+        assert_ne!(checksum, Checksum::of(b"data"));
+        for key in [Some(&key), None] {
+            let mut hasher = Checksum::hasher(key);
+            hasher.update(b"da").update(b"ta");
+            let expected = key.map_or(Checksum::of(b"data"), |key| Checksum::keyed(key, b"data"));
+            assert_eq!(Checksum::from(hasher.finalize()), expected);
+        }
+        assert_ne!(
+            checksum,
+            Checksum::keyed(&ChecksumKey::derive(b"other"), b"data")
+        );
+        assert_ne!(
+            key.fingerprint(),
+            ChecksumKey::derive(b"other").fingerprint()
+        );
+        assert!(!format!("{:?}", key).contains(&Checksum::from(key.0).to_string()));
+    }
+
+    #[test]
+    fn test_of_reader_matches_of() {
+        let data = vec![7u8; 100_000];
+        let checksum = Checksum::of_reader(&data[..]).unwrap();
+
+        assert_eq!(checksum, Checksum::of(&data));
+        assert!(checksum.matches(&data));
+        assert!(!checksum.matches(b"other"));
+    }
+}
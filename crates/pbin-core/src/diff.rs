@@ -0,0 +1,295 @@
+//! Comparing two manifests.
+
+use crate::{PbinEntry, PbinManifest};
+use std::fmt;
+
+/// One entry compared between two manifests.
+#[derive(Debug, Clone)]
+pub struct EntryDiff {
+    /// Identifies the entry, as [`PbinEntry::label`].
+    pub key: String,
+    /// The entry in the old manifest, if it was there.
+    pub old: Option<PbinEntry>,
+    /// The entry in the new manifest, if it is there.
+    pub new: Option<PbinEntry>,
+}
+
+impl EntryDiff {
+    /// Returns the change in uncompressed size. Missing entries count as
+    /// zero bytes.
+    pub fn size_delta(&self) -> i64 {
+        let size = |entry: &Option<PbinEntry>| entry.as_ref().map_or(0, |e| e.uncompressed_size);
+        size(&self.new) as i64 - size(&self.old) as i64
+    }
+
+    /// Returns the change in stored size. Missing entries count as zero
+    /// bytes.
+    pub fn compressed_size_delta(&self) -> i64 {
+        let size = |entry: &Option<PbinEntry>| entry.as_ref().map_or(0, |e| e.compressed_size);
+        size(&self.new) as i64 - size(&self.old) as i64
+    }
+
+    /// Returns true if the entry is in both manifests with different
+    /// uncompressed contents.
+    pub fn content_changed(&self) -> bool {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => {
+                old.checksum != new.checksum || old.uncompressed_size != new.uncompressed_size
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns true if the entry is in both manifests and its contents are
+    /// stored differently (compression, filters or stored bytes).
+    pub fn storage_changed(&self) -> bool {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => {
+                old.compression != new.compression
+                    || old.bcj != new.bcj
+                    || old.bcj_ranges != new.bcj_ranges
+                    || old.delta_reference != new.delta_reference
+                    || old.dict_id != new.dict_id
+                    || old.window_log != new.window_log
+                    || old.chunks != new.chunks
+                    || old.compressed_size != new.compressed_size
+                    || old.compressed_checksum != new.compressed_checksum
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a one-word description of the change.
+    fn status(&self) -> &'static str {
+        match (&self.old, &self.new) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ if self.content_changed() => "changed",
+            _ if self.storage_changed() => "repacked",
+            _ => "unchanged",
+        }
+    }
+}
+
+/// The differences between two manifests, from an old one to a new one.
+///
+/// Each list keeps the order entries appear in: the old manifest's order,
+/// then the new manifest's for added entries.
+#[derive(Debug, Clone, Default)]
+pub struct ManifestDiff {
+    /// Entries only in the new manifest.
+    pub added: Vec<EntryDiff>,
+    /// Entries only in the old manifest.
+    pub removed: Vec<EntryDiff>,
+    /// Entries in both whose contents or storage differ.
+    pub changed: Vec<EntryDiff>,
+    /// Entries in both that are identical.
+    pub unchanged: Vec<EntryDiff>,
+}
+
+impl ManifestDiff {
+    /// Compares `old` with `new`.
+    pub fn between(old: &PbinManifest, new: &PbinManifest) -> Self {
+        let mut diff = Self::default();
+        for entry in &old.entries {
+            let key = entry.label();
+            let new_entry = new.entries.iter().find(|e| e.label() == key);
+            let entry_diff = EntryDiff {
+                key,
+                old: Some(entry.clone()),
+                new: new_entry.cloned(),
+            };
+            if new_entry.is_none() {
+                diff.removed.push(entry_diff);
+            } else if entry_diff.content_changed() || entry_diff.storage_changed() {
+                diff.changed.push(entry_diff);
+            } else {
+                diff.unchanged.push(entry_diff);
+            }
+        }
+        for entry in &new.entries {
+            let key = entry.label();
+            if !old.entries.iter().any(|e| e.label() == key) {
+                diff.added.push(EntryDiff {
+                    key,
+                    old: None,
+                    new: Some(entry.clone()),
+                });
+            }
+        }
+        diff
+    }
+
+    /// Returns true if nothing was added, removed or changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Returns the change in total uncompressed size.
+    pub fn size_delta(&self) -> i64 {
+        self.entries().map(EntryDiff::size_delta).sum()
+    }
+
+    /// Returns the change in total stored size.
+    pub fn compressed_size_delta(&self) -> i64 {
+        self.entries().map(EntryDiff::compressed_size_delta).sum()
+    }
+
+    /// Iterates over every compared entry.
+    fn entries(&self) -> impl Iterator<Item = &EntryDiff> {
+        self.added
+            .iter()
+            .chain(&self.removed)
+            .chain(&self.changed)
+            .chain(&self.unchanged)
+    }
+}
+
+impl fmt::Display for ManifestDiff {
+    /// Renders a table of every entry with its sizes before and after.
+    ///
+    /// "repacked" marks entries whose contents are the same but whose
+    /// stored bytes differ, e.g. after a compression change.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = |entry: &Option<PbinEntry>| {
+            entry
+                .as_ref()
+                .map_or("-".to_string(), |e| e.uncompressed_size.to_string())
+        };
+        writeln!(
+            f,
+            "{:<28} {:<10} {:>12} {:>12} {:>10} {:>12}",
+            "Entry", "Status", "Old size", "New size", "Delta", "Stored delta"
+        )?;
+        for entry in self.entries() {
+            writeln!(
+                f,
+                "{:<28} {:<10} {:>12} {:>12} {:>+10} {:>+12}",
+                entry.key,
+                entry.status(),
+                size(&entry.old),
+                size(&entry.new),
+                entry.size_delta(),
+                entry.compressed_size_delta()
+            )?;
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} changed, {} unchanged; {:+} bytes ({:+} stored)",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len(),
+            self.unchanged.len(),
+            self.size_delta(),
+            self.compressed_size_delta()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Checksum, Compression, Target};
+
+    fn entry(target: Target, data: &[u8], stored_size: u64) -> PbinEntry {
+        PbinEntry::new(
+            target,
+            0,
+            stored_size,
+            data.len() as u64,
+            Checksum::of(data),
+            Checksum::of(&data[..stored_size.min(data.len() as u64) as usize]),
+        )
+    }
+
+    fn manifest(entries: Vec<PbinEntry>) -> PbinManifest {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        for entry in entries {
+            manifest.add_entry(entry);
+        }
+        manifest
+    }
+
+    fn keys(entries: &[EntryDiff]) -> Vec<&str> {
+        entries.iter().map(|e| e.key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_diff_classifies_entries() {
+        let old = manifest(vec![
+            entry(Target::LinuxX86_64, b"same", 4),
+            entry(Target::LinuxAarch64, b"old arm", 7),
+            entry(Target::DarwinAarch64, b"mac", 3),
+        ]);
+        let new = manifest(vec![
+            entry(Target::LinuxX86_64, b"same", 4),
+            entry(Target::LinuxAarch64, b"new arm build", 13),
+            entry(Target::WindowsX86_64, b"windows", 7),
+        ]);
+
+        let diff = old.diff(&new);
+        assert_eq!(keys(&diff.added), ["windows-x86_64"]);
+        assert_eq!(keys(&diff.removed), ["darwin-aarch64"]);
+        assert_eq!(keys(&diff.changed), ["linux-aarch64"]);
+        assert_eq!(keys(&diff.unchanged), ["linux-x86_64"]);
+        assert!(diff.changed[0].content_changed());
+        assert_eq!(diff.changed[0].size_delta(), 6);
+        assert_eq!(diff.size_delta(), 6 + 7 - 3);
+        assert!(!diff.is_empty());
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn test_diff_compression_change_is_storage_only() {
+        let data = b"binary contents";
+        let old = manifest(vec![entry(Target::LinuxX86_64, data, 15)]);
+        let new = manifest(vec![
+            entry(Target::LinuxX86_64, data, 9).with_compression(Compression::Zstd)
+        ]);
+
+        let diff = old.diff(&new);
+        assert_eq!(keys(&diff.changed), ["linux-x86_64"]);
+        assert!(!diff.changed[0].content_changed());
+        assert!(diff.changed[0].storage_changed());
+        assert_eq!(diff.changed[0].size_delta(), 0);
+        assert_eq!(diff.changed[0].compressed_size_delta(), -6);
+        assert!(diff.to_string().contains("repacked"));
+    }
+
+    #[test]
+    fn test_diff_zero_length_entries() {
+        let old = manifest(vec![
+            entry(Target::LinuxX86_64, b"", 0),
+            entry(Target::LinuxAarch64, b"", 0),
+        ]);
+        let new = manifest(vec![
+            entry(Target::LinuxX86_64, b"", 0),
+            entry(Target::LinuxAarch64, b"now has data", 12),
+        ]);
+
+        let diff = old.diff(&new);
+        assert_eq!(keys(&diff.unchanged), ["linux-x86_64"]);
+        assert_eq!(keys(&diff.changed), ["linux-aarch64"]);
+        assert_eq!(diff.changed[0].size_delta(), 12);
+    }
+
+    #[test]
+    fn test_diff_display() {
+        let old = manifest(vec![entry(Target::LinuxX86_64, b"v1", 2)]);
+        let new = manifest(vec![
+            entry(Target::LinuxX86_64, b"v2 longer", 9),
+            PbinEntry::new_asset("data.txt", 0, 4, 4, [1; 32], [1; 32]),
+        ]);
+
+        let table = old.diff(&new).to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("Entry"));
+        assert!(lines[1].starts_with("asset:data.txt") && lines[1].contains("added"));
+        assert!(lines[2].starts_with("linux-x86_64"));
+        assert!(lines[2].contains("changed") && lines[2].contains("+7"));
+        assert_eq!(
+            lines[3],
+            "1 added, 0 removed, 1 changed, 0 unchanged; +11 bytes (+11 stored)"
+        );
+    }
+}
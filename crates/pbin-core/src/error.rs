@@ -20,6 +20,10 @@ pub enum Error {
     #[error("unknown compression type: {0}")]
     UnknownCompression(u8),
 
+    /// Invalid compression name.
+    #[error("invalid compression: {0}")]
+    InvalidCompression(String),
+
     /// Invalid target string.
     #[error("invalid target: {0}")]
     InvalidTarget(String),
@@ -36,6 +40,137 @@ pub enum Error {
     #[error("checksum mismatch: expected {expected}, got {actual}")]
     ChecksumMismatch { expected: String, actual: String },
 
+    /// File has keyed checksums but no key was given to verify them.
+    #[error("file has keyed checksums (key fingerprint {fingerprint}); its checksum key is needed to verify it")]
+    ChecksumKeyRequired { fingerprint: String },
+
+    /// Given checksum key isn't the one the file was packed with.
+    #[error("wrong checksum key: file was packed with key fingerprint {expected}, got {actual}")]
+    WrongChecksumKey { expected: String, actual: String },
+
+    /// A checksum key was given but the file's checksums aren't keyed.
+    #[error("file has no keyed checksums, but a checksum key was given")]
+    ChecksumsNotKeyed,
+
+    /// Checksum is not 64 hex characters.
+    #[error("invalid checksum {0:?}: expected 64 hex characters")]
+    InvalidChecksum(String),
+
+    /// Manifest version is not a semantic version.
+    #[error("invalid version {version:?}: {source}")]
+    InvalidVersion {
+        version: String,
+        source: semver::Error,
+    },
+
+    /// Header following a payload marker doesn't parse.
+    #[error("invalid header at offset {offset}: {source}")]
+    InvalidHeader { offset: u64, source: Box<Error> },
+
+    /// File ends before the manifest the header describes.
+    #[error("manifest at offset {offset} truncated: expected {expected} bytes, got {got}")]
+    ManifestTruncated {
+        offset: u64,
+        expected: u64,
+        got: u64,
+    },
+
+    /// Manifest JSON doesn't parse. `offset` is the file position where
+    /// parsing failed.
+    #[error("invalid manifest JSON at offset {offset}: {source}")]
+    InvalidManifest {
+        offset: u64,
+        source: serde_json::Error,
+    },
+
+    /// Entry data lies outside the file. `offset` is the file position the
+    /// data would start at.
+    #[error("entry {target} at offset {offset} with size {size} exceeds file length {file_len}")]
+    EntryOutOfBounds {
+        target: String,
+        offset: u64,
+        size: u64,
+        file_len: u64,
+    },
+
+    /// Entry data doesn't decompress to the size the manifest declares.
+    ///
+    /// Decompression stops one byte past `declared`, so for oversized data
+    /// `actual` is `declared + 1` rather than the full size.
+    #[error("size mismatch: manifest declares {declared} bytes, data has {actual}")]
+    SizeMismatch { declared: u64, actual: u64 },
+
+    /// Entry declares more data than the reader accepts.
+    #[error("entry {target} declares {size} bytes, over the limit of {limit}")]
+    EntryTooLarge {
+        target: String,
+        size: u64,
+        limit: u64,
+    },
+
+    /// Entry has no checksum of the requested kind.
+    #[error("entry {0} has no compressed checksum")]
+    MissingChecksum(String),
+
+    /// Entry file name is not a single plain path component.
+    #[error("unsafe file name {0:?}: must be a single path component")]
+    UnsafeFileName(String),
+
+    /// Two entries for the same program and target, or two assets with the
+    /// same name.
+    #[error("duplicate target: {0}")]
+    DuplicateTarget(String),
+
+    /// More entries than the header's one-byte count can record.
+    #[error("too many entries: {0}, a file holds at most 255")]
+    TooManyEntries(usize),
+
+    /// Two entries' data overlaps without being the same payload.
+    #[error("entries {first} and {second} overlap without sharing identical data")]
+    OverlappingEntries { first: String, second: String },
+
+    /// Entry references a dictionary the file doesn't embed.
+    #[error("entry {target} needs dictionary {index}, which is not embedded")]
+    MissingDictionary { target: String, index: usize },
+
+    /// Embedded dictionary is not the one an entry was compressed with.
+    #[error("entry {target} needs dictionary {expected}, but the embedded dictionary is {actual}")]
+    DictionaryMismatch {
+        target: String,
+        expected: String,
+        actual: String,
+    },
+
+    /// Running OS is older than an entry's minimum version.
+    #[error("{target} binary requires {os} >= {required}, you have {current}")]
+    OsVersionTooOld {
+        target: String,
+        os: String,
+        required: String,
+        current: String,
+    },
+
+    /// Entry can't be appended because the file keeps its manifest in front
+    /// of the payloads.
+    #[error("entries can only be appended to files with a trailing manifest")]
+    NotAppendable,
+
+    /// Appended entry has the same key as an existing one.
+    #[error("entry {0} already exists")]
+    EntryExists(String),
+
+    /// Replaced entry is the base of another entry's delta patch.
+    #[error("entry {key} can't be replaced: {dependent} is a delta against it")]
+    EntryReferenced { key: String, dependent: String },
+
+    /// Chunked entry whose chunk list doesn't fit the chunk store.
+    #[error("entry {target} has invalid chunks: {reason}")]
+    InvalidChunks { target: String, reason: String },
+
+    /// Entry whose BCJ ranges don't fit its binary.
+    #[error("entry {target} has invalid BCJ ranges: {reason}")]
+    InvalidBcjRanges { target: String, reason: String },
+
     /// Header too short.
     #[error("header too short: expected at least {expected} bytes, got {actual}")]
     HeaderTooShort { expected: usize, actual: usize },
@@ -51,4 +186,122 @@ pub enum Error {
     /// Current platform not supported.
     #[error("current platform is not supported")]
     UnsupportedPlatform,
+
+    /// The selected entry is a WASI module and no runtime is installed.
+    #[error(
+        "running the wasi-wasm32 build needs a WebAssembly runtime: install wasmtime or wasmer"
+    )]
+    WasmRuntimeNotFound,
+
+    /// Compressing or decompressing data failed.
+    #[error("compression error: {0}")]
+    Compression(String),
+
+    /// Input given to a tool is unusable, e.g. a binary failing a check the
+    /// user asked for.
+    #[error("{0}")]
+    InvalidInput(String),
+}
+
+/// Broad kind of an [`Error`], for callers that handle failures by category
+/// rather than by variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// Reading or writing failed.
+    Io,
+    /// The file is malformed or fails verification.
+    Corrupt,
+    /// The file is valid but can't be used here: a newer format, an unknown
+    /// method, or no binary for this platform.
+    Unsupported,
+    /// Compressing or decompressing failed.
+    Compression,
+    /// Arguments or input files are unusable.
+    Invalid,
+}
+
+impl ErrorCategory {
+    /// Returns the process exit code for this category, following the BSD
+    /// `sysexits.h` values.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Invalid => 64,
+            ErrorCategory::Corrupt => 65,
+            ErrorCategory::Unsupported => 69,
+            ErrorCategory::Compression => 70,
+            ErrorCategory::Io => 74,
+        }
+    }
+}
+
+impl Error {
+    /// Returns the error's category.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::Io(_) => ErrorCategory::Io,
+            Error::InvalidMagic(_)
+            | Error::PayloadMarkerNotFound
+            | Error::ChecksumMismatch { .. }
+            | Error::ChecksumsNotKeyed
+            | Error::ManifestTruncated { .. }
+            | Error::InvalidManifest { .. }
+            | Error::EntryOutOfBounds { .. }
+            | Error::SizeMismatch { .. }
+            | Error::MissingChecksum(_)
+            | Error::OverlappingEntries { .. }
+            | Error::MissingDictionary { .. }
+            | Error::DictionaryMismatch { .. }
+            | Error::InvalidChunks { .. }
+            | Error::InvalidBcjRanges { .. }
+            | Error::HeaderTooShort { .. }
+            | Error::Json(_) => ErrorCategory::Corrupt,
+            Error::InvalidHeader { source, .. } => source.category(),
+            Error::UnsupportedVersion(_)
+            | Error::UnknownCompression(_)
+            | Error::TargetNotFound(_)
+            | Error::EntryTooLarge { .. }
+            | Error::OsVersionTooOld { .. }
+            | Error::NotAppendable
+            | Error::UnsupportedPlatform
+            | Error::WasmRuntimeNotFound => ErrorCategory::Unsupported,
+            Error::Compression(_) => ErrorCategory::Compression,
+            Error::InvalidTarget(_)
+            | Error::InvalidCompression(_)
+            | Error::InvalidChecksum(_)
+            | Error::ChecksumKeyRequired { .. }
+            | Error::WrongChecksumKey { .. }
+            | Error::InvalidVersion { .. }
+            | Error::UnsafeFileName(_)
+            | Error::DuplicateTarget(_)
+            | Error::TooManyEntries(_)
+            | Error::EntryExists(_)
+            | Error::EntryReferenced { .. }
+            | Error::InvalidInput(_) => ErrorCategory::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_categories() {
+        let io = Error::Io(std::io::Error::other("disk"));
+        assert_eq!(io.category(), ErrorCategory::Io);
+        assert_eq!(
+            Error::PayloadMarkerNotFound.category(),
+            ErrorCategory::Corrupt
+        );
+        assert_eq!(
+            Error::UnsupportedPlatform.category().exit_code(),
+            ErrorCategory::Unsupported.exit_code()
+        );
+
+        let header = Error::InvalidHeader {
+            offset: 100,
+            source: Box::new(Error::UnsupportedVersion(9)),
+        };
+        assert_eq!(header.category(), ErrorCategory::Unsupported);
+    }
 }
@@ -0,0 +1,419 @@
+//! Reading complete PBIN files.
+
+use crate::header::{locate_payload_header, HEADER_SIZE};
+use crate::{
+    ChecksumKey, Compression, Error, PbinDictionary, PbinEntry, PbinHeader, PbinManifest, Result,
+};
+use std::path::Path;
+
+/// Default for [`PbinFile::max_entry_size`]: 2 GiB.
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 2 << 30;
+
+/// A PBIN file loaded into memory.
+#[derive(Debug, Clone)]
+pub struct PbinFile {
+    /// Raw file contents, including the stub.
+    data: Vec<u8>,
+    /// Byte offset of the header within `data`.
+    header_offset: usize,
+    /// Parsed header.
+    header: PbinHeader,
+    /// Parsed manifest.
+    manifest: PbinManifest,
+    /// Largest uncompressed size an entry may declare.
+    max_entry_size: u64,
+    /// Key the entries' checksums are keyed with, if the file has one.
+    checksum_key: Option<ChecksumKey>,
+}
+
+impl PbinFile {
+    /// Parses a PBIN file from its raw bytes.
+    ///
+    /// Header and manifest errors carry the file offset of the problem.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self> {
+        let (header_offset, header) = locate_payload_header(&data)?;
+
+        let manifest_start = header
+            .manifest_start(header_offset)
+            .expect("located headers have a manifest within the file");
+        let manifest_end = manifest_start + header.manifest_size as usize;
+        let json = &data[manifest_start..manifest_end];
+        let manifest = PbinManifest::from_json_bytes(json).map_err(|e| match e {
+            Error::Json(source) => Error::InvalidManifest {
+                offset: (manifest_start + json_error_position(json, &source)) as u64,
+                source,
+            },
+            other => other,
+        })?;
+
+        Ok(Self {
+            data,
+            header_offset,
+            header,
+            manifest,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+            checksum_key: None,
+        })
+    }
+
+    /// Reads and parses a PBIN file from disk.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(std::fs::read(path)?)
+    }
+
+    /// Sets the largest uncompressed size an entry may declare, bounding the
+    /// memory extraction can use. Lower it when reading untrusted files.
+    pub fn with_max_entry_size(mut self, limit: u64) -> Self {
+        self.max_entry_size = limit;
+        self
+    }
+
+    /// Returns the largest uncompressed size an entry may declare.
+    pub fn max_entry_size(&self) -> u64 {
+        self.max_entry_size
+    }
+
+    /// Checks that `entry` declares no more than
+    /// [`max_entry_size`](Self::max_entry_size) bytes.
+    pub fn check_entry_size(&self, entry: &PbinEntry) -> Result<()> {
+        if entry.uncompressed_size > self.max_entry_size {
+            return Err(Error::EntryTooLarge {
+                target: entry.key(),
+                size: entry.uncompressed_size,
+                limit: self.max_entry_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Sets the key to check keyed entry checksums with (see
+    /// [`PbinManifest::key_fingerprint`]). Files with keyed checksums can
+    /// only be verified, and so extracted, with their key.
+    pub fn with_checksum_key(mut self, key: ChecksumKey) -> Self {
+        self.checksum_key = Some(key);
+        self
+    }
+
+    /// Returns the key set with [`with_checksum_key`](Self::with_checksum_key).
+    pub fn checksum_key(&self) -> Option<&ChecksumKey> {
+        self.checksum_key.as_ref()
+    }
+
+    /// Checks `data`, extracted from `entry`, against its checksum, keyed
+    /// with [`checksum_key`](Self::checksum_key) (see
+    /// [`PbinManifest::verify_entry`]).
+    pub fn verify_entry(&self, entry: &PbinEntry, data: &[u8]) -> Result<()> {
+        self.manifest
+            .verify_entry(entry, data, self.checksum_key.as_ref())
+    }
+
+    /// Returns the parsed header.
+    pub fn header(&self) -> &PbinHeader {
+        &self.header
+    }
+
+    /// Returns the byte offset of the header within the file.
+    pub fn header_offset(&self) -> usize {
+        self.header_offset
+    }
+
+    /// Returns the file position that entry and dictionary offsets count
+    /// from: the end of the manifest, or 0 for files with absolute offsets.
+    pub fn payload_offset(&self) -> usize {
+        self.header.payload_base(self.header_offset)
+    }
+
+    /// Returns the file position of the manifest.
+    pub fn manifest_start(&self) -> usize {
+        self.header
+            .manifest_start(self.header_offset)
+            .expect("located headers have a manifest within the file")
+    }
+
+    /// Returns the parsed manifest.
+    pub fn manifest(&self) -> &PbinManifest {
+        &self.manifest
+    }
+
+    /// Returns the raw file contents.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the compression used by an entry, taking the header's
+    /// compression for entries that don't record their own.
+    pub fn entry_compression(&self, entry: &PbinEntry) -> Compression {
+        entry.effective_compression(self.header.compression)
+    }
+
+    /// Returns the stored (possibly compressed) bytes of an entry.
+    pub fn entry_data(&self, entry: &PbinEntry) -> Result<&[u8]> {
+        self.region(entry.target.as_str(), entry.offset, entry.compressed_size)
+    }
+
+    /// Returns the bytes of the dictionary at `index` in the manifest.
+    ///
+    /// Only that dictionary is read, so callers extracting one entry don't pay
+    /// for the others.
+    pub fn dictionary(&self, index: usize) -> Result<Option<&[u8]>> {
+        match self.manifest.dictionaries.get(index) {
+            Some(dictionary) => self
+                .region("dictionary", dictionary.offset, dictionary.size)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the stored bytes of the chunk store, if the file has one.
+    pub fn chunk_store_data(&self) -> Result<Option<&[u8]>> {
+        match &self.manifest.chunk_store {
+            Some(store) => self
+                .region("chunk store", store.offset, store.compressed_size)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the dictionary an entry was compressed with.
+    ///
+    /// Both the manifest's dictionary ID and the hash of the stored bytes
+    /// must match the entry's `dict_id`, so a swapped or corrupted
+    /// dictionary is rejected before it reaches the decompressor.
+    pub fn entry_dictionary(&self, entry: &PbinEntry) -> Result<Option<&[u8]>> {
+        let Some(index) = entry.dict_index else {
+            return Ok(None);
+        };
+        let (Some(dictionary), Some(data)) = (
+            self.manifest.dictionaries.get(index),
+            self.dictionary(index)?,
+        ) else {
+            return Err(Error::MissingDictionary {
+                target: entry.target.to_string(),
+                index,
+            });
+        };
+
+        let expected = entry.dict_id.as_ref().unwrap_or(&dictionary.dict_id);
+        for actual in [dictionary.dict_id.clone(), PbinDictionary::id_for(data)] {
+            if &actual != expected {
+                return Err(Error::DictionaryMismatch {
+                    target: entry.target.to_string(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        Ok(Some(data))
+    }
+
+    /// Appends `entry` with its stored bytes to a file with a trailing
+    /// manifest.
+    ///
+    /// The data is written where the manifest was, followed by the updated
+    /// manifest, and the header is rewritten in place; the bytes before
+    /// [`manifest_start`](Self::manifest_start) are left untouched, so a
+    /// caller can persist the change by rewriting the header and everything
+    /// from the old manifest position onward. `entry`'s offset and
+    /// compressed size are filled in here.
+    ///
+    /// An entry with the same key (or, for assets, the same name) is
+    /// rejected unless `replace` is set, in which case it is dropped from the
+    /// manifest. Its bytes stay in the file unreferenced.
+    pub fn append_entry(
+        &mut self,
+        mut entry: PbinEntry,
+        stored: &[u8],
+        replace: bool,
+    ) -> Result<()> {
+        if !self.header.has_trailing_manifest() {
+            return Err(Error::NotAppendable);
+        }
+
+        let key = entry.key();
+        let same = |other: &PbinEntry| {
+            if entry.is_asset() {
+                other.is_asset() && other.file_name == entry.file_name
+            } else {
+                !other.is_asset() && other.key() == key
+            }
+        };
+        if self.manifest.entries.iter().any(same) {
+            if !replace {
+                return Err(Error::EntryExists(key));
+            }
+            if let Some(dependent) = self
+                .manifest
+                .entries
+                .iter()
+                .find(|e| e.delta_reference.as_deref() == Some(key.as_str()))
+            {
+                return Err(Error::EntryReferenced {
+                    key,
+                    dependent: dependent.key(),
+                });
+            }
+        }
+
+        let mut manifest = self.manifest.clone();
+        manifest.entries.retain(|other| !same(other));
+
+        let manifest_start = self.manifest_start();
+        entry.offset = (manifest_start - self.payload_offset()) as u64;
+        entry.compressed_size = stored.len() as u64;
+        manifest.add_entry(entry);
+        manifest.validate()?;
+        let json = manifest.to_json()?;
+
+        let mut header = self.header.clone();
+        header.entry_count = manifest.entry_count()?;
+        header.manifest_size = json.len() as u32;
+        header.manifest_offset += stored.len() as u64;
+
+        self.data.truncate(manifest_start);
+        self.data.extend_from_slice(stored);
+        self.data.extend_from_slice(json.as_bytes());
+        let header_end = self.header_offset + HEADER_SIZE;
+        self.data[self.header_offset..header_end].copy_from_slice(&header.to_bytes());
+        self.header = header;
+        self.manifest = manifest;
+        Ok(())
+    }
+
+    /// Returns `size` bytes at manifest offset `offset`, checking they lie
+    /// within the file.
+    fn region(&self, name: &str, offset: u64, size: u64) -> Result<&[u8]> {
+        let start = (offset as usize).checked_add(self.payload_offset());
+        let end = start.and_then(|start| start.checked_add(size as usize));
+        match (start, end) {
+            (Some(start), Some(end)) if end <= self.data.len() => Ok(&self.data[start..end]),
+            _ => Err(Error::EntryOutOfBounds {
+                target: name.to_string(),
+                offset: offset.saturating_add(self.payload_offset() as u64),
+                size,
+                file_len: self.data.len() as u64,
+            }),
+        }
+    }
+}
+
+/// Returns the byte position within `json` of a parse error, from the line
+/// and column serde_json reports.
+fn json_error_position(json: &[u8], error: &serde_json::Error) -> usize {
+    let line_start = json
+        .split_inclusive(|&b| b == b'\n')
+        .take(error.line().saturating_sub(1))
+        .map(<[u8]>::len)
+        .sum::<usize>();
+    (line_start + error.column().saturating_sub(1)).min(json.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Checksum, Target, PAYLOAD_MARKER};
+
+    const DATA: &[u8] = b"binary data";
+
+    /// A file with a stub that mentions the marker, then one entry.
+    /// Returns the bytes and the header's offset.
+    fn packed_file() -> (Vec<u8>, usize) {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            0,
+            DATA.len() as u64,
+            DATA.len() as u64,
+            Checksum::of(DATA),
+            Checksum::of(DATA),
+        ));
+        let json = manifest.to_json().unwrap();
+
+        let mut data = b"grep -abo __PBIN_PAYLOAD__ \"$0\"\nexit\n".to_vec();
+        data.extend_from_slice(PAYLOAD_MARKER);
+        let header_offset = data.len();
+        let header = PbinHeader::new(Compression::None, 1, json.len() as u32);
+        data.extend_from_slice(&header.with_relative_offsets().to_bytes());
+        data.extend_from_slice(json.as_bytes());
+        data.extend_from_slice(DATA);
+        (data, header_offset)
+    }
+
+    #[test]
+    fn test_corrupt_magic_reports_header_offset() {
+        let (mut data, header_offset) = packed_file();
+        data[header_offset + 1] = b'X';
+
+        assert!(matches!(
+            PbinFile::from_bytes(data),
+            Err(Error::InvalidHeader { offset, source })
+                if offset == header_offset as u64 && matches!(*source, Error::InvalidMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_version_reports_header_offset() {
+        let (mut data, header_offset) = packed_file();
+        data[header_offset + 4] = 0xFF;
+
+        assert!(matches!(
+            PbinFile::from_bytes(data),
+            Err(Error::InvalidHeader { offset, source })
+                if offset == header_offset as u64
+                    && matches!(*source, Error::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_manifest_reports_offset() {
+        let (mut data, header_offset) = packed_file();
+        let manifest_start = header_offset + HEADER_SIZE;
+        let manifest_size = data.len() - DATA.len() - manifest_start;
+        data.truncate(manifest_start + 10);
+
+        assert!(matches!(
+            PbinFile::from_bytes(data),
+            Err(Error::ManifestTruncated { offset, expected, got: 10 })
+                if offset == manifest_start as u64 && expected == manifest_size as u64
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_manifest_reports_offset() {
+        let (mut data, header_offset) = packed_file();
+        let manifest_start = header_offset + HEADER_SIZE;
+        let position = data[manifest_start..]
+            .windows(9)
+            .position(|w| w == b"\"version\"")
+            .unwrap()
+            + 9;
+        data[manifest_start + position] = b'#';
+
+        assert!(matches!(
+            PbinFile::from_bytes(data),
+            Err(Error::InvalidManifest { offset, .. })
+                if offset == (manifest_start + position) as u64
+        ));
+    }
+
+    #[test]
+    fn test_out_of_bounds_entry_reports_file_offset() {
+        let (mut data, _) = packed_file();
+        data.truncate(data.len() - 1);
+        let file = PbinFile::from_bytes(data).unwrap();
+        let entry = &file.manifest().entries[0];
+
+        assert!(matches!(
+            file.entry_data(entry),
+            Err(Error::EntryOutOfBounds { offset, .. })
+                if offset == file.payload_offset() as u64
+        ));
+    }
+
+    #[test]
+    fn test_missing_marker() {
+        assert!(matches!(
+            PbinFile::from_bytes(b"#!/bin/sh\nexit\n".to_vec()),
+            Err(Error::PayloadMarkerNotFound)
+        ));
+    }
+}
@@ -12,6 +12,28 @@ pub const PBIN_VERSION: u16 = 1;
 /// Header size in bytes.
 pub const HEADER_SIZE: usize = 64;
 
+/// Header flag: entry and dictionary offsets are relative to the start of
+/// the payload region rather than to the start of the file.
+///
+/// Without it offsets are absolute, as in files written before the flag was
+/// introduced.
+pub const FLAG_RELATIVE_OFFSETS: u32 = 1 << 0;
+
+/// Header flag: the manifest follows the payload region, at the header's
+/// `manifest_offset`, instead of sitting between the header and the
+/// payloads.
+///
+/// New entries can then be written where the old manifest was, followed by
+/// an updated manifest, without moving any existing data.
+pub const FLAG_TRAILING_MANIFEST: u32 = 1 << 1;
+
+/// Header flag: some entries are stored as lists of chunks from the
+/// manifest's `chunk_store` rather than as payloads of their own.
+///
+/// Readers that don't know the flag can still read the manifest, but must
+/// not extract an entry that records `chunks`.
+pub const FLAG_CHUNKED: u32 = 1 << 2;
+
 /// Payload marker string.
 pub const PAYLOAD_MARKER: &[u8] = b"__PBIN_PAYLOAD__";
 
@@ -28,8 +50,11 @@ pub struct PbinHeader {
     pub entry_count: u8,
     /// Size of the JSON manifest.
     pub manifest_size: u32,
-    /// Reserved flags.
+    /// Flags such as [`FLAG_RELATIVE_OFFSETS`].
     pub flags: u32,
+    /// Position of a trailing manifest, counted from the end of the header.
+    /// Zero unless [`FLAG_TRAILING_MANIFEST`] is set.
+    pub manifest_offset: u64,
 }
 
 impl PbinHeader {
@@ -42,6 +67,67 @@ impl PbinHeader {
             entry_count,
             manifest_size,
             flags: 0,
+            manifest_offset: 0,
+        }
+    }
+
+    /// Marks the manifest's offsets as relative to the end of the manifest.
+    pub fn with_relative_offsets(mut self) -> Self {
+        self.flags |= FLAG_RELATIVE_OFFSETS;
+        self
+    }
+
+    /// Places the manifest after the payload region, `manifest_offset` bytes
+    /// past the end of the header. Offsets become relative to the end of the
+    /// header.
+    pub fn with_trailing_manifest(mut self, manifest_offset: u64) -> Self {
+        self.flags |= FLAG_RELATIVE_OFFSETS | FLAG_TRAILING_MANIFEST;
+        self.manifest_offset = manifest_offset;
+        self
+    }
+
+    /// Marks the file as storing entries as chunks (see [`FLAG_CHUNKED`]).
+    pub fn with_chunks(mut self) -> Self {
+        self.flags |= FLAG_CHUNKED;
+        self
+    }
+
+    /// Returns true if the manifest's offsets are relative to the start of
+    /// the payload region.
+    pub fn has_relative_offsets(&self) -> bool {
+        self.flags & FLAG_RELATIVE_OFFSETS != 0
+    }
+
+    /// Returns true if the manifest follows the payload region.
+    pub fn has_trailing_manifest(&self) -> bool {
+        self.flags & FLAG_TRAILING_MANIFEST != 0
+    }
+
+    /// Returns true if entries may be stored as chunks.
+    pub fn has_chunks(&self) -> bool {
+        self.flags & FLAG_CHUNKED != 0
+    }
+
+    /// Returns the file position of the manifest, for a header located at
+    /// `header_offset`, or None if a trailing manifest's offset puts it past
+    /// any position a file can have.
+    pub fn manifest_start(&self, header_offset: usize) -> Option<usize> {
+        let header_end = header_offset.checked_add(HEADER_SIZE)?;
+        if self.has_trailing_manifest() {
+            header_end.checked_add(usize::try_from(self.manifest_offset).ok()?)
+        } else {
+            Some(header_end)
+        }
+    }
+
+    /// Returns the file position that manifest offsets count from, for a
+    /// header located at `header_offset`: the start of the payload region,
+    /// or 0 for absolute offsets.
+    pub fn payload_base(&self, header_offset: usize) -> usize {
+        match (self.has_relative_offsets(), self.has_trailing_manifest()) {
+            (false, _) => 0,
+            (true, false) => header_offset + HEADER_SIZE + self.manifest_size as usize,
+            (true, true) => header_offset + HEADER_SIZE,
         }
     }
 
@@ -68,6 +154,7 @@ impl PbinHeader {
         let entry_count = bytes[7];
         let manifest_size = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
         let flags = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        let manifest_offset = u64::from_le_bytes(bytes[16..24].try_into().unwrap());
 
         Ok(Self {
             magic,
@@ -76,6 +163,7 @@ impl PbinHeader {
             entry_count,
             manifest_size,
             flags,
+            manifest_offset,
         })
     }
 
@@ -95,7 +183,8 @@ impl PbinHeader {
         bytes[7] = self.entry_count;
         bytes[8..12].copy_from_slice(&self.manifest_size.to_le_bytes());
         bytes[12..16].copy_from_slice(&self.flags.to_le_bytes());
-        // bytes[16..64] are reserved (zeros)
+        bytes[16..24].copy_from_slice(&self.manifest_offset.to_le_bytes());
+        // bytes[24..64] are reserved (zeros)
         bytes
     }
 
@@ -106,8 +195,208 @@ impl PbinHeader {
     }
 }
 
-/// Finds the payload marker in a byte slice and returns its offset.
-pub fn find_payload_marker(data: &[u8]) -> Option<usize> {
-    data.windows(PAYLOAD_MARKER.len())
+/// Locates the PBIN header that follows the payload marker.
+///
+/// Embedded binaries may themselves contain the marker bytes (anything linked
+/// against pbin-core has the constant compiled in, and the stub mentions it in
+/// its own search commands), so every occurrence is considered and the first
+/// one immediately followed by a header that parses and whose manifest fits in
+/// `data` wins. Returns the offset of the header and the parsed header.
+pub fn find_payload_header(data: &[u8]) -> Option<(usize, PbinHeader)> {
+    locate_payload_header(data).ok()
+}
+
+/// Locates the PBIN header like [`find_payload_header`], explaining a
+/// failure.
+///
+/// When no marker is followed by a usable header, the error describes the
+/// candidate that came closest to one (a truncated manifest over a bad
+/// version over a bad magic), with the file offset of the problem.
+pub fn locate_payload_header(data: &[u8]) -> Result<(usize, PbinHeader)> {
+    // The closest failed candidate so far, ranked by how far parsing got:
+    // magic bytes matched (0-3), then version (4), compression (5) and the
+    // manifest's bounds (6).
+    let mut closest: Option<(usize, Error)> = None;
+    let mut start = 0;
+    while let Some(pos) = data[start..]
+        .windows(PAYLOAD_MARKER.len())
         .position(|window| window == PAYLOAD_MARKER)
+    {
+        let header_offset = start + pos + PAYLOAD_MARKER.len();
+        let (progress, error) = match PbinHeader::from_bytes(&data[header_offset..]) {
+            Ok(header) => {
+                let manifest_start = header.manifest_start(header_offset);
+                let manifest_end = manifest_start
+                    .and_then(|start| start.checked_add(header.manifest_size as usize));
+                if manifest_end.is_some_and(|end| end <= data.len()) {
+                    return Ok((header_offset, header));
+                }
+                // An offset too large for a position is past the end too.
+                let offset = manifest_start.map_or(
+                    ((header_offset + HEADER_SIZE) as u64).saturating_add(header.manifest_offset),
+                    |start| start as u64,
+                );
+                let error = Error::ManifestTruncated {
+                    offset,
+                    expected: header.manifest_size as u64,
+                    got: (data.len() as u64).saturating_sub(offset),
+                };
+                (6, error)
+            }
+            Err(error) => {
+                let progress = match &error {
+                    Error::UnknownCompression(_) => 5,
+                    Error::UnsupportedVersion(_) => 4,
+                    // A header with a damaged magic still shares some bytes
+                    // with it, unlike the stub's own mentions of the marker.
+                    Error::InvalidMagic(magic) => magic
+                        .iter()
+                        .zip(PBIN_MAGIC)
+                        .filter(|(a, b)| **a == *b)
+                        .count(),
+                    _ => 0,
+                };
+                let error = Error::InvalidHeader {
+                    offset: header_offset as u64,
+                    source: Box::new(error),
+                };
+                (progress, error)
+            }
+        };
+        if closest.as_ref().is_none_or(|(best, _)| progress > *best) {
+            closest = Some((progress, error));
+        }
+        start += pos + 1;
+    }
+    Err(closest.map_or(Error::PayloadMarkerNotFound, |(_, error)| error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packed_file(payload: &[u8]) -> (Vec<u8>, usize) {
+        let manifest = br#"{"name":"t","version":"1","entries":[]}"#;
+        let mut data = b"grep -abo __PBIN_PAYLOAD__ \"$0\"\n".to_vec();
+        data.extend_from_slice(PAYLOAD_MARKER);
+        let header_offset = data.len();
+        let header = PbinHeader::new(Compression::None, 1, manifest.len() as u32);
+        data.extend_from_slice(&header.to_bytes());
+        data.extend_from_slice(manifest);
+        data.extend_from_slice(payload);
+        (data, header_offset)
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = PbinHeader::new(Compression::Zstd, 3, 1234);
+        let parsed = PbinHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(parsed.compression, Compression::Zstd);
+        assert_eq!(parsed.entry_count, 3);
+        assert_eq!(parsed.manifest_size, 1234);
+        assert!(!parsed.has_relative_offsets());
+    }
+
+    #[test]
+    fn test_relative_offsets_flag() {
+        let header = PbinHeader::new(Compression::None, 1, 100).with_relative_offsets();
+        let parsed = PbinHeader::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(parsed.flags, FLAG_RELATIVE_OFFSETS);
+        assert!(parsed.has_relative_offsets());
+        assert_eq!(parsed.payload_base(20), 20 + HEADER_SIZE + 100);
+        assert_eq!(
+            PbinHeader::new(Compression::None, 1, 100).payload_base(20),
+            0
+        );
+    }
+
+    #[test]
+    fn test_chunked_flag() {
+        let header = PbinHeader::new(Compression::Zstd, 2, 100)
+            .with_relative_offsets()
+            .with_chunks();
+        let parsed = PbinHeader::from_bytes(&header.to_bytes()).unwrap();
+
+        assert_eq!(parsed.flags, FLAG_RELATIVE_OFFSETS | FLAG_CHUNKED);
+        assert!(parsed.has_chunks());
+        assert!(!PbinHeader::new(Compression::Zstd, 2, 100).has_chunks());
+    }
+
+    #[test]
+    fn test_trailing_manifest_roundtrip() {
+        let header = PbinHeader::new(Compression::None, 1, 100).with_trailing_manifest(5000);
+        let parsed = PbinHeader::from_bytes(&header.to_bytes()).unwrap();
+
+        assert!(parsed.has_trailing_manifest());
+        assert!(parsed.has_relative_offsets());
+        assert_eq!(parsed.manifest_offset, 5000);
+        assert_eq!(parsed.manifest_start(20), Some(20 + HEADER_SIZE + 5000));
+        assert_eq!(parsed.payload_base(20), 20 + HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_trailing_manifest_must_fit() {
+        let mut data = PAYLOAD_MARKER.to_vec();
+        let header = PbinHeader::new(Compression::None, 1, 2).with_trailing_manifest(10);
+        data.extend_from_slice(&header.to_bytes());
+        data.extend_from_slice(&[0; 11]);
+        assert!(find_payload_header(&data).is_none());
+
+        data.push(0);
+        assert!(find_payload_header(&data).is_some());
+    }
+
+    #[test]
+    fn test_huge_manifest_offset() {
+        for manifest_offset in [
+            u64::MAX,
+            u64::MAX - HEADER_SIZE as u64,
+            usize::MAX as u64 / 2,
+        ] {
+            let mut data = PAYLOAD_MARKER.to_vec();
+            let header =
+                PbinHeader::new(Compression::None, 1, 2).with_trailing_manifest(manifest_offset);
+            data.extend_from_slice(&header.to_bytes());
+            data.extend_from_slice(&[0; 16]);
+            let err = locate_payload_header(&data).unwrap_err();
+            assert!(
+                matches!(err, Error::ManifestTruncated { got: 0, .. }),
+                "{}",
+                err
+            );
+            assert!(crate::PbinFile::from_bytes(data).is_err());
+        }
+    }
+
+    #[test]
+    fn test_marker_inside_payload() {
+        // A payload that embeds the marker, once bare and once followed by
+        // something that looks like a header.
+        let mut payload = b"\x7fELF....".to_vec();
+        payload.extend_from_slice(PAYLOAD_MARKER);
+        payload.extend_from_slice(b"PBIN");
+        payload.extend_from_slice(PAYLOAD_MARKER);
+        payload.extend_from_slice(&PbinHeader::new(Compression::None, 1, 0).to_bytes());
+
+        let (data, header_offset) = packed_file(&payload);
+        let (found, header) = find_payload_header(&data).unwrap();
+        assert_eq!(found, header_offset);
+        assert_eq!(header.entry_count, 1);
+    }
+
+    #[test]
+    fn test_marker_without_header() {
+        let mut data = b"echo ".to_vec();
+        data.extend_from_slice(PAYLOAD_MARKER);
+        data.extend_from_slice(b"not a header");
+        assert!(find_payload_header(&data).is_none());
+    }
+
+    #[test]
+    fn test_manifest_must_fit() {
+        let mut data = PAYLOAD_MARKER.to_vec();
+        data.extend_from_slice(&PbinHeader::new(Compression::None, 1, 4096).to_bytes());
+        assert!(find_payload_header(&data).is_none());
+    }
 }
@@ -2,15 +2,34 @@
 //!
 //! Provides format parsing, manifest handling, and target detection for PBIN files.
 
+mod checksum;
+mod diff;
 mod error;
+mod file;
 mod header;
 mod manifest;
+#[cfg(feature = "runner")]
+pub mod runner;
+mod summary;
 mod target;
 
-pub use error::{Error, Result};
-pub use header::{PbinHeader, PAYLOAD_MARKER, PBIN_MAGIC, PBIN_VERSION};
-pub use manifest::{Compression, PbinEntry, PbinManifest};
-pub use target::Target;
+pub use checksum::{Checksum, ChecksumKey};
+pub use diff::{EntryDiff, ManifestDiff};
+pub use error::{Error, ErrorCategory, Result};
+pub use file::{PbinFile, DEFAULT_MAX_ENTRY_SIZE};
+pub use header::{
+    find_payload_header, locate_payload_header, PbinHeader, FLAG_CHUNKED, FLAG_RELATIVE_OFFSETS,
+    FLAG_TRAILING_MANIFEST, HEADER_SIZE, PAYLOAD_MARKER, PBIN_MAGIC, PBIN_VERSION,
+};
+pub use manifest::{
+    validate_file_name, Compression, EntryKind, PbinChunkStore, PbinDictionary, PbinEntry,
+    PbinManifest, Selection, ASSET_TARGET,
+};
+pub use summary::Summary;
+pub use target::{Arch, Emulation, EntryTarget, Os, PlatformTier, Target};
 
 /// Re-export blake3 for checksum verification.
 pub use blake3;
+
+/// Re-export semver for comparing manifest versions.
+pub use semver;
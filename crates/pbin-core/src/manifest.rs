@@ -1,18 +1,28 @@
 //! PBIN manifest structures and serialization.
 
-use crate::{Error, Result, Target};
+use crate::{
+    Checksum, ChecksumKey, Emulation, EntryTarget, Error, ManifestDiff, Result, Summary, Target,
+};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Component, Path};
+use std::str::FromStr;
 
 /// Compression algorithm used for payloads.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Compression {
     /// No compression.
     None,
     /// Zstandard compression.
+    #[default]
     Zstd,
     /// LZ4 compression.
     Lz4,
+    /// XZ (LZMA2) compression.
+    Xz,
+    /// Brotli compression.
+    Brotli,
 }
 
 impl Compression {
@@ -22,6 +32,8 @@ impl Compression {
             Compression::None => 0,
             Compression::Zstd => 1,
             Compression::Lz4 => 2,
+            Compression::Xz => 3,
+            Compression::Brotli => 4,
         }
     }
 
@@ -31,30 +43,160 @@ impl Compression {
             0 => Ok(Compression::None),
             1 => Ok(Compression::Zstd),
             2 => Ok(Compression::Lz4),
+            3 => Ok(Compression::Xz),
+            4 => Ok(Compression::Brotli),
             _ => Err(Error::UnknownCompression(b)),
         }
     }
+
+    /// Returns the name used in manifests and on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+            Compression::Xz => "xz",
+            Compression::Brotli => "brotli",
+        }
+    }
+
+    /// Returns all compression types.
+    pub fn all() -> &'static [Compression] {
+        &[
+            Compression::None,
+            Compression::Zstd,
+            Compression::Lz4,
+            Compression::Xz,
+            Compression::Brotli,
+        ]
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    /// Parses the names [`Compression::as_str`] produces.
+    fn from_str(s: &str) -> Result<Self> {
+        Compression::all()
+            .iter()
+            .find(|c| c.as_str() == s)
+            .copied()
+            .ok_or_else(|| Error::InvalidCompression(s.to_string()))
+    }
 }
 
-impl Default for Compression {
-    fn default() -> Self {
-        Compression::Zstd
+/// Target recorded for asset entries, which apply to every platform.
+pub const ASSET_TARGET: &str = "*";
+
+/// Kind of data an entry holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    /// A platform-specific executable.
+    #[default]
+    Binary,
+    /// A platform-independent data file shipped next to the binary.
+    Asset,
+}
+
+impl EntryKind {
+    /// Returns true for binary entries.
+    pub fn is_binary(&self) -> bool {
+        *self == EntryKind::Binary
     }
 }
 
-/// An entry in the PBIN manifest representing one embedded binary.
+/// An entry in the PBIN manifest representing one embedded binary or asset.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PbinEntry {
-    /// Target platform identifier (e.g., "linux-x86_64").
-    pub target: String,
-    /// Byte offset from start of file to compressed data.
+    /// Target platform (e.g., "linux-x86_64" in the manifest), or
+    /// [`ASSET_TARGET`] for assets.
+    pub target: EntryTarget,
+    /// Program this binary belongs to, for files packing several programs.
+    /// Absent means the program named by the manifest's `name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<String>,
+    /// Whether this entry is a binary or an asset. Absent means binary.
+    #[serde(default, skip_serializing_if = "EntryKind::is_binary")]
+    pub kind: EntryKind,
+    /// Name the entry is extracted as, normally the input file's name.
+    /// Always present for assets; empty in files written before it was
+    /// introduced, in which case binaries take the application name.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub file_name: String,
+    /// Unix permission bits of the input file. Zero when not recorded, in
+    /// which case binaries are extracted as `0o755` and assets as `0o644`.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub mode: u32,
+    /// Oldest OS version the binary runs on (e.g., "13.0" for a binary
+    /// built against the macOS 13 SDK).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_os_version: Option<String>,
+    /// Shared libraries the binary loads at startup (e.g., "libssl.so.3").
+    /// Empty for statically linked binaries.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dynamic_deps: Vec<String>,
+    /// Byte offset of the stored data, from the end of the manifest or, in
+    /// files without [`crate::FLAG_RELATIVE_OFFSETS`], from the start of the
+    /// file.
     pub offset: u64,
     /// Size of compressed data in bytes.
     pub compressed_size: u64,
     /// Size of uncompressed binary in bytes.
     pub uncompressed_size: u64,
-    /// BLAKE3 checksum of uncompressed data (hex string).
-    pub checksum: String,
+    /// BLAKE3 checksum of uncompressed data.
+    pub checksum: Checksum,
+    /// BLAKE3 checksum of the stored (compressed) data.
+    ///
+    /// Lets a reader reject corrupt data before decompressing it. Absent in
+    /// files written before it was introduced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed_checksum: Option<Checksum>,
+    /// Compression used for this entry. When absent the header's
+    /// compression applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// BCJ filter applied before compression (e.g., "x86v2", "arm64"), which
+    /// must be reversed after decompression.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bcj: Option<String>,
+    /// Byte ranges `[start, end)` of the binary the `bcj` filter was
+    /// applied to, sorted and not overlapping, each filtered at its offset
+    /// in the binary. When absent the filter covers the whole binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bcj_ranges: Option<Vec<[u64; 2]>>,
+    /// Key (see [`PbinEntry::key`]) of the entry whose data this entry is a
+    /// delta patch against. When present, the stored bytes are a compressed
+    /// patch rather than the binary itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta_reference: Option<String>,
+    /// Index into the manifest's `dictionaries` of the dictionary this entry
+    /// was compressed with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dict_index: Option<usize>,
+    /// ID of the dictionary this entry was compressed with. Must match the
+    /// dictionary at `dict_index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dict_id: Option<String>,
+    /// Base-2 log of the zstd window this entry was compressed with, when
+    /// it was compressed with long-distance matching. Decoders must accept
+    /// windows this large, which zstd refuses past 2^27 bytes by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_log: Option<u32>,
+    /// Indices into the manifest's `chunk_store` chunks which, concatenated,
+    /// make up this entry's data. A chunked entry stores no payload of its
+    /// own, so its `offset` and `compressed_size` are zero.
+    ///
+    /// Serialized last, after `offset`, so the stub's manifest scan sees it
+    /// as part of the entry it is extracting and refuses the entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<u32>>,
 }
 
 impl PbinEntry {
@@ -64,32 +206,419 @@ impl PbinEntry {
         offset: u64,
         compressed_size: u64,
         uncompressed_size: u64,
-        checksum: [u8; 32],
+        checksum: impl Into<Checksum>,
+        compressed_checksum: impl Into<Checksum>,
+    ) -> Self {
+        Self::from_parts(
+            EntryTarget::Known(target),
+            offset,
+            compressed_size,
+            uncompressed_size,
+            checksum,
+            compressed_checksum,
+        )
+    }
+
+    /// Creates a new asset entry extracted as `name`.
+    pub fn new_asset(
+        name: impl Into<String>,
+        offset: u64,
+        compressed_size: u64,
+        uncompressed_size: u64,
+        checksum: impl Into<Checksum>,
+        compressed_checksum: impl Into<Checksum>,
     ) -> Self {
         Self {
-            target: target.as_str().to_string(),
+            kind: EntryKind::Asset,
+            file_name: name.into(),
+            ..Self::from_parts(
+                EntryTarget::Any,
+                offset,
+                compressed_size,
+                uncompressed_size,
+                checksum,
+                compressed_checksum,
+            )
+        }
+    }
+
+    /// Creates an entry with only the required fields set.
+    fn from_parts(
+        target: EntryTarget,
+        offset: u64,
+        compressed_size: u64,
+        uncompressed_size: u64,
+        checksum: impl Into<Checksum>,
+        compressed_checksum: impl Into<Checksum>,
+    ) -> Self {
+        Self {
+            target,
+            program: None,
+            kind: EntryKind::Binary,
+            file_name: String::new(),
+            mode: 0,
+            min_os_version: None,
+            dynamic_deps: Vec::new(),
             offset,
             compressed_size,
             uncompressed_size,
-            checksum: hex_encode(&checksum),
+            checksum: checksum.into(),
+            compressed_checksum: Some(compressed_checksum.into()),
+            compression: None,
+            bcj: None,
+            bcj_ranges: None,
+            delta_reference: None,
+            dict_index: None,
+            dict_id: None,
+            window_log: None,
+            chunks: None,
+        }
+    }
+
+    /// Records the compression used for this entry.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Records the program this binary belongs to.
+    pub fn with_program(mut self, program: impl Into<String>) -> Self {
+        self.program = Some(program.into());
+        self
+    }
+
+    /// Returns the program this binary belongs to, taking `default` (the
+    /// manifest's name) for entries that don't record one.
+    pub fn program_name<'a>(&'a self, default: &'a str) -> &'a str {
+        self.program.as_deref().unwrap_or(default)
+    }
+
+    /// Returns the key identifying this entry among the manifest's entries:
+    /// the target, prefixed with "program/" for entries of a named program.
+    ///
+    /// Delta references name entries by this key.
+    pub fn key(&self) -> String {
+        match &self.program {
+            Some(program) => format!("{}/{}", program, self.target),
+            None => self.target.to_string(),
+        }
+    }
+
+    /// Returns this entry's position in the canonical manifest order.
+    fn id(&self) -> EntryId<'_> {
+        EntryId {
+            asset: self.is_asset(),
+            program: self.program.as_deref(),
+            target: &self.target,
+            name: if self.is_asset() { &self.file_name } else { "" },
         }
     }
 
+    /// Returns the key, or `asset:<name>` for assets, to identify this entry
+    /// in messages and reports.
+    pub fn label(&self) -> String {
+        if self.is_asset() {
+            format!("asset:{}", self.file_name)
+        } else {
+            self.key()
+        }
+    }
+
+    /// Records the name this entry is extracted as.
+    pub fn with_file_name(mut self, file_name: impl Into<String>) -> Self {
+        self.file_name = file_name.into();
+        self
+    }
+
+    /// Records the unix permission bits this entry is extracted with.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Records the oldest OS version this entry's binary runs on.
+    pub fn with_min_os_version(mut self, version: impl Into<String>) -> Self {
+        self.min_os_version = Some(version.into());
+        self
+    }
+
+    /// Records the BCJ filter applied to this entry before compression.
+    pub fn with_bcj(mut self, bcj: impl Into<String>) -> Self {
+        self.bcj = Some(bcj.into());
+        self
+    }
+
+    /// Records that the BCJ filter was applied only to `ranges` of the
+    /// binary, each a `[start, end)` pair.
+    pub fn with_bcj_ranges(mut self, ranges: Vec<[u64; 2]>) -> Self {
+        self.bcj_ranges = Some(ranges);
+        self
+    }
+
+    /// Records that this entry is stored as a delta against `reference`.
+    pub fn with_delta_reference(mut self, reference: impl Into<String>) -> Self {
+        self.delta_reference = Some(reference.into());
+        self
+    }
+
+    /// Records the dictionary this entry was compressed with.
+    pub fn with_dictionary(mut self, index: usize, dict_id: impl Into<String>) -> Self {
+        self.dict_index = Some(index);
+        self.dict_id = Some(dict_id.into());
+        self
+    }
+
+    /// Records the zstd window log this entry was compressed with.
+    pub fn with_window_log(mut self, window_log: u32) -> Self {
+        self.window_log = Some(window_log);
+        self
+    }
+
+    /// Returns true if this entry is stored as chunks of the chunk store.
+    pub fn is_chunked(&self) -> bool {
+        self.chunks.is_some()
+    }
+
+    /// Records that this entry is the concatenation of `chunks`, indices
+    /// into the manifest's chunk store.
+    pub fn with_chunks(mut self, chunks: Vec<u32>) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Returns the compression used for this entry, falling back to the
+    /// file-level default from the header.
+    pub fn effective_compression(&self, default: Compression) -> Compression {
+        self.compression.unwrap_or(default)
+    }
+
     /// Parses the target field.
     pub fn target(&self) -> Result<Target> {
-        Target::from_str(&self.target).ok_or_else(|| Error::InvalidTarget(self.target.clone()))
+        self.target
+            .known()
+            .ok_or_else(|| Error::InvalidTarget(self.target.to_string()))
     }
 
     /// Gets the checksum as bytes.
+    #[deprecated(note = "use the `checksum` field")]
     pub fn checksum_bytes(&self) -> Result<[u8; 32]> {
-        hex_decode(&self.checksum)
+        Ok(*self.checksum.as_bytes())
     }
 
-    /// Verifies that the given data matches the checksum.
+    /// Gets the compressed-data checksum as bytes.
+    #[deprecated(note = "use the `compressed_checksum` field")]
+    pub fn compressed_checksum_bytes(&self) -> Result<[u8; 32]> {
+        Ok(*self.stored_checksum()?.as_bytes())
+    }
+
+    /// Verifies that the given uncompressed data matches the checksum.
+    ///
+    /// Entries of files with keyed checksums (see
+    /// [`PbinManifest::key_fingerprint`]) need
+    /// [`verify_keyed_checksum`](Self::verify_keyed_checksum) instead.
     pub fn verify_checksum(&self, data: &[u8]) -> Result<bool> {
-        let expected = self.checksum_bytes()?;
-        let actual = blake3::hash(data);
-        Ok(actual.as_bytes() == &expected)
+        Ok(self.checksum.matches(data))
+    }
+
+    /// Verifies that the given uncompressed data matches a keyed checksum.
+    pub fn verify_keyed_checksum(&self, data: &[u8], key: &ChecksumKey) -> Result<bool> {
+        Ok(self.checksum == Checksum::keyed(key, data))
+    }
+
+    /// Verifies that the given stored (compressed) data matches the
+    /// compressed checksum.
+    pub fn verify_compressed(&self, data: &[u8]) -> Result<bool> {
+        Ok(self.stored_checksum()?.matches(data))
+    }
+
+    /// Returns the compressed checksum, which older files may lack.
+    fn stored_checksum(&self) -> Result<&Checksum> {
+        self.compressed_checksum
+            .as_ref()
+            .ok_or_else(|| Error::MissingChecksum(self.target.to_string()))
+    }
+
+    /// Returns the name to extract this entry as.
+    ///
+    /// Binaries without a recorded name use `default_name`, and binaries for
+    /// Windows targets always end in `.exe`. The name is checked with
+    /// [`validate_file_name`], so it is safe to join onto a directory.
+    pub fn output_name(&self, default_name: &str) -> Result<String> {
+        let mut name = if self.file_name.is_empty() && !self.is_asset() {
+            default_name.to_string()
+        } else {
+            self.file_name.clone()
+        };
+        if !self.is_asset()
+            && self.target.known().is_some_and(|t| t.is_windows())
+            && !name.to_ascii_lowercase().ends_with(".exe")
+        {
+            name.push_str(".exe");
+        }
+        validate_file_name(&name)?;
+        Ok(name)
+    }
+
+    /// Returns the permission bits to extract this entry with.
+    ///
+    /// Only the rwx bits are kept, so a manifest can't request setuid,
+    /// setgid or sticky files.
+    pub fn output_mode(&self) -> u32 {
+        match self.mode & 0o777 {
+            0 if self.is_asset() => 0o644,
+            0 => 0o755,
+            mode => mode,
+        }
+    }
+
+    /// Checks that an OS at version `current` meets this entry's minimum.
+    pub fn check_os_version(&self, current: &str) -> Result<()> {
+        let Some(required) = &self.min_os_version else {
+            return Ok(());
+        };
+        if compare_versions(current, required).is_lt() {
+            return Err(Error::OsVersionTooOld {
+                target: self.target.to_string(),
+                os: self.target().map_or("OS", |t| t.os_name()).to_string(),
+                required: required.clone(),
+                current: current.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns true for asset entries.
+    pub fn is_asset(&self) -> bool {
+        self.kind == EntryKind::Asset
+    }
+
+    /// Returns the stored size as a fraction of the original size.
+    pub fn compression_ratio(&self) -> f64 {
+        self.compressed_size as f64 / self.uncompressed_size.max(1) as f64
+    }
+
+    /// Returns true if both entries point at the same stored data.
+    fn shares_payload(&self, other: &PbinEntry) -> bool {
+        self.offset == other.offset
+            && self.compressed_size == other.compressed_size
+            && self.checksum == other.checksum
+            && self.compressed_checksum == other.compressed_checksum
+    }
+}
+
+/// Returns the targets whose entries can run on `target`, best first: its
+/// compatible targets, then WASI.
+fn selection_order(target: Target) -> impl Iterator<Item = Target> {
+    let wasi = (target != Target::WasiWasm32).then_some(Target::WasiWasm32);
+    target.compatible_targets().into_iter().chain(wasi)
+}
+
+/// A binary entry picked to run on a host platform.
+#[derive(Debug, Clone, Copy)]
+pub struct Selection<'a> {
+    /// The chosen entry.
+    pub entry: &'a PbinEntry,
+    /// The platform the entry was picked for.
+    pub host: Target,
+    /// The target the entry was built for: `host`, or one of its
+    /// [`Target::compatible_targets`].
+    pub target: Target,
+}
+
+impl<'a> Selection<'a> {
+    fn new(entry: &'a PbinEntry, host: Target, target: Target) -> Self {
+        Self {
+            entry,
+            host,
+            target,
+        }
+    }
+
+    /// Returns true if the entry was built for a target other than the
+    /// host's.
+    pub fn is_fallback(&self) -> bool {
+        self.target != self.host
+    }
+
+    /// Returns the emulation the host runs the entry through, if any.
+    pub fn emulation(&self) -> Option<Emulation> {
+        self.target.emulation_on(self.host)
+    }
+}
+
+/// Identity of an entry, ordered canonically: binaries before assets, the
+/// main program before named programs (by name), then by [`Target`] order
+/// with unknown targets last, and assets by name.
+///
+/// A manifest holds at most one entry per identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct EntryId<'a> {
+    asset: bool,
+    program: Option<&'a str>,
+    target: &'a EntryTarget,
+    name: &'a str,
+}
+
+/// A zstd dictionary stored in the payload section.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PbinDictionary {
+    /// Group of entries the dictionary was trained on: an architecture
+    /// (e.g., "x86_64") or "shared" for small groups pooled together.
+    pub group: String,
+    /// Dictionary ID: the first 8 bytes of the dictionary's BLAKE3 hash (hex).
+    pub dict_id: String,
+    /// Byte offset of the dictionary, counted like [`PbinEntry::offset`].
+    pub offset: u64,
+    /// Size of the dictionary in bytes.
+    pub size: u64,
+}
+
+impl PbinDictionary {
+    /// Describes `data`, trained for `group`, stored at `offset`.
+    pub fn new(group: impl Into<String>, data: &[u8], offset: u64) -> Self {
+        Self {
+            group: group.into(),
+            dict_id: Self::id_for(data),
+            offset,
+            size: data.len() as u64,
+        }
+    }
+
+    /// Computes the ID recorded for a dictionary's bytes.
+    pub fn id_for(data: &[u8]) -> String {
+        hex_encode(&blake3::hash(data).as_bytes()[..8])
+    }
+}
+
+/// Deduplicated chunks shared by chunked entries (see
+/// [`PbinEntry::chunks`]), stored as one payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PbinChunkStore {
+    /// Byte offset of the store, counted like [`PbinEntry::offset`].
+    pub offset: u64,
+    /// Size of the store as written.
+    pub compressed_size: u64,
+    /// Size of the store once decompressed: the sum of `chunk_sizes`.
+    pub uncompressed_size: u64,
+    /// BLAKE3 hash of the decompressed store.
+    pub checksum: Checksum,
+    /// Compression of the store. When absent the header's compression
+    /// applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+    /// Base-2 log of the zstd window the store was compressed with, as for
+    /// [`PbinEntry::window_log`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_log: Option<u32>,
+    /// Size of each chunk, in the order they are stored.
+    pub chunk_sizes: Vec<u32>,
+}
+
+impl PbinChunkStore {
+    /// Returns the compression used for the store, falling back to the
+    /// file-level default from the header.
+    pub fn effective_compression(&self, default: Compression) -> Compression {
+        self.compression.unwrap_or(default)
     }
 }
 
@@ -100,6 +629,44 @@ pub struct PbinManifest {
     pub name: String,
     /// Application version.
     pub version: String,
+    /// Short description of the application.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// License identifier (e.g., an SPDX expression like "MIT OR Apache-2.0").
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    /// Project homepage URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    /// Application authors.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    /// When the file was built, in seconds since the Unix epoch. Absent in
+    /// reproducible builds without a pinned timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Tool that built the file (e.g., "pbin-pack 0.1.0").
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    /// Identifier of the sources the binaries were built from, such as a
+    /// git commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+    /// Fingerprint of the key entry checksums are keyed with (see
+    /// [`ChecksumKey`]). Absent when they are plain BLAKE3 hashes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_fingerprint: Option<Checksum>,
+    /// Compression dictionaries embedded in the payload section, in the
+    /// order they are stored.
+    ///
+    /// Serialized ahead of `entries` so the stub's manifest scan has not yet
+    /// seen a target when it passes a dictionary's `offset`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dictionaries: Vec<PbinDictionary>,
+    /// Chunks of chunked entries, when the file has any. Serialized ahead of
+    /// `entries` for the same reason as `dictionaries`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_store: Option<PbinChunkStore>,
     /// List of embedded binary entries.
     pub entries: Vec<PbinEntry>,
 }
@@ -110,26 +677,450 @@ impl PbinManifest {
         Self {
             name,
             version,
+            description: None,
+            license: None,
+            homepage: None,
+            authors: Vec::new(),
+            created_at: None,
+            created_by: None,
+            source_id: None,
+            key_fingerprint: None,
+            dictionaries: Vec::new(),
+            chunk_store: None,
             entries: Vec::new(),
         }
     }
 
-    /// Adds an entry to the manifest.
+    /// Adds an entry at its place in the canonical order (see
+    /// [`PbinManifest::sort_entries`]).
+    ///
+    /// An existing entry for the same program and target (or, for assets,
+    /// the same name) is replaced.
     pub fn add_entry(&mut self, entry: PbinEntry) {
-        self.entries.push(entry);
+        match self.position(entry.id()) {
+            Ok(index) => self.entries[index] = entry,
+            Err(index) => self.entries.insert(index, entry),
+        }
     }
 
-    /// Finds an entry for the given target.
+    /// Adds an entry like [`add_entry`](Self::add_entry), but fails with
+    /// [`Error::DuplicateTarget`] instead of replacing an existing entry.
+    pub fn try_add_entry(&mut self, entry: PbinEntry) -> Result<()> {
+        match self.position(entry.id()) {
+            Ok(_) => Err(Error::DuplicateTarget(entry.label())),
+            Err(index) => {
+                self.entries.insert(index, entry);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails with [`Error::DuplicateTarget`] if two entries share a program
+    /// and target, or two assets share a name.
+    fn check_duplicates(&self) -> Result<()> {
+        let mut sorted: Vec<&PbinEntry> = self.entries.iter().collect();
+        sorted.sort_by(|a, b| a.id().cmp(&b.id()));
+        match sorted.windows(2).find(|w| w[0].id() == w[1].id()) {
+            Some(pair) => Err(Error::DuplicateTarget(pair[1].label())),
+            None => Ok(()),
+        }
+    }
+
+    /// Sorts the entries into the canonical order: binaries before assets,
+    /// the main program's before named programs', then by target (see
+    /// [`Target`]), and assets by name.
+    ///
+    /// [`add_entry`](Self::add_entry) and parsing keep entries in this
+    /// order, and lookups rely on it; call this after editing `entries`
+    /// directly. The sort is stable.
+    pub fn sort_entries(&mut self) {
+        self.entries.sort_by(|a, b| a.id().cmp(&b.id()));
+    }
+
+    /// Binary-searches the sorted entries for `id`.
+    fn position(&self, id: EntryId<'_>) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|e| e.id().cmp(&id))
+    }
+
+    /// Finds the binary entry of the given program (`None` for the main
+    /// program) for `target`.
+    fn find_keyed(&self, program: Option<&str>, target: Target) -> Option<&PbinEntry> {
+        let target = EntryTarget::Known(target);
+        let id = EntryId {
+            asset: false,
+            program,
+            target: &target,
+            name: "",
+        };
+        self.position(id).ok().map(|index| &self.entries[index])
+    }
+
+    /// Finds the binary entry for the given target: the main program's, or
+    /// in files without one, the first program's.
     pub fn find_entry(&self, target: Target) -> Option<&PbinEntry> {
-        let target_str = target.as_str();
-        self.entries.iter().find(|e| e.target == target_str)
+        self.find_keyed(None, target).or_else(|| {
+            self.entries
+                .iter()
+                .find(|e| !e.is_asset() && e.target == target)
+        })
+    }
+
+    /// Finds the binary entry of `program` for the given target.
+    pub fn find_program_entry(&self, program: &str, target: Target) -> Option<&PbinEntry> {
+        let main = (program == self.name)
+            .then(|| self.find_keyed(None, target))
+            .flatten();
+        main.or_else(|| self.find_keyed(Some(program), target))
+    }
+
+    /// Finds the binary entry best suited to run on `target`: its own, or
+    /// else the first of [`Target::compatible_targets`] the manifest has,
+    /// or as a last resort the wasi-wasm32 entry, which needs a WebAssembly
+    /// runtime.
+    ///
+    /// The selection records the target the entry was built for, which
+    /// differs from `target` when a fallback was chosen, so callers can warn
+    /// that it runs under emulation.
+    pub fn find_best_entry(&self, target: Target) -> Option<Selection<'_>> {
+        selection_order(target).find_map(|t| {
+            self.find_entry(t)
+                .map(|entry| Selection::new(entry, target, t))
+        })
+    }
+
+    /// Like [`find_best_entry`](Self::find_best_entry), for the binaries of
+    /// `program`.
+    pub fn find_best_program_entry(&self, program: &str, target: Target) -> Option<Selection<'_>> {
+        selection_order(target).find_map(|t| {
+            self.find_program_entry(program, t)
+                .map(|entry| Selection::new(entry, target, t))
+        })
+    }
+
+    /// Checks that `key` is the one this file's checksums are keyed with,
+    /// or `None` for a file with plain checksums.
+    ///
+    /// A key given for an unkeyed file is an error rather than ignored, since
+    /// otherwise stripping the keying from a file would pass verification.
+    pub fn check_checksum_key(&self, key: Option<&ChecksumKey>) -> Result<()> {
+        match (&self.key_fingerprint, key) {
+            (Some(fingerprint), None) => Err(Error::ChecksumKeyRequired {
+                fingerprint: fingerprint.to_string(),
+            }),
+            (Some(expected), Some(key)) if *expected != key.fingerprint() => {
+                Err(Error::WrongChecksumKey {
+                    expected: expected.to_string(),
+                    actual: key.fingerprint().to_string(),
+                })
+            }
+            (None, Some(_)) => Err(Error::ChecksumsNotKeyed),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `data`, extracted from `entry`, against its checksum.
+    ///
+    /// `key` must be given exactly when the file has keyed checksums (see
+    /// [`check_checksum_key`](Self::check_checksum_key)); a missing or wrong
+    /// key is reported as such rather than as a checksum mismatch.
+    pub fn verify_entry(
+        &self,
+        entry: &PbinEntry,
+        data: &[u8],
+        key: Option<&ChecksumKey>,
+    ) -> Result<()> {
+        self.check_checksum_key(key)?;
+        let actual = key.map_or_else(|| Checksum::of(data), |key| Checksum::keyed(key, data));
+        self.verify_entry_checksum(entry, actual, key)
+    }
+
+    /// Like [`verify_entry`](Self::verify_entry), for the `actual` checksum
+    /// of data hashed as it was extracted, with a hasher from
+    /// [`Checksum::hasher`] given the same `key`.
+    pub fn verify_entry_checksum(
+        &self,
+        entry: &PbinEntry,
+        actual: Checksum,
+        key: Option<&ChecksumKey>,
+    ) -> Result<()> {
+        self.check_checksum_key(key)?;
+        if actual != entry.checksum {
+            return Err(Error::ChecksumMismatch {
+                expected: entry.checksum.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        Ok(())
     }
 
-    /// Finds an entry for the current platform.
+    /// Parses `version` as a semantic version.
+    ///
+    /// The field itself accepts any string; this is for tools that need to
+    /// compare versions.
+    pub fn parsed_version(&self) -> Result<semver::Version> {
+        semver::Version::parse(&self.version).map_err(|source| Error::InvalidVersion {
+            version: self.version.clone(),
+            source,
+        })
+    }
+
+    /// Compares this manifest's version with `other`'s by semver precedence.
+    /// Returns `None` if either isn't a semantic version.
+    pub fn compare_version(&self, other: &PbinManifest) -> Option<std::cmp::Ordering> {
+        let ours = self.parsed_version().ok()?;
+        let theirs = other.parsed_version().ok()?;
+        Some(ours.cmp_precedence(&theirs))
+    }
+
+    /// Returns true if this manifest's version is a semantic version newer
+    /// than `other`'s.
+    pub fn is_newer_than(&self, other: &PbinManifest) -> bool {
+        self.compare_version(other) == Some(std::cmp::Ordering::Greater)
+    }
+
+    /// Returns the names of the programs with binaries in this file, in the
+    /// order they first appear.
+    pub fn programs(&self) -> Vec<&str> {
+        let mut programs = Vec::new();
+        for entry in self.entries.iter().filter(|e| !e.is_asset()) {
+            let program = entry.program_name(&self.name);
+            if !programs.contains(&program) {
+                programs.push(program);
+            }
+        }
+        programs
+    }
+
+    /// Picks the program to run when the file was invoked as `invoked_as`
+    /// (normally `argv[0]`).
+    ///
+    /// The file name, without a `.pbin` or `.exe` extension, selects the
+    /// program of that name, so a link named after a program runs it. Any
+    /// other name runs the first program.
+    pub fn select_program(&self, invoked_as: &str) -> Option<&str> {
+        let file_name = Path::new(invoked_as)
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let stem = file_name
+            .strip_suffix(".pbin")
+            .or_else(|| file_name.strip_suffix(".exe"))
+            .unwrap_or(&file_name);
+        let programs = self.programs();
+        programs
+            .iter()
+            .find(|program| **program == stem)
+            .or(programs.first())
+            .copied()
+    }
+
+    /// Returns the asset entries.
+    pub fn assets(&self) -> impl Iterator<Item = &PbinEntry> {
+        self.entries.iter().filter(|e| e.is_asset())
+    }
+
+    /// Finds an entry for the current platform, falling back to a
+    /// compatible target (see [`find_best_entry`](Self::find_best_entry))
+    /// when there is no native one. A fallback entry's `target` differs from
+    /// [`Target::detect_current`].
+    ///
+    /// Fails if the entry records a minimum OS version newer than the running
+    /// OS, rather than returning a binary that can't start.
     pub fn find_current_entry(&self) -> Result<&PbinEntry> {
+        self.select_current_entry().map(|selection| selection.entry)
+    }
+
+    /// Finds the entry for the current platform of the program selected by
+    /// `invoked_as` (see [`PbinManifest::select_program`]), falling back to
+    /// a compatible target like [`find_current_entry`](Self::find_current_entry).
+    pub fn find_current_program_entry(&self, invoked_as: &str) -> Result<&PbinEntry> {
+        self.select_current_program_entry(invoked_as)
+            .map(|selection| selection.entry)
+    }
+
+    /// Like [`find_current_entry`](Self::find_current_entry), also telling
+    /// whether the entry needs emulation.
+    pub fn select_current_entry(&self) -> Result<Selection<'_>> {
+        let target = Target::detect_current().ok_or(Error::UnsupportedPlatform)?;
+        let selection = self
+            .find_best_entry(target)
+            .ok_or_else(|| Error::TargetNotFound(target.as_str().to_string()))?;
+        Self::check_current_os(selection.entry)?;
+        Ok(selection)
+    }
+
+    /// Like [`find_current_program_entry`](Self::find_current_program_entry),
+    /// also telling whether the entry needs emulation.
+    pub fn select_current_program_entry(&self, invoked_as: &str) -> Result<Selection<'_>> {
         let target = Target::detect_current().ok_or(Error::UnsupportedPlatform)?;
-        self.find_entry(target)
-            .ok_or_else(|| Error::TargetNotFound(target.as_str().to_string()))
+        let selection = self
+            .select_program(invoked_as)
+            .and_then(|program| self.find_best_program_entry(program, target))
+            .ok_or_else(|| Error::TargetNotFound(target.as_str().to_string()))?;
+        Self::check_current_os(selection.entry)?;
+        Ok(selection)
+    }
+
+    /// Checks the running OS meets `entry`'s minimum version, if it has one.
+    fn check_current_os(entry: &PbinEntry) -> Result<()> {
+        if entry.min_os_version.is_some() {
+            if let Some(current) = Target::detect_os_version() {
+                entry.check_os_version(&current)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of entries, for the header's one-byte count, or
+    /// [`Error::TooManyEntries`] if it doesn't fit.
+    pub fn entry_count(&self) -> Result<u8> {
+        u8::try_from(self.entries.len()).map_err(|_| Error::TooManyEntries(self.entries.len()))
+    }
+
+    /// Checks that the header can count the entries, that no program or
+    /// asset appears twice for one target, that every recorded file name is
+    /// safe to extract and that no two entries' data overlaps.
+    ///
+    /// Several entries may point at one payload when the same binary serves
+    /// more than one target. That is accepted only when offset and size match
+    /// exactly and the entries' checksums agree.
+    pub fn validate(&self) -> Result<()> {
+        self.entry_count()?;
+        self.check_duplicates()?;
+        for entry in &self.entries {
+            if !entry.file_name.is_empty() || entry.is_asset() {
+                validate_file_name(&entry.file_name)?;
+            }
+            self.check_chunks(entry)?;
+            check_bcj_ranges(entry)?;
+        }
+
+        let mut entries: Vec<&PbinEntry> = self
+            .entries
+            .iter()
+            .filter(|e| e.compressed_size > 0)
+            .collect();
+        entries.sort_by_key(|e| (e.offset, e.compressed_size));
+
+        // The entry whose data ends furthest into the file so far.
+        let mut furthest: Option<&PbinEntry> = None;
+        for entry in entries {
+            if let Some(previous) = furthest {
+                if entry.offset < previous.offset.saturating_add(previous.compressed_size) {
+                    if !previous.shares_payload(entry) {
+                        return Err(Error::OverlappingEntries {
+                            first: previous.target.to_string(),
+                            second: entry.target.to_string(),
+                        });
+                    }
+                    continue;
+                }
+            }
+            furthest = Some(entry);
+        }
+        Ok(())
+    }
+
+    /// Fails with [`Error::InvalidChunks`] unless a chunked entry's chunks
+    /// are all in the chunk store and add up to its uncompressed size.
+    fn check_chunks(&self, entry: &PbinEntry) -> Result<()> {
+        let Some(chunks) = &entry.chunks else {
+            return Ok(());
+        };
+        let invalid = |reason: String| Error::InvalidChunks {
+            target: entry.label(),
+            reason,
+        };
+        let sizes = match &self.chunk_store {
+            Some(store) => &store.chunk_sizes,
+            None => return Err(invalid("the file has no chunk store".to_string())),
+        };
+        let mut total = 0u64;
+        for &index in chunks {
+            match sizes.get(index as usize) {
+                Some(&size) => total += u64::from(size),
+                None => {
+                    return Err(invalid(format!(
+                        "chunk {} is past the end of the store's {}",
+                        index,
+                        sizes.len()
+                    )))
+                }
+            }
+        }
+        if total != entry.uncompressed_size {
+            return Err(invalid(format!(
+                "chunks add up to {} bytes, not {}",
+                total, entry.uncompressed_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes stored for entry data, including the chunk store. A
+    /// payload shared by several entries counts once, so offsets must
+    /// already be assigned.
+    pub fn total_compressed_size(&self) -> u64 {
+        let store = self.chunk_store.as_ref().map_or(0, |s| s.compressed_size);
+        store + self.payloads().map(|e| e.compressed_size).sum::<u64>()
+    }
+
+    /// Returns true if any entry is stored as chunks.
+    pub fn has_chunked_entries(&self) -> bool {
+        self.entries.iter().any(PbinEntry::is_chunked)
+    }
+
+    /// Returns the bytes extracted if every entry were extracted.
+    pub fn total_uncompressed_size(&self) -> u64 {
+        self.entries.iter().map(|e| e.uncompressed_size).sum()
+    }
+
+    /// Returns the stored size of the entry data as a fraction of its
+    /// original size.
+    pub fn overall_ratio(&self) -> f64 {
+        self.total_compressed_size() as f64 / self.total_uncompressed_size().max(1) as f64
+    }
+
+    /// Returns the targets that have a binary, in [`Target`] order. Entries
+    /// with unknown targets are left out.
+    pub fn targets(&self) -> Vec<Target> {
+        let mut targets: Vec<Target> = self
+            .entries
+            .iter()
+            .filter(|e| !e.is_asset())
+            .filter_map(|e| e.target().ok())
+            .collect();
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+
+    /// Returns the entry with the largest uncompressed size, the first one
+    /// on a tie.
+    pub fn largest_entry(&self) -> Option<&PbinEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .max_by_key(|e| e.uncompressed_size)
+    }
+
+    /// Returns the manifest's totals in serializable form.
+    pub fn summary(&self) -> Summary {
+        Summary::of(self)
+    }
+
+    /// Iterates over the first entry for each distinct stored payload.
+    /// Chunked entries store no payload of their own and are skipped.
+    pub(crate) fn payloads(&self) -> impl Iterator<Item = &PbinEntry> {
+        let mut seen = std::collections::HashSet::new();
+        self.entries
+            .iter()
+            .filter(move |e| !e.is_chunked() && seen.insert((e.offset, e.compressed_size)))
+    }
+
+    /// Compares this manifest, as the old one, with `other`.
+    pub fn diff(&self, other: &PbinManifest) -> ManifestDiff {
+        ManifestDiff::between(self, other)
     }
 
     /// Serializes the manifest to JSON.
@@ -142,49 +1133,202 @@ impl PbinManifest {
         Ok(serde_json::to_string_pretty(self)?)
     }
 
-    /// Deserializes the manifest from JSON.
+    /// Deserializes the manifest from JSON, sorting its entries into the
+    /// canonical order. Duplicate entries are rejected, since lookups
+    /// couldn't tell them apart.
     pub fn from_json(json: &str) -> Result<Self> {
-        Ok(serde_json::from_str(json)?)
+        let mut manifest: Self = serde_json::from_str(json)?;
+        manifest.sort_entries();
+        manifest.check_duplicates()?;
+        Ok(manifest)
     }
 
-    /// Deserializes the manifest from JSON bytes.
+    /// Deserializes the manifest from JSON bytes, like
+    /// [`from_json`](Self::from_json).
     pub fn from_json_bytes(bytes: &[u8]) -> Result<Self> {
-        Ok(serde_json::from_slice(bytes)?)
+        let mut manifest: Self = serde_json::from_slice(bytes)?;
+        manifest.sort_entries();
+        manifest.check_duplicates()?;
+        Ok(manifest)
     }
 }
 
-/// Encodes bytes to a hex string.
-fn hex_encode(bytes: &[u8]) -> String {
-    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+/// Fails with [`Error::InvalidBcjRanges`] unless an entry's BCJ ranges go
+/// with a BCJ filter, are sorted, don't overlap and lie within the binary.
+fn check_bcj_ranges(entry: &PbinEntry) -> Result<()> {
+    let Some(ranges) = &entry.bcj_ranges else {
+        return Ok(());
+    };
+    let invalid = |reason: String| Error::InvalidBcjRanges {
+        target: entry.label(),
+        reason,
+    };
+    if entry.bcj.is_none() {
+        return Err(invalid("the entry has no BCJ filter".to_string()));
+    }
+    let mut previous_end = 0;
+    for &[start, end] in ranges {
+        if start >= end || start < previous_end {
+            return Err(invalid(format!(
+                "range {}..{} is empty, out of order or overlapping",
+                start, end
+            )));
+        }
+        if end > entry.uncompressed_size {
+            return Err(invalid(format!(
+                "range {}..{} ends past the binary's {} bytes",
+                start, end, entry.uncompressed_size
+            )));
+        }
+        previous_end = end;
+    }
+    Ok(())
 }
 
-/// Decodes a hex string to bytes.
-fn hex_decode(hex: &str) -> Result<[u8; 32]> {
-    if hex.len() != 64 {
-        return Err(Error::ChecksumMismatch {
-            expected: "64 hex characters".to_string(),
-            actual: format!("{} characters", hex.len()),
-        });
+/// Checks that an entry's file name is a single plain path component, so a
+/// manifest can't direct extraction outside the target directory.
+pub fn validate_file_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(()),
+        _ => Err(Error::UnsafeFileName(name.to_string())),
     }
+}
 
-    let mut bytes = [0u8; 32];
-    for (i, chunk) in hex.as_bytes().chunks(2).enumerate() {
-        let s = std::str::from_utf8(chunk).map_err(|_| Error::ChecksumMismatch {
-            expected: "valid hex".to_string(),
-            actual: "invalid utf8".to_string(),
-        })?;
-        bytes[i] = u8::from_str_radix(s, 16).map_err(|_| Error::ChecksumMismatch {
-            expected: "valid hex".to_string(),
-            actual: format!("invalid hex: {}", s),
-        })?;
-    }
-    Ok(bytes)
+/// Compares dotted numeric versions component by component, treating
+/// missing components as zero so "13" equals "13.0".
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let parse = |v: &str| -> Vec<u64> {
+        v.split('.')
+            .map(|part| part.trim().parse().unwrap_or(0))
+            .collect()
+    };
+    let (a, b) = (parse(a), parse(b));
+    let len = a.len().max(b.len());
+    let component = |v: &[u64], i: usize| v.get(i).copied().unwrap_or(0);
+    (0..len)
+        .map(|i| component(&a, i).cmp(&component(&b, i)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(std::cmp::Ordering::Equal)
+}
+
+/// Returns true for an unrecorded mode.
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// Encodes bytes to a hex string.
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_compression_string_roundtrip() {
+        for &compression in Compression::all() {
+            let name = compression.to_string();
+            assert_eq!(name.parse::<Compression>().unwrap(), compression);
+            assert_eq!(
+                serde_json::to_string(&compression).unwrap(),
+                format!("\"{}\"", name)
+            );
+            assert_eq!(
+                Compression::from_byte(compression.as_byte()).unwrap(),
+                compression
+            );
+        }
+        assert!(matches!(
+            "gzip".parse::<Compression>(),
+            Err(Error::InvalidCompression(ref name)) if name == "gzip"
+        ));
+    }
+
+    #[test]
+    fn test_dictionary_id() {
+        let dictionary = PbinDictionary::new("x86_64", b"dictionary bytes", 100);
+        assert_eq!(dictionary.dict_id.len(), 16);
+        assert_eq!(
+            dictionary.dict_id,
+            PbinDictionary::id_for(b"dictionary bytes")
+        );
+        assert_ne!(
+            dictionary.dict_id,
+            PbinDictionary::id_for(b"dictionary bytez")
+        );
+    }
+
+    #[test]
+    fn test_chunked_entries_validated() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        manifest.add_entry(
+            PbinEntry::new(Target::LinuxX86_64, 0, 0, 300, [1; 32], [0; 32])
+                .with_chunks(vec![0, 1, 0]),
+        );
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::InvalidChunks { .. })
+        ));
+
+        manifest.chunk_store = Some(PbinChunkStore {
+            offset: 0,
+            compressed_size: 150,
+            uncompressed_size: 200,
+            checksum: Checksum::of(b"store"),
+            compression: None,
+            window_log: None,
+            chunk_sizes: vec![100, 100],
+        });
+        manifest.validate().unwrap();
+        assert!(manifest.has_chunked_entries());
+        assert_eq!(manifest.total_compressed_size(), 150);
+
+        let json = manifest.to_json().unwrap();
+        assert!(json.find("chunk_store") < json.find("entries"));
+        let parsed = PbinManifest::from_json(&json).unwrap();
+        assert_eq!(parsed.chunk_store, manifest.chunk_store);
+        assert_eq!(parsed.entries[0].chunks, Some(vec![0, 1, 0]));
+
+        manifest.entries[0].chunks = Some(vec![0, 2]);
+        let err = manifest.validate().unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{}", err);
+        manifest.entries[0].chunks = Some(vec![0, 1]);
+        let err = manifest.validate().unwrap_err();
+        assert!(err.to_string().contains("add up to 200"), "{}", err);
+    }
+
+    #[test]
+    fn test_bcj_ranges_validated() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        manifest.add_entry(
+            PbinEntry::new(Target::LinuxX86_64, 0, 10, 300, [1; 32], [0; 32])
+                .with_bcj_ranges(vec![[64, 128], [128, 300]]),
+        );
+        let err = manifest.validate().unwrap_err();
+        assert!(err.to_string().contains("no BCJ filter"), "{}", err);
+
+        manifest.entries[0].bcj = Some("x86".to_string());
+        manifest.validate().unwrap();
+        let parsed = PbinManifest::from_json(&manifest.to_json().unwrap()).unwrap();
+        assert_eq!(
+            parsed.entries[0].bcj_ranges,
+            Some(vec![[64, 128], [128, 300]])
+        );
+
+        for ranges in [vec![[64, 64]], vec![[64, 128], [100, 200]]] {
+            manifest.entries[0].bcj_ranges = Some(ranges);
+            assert!(matches!(
+                manifest.validate(),
+                Err(Error::InvalidBcjRanges { .. })
+            ));
+        }
+        manifest.entries[0].bcj_ranges = Some(vec![[64, 301]]);
+        let err = manifest.validate().unwrap_err();
+        assert!(err.to_string().contains("past the binary's 300"), "{}", err);
+    }
+
     #[test]
     fn test_hex_roundtrip() {
         let bytes: [u8; 32] = [
@@ -193,8 +1337,20 @@ mod tests {
             0x89, 0xab, 0xcd, 0xef,
         ];
         let hex = hex_encode(&bytes);
-        let decoded = hex_decode(&hex).unwrap();
-        assert_eq!(bytes, decoded);
+        let decoded: Checksum = hex.parse().unwrap();
+        assert_eq!(decoded.as_bytes(), &bytes);
+    }
+
+    #[test]
+    fn test_checksum_json_unchanged() {
+        let entry = PbinEntry::new(Target::LinuxX86_64, 0, 1, 1, [0xab; 32], [0xcd; 32]);
+        let json = serde_json::to_string(&entry).unwrap();
+
+        assert!(json.contains(&format!(r#""checksum":"{}""#, "ab".repeat(32))));
+        assert!(json.contains(&format!(r#""compressed_checksum":"{}""#, "cd".repeat(32))));
+        let parsed: PbinEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.checksum, entry.checksum);
+        assert_eq!(parsed.compressed_checksum, entry.compressed_checksum);
     }
 
     #[test]
@@ -206,6 +1362,7 @@ mod tests {
             500,
             1000,
             [0u8; 32],
+            [1u8; 32],
         ));
 
         let json = manifest.to_json().unwrap();
@@ -214,6 +1371,558 @@ mod tests {
         assert_eq!(parsed.name, manifest.name);
         assert_eq!(parsed.version, manifest.version);
         assert_eq!(parsed.entries.len(), 1);
-        assert_eq!(parsed.entries[0].target, "linux-x86_64");
+        assert_eq!(parsed.entries[0].target, Target::LinuxX86_64);
+        assert_eq!(
+            parsed.entries[0].compressed_checksum,
+            Some(Checksum::from([1u8; 32]))
+        );
+    }
+
+    #[test]
+    fn test_entry_compression_falls_back_to_header() {
+        let entry = PbinEntry::new(Target::LinuxX86_64, 0, 1, 1, [0u8; 32], [0u8; 32]);
+        assert_eq!(
+            entry.effective_compression(Compression::Zstd),
+            Compression::Zstd
+        );
+
+        let entry = entry.with_compression(Compression::None);
+        assert_eq!(
+            entry.effective_compression(Compression::Zstd),
+            Compression::None
+        );
+
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""compression":"none""#));
+    }
+
+    #[test]
+    fn test_validate_accepts_aliased_payload() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            100,
+            50,
+            80,
+            [1; 32],
+            [2; 32],
+        ));
+        manifest.add_entry(PbinEntry::new(
+            Target::FreebsdX86_64,
+            100,
+            50,
+            80,
+            [1; 32],
+            [2; 32],
+        ));
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxAarch64,
+            150,
+            10,
+            20,
+            [3; 32],
+            [4; 32],
+        ));
+
+        assert!(manifest.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_overlap() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            100,
+            50,
+            80,
+            [1; 32],
+            [2; 32],
+        ));
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxAarch64,
+            149,
+            10,
+            20,
+            [3; 32],
+            [4; 32],
+        ));
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::OverlappingEntries { .. })
+        ));
+
+        // Same region, but the checksums disagree about what is stored there.
+        manifest.entries[1] = PbinEntry::new(Target::LinuxAarch64, 100, 50, 80, [3; 32], [4; 32]);
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::OverlappingEntries { .. })
+        ));
+    }
+
+    #[test]
+    fn test_assets_not_found_as_binaries() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            0,
+            1,
+            1,
+            [0; 32],
+            [0; 32],
+        ));
+        let mut asset = PbinEntry::new_asset("LICENSE", 1, 1, 1, [1; 32], [1; 32]);
+        // Even an asset carrying a real target must not be picked as a binary.
+        asset.target = Target::LinuxX86_64.into();
+        manifest.add_entry(asset);
+
+        let json = manifest.to_json().unwrap();
+        let parsed = PbinManifest::from_json(&json).unwrap();
+
+        assert_eq!(parsed.find_entry(Target::LinuxX86_64).unwrap().offset, 0);
+        let assets: Vec<_> = parsed.assets().collect();
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].file_name, "LICENSE");
+        assert!(!json.contains(r#""kind":"binary""#));
+    }
+
+    #[test]
+    fn test_unsafe_file_names_rejected() {
+        assert!(validate_file_name("hello").is_ok());
+        assert!(validate_file_name("hello.exe").is_ok());
+        for name in ["", ".", "..", "../evil", "a/b", "a\\b", "/etc/passwd"] {
+            assert!(validate_file_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_program_selection() {
+        let mut manifest = PbinManifest::new("suite".to_string(), "1.0.0".to_string());
+        for (i, program) in ["tool-a", "tool-b"].into_iter().enumerate() {
+            let offset = i as u64 * 10;
+            manifest.add_entry(
+                PbinEntry::new(Target::LinuxX86_64, offset, 1, 1, [i as u8; 32], [0; 32])
+                    .with_program(program),
+            );
+        }
+
+        assert_eq!(manifest.programs(), ["tool-a", "tool-b"]);
+        assert_eq!(manifest.select_program("/usr/bin/tool-b"), Some("tool-b"));
+        assert_eq!(manifest.select_program("tool-b.exe"), Some("tool-b"));
+        assert_eq!(manifest.select_program("./suite.pbin"), Some("tool-a"));
+        let entry = manifest
+            .find_program_entry("tool-b", Target::LinuxX86_64)
+            .unwrap();
+        assert_eq!(entry.offset, 10);
+        assert_eq!(entry.key(), "tool-b/linux-x86_64");
+        assert!(manifest
+            .find_program_entry("tool-c", Target::LinuxX86_64)
+            .is_none());
+    }
+
+    #[test]
+    fn test_best_entry_falls_back_to_compatible_target() {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::DarwinX86_64,
+            0,
+            1,
+            1,
+            [0; 32],
+            [0; 32],
+        ));
+        manifest.add_entry(
+            PbinEntry::new(Target::WindowsX86, 1, 1, 1, [1; 32], [0; 32]).with_program("tool"),
+        );
+
+        let selection = manifest.find_best_entry(Target::DarwinAarch64).unwrap();
+        assert_eq!(selection.target, Target::DarwinX86_64);
+        assert_eq!(selection.entry.target, Target::DarwinX86_64);
+        assert!(selection.is_fallback());
+        assert_eq!(selection.emulation(), Some(Emulation::Rosetta));
+        let selection = manifest.find_best_entry(Target::DarwinX86_64).unwrap();
+        assert_eq!(selection.target, Target::DarwinX86_64);
+        assert!(!selection.is_fallback());
+        assert_eq!(selection.emulation(), None);
+        assert!(manifest.find_best_entry(Target::LinuxX86_64).is_none());
+
+        let selection = manifest
+            .find_best_program_entry("tool", Target::WindowsAarch64)
+            .unwrap();
+        assert_eq!(selection.target, Target::WindowsX86);
+        assert_eq!(selection.host, Target::WindowsAarch64);
+        assert_eq!(selection.emulation(), Some(Emulation::WindowsOnArm));
+        assert_eq!(selection.entry.key(), "tool/windows-x86");
+
+        // A native entry wins over a compatible one.
+        manifest.add_entry(PbinEntry::new(
+            Target::DarwinAarch64,
+            2,
+            1,
+            1,
+            [2; 32],
+            [0; 32],
+        ));
+        let selection = manifest.find_best_entry(Target::DarwinAarch64).unwrap();
+        assert_eq!(selection.target, Target::DarwinAarch64);
+        assert_eq!(selection.emulation(), None);
+
+        // WASI is the last resort for any host.
+        manifest.add_entry(PbinEntry::new(
+            Target::WasiWasm32,
+            3,
+            1,
+            1,
+            [3; 32],
+            [0; 32],
+        ));
+        let selection = manifest.find_best_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(selection.target, Target::WasiWasm32);
+        assert_eq!(selection.emulation(), Some(Emulation::WasmRuntime));
+        let selection = manifest.find_best_entry(Target::DarwinAarch64).unwrap();
+        assert_eq!(selection.target, Target::DarwinAarch64);
+        let selection = manifest.find_best_entry(Target::WasiWasm32).unwrap();
+        assert_eq!(selection.emulation(), None);
+    }
+
+    #[test]
+    fn test_single_program_uses_manifest_name() {
+        let mut manifest = PbinManifest::new("hello".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            0,
+            1,
+            1,
+            [0; 32],
+            [0; 32],
+        ));
+
+        assert_eq!(manifest.programs(), ["hello"]);
+        assert_eq!(manifest.select_program("anything"), Some("hello"));
+        let entry = manifest.find_program_entry("hello", Target::LinuxX86_64);
+        assert_eq!(entry.unwrap().key(), "linux-x86_64");
+        assert!(!manifest.to_json().unwrap().contains("program"));
+    }
+
+    fn ordered_entries() -> Vec<PbinEntry> {
+        let binary = |target| PbinEntry::new(target, 0, 1, 1, [0; 32], [0; 32]);
+        let asset = |name| PbinEntry::new_asset(name, 0, 1, 1, [0; 32], [0; 32]);
+        vec![
+            binary(Target::LinuxX86_64),
+            binary(Target::LinuxAarch64),
+            binary(Target::DarwinAarch64),
+            binary(Target::WindowsX86_64),
+            binary(Target::LinuxX86_64).with_program("helper"),
+            asset("a.txt"),
+            asset("b.txt"),
+        ]
+    }
+
+    #[test]
+    fn test_insertion_order_does_not_matter() {
+        let mut forward = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        let mut backward = forward.clone();
+        for entry in ordered_entries() {
+            forward.add_entry(entry);
+        }
+        for entry in ordered_entries().into_iter().rev() {
+            backward.add_entry(entry);
+        }
+
+        assert_eq!(forward.to_json().unwrap(), backward.to_json().unwrap());
+        let keys: Vec<String> = forward.entries.iter().map(|e| e.key()).collect();
+        assert_eq!(
+            keys,
+            [
+                "linux-x86_64",
+                "linux-aarch64",
+                "darwin-aarch64",
+                "windows-x86_64",
+                "helper/linux-x86_64",
+                "*",
+                "*"
+            ]
+        );
+        assert_eq!(forward.entries[5].file_name, "a.txt");
+    }
+
+    #[test]
+    fn test_add_entry_replaces_same_entry() {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        for entry in ordered_entries() {
+            manifest.add_entry(entry);
+        }
+        manifest.add_entry(PbinEntry::new(
+            Target::DarwinAarch64,
+            7,
+            1,
+            1,
+            [0; 32],
+            [0; 32],
+        ));
+        manifest.add_entry(PbinEntry::new_asset("b.txt", 9, 1, 1, [0; 32], [0; 32]));
+
+        assert_eq!(manifest.entries.len(), ordered_entries().len());
+        assert_eq!(
+            manifest.find_entry(Target::DarwinAarch64).unwrap().offset,
+            7
+        );
+        assert_eq!(manifest.assets().last().unwrap().offset, 9);
+    }
+
+    #[test]
+    fn test_parsed_entries_sorted_for_lookup() {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        manifest.entries = ordered_entries().into_iter().rev().collect();
+        let parsed = PbinManifest::from_json(&manifest.to_json().unwrap()).unwrap();
+
+        for target in [Target::LinuxAarch64, Target::WindowsX86_64] {
+            assert_eq!(parsed.find_entry(target).unwrap().target, target);
+        }
+        assert!(parsed.find_entry(Target::FreebsdX86_64).is_none());
+        let helper = parsed
+            .find_program_entry("helper", Target::LinuxX86_64)
+            .unwrap();
+        assert_eq!(helper.program.as_deref(), Some("helper"));
+        let main = parsed
+            .find_program_entry("app", Target::LinuxX86_64)
+            .unwrap();
+        assert_eq!(main.program, None);
+    }
+
+    #[test]
+    fn test_duplicate_target_rejected() {
+        let entry = || PbinEntry::new(Target::LinuxX86_64, 0, 1, 1, [0; 32], [0; 32]);
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        manifest.try_add_entry(entry()).unwrap();
+        manifest
+            .try_add_entry(entry().with_program("helper"))
+            .unwrap();
+
+        assert!(matches!(
+            manifest.try_add_entry(entry()),
+            Err(Error::DuplicateTarget(key)) if key == "linux-x86_64"
+        ));
+        assert!(matches!(
+            manifest.try_add_entry(entry().with_program("helper")),
+            Err(Error::DuplicateTarget(key)) if key == "helper/linux-x86_64"
+        ));
+        assert_eq!(manifest.entries.len(), 2);
+
+        // Duplicates written by other tools are caught when parsing.
+        manifest.entries.push(entry());
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::DuplicateTarget(_))
+        ));
+        let json = manifest.to_json().unwrap();
+        assert!(matches!(
+            PbinManifest::from_json(&json),
+            Err(Error::DuplicateTarget(key)) if key == "linux-x86_64"
+        ));
+    }
+
+    #[test]
+    fn test_parsed_version() {
+        let manifest = |version: &str| PbinManifest::new("app".to_string(), version.to_string());
+
+        assert_eq!(
+            manifest("1.2.3-beta.1").parsed_version().unwrap(),
+            semver::Version::parse("1.2.3-beta.1").unwrap()
+        );
+        for version in ["1.0..0", "1.0", "latest", ""] {
+            assert!(matches!(
+                manifest(version).parsed_version(),
+                Err(Error::InvalidVersion { .. })
+            ));
+        }
+
+        assert!(manifest("1.10.0").is_newer_than(&manifest("1.9.0")));
+        assert!(manifest("1.0.0").is_newer_than(&manifest("1.0.0-rc.1")));
+        assert!(!manifest("1.0.0").is_newer_than(&manifest("1.0.0+build.5")));
+        assert_eq!(
+            manifest("1.0.0").compare_version(&manifest("1.0.0+build.5")),
+            Some(std::cmp::Ordering::Equal)
+        );
+        assert_eq!(
+            manifest("nightly").compare_version(&manifest("1.0.0")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        use std::cmp::Ordering;
+        assert_eq!(compare_versions("13", "13.0"), Ordering::Equal);
+        assert_eq!(compare_versions("11.6", "13.0"), Ordering::Less);
+        assert_eq!(compare_versions("10.0.22631", "6.0"), Ordering::Greater);
+        assert_eq!(compare_versions("13.0.1", "13.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_check_os_version() {
+        let entry = PbinEntry::new(Target::DarwinAarch64, 0, 1, 1, [0; 32], [0; 32]);
+        assert!(entry.check_os_version("11.6").is_ok());
+
+        let entry = entry.with_min_os_version("13.0");
+        assert!(entry.check_os_version("13.0").is_ok());
+        assert!(entry.check_os_version("14.2.1").is_ok());
+        let err = entry.check_os_version("11.6").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "darwin-aarch64 binary requires macOS >= 13.0, you have 11.6"
+        );
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let mut manifest = PbinManifest::new("test".to_string(), "1.0.0".to_string());
+        let bare = manifest.to_json().unwrap();
+        assert_eq!(bare, r#"{"name":"test","version":"1.0.0","entries":[]}"#);
+
+        manifest.description = Some("A test application".to_string());
+        manifest.license = Some("MIT OR Apache-2.0".to_string());
+        manifest.homepage = Some("https://example.com".to_string());
+        manifest.authors = vec!["Jane Doe".to_string(), "John Roe".to_string()];
+        manifest.created_at = Some(1_700_000_000);
+        manifest.created_by = Some("pbin-pack 0.1.0".to_string());
+        manifest.source_id = Some("0123abcd".to_string());
+        let parsed = PbinManifest::from_json(&manifest.to_json().unwrap()).unwrap();
+
+        assert_eq!(parsed.description, manifest.description);
+        assert_eq!(parsed.license, manifest.license);
+        assert_eq!(parsed.homepage, manifest.homepage);
+        assert_eq!(parsed.authors, manifest.authors);
+        assert_eq!(parsed.created_at, manifest.created_at);
+        assert_eq!(parsed.created_by, manifest.created_by);
+        assert_eq!(parsed.source_id, manifest.source_id);
+    }
+
+    #[test]
+    fn test_metadata_missing_or_unknown() {
+        let old =
+            PbinManifest::from_json(r#"{"name":"test","version":"1.0.0","entries":[]}"#).unwrap();
+        assert!(old.description.is_none() && old.license.is_none() && old.homepage.is_none());
+        assert!(old.authors.is_empty());
+
+        let newer = PbinManifest::from_json(
+            r#"{"name":"test","version":"1.0.0","maintainer":"someone","entries":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(newer.name, "test");
+    }
+
+    #[test]
+    fn test_output_name_and_mode() {
+        let entry = PbinEntry::new(Target::LinuxX86_64, 0, 1, 1, [0; 32], [0; 32]);
+        assert_eq!(entry.output_name("app").unwrap(), "app");
+        assert_eq!(entry.output_mode(), 0o755);
+
+        let entry = entry.with_file_name("tool").with_mode(0o4750);
+        assert_eq!(entry.output_name("app").unwrap(), "tool");
+        assert_eq!(entry.output_mode(), 0o750);
+
+        let windows = PbinEntry::new(Target::WindowsX86_64, 0, 1, 1, [0; 32], [0; 32]);
+        assert_eq!(windows.output_name("app").unwrap(), "app.exe");
+        let windows = windows.with_file_name("Tool.EXE");
+        assert_eq!(windows.output_name("app").unwrap(), "Tool.EXE");
+
+        let asset = PbinEntry::new_asset("data.txt", 0, 1, 1, [0; 32], [0; 32]);
+        assert_eq!(asset.output_name("app").unwrap(), "data.txt");
+        assert_eq!(asset.output_mode(), 0o644);
+    }
+
+    #[test]
+    fn test_too_many_entries_rejected() {
+        let mut manifest = PbinManifest::new("test".into(), "1.0.0".into());
+        for i in 0..255u64 {
+            let name = format!("asset-{}", i);
+            manifest.add_entry(PbinEntry::new_asset(&name, i, 1, 1, [0; 32], [0; 32]));
+        }
+        assert_eq!(manifest.entry_count().unwrap(), 255);
+        assert!(manifest.validate().is_ok());
+
+        manifest.add_entry(PbinEntry::new_asset(
+            "one-more", 255, 1, 1, [0; 32], [0; 32],
+        ));
+        assert!(matches!(
+            manifest.entry_count(),
+            Err(Error::TooManyEntries(256))
+        ));
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::TooManyEntries(256))
+        ));
+    }
+
+    #[test]
+    fn test_validate_rejects_unsafe_file_name() {
+        let json = format!(
+            r#"{{"name":"test","version":"1.0.0","entries":[{{"target":"linux-x86_64","file_name":"../evil","mode":493,"offset":0,"compressed_size":1,"uncompressed_size":1,"checksum":"{}"}}]}}"#,
+            "00".repeat(32)
+        );
+        let manifest = PbinManifest::from_json(&json).unwrap();
+
+        assert_eq!(manifest.entries[0].mode, 0o755);
+        assert!(matches!(
+            manifest.validate(),
+            Err(Error::UnsafeFileName(name)) if name == "../evil"
+        ));
+        assert!(manifest.entries[0].output_name("test").is_err());
+    }
+
+    #[test]
+    fn test_entry_without_compressed_checksum() {
+        let json = format!(
+            r#"{{"target":"linux-x86_64","offset":0,"compressed_size":1,"uncompressed_size":1,"checksum":"{}"}}"#,
+            "00".repeat(32)
+        );
+        let entry: PbinEntry = serde_json::from_str(&json).unwrap();
+
+        assert!(entry.compressed_checksum.is_none());
+        assert!(matches!(
+            entry.verify_compressed(b"x"),
+            Err(Error::MissingChecksum(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_entry_keyed() {
+        let key = ChecksumKey::derive(b"secret");
+        let data = b"binary";
+        let entry =
+            |checksum: Checksum| PbinEntry::new(Target::LinuxX86_64, 0, 6, 6, checksum, checksum);
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+
+        let plain = entry(Checksum::of(data));
+        assert!(manifest.verify_entry(&plain, data, None).is_ok());
+        assert!(matches!(
+            manifest.verify_entry(&plain, data, Some(&key)),
+            Err(Error::ChecksumsNotKeyed)
+        ));
+
+        manifest.key_fingerprint = Some(key.fingerprint());
+        let keyed = entry(Checksum::keyed(&key, data));
+        assert!(keyed.verify_keyed_checksum(data, &key).unwrap());
+        assert!(!keyed.verify_checksum(data).unwrap());
+        assert!(manifest.verify_entry(&keyed, data, Some(&key)).is_ok());
+        assert!(matches!(
+            manifest.verify_entry(&keyed, data, None),
+            Err(Error::ChecksumKeyRequired { .. })
+        ));
+        assert!(matches!(
+            manifest.verify_entry(&keyed, data, Some(&ChecksumKey::derive(b"guess"))),
+            Err(Error::WrongChecksumKey { .. })
+        ));
+        assert!(matches!(
+            manifest.verify_entry(&keyed, b"forged", Some(&key)),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        let json = manifest.to_json().unwrap();
+        assert!(json.contains(&format!(r#""key_fingerprint":"{}""#, key.fingerprint())));
+        assert_eq!(
+            PbinManifest::from_json(&json).unwrap().key_fingerprint,
+            manifest.key_fingerprint
+        );
     }
 }
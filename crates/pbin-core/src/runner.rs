@@ -0,0 +1,131 @@
+//! Running extracted binaries.
+//!
+//! Available with the `runner` feature.
+
+use crate::{Emulation, Error, Result, Selection};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A WebAssembly runtime found on `PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasmRuntime {
+    /// The runtime's command name: `wasmtime` or `wasmer`.
+    pub name: &'static str,
+    /// Where it was found.
+    pub path: PathBuf,
+}
+
+impl WasmRuntime {
+    /// Finds wasmtime, or else wasmer, on `PATH`.
+    pub fn detect() -> Option<Self> {
+        crate::target::find_wasm_runtime().map(|(name, path)| Self { name, path })
+    }
+
+    /// Returns a command running the WASI module at `module` with the
+    /// current directory preopened. Arguments added to the command go to the
+    /// module.
+    pub fn command(&self, module: &Path) -> Command {
+        let mut command = Command::new(&self.path);
+        command.args(["run", "--dir=."]).arg(module);
+        if self.name == "wasmer" {
+            command.arg("--");
+        }
+        command
+    }
+}
+
+/// Returns a command running `binary`, the extracted data of `selection`'s
+/// entry, through a WebAssembly runtime if the entry needs one.
+pub fn command(selection: &Selection<'_>, binary: &Path) -> Result<Command> {
+    if selection.emulation() != Some(Emulation::WasmRuntime) {
+        return Ok(Command::new(binary));
+    }
+    let runtime = WasmRuntime::detect().ok_or(Error::WasmRuntimeNotFound)?;
+    Ok(runtime.command(binary))
+}
+
+/// Runs [`command`] with `args` and waits for it, returning its exit code.
+///
+/// A process killed by a signal gives 128 plus the signal number, as shells
+/// report it.
+pub fn run<I, S>(selection: &Selection<'_>, binary: &Path, args: I) -> Result<i32>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let status = command(selection, binary)?.args(args).status()?;
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return Ok(128 + signal);
+    }
+    Ok(status.code().unwrap_or(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PbinEntry, PbinManifest, Target};
+
+    fn manifest() -> PbinManifest {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxX86_64,
+            0,
+            1,
+            1,
+            [0; 32],
+            [0; 32],
+        ));
+        manifest.add_entry(PbinEntry::new(
+            Target::WasiWasm32,
+            1,
+            1,
+            1,
+            [1; 32],
+            [0; 32],
+        ));
+        manifest
+    }
+
+    #[test]
+    fn test_wasm_runtime_command() {
+        let module = Path::new("/tmp/app.wasm");
+        let args = |runtime: WasmRuntime| -> Vec<String> {
+            let command = runtime.command(module);
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        };
+        let wasmtime = WasmRuntime {
+            name: "wasmtime",
+            path: PathBuf::from("/usr/bin/wasmtime"),
+        };
+        assert_eq!(args(wasmtime), ["run", "--dir=.", "/tmp/app.wasm"]);
+        let wasmer = WasmRuntime {
+            name: "wasmer",
+            path: PathBuf::from("/usr/bin/wasmer"),
+        };
+        assert_eq!(args(wasmer), ["run", "--dir=.", "/tmp/app.wasm", "--"]);
+    }
+
+    #[test]
+    fn test_native_entry_runs_directly() {
+        let manifest = manifest();
+        let selection = manifest.find_best_entry(Target::LinuxX86_64).unwrap();
+        let command = command(&selection, Path::new("/tmp/app")).unwrap();
+        assert_eq!(command.get_program(), "/tmp/app");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_run_exit_code() {
+        let manifest = manifest();
+        let selection = manifest.find_best_entry(Target::LinuxX86_64).unwrap();
+        let code = run(&selection, Path::new("/bin/sh"), ["-c", "exit 3"]).unwrap();
+        assert_eq!(code, 3);
+        let code = run(&selection, Path::new("/bin/sh"), ["-c", "kill -TERM $$"]).unwrap();
+        assert_eq!(code, 128 + 15);
+    }
+}
@@ -0,0 +1,147 @@
+//! Totals describing a whole manifest.
+
+use crate::PbinManifest;
+use serde::{Deserialize, Serialize};
+
+/// Sizes and contents of a PBIN file, computed from its manifest.
+///
+/// Serializes to JSON for tools that consume pack results; `pbin-pack`
+/// prints its report from the same values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Application name.
+    pub name: String,
+    /// Application version.
+    pub version: String,
+    /// Number of binary entries, aliases included.
+    pub binaries: usize,
+    /// Number of asset entries.
+    pub assets: usize,
+    /// Targets with a binary, in [`crate::Target`] order.
+    pub targets: Vec<String>,
+    /// Programs with binaries, in the order they first appear.
+    pub programs: Vec<String>,
+    /// Bytes stored for entry data, each shared payload counted once, and
+    /// the chunk store if there is one.
+    pub total_compressed_size: u64,
+    /// Bytes of every entry once extracted.
+    pub total_uncompressed_size: u64,
+    /// `total_compressed_size` as a fraction of `total_uncompressed_size`.
+    pub overall_ratio: f64,
+    /// Label (see [`crate::PbinEntry::label`]) of the largest entry.
+    pub largest_entry: Option<String>,
+    /// Entries sharing another entry's payload.
+    pub deduplicated: usize,
+    /// Bytes those entries would have taken if stored separately.
+    pub deduplicated_size: u64,
+}
+
+impl Summary {
+    /// Summarizes `manifest`, whose offsets must already be assigned.
+    pub fn of(manifest: &PbinManifest) -> Self {
+        let payloads = manifest.payloads().count();
+        let unchunked = manifest.entries.iter().filter(|e| !e.is_chunked()).count();
+        let stored: u64 = manifest.entries.iter().map(|e| e.compressed_size).sum();
+        let store = manifest
+            .chunk_store
+            .as_ref()
+            .map_or(0, |s| s.compressed_size);
+        let total_compressed_size = manifest.total_compressed_size();
+        Self {
+            name: manifest.name.clone(),
+            version: manifest.version.clone(),
+            binaries: manifest.entries.iter().filter(|e| !e.is_asset()).count(),
+            assets: manifest.assets().count(),
+            targets: manifest
+                .targets()
+                .iter()
+                .map(|target| target.as_str().to_string())
+                .collect(),
+            programs: manifest.programs().into_iter().map(String::from).collect(),
+            total_compressed_size,
+            total_uncompressed_size: manifest.total_uncompressed_size(),
+            overall_ratio: manifest.overall_ratio(),
+            largest_entry: manifest.largest_entry().map(|e| e.label()),
+            deduplicated: unchunked - payloads,
+            deduplicated_size: stored + store - total_compressed_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PbinEntry, PbinManifest, Target};
+
+    fn entry(target: Target, offset: u64, stored: u64, size: u64) -> PbinEntry {
+        PbinEntry::new(target, offset, stored, size, [0; 32], [0; 32])
+    }
+
+    /// Two binaries, one alias sharing the first one's payload, and an
+    /// asset.
+    fn manifest() -> PbinManifest {
+        let mut manifest = PbinManifest::new("app".to_string(), "1.2.0".to_string());
+        manifest.add_entry(entry(Target::LinuxX86_64, 0, 400, 1000));
+        manifest.add_entry(entry(Target::LinuxAarch64, 400, 600, 1500));
+        manifest.add_entry(entry(Target::DarwinX86_64, 0, 400, 1000));
+        manifest.add_entry(PbinEntry::new_asset(
+            "data.txt", 1000, 100, 500, [1; 32], [1; 32],
+        ));
+        manifest
+    }
+
+    #[test]
+    fn test_manifest_totals() {
+        let manifest = manifest();
+
+        assert_eq!(manifest.total_compressed_size(), 1100);
+        assert_eq!(manifest.total_uncompressed_size(), 4000);
+        assert_eq!(manifest.overall_ratio(), 0.275);
+        assert_eq!(
+            manifest.targets(),
+            [
+                Target::LinuxX86_64,
+                Target::LinuxAarch64,
+                Target::DarwinX86_64
+            ]
+        );
+        assert_eq!(
+            manifest.largest_entry().unwrap().target,
+            Target::LinuxAarch64
+        );
+    }
+
+    #[test]
+    fn test_empty_manifest_totals() {
+        let manifest = PbinManifest::new("app".to_string(), "1.0.0".to_string());
+
+        assert_eq!(manifest.total_compressed_size(), 0);
+        assert_eq!(manifest.overall_ratio(), 0.0);
+        assert!(manifest.targets().is_empty());
+        assert!(manifest.largest_entry().is_none());
+    }
+
+    #[test]
+    fn test_summary_json() {
+        let summary = manifest().summary();
+
+        assert_eq!(summary.binaries, 3);
+        assert_eq!(summary.assets, 1);
+        assert_eq!(summary.programs, ["app"]);
+        assert_eq!(summary.largest_entry.as_deref(), Some("linux-aarch64"));
+        assert_eq!(summary.deduplicated, 1);
+        assert_eq!(summary.deduplicated_size, 400);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["total_compressed_size"], 1100);
+        assert_eq!(json["total_uncompressed_size"], 4000);
+        assert_eq!(json["overall_ratio"], 0.275);
+        assert_eq!(
+            json["targets"],
+            serde_json::json!(["linux-x86_64", "linux-aarch64", "darwin-x86_64"])
+        );
+        assert_eq!(
+            serde_json::from_value::<super::Summary>(json).unwrap(),
+            summary
+        );
+    }
+}
@@ -1,13 +1,30 @@
 //! Target platform detection and representation.
 
+use crate::manifest::ASSET_TARGET;
+use crate::{Error, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
 /// Represents a supported target platform.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+///
+/// Targets have a canonical order: as declared here, grouped by OS family
+/// and then by architecture, the same order as [`Target::all`]. Manifests
+/// list entries in this order and `pbin-pack` compresses binaries in it, so
+/// output never depends on hash iteration. New targets may be inserted
+/// within their group, so the relative order of two targets is stable but
+/// [`canonical_index`](Target::canonical_index) values are not; don't
+/// persist them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Target {
     // Linux variants
     LinuxX86_64,
     LinuxAarch64,
+    LinuxX86_64Musl,
+    LinuxAarch64Musl,
     LinuxRiscv64,
     LinuxArmv7,
+    LinuxArmv6,
     LinuxPpc64le,
     LinuxS390x,
     LinuxMips64,
@@ -22,18 +39,29 @@ pub enum Target {
     WindowsX86_64,
     WindowsAarch64,
     WindowsX86,
+    WindowsX86_64Gnu,
 
     // BSD
     FreebsdX86_64,
     FreebsdAarch64,
     NetbsdX86_64,
     OpenbsdX86_64,
+    DragonflyX86_64,
+
+    // illumos (SmartOS, OmniOS, OpenIndiana)
+    IllumosX86_64,
+
+    // Haiku
+    HaikuX86_64,
 
     // Mobile
     AndroidAarch64,
     AndroidArmv7,
     AndroidX86_64,
+    AndroidI686,
     IosAarch64,
+    TvosAarch64,
+    WatchosAarch64,
 
     // WebAssembly
     WasiWasm32,
@@ -41,18 +69,34 @@ pub enum Target {
 
 impl Target {
     /// Detects the current platform at runtime.
+    ///
+    /// This is the host's platform, not necessarily the one this binary was
+    /// built for: an x86_64 build running under Rosetta 2 detects
+    /// darwin-aarch64, and an x86_64 or x86 build on Windows ARM64 detects
+    /// windows-aarch64.
+    ///
+    /// On x86_64 and aarch64 Linux this is the musl target when the system's
+    /// C library is musl (e.g., Alpine), whichever libc this binary uses.
     pub fn detect_current() -> Option<Self> {
         #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-        return Some(Target::LinuxX86_64);
+        return Some(if detect_linux_musl() {
+            Target::LinuxX86_64Musl
+        } else {
+            Target::LinuxX86_64
+        });
 
         #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-        return Some(Target::LinuxAarch64);
+        return Some(if detect_linux_musl() {
+            Target::LinuxAarch64Musl
+        } else {
+            Target::LinuxAarch64
+        });
 
         #[cfg(all(target_os = "linux", target_arch = "riscv64"))]
         return Some(Target::LinuxRiscv64);
 
         #[cfg(all(target_os = "linux", target_arch = "arm"))]
-        return Some(Target::LinuxArmv7);
+        return Some(detect_linux_arm());
 
         #[cfg(all(target_os = "linux", target_arch = "powerpc64"))]
         return Some(Target::LinuxPpc64le);
@@ -70,19 +114,17 @@ impl Target {
         return Some(Target::LinuxLoongarch64);
 
         #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
-        return Some(Target::DarwinX86_64);
+        return Some(if is_translated() {
+            Target::DarwinAarch64
+        } else {
+            Target::DarwinX86_64
+        });
 
         #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
         return Some(Target::DarwinAarch64);
 
-        #[cfg(all(target_os = "windows", target_arch = "x86_64"))]
-        return Some(Target::WindowsX86_64);
-
-        #[cfg(all(target_os = "windows", target_arch = "aarch64"))]
-        return Some(Target::WindowsAarch64);
-
-        #[cfg(all(target_os = "windows", target_arch = "x86"))]
-        return Some(Target::WindowsX86);
+        #[cfg(target_os = "windows")]
+        return Some(detect_windows());
 
         #[cfg(all(target_os = "freebsd", target_arch = "x86_64"))]
         return Some(Target::FreebsdX86_64);
@@ -96,6 +138,15 @@ impl Target {
         #[cfg(all(target_os = "openbsd", target_arch = "x86_64"))]
         return Some(Target::OpenbsdX86_64);
 
+        #[cfg(all(target_os = "dragonfly", target_arch = "x86_64"))]
+        return Some(Target::DragonflyX86_64);
+
+        #[cfg(all(target_os = "illumos", target_arch = "x86_64"))]
+        return Some(Target::IllumosX86_64);
+
+        #[cfg(all(target_os = "haiku", target_arch = "x86_64"))]
+        return Some(Target::HaikuX86_64);
+
         #[cfg(all(target_os = "android", target_arch = "aarch64"))]
         return Some(Target::AndroidAarch64);
 
@@ -105,9 +156,18 @@ impl Target {
         #[cfg(all(target_os = "android", target_arch = "x86_64"))]
         return Some(Target::AndroidX86_64);
 
+        #[cfg(all(target_os = "android", target_arch = "x86"))]
+        return Some(Target::AndroidI686);
+
         #[cfg(all(target_os = "ios", target_arch = "aarch64"))]
         return Some(Target::IosAarch64);
 
+        #[cfg(all(target_os = "tvos", target_arch = "aarch64"))]
+        return Some(Target::TvosAarch64);
+
+        #[cfg(all(target_os = "watchos", target_arch = "aarch64"))]
+        return Some(Target::WatchosAarch64);
+
         #[cfg(target_os = "wasi")]
         return Some(Target::WasiWasm32);
 
@@ -115,13 +175,154 @@ impl Target {
         None
     }
 
+    /// Detects the running operating system's version (e.g., "14.2.1" on
+    /// macOS, "10.0.22631" on Windows).
+    ///
+    /// Only macOS and Windows are supported, where entries' minimum versions
+    /// come from the binaries' headers. Returns `None` elsewhere or if the
+    /// version can't be determined.
+    pub fn detect_os_version() -> Option<String> {
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        {
+            let output = std::process::Command::new("sw_vers")
+                .arg("-productVersion")
+                .output()
+                .ok()?;
+            let version = String::from_utf8(output.stdout).ok()?;
+            return Some(version.trim().to_string()).filter(|v| !v.is_empty());
+        }
+
+        #[cfg(windows)]
+        {
+            // `ver` prints e.g. "Microsoft Windows [Version 10.0.22631.2861]".
+            let output = std::process::Command::new("cmd")
+                .args(["/C", "ver"])
+                .output()
+                .ok()?;
+            let text = String::from_utf8_lossy(&output.stdout);
+            let start = text.find("Version ")? + "Version ".len();
+            let end = start + text[start..].find(']')?;
+            return Some(text[start..end].trim().to_string());
+        }
+
+        #[allow(unreachable_code)]
+        None
+    }
+
+    /// Returns the operating system's display name (e.g., "macOS").
+    pub fn os_name(&self) -> &'static str {
+        self.os().name()
+    }
+
+    /// Returns the operating system this target runs on.
+    pub fn os(&self) -> Os {
+        match self {
+            Target::LinuxX86_64
+            | Target::LinuxAarch64
+            | Target::LinuxX86_64Musl
+            | Target::LinuxAarch64Musl
+            | Target::LinuxRiscv64
+            | Target::LinuxArmv7
+            | Target::LinuxArmv6
+            | Target::LinuxPpc64le
+            | Target::LinuxS390x
+            | Target::LinuxMips64
+            | Target::LinuxI686
+            | Target::LinuxLoongarch64 => Os::Linux,
+            Target::DarwinX86_64 | Target::DarwinAarch64 => Os::Darwin,
+            Target::WindowsX86_64
+            | Target::WindowsAarch64
+            | Target::WindowsX86
+            | Target::WindowsX86_64Gnu => Os::Windows,
+            Target::FreebsdX86_64 | Target::FreebsdAarch64 => Os::FreeBsd,
+            Target::NetbsdX86_64 => Os::NetBsd,
+            Target::OpenbsdX86_64 => Os::OpenBsd,
+            Target::DragonflyX86_64 => Os::DragonFly,
+            Target::IllumosX86_64 => Os::Illumos,
+            Target::HaikuX86_64 => Os::Haiku,
+            Target::AndroidAarch64
+            | Target::AndroidArmv7
+            | Target::AndroidX86_64
+            | Target::AndroidI686 => Os::Android,
+            Target::IosAarch64 => Os::Ios,
+            Target::TvosAarch64 => Os::Tvos,
+            Target::WatchosAarch64 => Os::Watchos,
+            Target::WasiWasm32 => Os::Wasi,
+        }
+    }
+
+    /// Returns the CPU architecture this target runs on.
+    pub fn arch(&self) -> Arch {
+        match self {
+            Target::LinuxX86_64
+            | Target::LinuxX86_64Musl
+            | Target::DarwinX86_64
+            | Target::WindowsX86_64
+            | Target::WindowsX86_64Gnu
+            | Target::FreebsdX86_64
+            | Target::NetbsdX86_64
+            | Target::OpenbsdX86_64
+            | Target::DragonflyX86_64
+            | Target::IllumosX86_64
+            | Target::HaikuX86_64
+            | Target::AndroidX86_64 => Arch::X86_64,
+            Target::LinuxAarch64
+            | Target::LinuxAarch64Musl
+            | Target::DarwinAarch64
+            | Target::WindowsAarch64
+            | Target::FreebsdAarch64
+            | Target::AndroidAarch64
+            | Target::IosAarch64
+            | Target::TvosAarch64
+            | Target::WatchosAarch64 => Arch::Aarch64,
+            Target::LinuxRiscv64 => Arch::Riscv64,
+            Target::LinuxArmv7 | Target::AndroidArmv7 => Arch::Armv7,
+            Target::LinuxArmv6 => Arch::Armv6,
+            Target::LinuxPpc64le => Arch::Ppc64le,
+            Target::LinuxS390x => Arch::S390x,
+            Target::LinuxMips64 => Arch::Mips64,
+            Target::LinuxI686 | Target::WindowsX86 | Target::AndroidI686 => Arch::X86,
+            Target::LinuxLoongarch64 => Arch::Loongarch64,
+            Target::WasiWasm32 => Arch::Wasm32,
+        }
+    }
+
+    /// Returns the extension executables for this target conventionally
+    /// have: ".exe" on Windows, ".wasm" on WASI, and none elsewhere.
+    pub fn exe_extension(&self) -> &'static str {
+        match self.os() {
+            Os::Windows => ".exe",
+            Os::Wasi => ".wasm",
+            _ => "",
+        }
+    }
+
+    /// Returns true for Windows targets.
+    pub fn is_windows(&self) -> bool {
+        self.os() == Os::Windows
+    }
+
+    /// Returns true for targets with a Unix-like OS: everything except
+    /// Windows and WASI.
+    pub fn is_unix(&self) -> bool {
+        !matches!(self.os(), Os::Windows | Os::Wasi)
+    }
+
+    /// Returns the size of a pointer in bits.
+    pub fn pointer_width(&self) -> u32 {
+        self.arch().pointer_width()
+    }
+
     /// Returns the string representation used in PBIN manifests.
     pub fn as_str(&self) -> &'static str {
         match self {
             Target::LinuxX86_64 => "linux-x86_64",
             Target::LinuxAarch64 => "linux-aarch64",
+            Target::LinuxX86_64Musl => "linux-x86_64-musl",
+            Target::LinuxAarch64Musl => "linux-aarch64-musl",
             Target::LinuxRiscv64 => "linux-riscv64",
             Target::LinuxArmv7 => "linux-armv7",
+            Target::LinuxArmv6 => "linux-armv6",
             Target::LinuxPpc64le => "linux-ppc64le",
             Target::LinuxS390x => "linux-s390x",
             Target::LinuxMips64 => "linux-mips64",
@@ -132,55 +333,35 @@ impl Target {
             Target::WindowsX86_64 => "windows-x86_64",
             Target::WindowsAarch64 => "windows-aarch64",
             Target::WindowsX86 => "windows-x86",
+            Target::WindowsX86_64Gnu => "windows-x86_64-gnu",
             Target::FreebsdX86_64 => "freebsd-x86_64",
             Target::FreebsdAarch64 => "freebsd-aarch64",
             Target::NetbsdX86_64 => "netbsd-x86_64",
             Target::OpenbsdX86_64 => "openbsd-x86_64",
+            Target::DragonflyX86_64 => "dragonfly-x86_64",
+            Target::IllumosX86_64 => "illumos-x86_64",
+            Target::HaikuX86_64 => "haiku-x86_64",
             Target::AndroidAarch64 => "android-aarch64",
             Target::AndroidArmv7 => "android-armv7",
             Target::AndroidX86_64 => "android-x86_64",
+            Target::AndroidI686 => "android-i686",
             Target::IosAarch64 => "ios-aarch64",
+            Target::TvosAarch64 => "tvos-aarch64",
+            Target::WatchosAarch64 => "watchos-aarch64",
             Target::WasiWasm32 => "wasi-wasm32",
         }
     }
 
-    /// Parses a target string into a Target enum.
-    pub fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "linux-x86_64" => Some(Target::LinuxX86_64),
-            "linux-aarch64" => Some(Target::LinuxAarch64),
-            "linux-riscv64" => Some(Target::LinuxRiscv64),
-            "linux-armv7" => Some(Target::LinuxArmv7),
-            "linux-ppc64le" => Some(Target::LinuxPpc64le),
-            "linux-s390x" => Some(Target::LinuxS390x),
-            "linux-mips64" => Some(Target::LinuxMips64),
-            "linux-i686" => Some(Target::LinuxI686),
-            "linux-loongarch64" => Some(Target::LinuxLoongarch64),
-            "darwin-x86_64" => Some(Target::DarwinX86_64),
-            "darwin-aarch64" => Some(Target::DarwinAarch64),
-            "windows-x86_64" => Some(Target::WindowsX86_64),
-            "windows-aarch64" => Some(Target::WindowsAarch64),
-            "windows-x86" => Some(Target::WindowsX86),
-            "freebsd-x86_64" => Some(Target::FreebsdX86_64),
-            "freebsd-aarch64" => Some(Target::FreebsdAarch64),
-            "netbsd-x86_64" => Some(Target::NetbsdX86_64),
-            "openbsd-x86_64" => Some(Target::OpenbsdX86_64),
-            "android-aarch64" => Some(Target::AndroidAarch64),
-            "android-armv7" => Some(Target::AndroidArmv7),
-            "android-x86_64" => Some(Target::AndroidX86_64),
-            "ios-aarch64" => Some(Target::IosAarch64),
-            "wasi-wasm32" => Some(Target::WasiWasm32),
-            _ => None,
-        }
-    }
-
     /// Returns the Rust target triple for this target.
     pub fn rust_triple(&self) -> &'static str {
         match self {
             Target::LinuxX86_64 => "x86_64-unknown-linux-gnu",
             Target::LinuxAarch64 => "aarch64-unknown-linux-gnu",
+            Target::LinuxX86_64Musl => "x86_64-unknown-linux-musl",
+            Target::LinuxAarch64Musl => "aarch64-unknown-linux-musl",
             Target::LinuxRiscv64 => "riscv64gc-unknown-linux-gnu",
             Target::LinuxArmv7 => "armv7-unknown-linux-gnueabihf",
+            Target::LinuxArmv6 => "arm-unknown-linux-gnueabihf",
             Target::LinuxPpc64le => "powerpc64le-unknown-linux-gnu",
             Target::LinuxS390x => "s390x-unknown-linux-gnu",
             Target::LinuxMips64 => "mips64-unknown-linux-gnuabi64",
@@ -191,25 +372,292 @@ impl Target {
             Target::WindowsX86_64 => "x86_64-pc-windows-msvc",
             Target::WindowsAarch64 => "aarch64-pc-windows-msvc",
             Target::WindowsX86 => "i686-pc-windows-msvc",
+            Target::WindowsX86_64Gnu => "x86_64-pc-windows-gnu",
             Target::FreebsdX86_64 => "x86_64-unknown-freebsd",
             Target::FreebsdAarch64 => "aarch64-unknown-freebsd",
             Target::NetbsdX86_64 => "x86_64-unknown-netbsd",
             Target::OpenbsdX86_64 => "x86_64-unknown-openbsd",
+            Target::DragonflyX86_64 => "x86_64-unknown-dragonfly",
+            Target::IllumosX86_64 => "x86_64-unknown-illumos",
+            Target::HaikuX86_64 => "x86_64-unknown-haiku",
             Target::AndroidAarch64 => "aarch64-linux-android",
             Target::AndroidArmv7 => "armv7-linux-androideabi",
             Target::AndroidX86_64 => "x86_64-linux-android",
+            Target::AndroidI686 => "i686-linux-android",
             Target::IosAarch64 => "aarch64-apple-ios",
+            Target::TvosAarch64 => "aarch64-apple-tvos",
+            Target::WatchosAarch64 => "aarch64-apple-watchos",
             Target::WasiWasm32 => "wasm32-wasip1",
         }
     }
 
-    /// Returns all supported targets.
+    /// Returns the target a Rust target triple builds for, the inverse of
+    /// [`rust_triple`](Self::rust_triple).
+    ///
+    /// Triples that differ only in ways targets don't distinguish map to the
+    /// same target: vendor, CPU variant (`riscv64gc`, `thumbv7neon`),
+    /// `wasm32-wasi` for `wasm32-wasip1`, and the C library on architectures
+    /// without a separate musl target.
+    /// Returns `None` for triples no target runs, such as simulators,
+    /// big-endian variants or `wasm32-wasip2`.
+    pub fn from_rust_triple(triple: &str) -> Option<Target> {
+        if let Some(&target) = Target::all().iter().find(|t| t.rust_triple() == triple) {
+            return Some(target);
+        }
+
+        let (arch, rest) = triple.split_once('-')?;
+        let parts: Vec<&str> = rest.split('-').collect();
+        let has = |name: &str| parts.contains(&name);
+        let os = if parts.iter().any(|p| p.starts_with("android")) {
+            "android"
+        } else if has("linux") {
+            "linux"
+        } else if has("darwin") {
+            "darwin"
+        } else if has("windows") {
+            "windows"
+        } else if has("sim") || has("macabi") {
+            return None;
+        } else if let Some(os) = ["ios", "tvos", "watchos"].into_iter().find(|os| has(os)) {
+            os
+        } else if let Some(os) = [
+            "freebsd",
+            "netbsd",
+            "openbsd",
+            "dragonfly",
+            "illumos",
+            "haiku",
+        ]
+        .into_iter()
+        .find(|os| has(os))
+        {
+            os
+        } else if arch == "wasm32" && matches!(parts[..], ["wasi"] | ["wasip1"]) {
+            "wasi"
+        } else {
+            return None;
+        };
+        let arch = match arch {
+            "x86_64" | "x86_64h" | "amd64" => "x86_64",
+            "aarch64" | "arm64" => "aarch64",
+            "i686" | "i586" | "i386" if os == "windows" => "x86",
+            "i686" | "i586" | "i386" => "i686",
+            "powerpc64le" => "ppc64le",
+            "mips64" => "mips64",
+            "s390x" | "loongarch64" | "wasm32" => arch,
+            _ if arch.starts_with("riscv64") => "riscv64",
+            _ if arch.starts_with("armv7") || arch.starts_with("thumbv7") => "armv7",
+            "arm" | "armv6" => "armv6",
+            _ => return None,
+        };
+        let env = match os {
+            "linux" if parts.iter().any(|p| p.starts_with("musl")) => Some("musl"),
+            "windows" if parts.iter().any(|p| p.starts_with("gnu")) => Some("gnu"),
+            _ => None,
+        };
+        Self::from_parts(os, arch, env)
+    }
+
+    /// Returns the target named `os-arch`, or its `os-arch-env` variant
+    /// (musl on Linux, GNU on Windows) if `env` is given and there is one.
+    fn from_parts(os: &str, arch: &str, env: Option<&str>) -> Option<Target> {
+        let name = format!("{}-{}", os, arch);
+        env.and_then(|env| format!("{}-{}", name, env).parse().ok())
+            .or_else(|| name.parse().ok())
+    }
+
+    /// Parses a target name the way people tend to write it.
+    ///
+    /// Accepts the canonical names, Rust triples, and OS/architecture pairs
+    /// in any case, separated by `-`, `_`, `/` or spaces, using common
+    /// aliases: `macos`/`osx` for darwin, `amd64`/`x64` for x86_64, `arm64`
+    /// for aarch64, and `win64`/`win32` for Windows with its architecture.
+    /// `musl` selects a musl target where there is one, and `gnu` or `mingw`
+    /// the GNU build of a Windows target. Other vendor and ABI words
+    /// (`unknown`, `pc`, `msvc`, ...) are ignored. Any other word, or a name
+    /// giving two OSes or architectures, is rejected.
+    ///
+    /// Manifests are parsed strictly with [`FromStr`]; this is for names
+    /// typed by users.
+    pub fn parse_lenient(name: &str) -> Option<Target> {
+        let name = name.trim().to_ascii_lowercase();
+        if let Ok(target) = name.parse() {
+            return Some(target);
+        }
+        if let Some(target) = Target::from_rust_triple(&name) {
+            return Some(target);
+        }
+
+        let name = name.replace("x86_64", "x64").replace("x86-64", "x64");
+        let mut os = None;
+        let mut arch = None;
+        let mut musl = false;
+        let mut gnu = false;
+        for word in name.split(['-', '_', '/', ' ']).filter(|w| !w.is_empty()) {
+            let (word_os, word_arch) = match word {
+                "linux" => (Some("linux"), None),
+                "darwin" | "macos" | "osx" | "mac" => (Some("darwin"), None),
+                "windows" | "win" => (Some("windows"), None),
+                "win64" => (Some("windows"), Some("x86_64")),
+                "win32" => (Some("windows"), Some("x86")),
+                "freebsd" | "netbsd" | "openbsd" | "ios" | "tvos" | "watchos" | "illumos"
+                | "haiku" => (Some(word), None),
+                "smartos" | "omnios" | "openindiana" => (Some("illumos"), None),
+                "dragonfly" | "dragonflybsd" => (Some("dragonfly"), None),
+                "wasi" | "wasip1" => (Some("wasi"), None),
+                _ if word.starts_with("android") => (Some("android"), None),
+                "x64" | "amd64" => (None, Some("x86_64")),
+                "aarch64" | "arm64" => (None, Some("aarch64")),
+                "x86" | "i386" | "i586" | "i686" | "386" | "ia32" => (None, Some("x86")),
+                "armv7" | "armv7l" | "armhf" | "arm" => (None, Some("armv7")),
+                "armv6" | "armv6l" | "armv6hf" => (None, Some("armv6")),
+                "riscv64" | "riscv64gc" => (None, Some("riscv64")),
+                "ppc64le" | "powerpc64le" => (None, Some("ppc64le")),
+                "loongarch64" | "loong64" => (None, Some("loongarch64")),
+                "s390x" | "mips64" => (None, Some(word)),
+                "wasm32" | "wasm" => (Some("wasi"), Some("wasm32")),
+                "musl" | "musleabihf" => {
+                    musl = true;
+                    (None, None)
+                }
+                "gnu" | "gnullvm" | "mingw" | "mingw32" | "mingw64" => {
+                    gnu = true;
+                    (None, None)
+                }
+                "unknown" | "pc" | "apple" | "msvc" | "gnueabihf" | "gnuabi64" => (None, None),
+                _ => return None,
+            };
+            for (slot, value) in [(&mut os, word_os), (&mut arch, word_arch)] {
+                match (*slot, value) {
+                    (Some(existing), Some(new)) if existing != new => return None,
+                    (_, Some(new)) => *slot = Some(new),
+                    _ => {}
+                }
+            }
+        }
+
+        let os = os?;
+        let arch = match arch {
+            Some("x86") if os != "windows" => "i686",
+            Some(arch) => arch,
+            None if os == "wasi" => "wasm32",
+            None => return None,
+        };
+        let env = match os {
+            "linux" if musl => Some("musl"),
+            "windows" if gnu => Some("gnu"),
+            _ => None,
+        };
+        Self::from_parts(os, arch, env)
+    }
+
+    /// Returns the targets whose binaries run on this one, best first.
+    ///
+    /// The list starts with the target itself, followed by targets the
+    /// platform runs through emulation or a compatibility layer: x86_64 on
+    /// Apple silicon (Rosetta 2), x86_64 and x86 on Windows ARM64, and
+    /// 32-bit x86 on x86_64 Windows, Linux and Android. Fallbacks are slower, and on
+    /// Linux need the 32-bit libraries for dynamically linked binaries.
+    /// ARMv7 also runs ARMv6 binaries natively, just without newer
+    /// instructions.
+    ///
+    /// glibc systems also run musl binaries, which are normally static, but
+    /// prefer their own libc's build. musl systems can't run glibc binaries.
+    ///
+    /// [`emulation_on`](Self::emulation_on) tells which fallbacks need
+    /// emulation.
+    pub fn compatible_targets(&self) -> Vec<Target> {
+        let fallbacks: &[Target] = match self {
+            Target::DarwinAarch64 => &[Target::DarwinX86_64],
+            Target::WindowsAarch64 => &[
+                Target::WindowsX86_64,
+                Target::WindowsX86_64Gnu,
+                Target::WindowsX86,
+            ],
+            Target::WindowsX86_64 => &[Target::WindowsX86_64Gnu, Target::WindowsX86],
+            Target::WindowsX86_64Gnu => &[Target::WindowsX86_64, Target::WindowsX86],
+            Target::LinuxX86_64 => &[Target::LinuxX86_64Musl, Target::LinuxI686],
+            Target::LinuxAarch64 => &[Target::LinuxAarch64Musl],
+            Target::LinuxArmv7 => &[Target::LinuxArmv6],
+            Target::AndroidX86_64 => &[Target::AndroidI686],
+            _ => &[],
+        };
+        std::iter::once(*self)
+            .chain(fallbacks.iter().copied())
+            .collect()
+    }
+
+    /// Returns the emulation `host` needs to run this target's binaries, or
+    /// `None` if it runs them natively (or not at all).
+    pub fn emulation_on(&self, host: Target) -> Option<Emulation> {
+        match (host, self) {
+            (Target::DarwinAarch64, Target::DarwinX86_64) => Some(Emulation::Rosetta),
+            (
+                Target::WindowsAarch64,
+                Target::WindowsX86_64 | Target::WindowsX86_64Gnu | Target::WindowsX86,
+            ) => Some(Emulation::WindowsOnArm),
+            (host, Target::WasiWasm32) if host != Target::WasiWasm32 => {
+                Some(Emulation::WasmRuntime)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the support tier this target belongs to.
+    pub fn tier(&self) -> PlatformTier {
+        match self {
+            Target::LinuxX86_64
+            | Target::LinuxAarch64
+            | Target::DarwinX86_64
+            | Target::DarwinAarch64
+            | Target::WindowsX86_64
+            | Target::WindowsAarch64 => PlatformTier::Core,
+            Target::LinuxX86_64Musl
+            | Target::LinuxAarch64Musl
+            | Target::LinuxRiscv64
+            | Target::LinuxArmv7
+            | Target::LinuxPpc64le
+            | Target::LinuxS390x
+            | Target::LinuxI686
+            | Target::WindowsX86
+            | Target::WindowsX86_64Gnu => PlatformTier::Standard,
+            Target::LinuxArmv6
+            | Target::LinuxMips64
+            | Target::LinuxLoongarch64
+            | Target::FreebsdX86_64
+            | Target::FreebsdAarch64
+            | Target::NetbsdX86_64
+            | Target::OpenbsdX86_64
+            | Target::DragonflyX86_64
+            | Target::IllumosX86_64
+            | Target::HaikuX86_64
+            | Target::AndroidAarch64
+            | Target::AndroidArmv7
+            | Target::AndroidX86_64
+            | Target::AndroidI686
+            | Target::IosAarch64
+            | Target::TvosAarch64
+            | Target::WatchosAarch64
+            | Target::WasiWasm32 => PlatformTier::Extended,
+        }
+    }
+
+    /// Returns this target's position in the canonical order, i.e. in
+    /// [`Target::all`].
+    pub fn canonical_index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Returns all supported targets, in canonical order.
     pub fn all() -> &'static [Target] {
         &[
             Target::LinuxX86_64,
             Target::LinuxAarch64,
+            Target::LinuxX86_64Musl,
+            Target::LinuxAarch64Musl,
             Target::LinuxRiscv64,
             Target::LinuxArmv7,
+            Target::LinuxArmv6,
             Target::LinuxPpc64le,
             Target::LinuxS390x,
             Target::LinuxMips64,
@@ -220,21 +668,884 @@ impl Target {
             Target::WindowsX86_64,
             Target::WindowsAarch64,
             Target::WindowsX86,
+            Target::WindowsX86_64Gnu,
             Target::FreebsdX86_64,
             Target::FreebsdAarch64,
             Target::NetbsdX86_64,
             Target::OpenbsdX86_64,
+            Target::DragonflyX86_64,
+            Target::IllumosX86_64,
+            Target::HaikuX86_64,
             Target::AndroidAarch64,
             Target::AndroidArmv7,
             Target::AndroidX86_64,
+            Target::AndroidI686,
             Target::IosAarch64,
+            Target::TvosAarch64,
+            Target::WatchosAarch64,
             Target::WasiWasm32,
         ]
     }
 }
 
-impl std::fmt::Display for Target {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.as_str())
     }
 }
+
+impl FromStr for Target {
+    type Err = Error;
+
+    /// Parses the names [`Target::as_str`] produces.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "linux-x86_64" => Ok(Target::LinuxX86_64),
+            "linux-aarch64" => Ok(Target::LinuxAarch64),
+            "linux-x86_64-musl" => Ok(Target::LinuxX86_64Musl),
+            "linux-aarch64-musl" => Ok(Target::LinuxAarch64Musl),
+            "linux-riscv64" => Ok(Target::LinuxRiscv64),
+            "linux-armv7" => Ok(Target::LinuxArmv7),
+            "linux-armv6" => Ok(Target::LinuxArmv6),
+            "linux-ppc64le" => Ok(Target::LinuxPpc64le),
+            "linux-s390x" => Ok(Target::LinuxS390x),
+            "linux-mips64" => Ok(Target::LinuxMips64),
+            "linux-i686" => Ok(Target::LinuxI686),
+            "linux-loongarch64" => Ok(Target::LinuxLoongarch64),
+            "darwin-x86_64" => Ok(Target::DarwinX86_64),
+            "darwin-aarch64" => Ok(Target::DarwinAarch64),
+            "windows-x86_64" => Ok(Target::WindowsX86_64),
+            "windows-aarch64" => Ok(Target::WindowsAarch64),
+            "windows-x86" => Ok(Target::WindowsX86),
+            "windows-x86_64-gnu" => Ok(Target::WindowsX86_64Gnu),
+            "freebsd-x86_64" => Ok(Target::FreebsdX86_64),
+            "freebsd-aarch64" => Ok(Target::FreebsdAarch64),
+            "netbsd-x86_64" => Ok(Target::NetbsdX86_64),
+            "openbsd-x86_64" => Ok(Target::OpenbsdX86_64),
+            "dragonfly-x86_64" => Ok(Target::DragonflyX86_64),
+            "illumos-x86_64" => Ok(Target::IllumosX86_64),
+            "haiku-x86_64" => Ok(Target::HaikuX86_64),
+            "android-aarch64" => Ok(Target::AndroidAarch64),
+            "android-armv7" => Ok(Target::AndroidArmv7),
+            "android-x86_64" => Ok(Target::AndroidX86_64),
+            "android-i686" => Ok(Target::AndroidI686),
+            "ios-aarch64" => Ok(Target::IosAarch64),
+            "tvos-aarch64" => Ok(Target::TvosAarch64),
+            "watchos-aarch64" => Ok(Target::WatchosAarch64),
+            "wasi-wasm32" => Ok(Target::WasiWasm32),
+            _ => Err(Error::InvalidTarget(s.to_string())),
+        }
+    }
+}
+
+impl Serialize for Target {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Target {
+    /// Accepts only the names [`Target::as_str`] produces.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|_| de::Error::custom(format!("unknown target {:?}", name)))
+    }
+}
+
+/// Detects whether a 32-bit ARM Linux host is ARMv7 or ARMv6 from
+/// `/proc/cpuinfo`, falling back to what this binary was built for.
+#[cfg(all(target_os = "linux", target_arch = "arm"))]
+fn detect_linux_arm() -> Target {
+    std::fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|cpuinfo| arm_target_from_cpuinfo(&cpuinfo))
+        .unwrap_or(if cfg!(target_feature = "v7") {
+            Target::LinuxArmv7
+        } else {
+            Target::LinuxArmv6
+        })
+}
+
+/// Picks the ARM Linux target for a host from its `/proc/cpuinfo`.
+///
+/// The "CPU architecture" field isn't enough on its own: the ARM1176 in the
+/// original Raspberry Pi reports 7 but is ARMv6, which its model name
+/// ("... (v6l)") gives away.
+#[cfg(any(test, all(target_os = "linux", target_arch = "arm")))]
+fn arm_target_from_cpuinfo(cpuinfo: &str) -> Option<Target> {
+    if cpuinfo.contains("(v6l)") {
+        return Some(Target::LinuxArmv6);
+    }
+    let architecture = cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        (key.trim() == "CPU architecture").then(|| value.trim())
+    })?;
+    let major: String = architecture
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    match major.parse::<u32>().ok()? {
+        7.. => Some(Target::LinuxArmv7),
+        _ => Some(Target::LinuxArmv6),
+    }
+}
+
+/// Detects whether the Linux host's C library is musl.
+///
+/// This binary's own libc says nothing about the system's, since a static
+/// build runs on either, so this looks for the system's dynamic loaders.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn detect_linux_musl() -> bool {
+    let loaders: Vec<String> = ["/lib", "/lib64"]
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    let has_loader = |prefix: &str| loaders.iter().any(|name| name.starts_with(prefix));
+    is_musl_host(has_loader("ld-musl-"), has_loader("ld-linux-"), || {
+        let output = std::process::Command::new("ldd")
+            .arg("--version")
+            .output()
+            .ok()?;
+        // musl's ldd prints its version to stderr, glibc's to stdout.
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        Some(text)
+    })
+}
+
+/// Decides whether a Linux host uses musl from which dynamic loaders it
+/// has, running `ldd --version` only when it has both.
+///
+/// glibc systems can have musl's loader installed (Debian's `musl`
+/// package), so then `ldd` decides, defaulting to glibc. A system with
+/// neither loader only runs static binaries, which musl builds normally
+/// are.
+#[cfg(any(
+    test,
+    all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    )
+))]
+fn is_musl_host(
+    musl_loader: bool,
+    glibc_loader: bool,
+    ldd_version: impl FnOnce() -> Option<String>,
+) -> bool {
+    match (musl_loader, glibc_loader) {
+        (true, true) => ldd_version().is_some_and(|version| version.contains("musl")),
+        (_, glibc_loader) => !glibc_loader,
+    }
+}
+
+/// Detects whether this x86_64 process runs under Rosetta 2, i.e. on
+/// Apple silicon.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+fn is_translated() -> bool {
+    std::process::Command::new("sysctl")
+        .args(["-n", "sysctl.proc_translated"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "1")
+}
+
+/// Detects the Windows host's platform from the processor environment
+/// variables, which describe the machine even to emulated processes,
+/// falling back to what this binary was built for.
+#[cfg(target_os = "windows")]
+fn detect_windows() -> Target {
+    let built_for = if cfg!(target_arch = "aarch64") {
+        Target::WindowsAarch64
+    } else if cfg!(target_arch = "x86") {
+        Target::WindowsX86
+    } else if cfg!(target_env = "gnu") {
+        Target::WindowsX86_64Gnu
+    } else {
+        Target::WindowsX86_64
+    };
+    let var = |name: &str| std::env::var(name).ok();
+    let architecture = var("PROCESSOR_ARCHITEW6432").or_else(|| var("PROCESSOR_ARCHITECTURE"));
+    match windows_arch_from_env(
+        architecture.as_deref(),
+        var("PROCESSOR_IDENTIFIER").as_deref(),
+    ) {
+        Some(Arch::Aarch64) => Target::WindowsAarch64,
+        Some(Arch::X86_64) if built_for.arch() != Arch::X86_64 => Target::WindowsX86_64,
+        _ => built_for,
+    }
+}
+
+/// Picks the Windows host's architecture from `PROCESSOR_ARCHITECTURE`
+/// (or `PROCESSOR_ARCHITEW6432`, set for 32-bit processes) and
+/// `PROCESSOR_IDENTIFIER`.
+///
+/// x64 processes emulated on ARM64 see an `AMD64` architecture, but the
+/// identifier still names the ARM CPU.
+#[cfg(any(test, target_os = "windows"))]
+fn windows_arch_from_env(architecture: Option<&str>, identifier: Option<&str>) -> Option<Arch> {
+    if identifier.is_some_and(|identifier| identifier.starts_with("ARM")) {
+        return Some(Arch::Aarch64);
+    }
+    match architecture? {
+        a if a.eq_ignore_ascii_case("ARM64") => Some(Arch::Aarch64),
+        a if a.eq_ignore_ascii_case("AMD64") => Some(Arch::X86_64),
+        a if a.eq_ignore_ascii_case("x86") => Some(Arch::X86),
+        _ => None,
+    }
+}
+
+/// Emulation a host uses to run binaries built for another target (see
+/// [`Target::emulation_on`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emulation {
+    /// Rosetta 2, running x86_64 macOS binaries on Apple silicon.
+    Rosetta,
+    /// Windows on ARM's built-in emulation of x64 and x86 binaries.
+    WindowsOnArm,
+    /// A WebAssembly runtime (wasmtime or wasmer) running a WASI module.
+    WasmRuntime,
+}
+
+impl Emulation {
+    /// Returns the emulation's name, for messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Emulation::Rosetta => "Rosetta 2",
+            Emulation::WindowsOnArm => "Windows on ARM emulation",
+            Emulation::WasmRuntime => "a WebAssembly runtime",
+        }
+    }
+
+    /// Returns true if the running system has this emulation installed.
+    ///
+    /// Rosetta 2 is installed on first use, or with
+    /// `softwareupdate --install-rosetta`. Windows on ARM always emulates
+    /// x86, and x64 since Windows 11. A WebAssembly runtime must be on
+    /// `PATH`.
+    pub fn is_available(&self) -> bool {
+        match self {
+            Emulation::Rosetta => {
+                cfg!(target_os = "macos")
+                    && std::path::Path::new("/Library/Apple/usr/share/rosetta").exists()
+            }
+            Emulation::WindowsOnArm => cfg!(target_os = "windows"),
+            Emulation::WasmRuntime => find_wasm_runtime().is_some(),
+        }
+    }
+}
+
+impl fmt::Display for Emulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Finds a WebAssembly runtime on `PATH`: wasmtime, or else wasmer.
+pub(crate) fn find_wasm_runtime() -> Option<(&'static str, std::path::PathBuf)> {
+    let path = std::env::var_os("PATH")?;
+    ["wasmtime", "wasmer"].into_iter().find_map(|name| {
+        let file_name = format!("{}{}", name, std::env::consts::EXE_SUFFIX);
+        std::env::split_paths(&path)
+            .map(|dir| dir.join(&file_name))
+            .find(|candidate| candidate.is_file())
+            .map(|candidate| (name, candidate))
+    })
+}
+
+/// Platform tier classification.
+///
+/// Tiers are ordered and nested: each includes the targets of the tiers
+/// before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PlatformTier {
+    /// Core platforms (most common, highest priority).
+    /// Linux/macOS/Windows on x86_64 and ARM64.
+    Core,
+    /// Standard platforms (common, good support).
+    /// Adds more Linux architectures, musl, 32-bit x86 and MinGW variants.
+    Standard,
+    /// Extended platforms (specialized, full support).
+    /// Adds BSDs, Android, Apple mobile, embedded targets and WASI.
+    Extended,
+}
+
+impl PlatformTier {
+    /// Returns the targets in this tier, in canonical order.
+    pub fn targets(&self) -> Vec<Target> {
+        Target::all()
+            .iter()
+            .copied()
+            .filter(|target| target.tier() <= *self)
+            .collect()
+    }
+}
+
+/// Operating system a [`Target`] runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Os {
+    Linux,
+    Darwin,
+    Windows,
+    FreeBsd,
+    NetBsd,
+    OpenBsd,
+    DragonFly,
+    Illumos,
+    Haiku,
+    Android,
+    Ios,
+    Tvos,
+    Watchos,
+    Wasi,
+}
+
+impl Os {
+    /// Returns the name used in target strings (e.g., "darwin").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Os::Linux => "linux",
+            Os::Darwin => "darwin",
+            Os::Windows => "windows",
+            Os::FreeBsd => "freebsd",
+            Os::NetBsd => "netbsd",
+            Os::OpenBsd => "openbsd",
+            Os::DragonFly => "dragonfly",
+            Os::Illumos => "illumos",
+            Os::Haiku => "haiku",
+            Os::Android => "android",
+            Os::Ios => "ios",
+            Os::Tvos => "tvos",
+            Os::Watchos => "watchos",
+            Os::Wasi => "wasi",
+        }
+    }
+
+    /// Returns the display name (e.g., "macOS").
+    pub fn name(&self) -> &'static str {
+        match self {
+            Os::Linux => "Linux",
+            Os::Darwin => "macOS",
+            Os::Windows => "Windows",
+            Os::FreeBsd => "FreeBSD",
+            Os::NetBsd => "NetBSD",
+            Os::OpenBsd => "OpenBSD",
+            Os::DragonFly => "DragonFly BSD",
+            Os::Illumos => "illumos",
+            Os::Haiku => "Haiku",
+            Os::Android => "Android",
+            Os::Ios => "iOS",
+            Os::Tvos => "tvOS",
+            Os::Watchos => "watchOS",
+            Os::Wasi => "WASI",
+        }
+    }
+}
+
+impl fmt::Display for Os {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// CPU architecture a [`Target`] runs on.
+///
+/// 32-bit x86 is one architecture, though Linux target names call it i686
+/// and Windows ones x86.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Armv7,
+    Armv6,
+    Ppc64le,
+    S390x,
+    Mips64,
+    X86,
+    Loongarch64,
+    Wasm32,
+}
+
+impl Arch {
+    /// Returns the architecture's name (e.g., "aarch64").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64 => "riscv64",
+            Arch::Armv7 => "armv7",
+            Arch::Armv6 => "armv6",
+            Arch::Ppc64le => "ppc64le",
+            Arch::S390x => "s390x",
+            Arch::Mips64 => "mips64",
+            Arch::X86 => "x86",
+            Arch::Loongarch64 => "loongarch64",
+            Arch::Wasm32 => "wasm32",
+        }
+    }
+
+    /// Returns the size of a pointer in bits.
+    pub fn pointer_width(&self) -> u32 {
+        match self {
+            Arch::Armv7 | Arch::Armv6 | Arch::X86 | Arch::Wasm32 => 32,
+            _ => 64,
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The target a manifest entry is for.
+///
+/// Manifests may name targets this version doesn't know, written by a newer
+/// packer. Rather than rejecting the whole file, such names are kept as
+/// [`EntryTarget::Unknown`] and written back unchanged; they never match a
+/// lookup by [`Target`]. Serialized as the plain target string, so manifests
+/// are unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EntryTarget {
+    /// A known target.
+    Known(Target),
+    /// [`ASSET_TARGET`], recorded by assets, which apply to every platform.
+    Any,
+    /// A target name this version doesn't recognize.
+    Unknown(String),
+}
+
+impl EntryTarget {
+    /// Parses a manifest target string. Never fails: unrecognized names
+    /// become [`EntryTarget::Unknown`].
+    pub fn parse(name: &str) -> Self {
+        match name.parse() {
+            Ok(target) => EntryTarget::Known(target),
+            Err(_) if name == ASSET_TARGET => EntryTarget::Any,
+            Err(_) => EntryTarget::Unknown(name.to_string()),
+        }
+    }
+
+    /// Returns the string recorded in the manifest.
+    pub fn as_str(&self) -> &str {
+        match self {
+            EntryTarget::Known(target) => target.as_str(),
+            EntryTarget::Any => ASSET_TARGET,
+            EntryTarget::Unknown(name) => name,
+        }
+    }
+
+    /// Returns the known target, if it is one.
+    pub fn known(&self) -> Option<Target> {
+        match self {
+            EntryTarget::Known(target) => Some(*target),
+            _ => None,
+        }
+    }
+}
+
+impl From<Target> for EntryTarget {
+    fn from(target: Target) -> Self {
+        EntryTarget::Known(target)
+    }
+}
+
+impl PartialEq<Target> for EntryTarget {
+    fn eq(&self, other: &Target) -> bool {
+        *self == EntryTarget::Known(*other)
+    }
+}
+
+impl fmt::Display for EntryTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for EntryTarget {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for EntryTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(EntryTarget::parse(&String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tier_targets() {
+        let core = PlatformTier::Core.targets();
+        let standard = PlatformTier::Standard.targets();
+        let extended = PlatformTier::Extended.targets();
+
+        assert!(core.len() < standard.len());
+        assert!(standard.len() < extended.len());
+        assert!(core.iter().all(|target| standard.contains(target)));
+        assert!(standard.iter().all(|target| extended.contains(target)));
+        assert_eq!(extended, Target::all());
+
+        assert!(extended.contains(&Target::DragonflyX86_64));
+        assert!(!standard.contains(&Target::DragonflyX86_64));
+
+        for target in extended {
+            assert_eq!(target.as_str().parse::<Target>().unwrap(), target);
+            assert!(target.tier().targets().contains(&target));
+        }
+    }
+
+    #[test]
+    fn test_arm_target_from_cpuinfo() {
+        let pi_zero = "processor\t: 0\nmodel name\t: ARMv6-compatible processor rev 7 (v6l)\n\
+                       CPU architecture: 7\nHardware\t: BCM2835\n";
+        // ARM1176 cores report architecture 7 with the "(v6l)" model name.
+        assert_eq!(arm_target_from_cpuinfo(pi_zero), Some(Target::LinuxArmv6));
+        let pi_3 = "model name\t: ARMv7 Processor rev 4 (v7l)\nCPU architecture: 7\n";
+        assert_eq!(arm_target_from_cpuinfo(pi_3), Some(Target::LinuxArmv7));
+        let aarch64_kernel = "processor\t: 0\nCPU architecture: 8\n";
+        assert_eq!(
+            arm_target_from_cpuinfo(aarch64_kernel),
+            Some(Target::LinuxArmv7)
+        );
+        assert_eq!(
+            arm_target_from_cpuinfo("CPU architecture: 6TEJ\n"),
+            Some(Target::LinuxArmv6)
+        );
+        assert_eq!(arm_target_from_cpuinfo(""), None);
+    }
+
+    #[test]
+    fn test_is_musl_host() {
+        let no_ldd = || -> Option<String> { panic!("ldd run with one loader") };
+        assert!(is_musl_host(true, false, no_ldd));
+        assert!(!is_musl_host(false, true, no_ldd));
+        assert!(is_musl_host(false, false, no_ldd));
+
+        let glibc_ldd = "ldd (Debian GLIBC 2.36-9) 2.36\nCopyright (C) 2022 Free Software";
+        let musl_ldd = "musl libc (x86_64)\nVersion 1.2.4\nDynamic Program Loader";
+        assert!(!is_musl_host(true, true, || Some(glibc_ldd.to_string())));
+        assert!(is_musl_host(true, true, || Some(musl_ldd.to_string())));
+        assert!(!is_musl_host(true, true, || None));
+    }
+
+    #[test]
+    fn test_windows_arch_from_env() {
+        let surface = "ARMv8 (64-bit) Family 8 Model D4B Revision   0, Qualcomm Technologies Inc";
+        let intel = "Intel64 Family 6 Model 154 Stepping 3, GenuineIntel";
+        assert_eq!(
+            windows_arch_from_env(Some("ARM64"), Some(surface)),
+            Some(Arch::Aarch64)
+        );
+        // An emulated x64 process on the same machine.
+        assert_eq!(
+            windows_arch_from_env(Some("AMD64"), Some(surface)),
+            Some(Arch::Aarch64)
+        );
+        assert_eq!(
+            windows_arch_from_env(Some("AMD64"), Some(intel)),
+            Some(Arch::X86_64)
+        );
+        assert_eq!(windows_arch_from_env(Some("x86"), None), Some(Arch::X86));
+        assert_eq!(windows_arch_from_env(Some("IA64"), Some(intel)), None);
+        assert_eq!(windows_arch_from_env(None, None), None);
+    }
+
+    #[test]
+    fn test_emulation_on() {
+        assert_eq!(
+            Target::DarwinX86_64.emulation_on(Target::DarwinAarch64),
+            Some(Emulation::Rosetta)
+        );
+        assert_eq!(
+            Target::DarwinAarch64.emulation_on(Target::DarwinX86_64),
+            None
+        );
+        assert_eq!(
+            Target::LinuxX86_64Musl.emulation_on(Target::LinuxX86_64),
+            None
+        );
+        assert_eq!(
+            Target::WindowsX86.emulation_on(Target::WindowsAarch64),
+            Some(Emulation::WindowsOnArm)
+        );
+        assert_eq!(Target::WindowsX86.emulation_on(Target::WindowsX86_64), None);
+        assert_eq!(
+            Target::WasiWasm32.emulation_on(Target::LinuxRiscv64),
+            Some(Emulation::WasmRuntime)
+        );
+        for &target in Target::all() {
+            assert_eq!(target.emulation_on(target), None);
+        }
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        let all = Target::all();
+        for (index, target) in all.iter().enumerate() {
+            assert_eq!(target.canonical_index(), index, "{}", target);
+        }
+        assert!(all.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let mut shuffled = all.to_vec();
+        shuffled.reverse();
+        shuffled.sort();
+        assert_eq!(shuffled, all);
+
+        // Grouped by OS family, so each OS's targets are contiguous.
+        let mut seen = Vec::new();
+        for target in all {
+            if seen.last() != Some(&target.os()) {
+                assert!(!seen.contains(&target.os()), "{}", target);
+                seen.push(target.os());
+            }
+        }
+    }
+
+    #[test]
+    fn test_compatible_targets() {
+        for &target in Target::all() {
+            let compatible = target.compatible_targets();
+            assert_eq!(compatible[0], target);
+            for (i, other) in compatible.iter().enumerate().skip(1) {
+                assert_eq!(other.os(), target.os(), "{} on {}", other, target);
+                assert!(!compatible[..i].contains(other));
+            }
+        }
+        assert_eq!(
+            Target::DarwinAarch64.compatible_targets(),
+            [Target::DarwinAarch64, Target::DarwinX86_64]
+        );
+        assert_eq!(
+            Target::LinuxAarch64.compatible_targets(),
+            [Target::LinuxAarch64, Target::LinuxAarch64Musl]
+        );
+        assert_eq!(
+            Target::LinuxAarch64Musl.compatible_targets(),
+            [Target::LinuxAarch64Musl]
+        );
+        assert_eq!(
+            Target::WindowsAarch64.compatible_targets(),
+            [
+                Target::WindowsAarch64,
+                Target::WindowsX86_64,
+                Target::WindowsX86_64Gnu,
+                Target::WindowsX86
+            ]
+        );
+        assert_eq!(
+            Target::AndroidX86_64.compatible_targets(),
+            [Target::AndroidX86_64, Target::AndroidI686]
+        );
+    }
+
+    #[test]
+    fn test_target_string_roundtrip() {
+        for &target in Target::all() {
+            assert_eq!(target.to_string(), target.as_str());
+            assert_eq!(target.to_string().parse::<Target>().unwrap(), target);
+        }
+        for name in [
+            "",
+            "linux",
+            "Linux-x86_64",
+            "linux_x86_64",
+            "--linux-x86_64",
+            "*",
+        ] {
+            assert!(matches!(
+                name.parse::<Target>(),
+                Err(Error::InvalidTarget(ref n)) if n == name
+            ));
+        }
+    }
+
+    #[test]
+    fn test_rust_triple_roundtrip() {
+        for &target in Target::all() {
+            assert_eq!(Target::from_rust_triple(target.rust_triple()), Some(target));
+        }
+    }
+
+    #[test]
+    fn test_from_rust_triple_variants() {
+        for (triple, target) in [
+            ("x86_64-alpine-linux-musl", Target::LinuxX86_64Musl),
+            ("armv7-unknown-linux-musleabihf", Target::LinuxArmv7),
+            ("thumbv7neon-unknown-linux-gnueabihf", Target::LinuxArmv7),
+            ("arm-unknown-linux-musleabihf", Target::LinuxArmv6),
+            ("riscv64gc-unknown-linux-musl", Target::LinuxRiscv64),
+            ("i586-unknown-linux-gnu", Target::LinuxI686),
+            ("x86_64-pc-windows-gnullvm", Target::WindowsX86_64Gnu),
+            ("i686-pc-windows-gnu", Target::WindowsX86),
+            ("aarch64-pc-windows-gnullvm", Target::WindowsAarch64),
+            ("x86_64h-apple-darwin", Target::DarwinX86_64),
+            ("thumbv7neon-linux-androideabi", Target::AndroidArmv7),
+            ("x86_64-pc-illumos", Target::IllumosX86_64),
+            ("wasm32-wasi", Target::WasiWasm32),
+        ] {
+            assert_eq!(Target::from_rust_triple(triple), Some(target), "{}", triple);
+        }
+        for triple in [
+            "",
+            "x86_64",
+            "aarch64-apple-ios-sim",
+            "aarch64-apple-tvos-sim",
+            "aarch64-apple-watchos-sim",
+            "x86_64-apple-ios",
+            "mips64el-unknown-linux-gnuabi64",
+            "powerpc64-unknown-linux-gnu",
+            "wasm32-wasip2",
+            "wasm32-unknown-unknown",
+            "x86_64-unknown-redox",
+            "x86_64-pc-solaris",
+        ] {
+            assert_eq!(Target::from_rust_triple(triple), None, "{}", triple);
+        }
+    }
+
+    #[test]
+    fn test_parse_lenient_aliases() {
+        for (name, target) in [
+            ("linux-x86_64", Target::LinuxX86_64),
+            ("Linux-X86_64", Target::LinuxX86_64),
+            ("linux-amd64", Target::LinuxX86_64),
+            ("linux_amd64", Target::LinuxX86_64),
+            ("linux/amd64", Target::LinuxX86_64),
+            ("linux-x64", Target::LinuxX86_64),
+            ("linux-x86-64", Target::LinuxX86_64),
+            ("amd64-linux", Target::LinuxX86_64),
+            ("x86_64-unknown-linux-musl", Target::LinuxX86_64Musl),
+            ("linux-amd64-musl", Target::LinuxX86_64Musl),
+            ("musl-linux-arm64", Target::LinuxAarch64Musl),
+            ("linux-arm64", Target::LinuxAarch64),
+            ("linux/arm64", Target::LinuxAarch64),
+            ("linux-armhf", Target::LinuxArmv7),
+            ("linux-armv6l", Target::LinuxArmv6),
+            ("linux-386", Target::LinuxI686),
+            ("linux-i386", Target::LinuxI686),
+            ("linux-loong64", Target::LinuxLoongarch64),
+            ("linux-riscv64gc", Target::LinuxRiscv64),
+            ("macos-arm64", Target::DarwinAarch64),
+            ("MacOS-ARM64", Target::DarwinAarch64),
+            ("osx-x86_64", Target::DarwinX86_64),
+            ("darwin_amd64", Target::DarwinX86_64),
+            ("mac-aarch64", Target::DarwinAarch64),
+            ("apple-darwin-arm64", Target::DarwinAarch64),
+            ("win64", Target::WindowsX86_64),
+            ("Win32", Target::WindowsX86),
+            ("windows-amd64", Target::WindowsX86_64),
+            ("windows-x64", Target::WindowsX86_64),
+            ("win-arm64", Target::WindowsAarch64),
+            ("windows-i686", Target::WindowsX86),
+            ("win64-msvc", Target::WindowsX86_64),
+            ("windows-x64-mingw", Target::WindowsX86_64Gnu),
+            ("win64_gnu", Target::WindowsX86_64Gnu),
+            ("linux-x86_64-gnu", Target::LinuxX86_64),
+            ("freebsd-amd64", Target::FreebsdX86_64),
+            ("smartos-x64", Target::IllumosX86_64),
+            ("DragonFlyBSD-amd64", Target::DragonflyX86_64),
+            ("haiku-x64", Target::HaikuX86_64),
+            ("illumos_amd64", Target::IllumosX86_64),
+            ("android-arm64", Target::AndroidAarch64),
+            ("android-arm", Target::AndroidArmv7),
+            ("android-x86", Target::AndroidI686),
+            ("ios-arm64", Target::IosAarch64),
+            ("tvOS-arm64", Target::TvosAarch64),
+            ("watchos_aarch64", Target::WatchosAarch64),
+            ("wasi", Target::WasiWasm32),
+            ("wasm32", Target::WasiWasm32),
+            ("  wasi-wasm32 ", Target::WasiWasm32),
+        ] {
+            assert_eq!(Target::parse_lenient(name), Some(target), "{}", name);
+        }
+        for name in [
+            "",
+            "linux",
+            "amd64",
+            "macos-linux-x64",
+            "linux-amd64-arm64",
+            "win64-arm64",
+            "linux-sparc64",
+            "ios-x64",
+        ] {
+            assert_eq!(Target::parse_lenient(name), None, "{}", name);
+        }
+        assert!("macos-arm64".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_target_properties() {
+        let bits_32 = [
+            Target::LinuxArmv7,
+            Target::LinuxArmv6,
+            Target::LinuxI686,
+            Target::WindowsX86,
+            Target::AndroidArmv7,
+            Target::AndroidI686,
+            Target::WasiWasm32,
+        ];
+        for &target in Target::all() {
+            let name = target
+                .as_str()
+                .trim_end_matches("-musl")
+                .trim_end_matches("-gnu");
+            let (os, arch) = name.split_once('-').unwrap();
+            assert_eq!(target.os().as_str(), os, "{}", target);
+            let arch = if arch == "i686" { "x86" } else { arch };
+            assert_eq!(target.arch().as_str(), arch, "{}", target);
+            assert_eq!(target.os_name(), target.os().name());
+
+            assert_eq!(
+                target.is_windows(),
+                target.rust_triple().contains("windows")
+            );
+            assert_eq!(
+                target.is_unix(),
+                !target.is_windows() && target.os() != Os::Wasi
+            );
+            let extension = match target.os() {
+                Os::Windows => ".exe",
+                Os::Wasi => ".wasm",
+                _ => "",
+            };
+            assert_eq!(target.exe_extension(), extension, "{}", target);
+
+            let bits = if bits_32.contains(&target) { 32 } else { 64 };
+            assert_eq!(target.pointer_width(), bits, "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_target_serde_roundtrip() {
+        for &target in Target::all() {
+            let json = serde_json::to_string(&target).unwrap();
+            assert_eq!(json, format!("\"{}\"", target.as_str()));
+            assert_eq!(serde_json::from_str::<Target>(&json).unwrap(), target);
+        }
+        assert!(serde_json::from_str::<Target>("\"linux_x86_64\"").is_err());
+    }
+
+    #[test]
+    fn test_entry_target_keeps_unknown_names() {
+        for name in ["linux-x86_64", "*", "plan9-amd64", "linux_x86_64"] {
+            let json = format!("\"{}\"", name);
+            let target: EntryTarget = serde_json::from_str(&json).unwrap();
+            assert_eq!(serde_json::to_string(&target).unwrap(), json);
+        }
+        assert_eq!(EntryTarget::parse("linux-x86_64"), Target::LinuxX86_64);
+        assert_eq!(EntryTarget::parse("*"), EntryTarget::Any);
+        assert_eq!(EntryTarget::parse("plan9-amd64").known(), None);
+    }
+}
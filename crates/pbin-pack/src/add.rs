@@ -0,0 +1,296 @@
+//! `pbin-pack add`: appending binaries to an existing file.
+
+use crate::args::{parse_level, parse_target};
+use crate::pack::{
+    check_windows_abi, entry_checksum, file_mode, read_binary, read_checksum_key, split_universal,
+};
+use pbin_compress::CompressionLevel;
+use pbin_core::{Checksum, Compression, PbinEntry, PbinFile, Target};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub(crate) const ADD_USAGE: &str = r#"pbin-pack add - Append binaries to an existing PBIN file
+
+USAGE:
+    pbin-pack add --input <PATH> [OPTIONS]
+
+The file must have been packed with --layout trailing. Existing entries are
+neither moved nor recompressed.
+
+OPTIONS:
+    --input <PATH>              PBIN file to append to (required)
+    --<TARGET> <PATH>           Binary for TARGET, e.g. --linux-riscv64 ./bin
+                                (may be repeated)
+    --replace                   Replace entries for targets already present
+    --hmac-key <PATH>           Key file the input was packed with, if any
+    --compress <LEVEL>          Compression level: fast, balanced, maximum (default: balanced)
+    --no-compress               Store the binaries uncompressed
+    --help                      Show this help message
+
+EXAMPLE:
+    pbin-pack add --input hello.pbin --linux-riscv64 ./hello-riscv64
+"#;
+
+/// Settings for `pbin-pack add`.
+pub(crate) struct AddConfig {
+    /// File to append to.
+    input: PathBuf,
+    /// Binaries to append, in the order given.
+    binaries: Vec<(Target, PathBuf)>,
+    /// Replace entries whose target is already present.
+    replace: bool,
+    /// Key file the input's checksums are keyed with.
+    hmac_key: Option<PathBuf>,
+    compression_level: Option<CompressionLevel>,
+}
+
+pub(crate) fn parse_add_args(args: &[String]) -> Result<AddConfig, String> {
+    let mut input = None;
+    let mut binaries: Vec<(Target, PathBuf)> = Vec::new();
+    let mut replace = false;
+    let mut hmac_key = None;
+    let mut compression_level = Some(CompressionLevel::Balanced);
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--help" | "-h" => {
+                println!("{}", ADD_USAGE);
+                process::exit(0);
+            }
+            "--input" => {
+                i += 1;
+                input = Some(PathBuf::from(
+                    args.get(i).ok_or("--input requires a value")?,
+                ));
+            }
+            "--replace" => {
+                replace = true;
+            }
+            "--hmac-key" => {
+                i += 1;
+                hmac_key = Some(PathBuf::from(
+                    args.get(i).ok_or("--hmac-key requires a value")?,
+                ));
+            }
+            "--compress" => {
+                i += 1;
+                let level_str = args.get(i).ok_or("--compress requires a value")?;
+                compression_level = Some(parse_level(level_str)?);
+            }
+            "--no-compress" => {
+                compression_level = None;
+            }
+            arg => {
+                let target = arg
+                    .strip_prefix("--")
+                    .and_then(parse_target)
+                    .ok_or_else(|| format!("Unknown argument: {}", arg))?;
+                i += 1;
+                let path = args
+                    .get(i)
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                if binaries.iter().any(|(existing, _)| *existing == target) {
+                    return Err(pbin_core::Error::DuplicateTarget(target.to_string()).to_string());
+                }
+                binaries.push((target, PathBuf::from(path)));
+            }
+        }
+        i += 1;
+    }
+
+    let input = input.ok_or("--input is required")?;
+    if binaries.is_empty() {
+        return Err("At least one binary must be specified".to_string());
+    }
+
+    Ok(AddConfig {
+        input,
+        binaries,
+        replace,
+        hmac_key,
+        compression_level,
+    })
+}
+
+/// Appends binaries to a trailing-layout PBIN file.
+///
+/// Only the header and the bytes from the old manifest onward are
+/// rewritten; existing entries are neither read back nor recompressed.
+pub(crate) fn add(config: AddConfig) -> pbin_core::Result<()> {
+    println!("Adding to {}", config.input.display());
+    let mut file = PbinFile::open(&config.input)?;
+    let tail = file.manifest_start();
+    let checksum_key = config
+        .hmac_key
+        .as_ref()
+        .map(read_checksum_key)
+        .transpose()?;
+    file.manifest().check_checksum_key(checksum_key.as_ref())?;
+
+    let mut appended = 0;
+    for (target, path) in &config.binaries {
+        println!("  Reading {} from {}", target, path.display());
+        let data = read_binary(path)?;
+        let given: Vec<(Target, &Path)> = config
+            .binaries
+            .iter()
+            .map(|(target, path)| (*target, path.as_path()))
+            .collect();
+        for (target, _, data) in split_universal(*target, path, data, &given)? {
+            check_windows_abi(target, &data)?;
+            let (stored, compression) = match config.compression_level {
+                Some(level) => (
+                    pbin_compress::dict::compress(&data, level.zstd_level())?,
+                    Compression::Zstd,
+                ),
+                None => (data.clone(), Compression::None),
+            };
+            println!(
+                "    Size: {} bytes, stored: {} bytes",
+                data.len(),
+                stored.len()
+            );
+
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| file.manifest().name.clone());
+            pbin_core::validate_file_name(&file_name)?;
+            let mut entry = PbinEntry::new(
+                target,
+                0, // Assigned by append_entry
+                stored.len() as u64,
+                data.len() as u64,
+                entry_checksum(checksum_key.as_ref(), &data),
+                Checksum::of(&stored),
+            )
+            .with_compression(compression)
+            .with_file_name(file_name)
+            .with_mode(file_mode(path, 0o755)?);
+            if let Some(version) = pbin_compress::segment::detect_min_os_version(&data) {
+                entry = entry.with_min_os_version(version);
+            }
+            entry.dynamic_deps = pbin_compress::segment::detect_dynamic_deps(&data);
+
+            file.append_entry(entry, &stored, config.replace)?;
+            appended += 1;
+        }
+    }
+
+    let mut output = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&config.input)?;
+    output.seek(SeekFrom::Start(tail as u64))?;
+    output.write_all(&file.as_bytes()[tail..])?;
+    output.set_len(file.as_bytes().len() as u64)?;
+    output.seek(SeekFrom::Start(file.header_offset() as u64))?;
+    output.write_all(&file.header().to_bytes())?;
+    output.flush()?;
+
+    println!(
+        "\n  Appended {} entries; {} entries total",
+        appended,
+        file.manifest().entries.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::pack;
+    use crate::pack::tests::{sample_binary, test_config, write_input};
+    use std::collections::HashMap;
+
+    /// Packs `binary` for linux-x86_64 with a trailing manifest.
+    fn pack_trailing(dir: &Path, binary: &[u8]) -> PathBuf {
+        let input = write_input(dir, "app", binary);
+        let mut config = test_config(dir, HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.trailing_manifest = true;
+        let output = config.output.clone();
+        pack(config).unwrap();
+        output
+    }
+
+    fn add_config(input: &Path, binaries: Vec<(Target, PathBuf)>) -> AddConfig {
+        AddConfig {
+            input: input.to_path_buf(),
+            binaries,
+            replace: false,
+            hmac_key: None,
+            compression_level: Some(CompressionLevel::Fast),
+        }
+    }
+
+    #[test]
+    fn test_add_appends_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let x86 = sample_binary(14);
+        let riscv = sample_binary(15);
+        let output = pack_trailing(dir.path(), &x86);
+        let before = std::fs::read(&output).unwrap();
+        let manifest_start = PbinFile::from_bytes(before.clone())
+            .unwrap()
+            .manifest_start();
+
+        let input = write_input(dir.path(), "app-riscv", &riscv);
+        add(add_config(&output, vec![(Target::LinuxRiscv64, input)])).unwrap();
+
+        let after = std::fs::read(&output).unwrap();
+        let file = PbinFile::from_bytes(after.clone()).unwrap();
+        let payload = file.header_offset() + pbin_core::HEADER_SIZE..manifest_start;
+        assert_eq!(after[payload.clone()], before[payload]);
+        file.manifest().validate().unwrap();
+        assert_eq!(file.header().entry_count, 2);
+        for (target, binary) in [(Target::LinuxX86_64, &x86), (Target::LinuxRiscv64, &riscv)] {
+            let entry = file.manifest().find_entry(target).unwrap();
+            assert!(entry
+                .verify_compressed(file.entry_data(entry).unwrap())
+                .unwrap());
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, binary);
+            assert!(entry.verify_checksum(&extracted).unwrap());
+        }
+        let riscv_entry = file.manifest().find_entry(Target::LinuxRiscv64).unwrap();
+        assert_eq!(riscv_entry.file_name, "app-riscv");
+    }
+
+    #[test]
+    fn test_add_existing_target_needs_replace() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = pack_trailing(dir.path(), &sample_binary(16));
+        let updated = sample_binary(17);
+        let input = write_input(dir.path(), "app-new", &updated);
+
+        let binaries = vec![(Target::LinuxX86_64, input)];
+        let err = add(add_config(&output, binaries.clone())).unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+
+        let mut config = add_config(&output, binaries);
+        config.replace = true;
+        add(config).unwrap();
+        let file = PbinFile::open(&output).unwrap();
+        assert_eq!(file.manifest().entries.len(), 1);
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+        assert_eq!(extracted, updated);
+    }
+
+    #[test]
+    fn test_add_requires_trailing_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "app", &sample_binary(18));
+        let config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        let output = config.output.clone();
+        pack(config).unwrap();
+        let before = std::fs::read(&output).unwrap();
+
+        let riscv = write_input(dir.path(), "app-riscv", &sample_binary(19));
+        let err = add(add_config(&output, vec![(Target::LinuxRiscv64, riscv)])).unwrap_err();
+        assert!(err.to_string().contains("trailing manifest"), "{}", err);
+        assert_eq!(std::fs::read(&output).unwrap(), before);
+    }
+}
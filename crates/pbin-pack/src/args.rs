@@ -0,0 +1,1180 @@
+//! Command-line parsing for `pbin-pack`.
+
+use pbin_compress::CompressionLevel;
+use pbin_core::{Compression, Target};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const USAGE: &str = r#"pbin-pack - Pack binaries into PBIN format
+
+USAGE:
+    pbin-pack [OPTIONS]
+    pbin-pack add --input <PATH> [OPTIONS]    (see pbin-pack add --help)
+
+OPTIONS:
+    --name <NAME>               Application name (required)
+    --version <VERSION>         Application version (default: 1.0.0)
+    --output <PATH>             Output .pbin file (required)
+    --description <TEXT>        Short description of the application
+    --license <ID>              License identifier (e.g., MIT OR Apache-2.0)
+    --homepage <URL>            Project homepage
+    --author <NAME>             Application author (may be repeated)
+    --source-id <ID>            Identifier of the sources built (e.g., a git commit)
+    --hmac-key <PATH>           Key entry checksums (keyed BLAKE3) with the
+                                contents of PATH; verifying them needs the
+                                same key file
+    --reproducible              Omit the build timestamp and train
+                                dictionaries with pinned parameters so
+                                identical inputs give identical output.
+                                SOURCE_DATE_EPOCH, when set, is recorded as
+                                the timestamp instead, and also pins training
+    --exe-name <NAME>           Name the main program's binaries are extracted
+                                as (default: each input's file name; Windows
+                                adds .exe)
+    --require-static            Fail if a Linux binary is dynamically linked
+    --strict                    Fail if --version isn't a semantic version
+                                (e.g., 1.2.3) instead of warning
+    --min-os-version <TARGET>=<VERSION>
+                                Oldest OS version TARGET's binary runs on
+                                (default: read from Mach-O/PE headers)
+    --target <TARGET> <PATH>    Binary for a target given by name, Rust triple
+                                or alias (e.g., x86_64-unknown-linux-musl,
+                                macos-arm64). --<TARGET> <PATH> accepts the
+                                same forms
+
+    Linux binaries:
+    --linux-x86_64 <PATH>       Linux x86_64 binary
+    --linux-aarch64 <PATH>      Linux aarch64 binary
+    --linux-x86_64-musl <PATH>  Linux x86_64 binary linked against musl
+    --linux-aarch64-musl <PATH> Linux aarch64 binary linked against musl
+    --linux-riscv64 <PATH>      Linux RISC-V 64 binary
+    --linux-armv7 <PATH>        Linux ARMv7 binary
+    --linux-armv6 <PATH>        Linux ARMv6 hard-float binary (Raspberry Pi
+                                Zero/1)
+    --linux-ppc64le <PATH>      Linux PowerPC 64 LE binary
+    --linux-s390x <PATH>        Linux s390x binary
+    --linux-mips64 <PATH>       Linux MIPS64 binary
+    --linux-i686 <PATH>         Linux i686 (32-bit x86) binary
+    --linux-loongarch64 <PATH>  Linux LoongArch64 binary
+
+    macOS binaries:
+    --darwin-x86_64 <PATH>      macOS x86_64 binary
+    --darwin-aarch64 <PATH>     macOS aarch64 binary
+                                A universal binary given for either is split
+                                into an entry per architecture
+
+    Windows binaries:
+    --windows-x86_64 <PATH>     Windows x86_64 binary (.exe)
+    --windows-aarch64 <PATH>    Windows aarch64 binary (.exe)
+    --windows-x86 <PATH>        Windows x86 (32-bit) binary (.exe)
+    --windows-x86_64-gnu <PATH> Windows x86_64 binary built with MinGW (.exe)
+
+    BSD binaries:
+    --freebsd-x86_64 <PATH>     FreeBSD x86_64 binary
+    --freebsd-aarch64 <PATH>    FreeBSD aarch64 binary
+    --netbsd-x86_64 <PATH>      NetBSD x86_64 binary
+    --openbsd-x86_64 <PATH>     OpenBSD x86_64 binary
+    --dragonfly-x86_64 <PATH>   DragonFly BSD x86_64 binary
+    --illumos-x86_64 <PATH>     illumos x86_64 binary (SmartOS, OmniOS)
+    --haiku-x86_64 <PATH>       Haiku x86_64 binary
+
+    Mobile binaries:
+    --android-aarch64 <PATH>    Android aarch64 binary
+    --android-armv7 <PATH>      Android ARMv7 binary
+    --android-x86_64 <PATH>     Android x86_64 binary
+    --android-i686 <PATH>       Android x86 binary (emulators)
+    --ios-aarch64 <PATH>        iOS aarch64 binary
+    --tvos-aarch64 <PATH>       tvOS aarch64 binary
+    --watchos-aarch64 <PATH>    watchOS aarch64 binary
+
+    WebAssembly:
+    --wasi-wasm32 <PATH>        WASI wasm32 module
+
+    Programs:
+    --program <NAME>            Start the binaries of another program. Target
+                                options after it belong to NAME; those before
+                                the first --program belong to --name. At run
+                                time the program matching the file's name (or
+                                --pbin-program <NAME>) runs, so links named
+                                after each program dispatch to it
+
+    Assets:
+    --asset <NAME>=<PATH>       Data file extracted as NAME next to the binary
+                                (may be repeated)
+
+    Compression options:
+    --compress <LEVEL>          Compression level: fast, balanced, maximum, or
+                                ultra for zstd long-distance matching on very
+                                large binaries (default: balanced)
+    --no-compress               Disable compression entirely
+    --compression-algo <ALGO>   Compression algorithm: zstd, lz4 for faster
+                                startup at a lower ratio, xz for smaller
+                                files, or brotli (default: zstd). Only zstd
+                                trains dictionaries
+    --brotli-window <BITS>      Brotli window size as log2 bytes, 10-24
+                                (default: 22)
+    --zstd-level <N>            Zstd level overriding the --compress preset's
+    --zstd-window-log <BITS>    Zstd window size as log2 bytes, 10-31
+                                (default: the level's, or 27 for --compress
+                                ultra). Packing and unpacking need this much
+                                memory
+    --zstd-workers <N>          Threads compressing each zstd entry in
+                                parallel (default: 0, the main thread only).
+                                Output is the same for any N of 1 or more,
+                                but differs from N = 0
+    --jobs <N>                  Binaries compressed at once (default: number
+                                of CPUs). Output doesn't depend on it
+    --delta-threshold <F>       Similarity (0-1) above which binaries are
+                                delta compressed, overriding the preset's
+    --min-savings <F>           Fraction (0-1) of an entry's size compression
+                                must save, or the entry is stored raw
+                                (default: 0, raw only if it doesn't shrink)
+    --no-bcj                    Disable BCJ preprocessing filter
+    --x86-jcc                   Also BCJ-filter x86 conditional jumps; helps
+                                optimized code, can hurt debug builds
+    --no-delta                  Disable delta compression
+    --delta-chains              Delta compress each similar binary against the
+                                closest one before it, not the group's
+                                reference; suits families that change a
+                                little from one build to the next
+    --no-dict                   Disable dictionary training
+    --dict-size <SIZE>          Largest dictionary to train, in bytes or with
+                                a K or M suffix (default: 32K; 256 to 16M)
+    --dict-in <FILE>            Reuse dictionaries saved by --dict-out
+                                instead of training them, retraining any
+                                that no longer help these binaries. A
+                                single dictionary, such as one trained with
+                                zstd --train, is used for every binary
+    --dict-out <FILE>           Save the dictionaries this run trained or
+                                reused, for --dict-in on a later run
+    --stats-json <FILE>         Write the compression statistics, with each
+                                entry's sizes and stage timings, as JSON.
+                                Not with --no-compress
+    --verify                    Decompress every entry again after
+                                compressing it and fail unless it gives
+                                back its binary (default for --compress
+                                maximum). Not with --dedup chunks or
+                                segments, or --low-memory
+    --dedup <MODE>              Deduplication across binaries: files stores
+                                identical inputs once; chunks also splits
+                                every binary into content-defined chunks and
+                                stores each distinct chunk once, for targets
+                                sharing long runs at different offsets;
+                                segments stores each read-only data section
+                                that binaries share once. chunks and segments
+                                imply --no-delta and --no-dict and need
+                                native extraction (default: files)
+    --low-memory                Compress each binary as it is read from disk
+                                instead of holding every input in memory.
+                                Implies --no-delta, --no-dict and
+                                --layout trailing; zstd or --no-compress only
+    --memory-budget <SIZE>      Keep compression's memory, besides the
+                                inputs, to about SIZE (in bytes or with a K,
+                                M or G suffix) by sampling dictionaries,
+                                skipping delta patches that wouldn't fit and
+                                running fewer jobs; the output is still
+                                correct. Not with --dedup chunks or
+                                segments, --low-memory or --no-compress
+
+    Layout options:
+    --layout <LAYOUT>           Where the manifest goes: standard (after the
+                                header) or trailing (after the payloads, so
+                                entries can be appended cheaply)
+                                (default: standard)
+
+    --help                      Show this help message
+
+EXIT STATUS:
+    0 on success; 64 for invalid arguments or inputs, 65 for a corrupt
+    file, 69 for an unsupported file, 70 for a compression failure and
+    74 for an I/O error
+
+EXAMPLE:
+    pbin-pack \
+        --name hello \
+        --version 1.0.0 \
+        --compress balanced \
+        --linux-x86_64 ./target/x86_64-unknown-linux-gnu/release/hello \
+        --darwin-aarch64 ./target/aarch64-apple-darwin/release/hello \
+        --output hello.pbin
+"#;
+
+/// How binaries share stored data (`--dedup`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dedup {
+    /// Identical inputs are stored once.
+    Files,
+    /// Binaries are stored as content-defined chunks, each stored once.
+    Chunks,
+    /// Read-only data sections shared by binaries are stored once.
+    Segments,
+}
+
+pub(crate) struct Config {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) output: PathBuf,
+    pub(crate) description: Option<String>,
+    pub(crate) license: Option<String>,
+    pub(crate) homepage: Option<String>,
+    pub(crate) authors: Vec<String>,
+    pub(crate) source_id: Option<String>,
+    /// File whose contents key the entry checksums.
+    pub(crate) hmac_key: Option<PathBuf>,
+    /// Build time recorded in the manifest, in seconds since the Unix epoch.
+    pub(crate) created_at: Option<u64>,
+    /// Give identical output for identical inputs (`--reproducible` or
+    /// `SOURCE_DATE_EPOCH`).
+    pub(crate) reproducible: bool,
+    /// Binaries of the main program, named by `name`.
+    pub(crate) binaries: HashMap<Target, PathBuf>,
+    /// Binaries of further programs, by program name.
+    pub(crate) programs: Vec<(String, HashMap<Target, PathBuf>)>,
+    /// Name the main program's binaries are extracted as, overriding the
+    /// input file names.
+    pub(crate) exe_name: Option<String>,
+    /// Minimum OS versions set on the command line, overriding detection.
+    pub(crate) min_os_versions: HashMap<Target, String>,
+    /// Reject dynamically linked Linux binaries instead of warning.
+    pub(crate) require_static: bool,
+    /// Reject a version that isn't semver instead of warning.
+    pub(crate) strict: bool,
+    /// Platform-independent files, by the name they are extracted as.
+    pub(crate) assets: Vec<(String, PathBuf)>,
+    pub(crate) compression_level: Option<CompressionLevel>,
+    /// Algorithm used when compression is enabled.
+    pub(crate) compression_algo: Compression,
+    /// Brotli window size, as log2 bytes.
+    pub(crate) brotli_window: u32,
+    /// Zstd level overriding the preset's.
+    pub(crate) zstd_level: Option<i32>,
+    /// Zstd window log overriding the level's.
+    pub(crate) zstd_window_log: Option<u32>,
+    /// Zstd worker threads.
+    pub(crate) zstd_workers: u32,
+    /// Binaries compressed at once.
+    pub(crate) jobs: usize,
+    /// Delta similarity threshold overriding the preset's.
+    pub(crate) delta_threshold: Option<f64>,
+    /// Fraction of an entry compression must save to be kept.
+    pub(crate) min_savings: f64,
+    /// Chain delta targets instead of patching each against the reference.
+    pub(crate) delta_chains: bool,
+    /// How binaries share stored data.
+    pub(crate) dedup: Dedup,
+    pub(crate) use_bcj: bool,
+    /// Filter x86 conditional near jumps too (`--x86-jcc`).
+    pub(crate) x86_jcc: bool,
+    pub(crate) use_delta: bool,
+    pub(crate) use_dict: bool,
+    /// Largest dictionary to train (`--dict-size`).
+    pub(crate) dict_size: Option<usize>,
+    /// Dictionaries saved by an earlier run, or one dictionary, to reuse
+    /// (`--dict-in`).
+    pub(crate) dict_in: Option<PathBuf>,
+    /// Where to save the dictionaries used (`--dict-out`).
+    pub(crate) dict_out: Option<PathBuf>,
+    /// Where to write the compression statistics as JSON (`--stats-json`).
+    pub(crate) stats_json: Option<PathBuf>,
+    /// Check each compressed entry reverses to its binary (`--verify`).
+    pub(crate) verify: bool,
+    /// Write the manifest after the payloads (`--layout trailing`).
+    pub(crate) trailing_manifest: bool,
+    /// Stream binaries from disk into the output (`--low-memory`).
+    pub(crate) low_memory: bool,
+    /// Memory compression may use besides the inputs (`--memory-budget`).
+    pub(crate) memory_budget: Option<usize>,
+}
+
+pub(crate) fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut name = None;
+    let mut version = String::from("1.0.0");
+    let mut output = None;
+    let mut description = None;
+    let mut license = None;
+    let mut homepage = None;
+    let mut authors = Vec::new();
+    let mut source_id = None;
+    let mut hmac_key = None;
+    let mut reproducible = false;
+    let mut binaries = HashMap::new();
+    let mut main_binaries = None;
+    let mut programs: Vec<(String, HashMap<Target, PathBuf>)> = Vec::new();
+    let mut exe_name = None;
+    let mut min_os_versions = HashMap::new();
+    let mut require_static = false;
+    let mut strict = false;
+    let mut assets: Vec<(String, PathBuf)> = Vec::new();
+    let mut compression_level = Some(CompressionLevel::Balanced);
+    let mut compression_algo = Compression::Zstd;
+    let mut brotli_window = pbin_compress::brotli::DEFAULT_WINDOW;
+    let mut zstd_level = None;
+    let mut zstd_window_log = None;
+    let mut zstd_workers = 0;
+    let mut jobs = std::thread::available_parallelism().map_or(1, usize::from);
+    let mut delta_threshold = None;
+    let mut min_savings = 0.0;
+    let mut delta_chains = false;
+    let mut dedup = Dedup::Files;
+    let mut use_bcj = true;
+    let mut x86_jcc = false;
+    let mut use_delta = true;
+    let mut use_dict = true;
+    let mut dict_size = None;
+    let mut dict_in = None;
+    let mut dict_out = None;
+    let mut stats_json = None;
+    let mut verify = false;
+    let mut trailing_manifest = None;
+    let mut low_memory = false;
+    let mut memory_budget = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--name" => {
+                i += 1;
+                name = Some(args.get(i).ok_or("--name requires a value")?.clone());
+            }
+            "--version" => {
+                i += 1;
+                version = args.get(i).ok_or("--version requires a value")?.clone();
+            }
+            "--description" => {
+                i += 1;
+                description = Some(args.get(i).ok_or("--description requires a value")?.clone());
+            }
+            "--license" => {
+                i += 1;
+                license = Some(args.get(i).ok_or("--license requires a value")?.clone());
+            }
+            "--homepage" => {
+                i += 1;
+                homepage = Some(args.get(i).ok_or("--homepage requires a value")?.clone());
+            }
+            "--author" => {
+                i += 1;
+                authors.push(args.get(i).ok_or("--author requires a value")?.clone());
+            }
+            "--source-id" => {
+                i += 1;
+                source_id = Some(args.get(i).ok_or("--source-id requires a value")?.clone());
+            }
+            "--hmac-key" => {
+                i += 1;
+                hmac_key = Some(PathBuf::from(
+                    args.get(i).ok_or("--hmac-key requires a value")?,
+                ));
+            }
+            "--reproducible" => {
+                reproducible = true;
+            }
+            "--output" => {
+                i += 1;
+                output = Some(PathBuf::from(
+                    args.get(i).ok_or("--output requires a value")?,
+                ));
+            }
+            "--compress" => {
+                i += 1;
+                let level_str = args.get(i).ok_or("--compress requires a value")?;
+                compression_level = Some(parse_level(level_str)?);
+            }
+            "--no-compress" => {
+                compression_level = None;
+            }
+            "--compression-algo" => {
+                i += 1;
+                let algo = args.get(i).ok_or("--compression-algo requires a value")?;
+                compression_algo = match algo.parse() {
+                    Ok(Compression::None) | Err(_) => {
+                        return Err(format!("Unknown compression algorithm: {}", algo))
+                    }
+                    Ok(algo) => algo,
+                };
+            }
+            "--brotli-window" => {
+                i += 1;
+                let bits = args.get(i).ok_or("--brotli-window requires a value")?;
+                brotli_window = bits
+                    .parse()
+                    .ok()
+                    .filter(|bits| pbin_compress::brotli::WINDOW_RANGE.contains(bits))
+                    .ok_or_else(|| format!("Invalid Brotli window: {}", bits))?;
+            }
+            "--zstd-level" => {
+                i += 1;
+                let level = args.get(i).ok_or("--zstd-level requires a value")?;
+                let range = pbin_compress::dict::level_range();
+                zstd_level = Some(
+                    level
+                        .parse()
+                        .ok()
+                        .filter(|level| range.contains(level))
+                        .ok_or_else(|| {
+                            format!(
+                                "Invalid zstd level: {} (expected {} to {})",
+                                level,
+                                range.start(),
+                                range.end()
+                            )
+                        })?,
+                );
+            }
+            "--zstd-window-log" => {
+                i += 1;
+                let bits = args.get(i).ok_or("--zstd-window-log requires a value")?;
+                zstd_window_log = Some(
+                    bits.parse()
+                        .ok()
+                        .filter(|bits| pbin_compress::dict::WINDOW_LOG_RANGE.contains(bits))
+                        .ok_or_else(|| format!("Invalid zstd window log: {}", bits))?,
+                );
+            }
+            "--jobs" => {
+                i += 1;
+                let count = args.get(i).ok_or("--jobs requires a value")?;
+                jobs = count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| format!("Invalid job count: {}", count))?;
+            }
+            "--zstd-workers" => {
+                i += 1;
+                let workers = args.get(i).ok_or("--zstd-workers requires a value")?;
+                zstd_workers = workers
+                    .parse()
+                    .map_err(|_| format!("Invalid zstd worker count: {}", workers))?;
+            }
+            "--delta-threshold" => {
+                i += 1;
+                let threshold = args.get(i).ok_or("--delta-threshold requires a value")?;
+                delta_threshold = Some(
+                    threshold
+                        .parse()
+                        .ok()
+                        .filter(|threshold| (0.0..=1.0).contains(threshold))
+                        .ok_or_else(|| format!("Invalid delta threshold: {}", threshold))?,
+                );
+            }
+            "--min-savings" => {
+                i += 1;
+                let fraction = args.get(i).ok_or("--min-savings requires a value")?;
+                min_savings = fraction
+                    .parse()
+                    .ok()
+                    .filter(|fraction| (0.0..=1.0).contains(fraction))
+                    .ok_or_else(|| format!("Invalid minimum savings: {}", fraction))?;
+            }
+            "--delta-chains" => {
+                delta_chains = true;
+            }
+            "--dedup" => {
+                i += 1;
+                let mode = args.get(i).ok_or("--dedup requires a value")?;
+                dedup = match mode.as_str() {
+                    "files" => Dedup::Files,
+                    "chunks" => Dedup::Chunks,
+                    "segments" => Dedup::Segments,
+                    _ => return Err(format!("Unknown dedup mode: {}", mode)),
+                };
+            }
+            "--no-bcj" => {
+                use_bcj = false;
+            }
+            "--x86-jcc" => {
+                x86_jcc = true;
+            }
+            "--no-delta" => {
+                use_delta = false;
+            }
+            "--no-dict" => {
+                use_dict = false;
+            }
+            "--dict-size" => {
+                i += 1;
+                let size = args.get(i).ok_or("--dict-size requires a value")?;
+                dict_size = Some(
+                    parse_size(size)
+                        .filter(|size| pbin_compress::dict::DICT_SIZE_RANGE.contains(size))
+                        .ok_or_else(|| format!("Invalid dictionary size: {}", size))?,
+                );
+            }
+            "--dict-in" => {
+                i += 1;
+                dict_in = Some(PathBuf::from(
+                    args.get(i).ok_or("--dict-in requires a value")?,
+                ));
+            }
+            "--dict-out" => {
+                i += 1;
+                dict_out = Some(PathBuf::from(
+                    args.get(i).ok_or("--dict-out requires a value")?,
+                ));
+            }
+            "--stats-json" => {
+                i += 1;
+                stats_json = Some(PathBuf::from(
+                    args.get(i).ok_or("--stats-json requires a value")?,
+                ));
+            }
+            "--verify" => {
+                verify = true;
+            }
+            "--layout" => {
+                i += 1;
+                let layout = args.get(i).ok_or("--layout requires a value")?;
+                trailing_manifest = Some(match layout.as_str() {
+                    "standard" => false,
+                    "trailing" => true,
+                    _ => return Err(format!("Unknown layout: {}", layout)),
+                });
+            }
+            "--low-memory" => {
+                low_memory = true;
+            }
+            "--memory-budget" => {
+                i += 1;
+                let size = args.get(i).ok_or("--memory-budget requires a value")?;
+                memory_budget = Some(
+                    parse_size(size)
+                        .filter(|&size| size > 0)
+                        .ok_or_else(|| format!("Invalid memory budget: {}", size))?,
+                );
+            }
+            "--target" => {
+                let name = args.get(i + 1).ok_or("--target requires a target")?;
+                let path = args.get(i + 2).ok_or("--target requires a path")?;
+                let target =
+                    parse_target(name).ok_or_else(|| format!("Unknown target: {}", name))?;
+                add_binary(&mut binaries, target, PathBuf::from(path))?;
+                i += 2;
+            }
+            "--exe-name" => {
+                i += 1;
+                let value = args.get(i).ok_or("--exe-name requires a value")?;
+                pbin_core::validate_file_name(value).map_err(|e| e.to_string())?;
+                exe_name = Some(value.clone());
+            }
+            "--require-static" => {
+                require_static = true;
+            }
+            "--strict" => {
+                strict = true;
+            }
+            "--min-os-version" => {
+                i += 1;
+                let spec = args.get(i).ok_or("--min-os-version requires a value")?;
+                let (target, version) = spec.split_once('=').ok_or_else(|| {
+                    format!("--min-os-version expects <TARGET>=<VERSION>, got {}", spec)
+                })?;
+                let target =
+                    parse_target(target).ok_or_else(|| format!("Unknown target: {}", target))?;
+                if version.is_empty() || !version.split('.').all(|part| part.parse::<u64>().is_ok())
+                {
+                    return Err(format!("Invalid OS version: {}", version));
+                }
+                min_os_versions.insert(target, version.to_string());
+            }
+            "--program" => {
+                i += 1;
+                let program = args.get(i).ok_or("--program requires a value")?;
+                pbin_core::validate_file_name(program).map_err(|e| e.to_string())?;
+                if programs.iter().any(|(existing, _)| existing == program) {
+                    return Err(format!("Duplicate program: {}", program));
+                }
+                // Binaries given so far belong to the previous section.
+                let section = std::mem::take(&mut binaries);
+                match programs.last_mut() {
+                    Some((_, previous)) => *previous = section,
+                    None => main_binaries = Some(section),
+                }
+                programs.push((program.clone(), HashMap::new()));
+            }
+            "--asset" => {
+                i += 1;
+                let spec = args.get(i).ok_or("--asset requires a value")?;
+                let (asset_name, path) = spec
+                    .split_once('=')
+                    .ok_or_else(|| format!("--asset expects <NAME>=<PATH>, got {}", spec))?;
+                pbin_core::validate_file_name(asset_name).map_err(|e| e.to_string())?;
+                if assets.iter().any(|(existing, _)| existing == asset_name) {
+                    return Err(format!("Duplicate asset: {}", asset_name));
+                }
+                assets.push((asset_name.to_string(), PathBuf::from(path)));
+            }
+            arg => {
+                let target = arg
+                    .strip_prefix("--")
+                    .and_then(parse_target)
+                    .ok_or_else(|| format!("Unknown argument: {}", arg))?;
+                i += 1;
+                let path = args
+                    .get(i)
+                    .ok_or_else(|| format!("{} requires a value", arg))?;
+                add_binary(&mut binaries, target, PathBuf::from(path))?;
+            }
+        }
+        i += 1;
+    }
+
+    let name = name.ok_or("--name is required")?;
+    let output = output.ok_or("--output is required")?;
+
+    if let Some((_, last)) = programs.last_mut() {
+        *last = std::mem::replace(&mut binaries, main_binaries.unwrap_or_default());
+    }
+    if let Some((program, _)) = programs.iter().find(|(program, _)| *program == name) {
+        return Err(format!(
+            "--program {} repeats --name; give its binaries before any --program",
+            program
+        ));
+    }
+    if let Some((program, _)) = programs.iter().find(|(_, binaries)| binaries.is_empty()) {
+        return Err(format!("Program {} has no binaries", program));
+    }
+    if binaries.is_empty() && programs.is_empty() {
+        return Err("At least one binary must be specified".to_string());
+    }
+
+    // Streamed entries are only sized once written, so the manifest must
+    // follow them, and delta and dictionary training need whole binaries.
+    if low_memory {
+        if trailing_manifest == Some(false) {
+            return Err("--low-memory writes the trailing layout".to_string());
+        }
+        if compression_level.is_some() && compression_algo != Compression::Zstd {
+            return Err(format!(
+                "--low-memory can't compress with {}",
+                compression_algo
+            ));
+        }
+        trailing_manifest = Some(true);
+        use_delta = false;
+        use_dict = false;
+    }
+    // Chunks replace per-binary payloads, so there is nothing to patch or
+    // compress with a dictionary, and chunking needs whole binaries.
+    if dedup != Dedup::Files {
+        if low_memory {
+            return Err("--dedup chunks and segments can't stream with --low-memory".to_string());
+        }
+        use_delta = false;
+        use_dict = false;
+    }
+
+    // Only per-binary entries are compressed with dictionaries.
+    if (dict_in.is_some() || dict_out.is_some()) && (!use_dict || dedup != Dedup::Files) {
+        return Err(
+            "--dict-in and --dict-out need dictionaries: not with --no-dict, --dedup chunks or segments, or --low-memory"
+                .to_string(),
+        );
+    }
+
+    if stats_json.is_some() && compression_level.is_none() {
+        return Err("--stats-json needs compression: not with --no-compress".to_string());
+    }
+
+    // Only per-binary entries are reversed and checked.
+    if verify && (low_memory || dedup != Dedup::Files) {
+        return Err("--verify can't check --dedup chunks or segments or --low-memory".to_string());
+    }
+
+    // Chunking and streaming don't go through the budgeted stages.
+    if memory_budget.is_some()
+        && (low_memory || dedup != Dedup::Files || compression_level.is_none())
+    {
+        return Err(
+            "--memory-budget can't bound --dedup chunks or segments, --low-memory or --no-compress"
+                .to_string(),
+        );
+    }
+
+    let created_at = build_timestamp(reproducible)?;
+    let reproducible = reproducible || std::env::var_os("SOURCE_DATE_EPOCH").is_some();
+
+    Ok(Config {
+        name,
+        version,
+        output,
+        description,
+        license,
+        homepage,
+        authors,
+        source_id,
+        hmac_key,
+        created_at,
+        reproducible,
+        binaries,
+        programs,
+        exe_name,
+        min_os_versions,
+        require_static,
+        strict,
+        assets,
+        compression_level,
+        compression_algo,
+        brotli_window,
+        zstd_level,
+        zstd_window_log,
+        zstd_workers,
+        jobs,
+        delta_threshold,
+        min_savings,
+        delta_chains,
+        dedup,
+        use_bcj,
+        x86_jcc,
+        use_delta,
+        use_dict,
+        dict_size,
+        dict_in,
+        dict_out,
+        stats_json,
+        verify,
+        trailing_manifest: trailing_manifest.unwrap_or(false),
+        low_memory,
+        memory_budget,
+    })
+}
+
+/// Parses a size in bytes, optionally with a `K`, `M` or `G` suffix for
+/// KiB, MiB or GiB.
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, unit) = match size.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match size.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => match size.strip_suffix(['G', 'g']) {
+                Some(digits) => (digits, 1024 * 1024 * 1024),
+                None => (size, 1),
+            },
+        },
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// Parses a target name given on the command line, accepting aliases such
+/// as `macos-arm64` and Rust triples. Names other than the canonical one are
+/// echoed back so the user sees what they resolved to.
+pub(crate) fn parse_target(name: &str) -> Option<Target> {
+    let target = Target::parse_lenient(name)?;
+    if name != target.as_str() {
+        eprintln!("  Using target {} for {}", target, name);
+    }
+    Some(target)
+}
+
+/// Records `path` as the binary for `target`. Giving the same target twice
+/// is an error unless the paths agree.
+fn add_binary(
+    binaries: &mut HashMap<Target, PathBuf>,
+    target: Target,
+    path: PathBuf,
+) -> Result<(), String> {
+    match binaries.get(&target) {
+        Some(existing) if *existing != path => Err(format!(
+            "{} ({} and {})",
+            pbin_core::Error::DuplicateTarget(target.to_string()),
+            existing.display(),
+            path.display()
+        )),
+        _ => {
+            binaries.insert(target, path);
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn parse_level(level: &str) -> Result<CompressionLevel, String> {
+    match level {
+        "fast" => Ok(CompressionLevel::Fast),
+        "balanced" => Ok(CompressionLevel::Balanced),
+        "maximum" | "max" => Ok(CompressionLevel::Maximum),
+        "ultra" => Ok(CompressionLevel::Ultra),
+        _ => Err(format!("Unknown compression level: {}", level)),
+    }
+}
+
+/// Returns the build time to record: `SOURCE_DATE_EPOCH` if set, otherwise
+/// the current time, or none for a reproducible build.
+fn build_timestamp(reproducible: bool) -> Result<Option<u64>, String> {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return epoch
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid SOURCE_DATE_EPOCH: {}", epoch));
+    }
+    if reproducible {
+        return Ok(None);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Some(now.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("pbin-pack")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_every_target_flag_accepted() {
+        let mut flags = args(&["--name", "app", "--output", "app.pbin"]);
+        for target in Target::all() {
+            flags.push(format!("--{}", target));
+            flags.push(format!("{}.bin", target));
+        }
+        let config = parse_args(&flags).unwrap();
+        assert_eq!(config.binaries.len(), Target::all().len());
+        assert_eq!(
+            config.binaries[&Target::WasiWasm32],
+            PathBuf::from("wasi-wasm32.bin")
+        );
+
+        let config = parse_args(&args(&[
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--macos-arm64",
+            "app-mac",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.binaries[&Target::DarwinAarch64],
+            PathBuf::from("app-mac")
+        );
+
+        let err = parse_args(&args(&["--name", "app", "--linux-sparc64", "a"]))
+            .err()
+            .unwrap();
+        assert_eq!(err, "Unknown argument: --linux-sparc64");
+    }
+
+    #[test]
+    fn test_target_triple_flag() {
+        let config = parse_args(&args(&[
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--target",
+            "x86_64-unknown-linux-musl",
+            "app-musl",
+            "--darwin-aarch64",
+            "app-mac",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.binaries[&Target::LinuxX86_64Musl],
+            PathBuf::from("app-musl")
+        );
+        assert_eq!(config.binaries.len(), 2);
+
+        let err = parse_args(&args(&[
+            "--name",
+            "app",
+            "--target",
+            "sparc-sun-solaris",
+            "a",
+        ]))
+        .err()
+        .unwrap();
+        assert_eq!(err, "Unknown target: sparc-sun-solaris");
+        let err = parse_args(&args(&["--target", "x86_64-unknown-linux-gnu"]))
+            .err()
+            .unwrap();
+        assert_eq!(err, "--target requires a path");
+    }
+
+    #[test]
+    fn test_duplicate_target_flag_rejected() {
+        let base = ["--name", "app", "--output", "app.pbin"];
+        let err = parse_args(&args(
+            &[&base[..], &["--linux-x86_64", "a", "--linux-x86_64", "b"]].concat(),
+        ))
+        .err()
+        .unwrap();
+        assert!(err.contains("duplicate target: linux-x86_64"), "{}", err);
+
+        // Repeating the same path is harmless.
+        let config = parse_args(&args(
+            &[&base[..], &["--linux-x86_64", "a", "--linux-x86_64", "a"]].concat(),
+        ))
+        .unwrap();
+        assert_eq!(config.binaries.len(), 1);
+    }
+
+    #[test]
+    fn test_compression_algo_flag() {
+        let base = [
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--linux-x86_64",
+            "a",
+        ];
+        let config = parse_args(&args(&base)).unwrap();
+        assert_eq!(config.compression_algo, Compression::Zstd);
+        let config =
+            parse_args(&args(&[&base[..], &["--compression-algo", "lz4"]].concat())).unwrap();
+        assert_eq!(config.compression_algo, Compression::Lz4);
+        assert_eq!(config.brotli_window, 22);
+        let config = parse_args(&args(
+            &[
+                &base[..],
+                &["--compression-algo", "brotli", "--brotli-window", "24"],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert_eq!(config.compression_algo, Compression::Brotli);
+        assert_eq!(config.brotli_window, 24);
+        for bits in ["9", "25", "big"] {
+            let err = parse_args(&args(&[&base[..], &["--brotli-window", bits]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Invalid Brotli window: {}", bits));
+        }
+
+        for algo in ["none", "gzip"] {
+            let err = parse_args(&args(&[&base[..], &["--compression-algo", algo]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Unknown compression algorithm: {}", algo));
+        }
+    }
+
+    #[test]
+    fn test_compression_override_flags() {
+        let base = [
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--linux-x86_64",
+            "a",
+        ];
+        let config = parse_args(&args(&base)).unwrap();
+        assert_eq!((config.zstd_level, config.delta_threshold), (None, None));
+        let config = parse_args(&args(
+            &[
+                &base[..],
+                &["--zstd-level", "15", "--delta-threshold", "0.75"],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert_eq!(config.zstd_level, Some(15));
+        assert_eq!(config.delta_threshold, Some(0.75));
+        let config = parse_args(&args(&[&base[..], &["--min-savings", "0.05"]].concat())).unwrap();
+        assert_eq!(config.min_savings, 0.05);
+        let err = parse_args(&args(&[&base[..], &["--min-savings", "2"]].concat()))
+            .err()
+            .unwrap();
+        assert_eq!(err, "Invalid minimum savings: 2");
+        assert!(!config.delta_chains);
+        let config = parse_args(&args(&[&base[..], &["--delta-chains"]].concat())).unwrap();
+        assert!(config.delta_chains);
+        assert!(!config.x86_jcc);
+        let config = parse_args(&args(&[&base[..], &["--x86-jcc"]].concat())).unwrap();
+        assert!(config.x86_jcc);
+        assert!(!config.verify);
+        let config = parse_args(&args(&[&base[..], &["--verify"]].concat())).unwrap();
+        assert!(config.verify);
+        let err = parse_args(&args(&[&base[..], &["--verify", "--low-memory"]].concat()))
+            .err()
+            .unwrap();
+        assert!(err.starts_with("--verify can't"), "{}", err);
+        let config = parse_args(&args(
+            &[
+                &base[..],
+                &["--dict-in", "in.dict", "--dict-out", "out.dict"],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert_eq!(config.dict_in, Some(PathBuf::from("in.dict")));
+        assert_eq!(config.dict_out, Some(PathBuf::from("out.dict")));
+        assert_eq!(config.dict_size, None);
+        for (size, bytes) in [
+            ("8K", 8 * 1024),
+            ("512k", 512 * 1024),
+            ("1M", 1 << 20),
+            ("300", 300),
+        ] {
+            let config = parse_args(&args(&[&base[..], &["--dict-size", size]].concat())).unwrap();
+            assert_eq!(config.dict_size, Some(bytes));
+        }
+        for size in ["100", "17M", "big", "K"] {
+            let err = parse_args(&args(&[&base[..], &["--dict-size", size]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Invalid dictionary size: {}", size));
+        }
+        let err = parse_args(&args(
+            &[&base[..], &["--dict-out", "d", "--no-dict"]].concat(),
+        ))
+        .err()
+        .unwrap();
+        assert!(err.starts_with("--dict-in and --dict-out need"), "{}", err);
+        let config = parse_args(&args(&[&base[..], &["--stats-json", "s.json"]].concat())).unwrap();
+        assert_eq!(config.stats_json, Some(PathBuf::from("s.json")));
+        let err = parse_args(&args(
+            &[&base[..], &["--stats-json", "s.json", "--no-compress"]].concat(),
+        ))
+        .err()
+        .unwrap();
+        assert!(err.starts_with("--stats-json needs compression"), "{}", err);
+        assert_eq!(config.dedup, Dedup::Files);
+        let config = parse_args(&args(&[&base[..], &["--dedup", "chunks"]].concat())).unwrap();
+        assert_eq!(config.dedup, Dedup::Chunks);
+        assert!(!config.use_delta && !config.use_dict);
+        let config = parse_args(&args(&[&base[..], &["--dedup", "segments"]].concat())).unwrap();
+        assert_eq!(config.dedup, Dedup::Segments);
+        let err = parse_args(&args(&[&base[..], &["--dedup", "blocks"]].concat()))
+            .err()
+            .unwrap();
+        assert_eq!(err, "Unknown dedup mode: blocks");
+
+        for level in ["23", "fast"] {
+            let err = parse_args(&args(&[&base[..], &["--zstd-level", level]].concat()))
+                .err()
+                .unwrap();
+            assert!(
+                err.starts_with(&format!("Invalid zstd level: {} (", level)),
+                "{}",
+                err
+            );
+        }
+        for threshold in ["1.5", "-0.1", "high"] {
+            let err = parse_args(&args(
+                &[&base[..], &["--delta-threshold", threshold]].concat(),
+            ))
+            .err()
+            .unwrap();
+            assert_eq!(err, format!("Invalid delta threshold: {}", threshold));
+        }
+
+        let config = parse_args(&args(
+            &[
+                &base[..],
+                &["--compress", "ultra", "--zstd-window-log", "24"],
+                &["--zstd-workers", "4"],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert_eq!(config.compression_level, Some(CompressionLevel::Ultra));
+        assert_eq!((config.zstd_window_log, config.zstd_workers), (Some(24), 4));
+        let config = parse_args(&args(&[&base[..], &["--jobs", "3"]].concat())).unwrap();
+        assert_eq!(config.jobs, 3);
+        for count in ["0", "many"] {
+            let err = parse_args(&args(&[&base[..], &["--jobs", count]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Invalid job count: {}", count));
+        }
+        for bits in ["9", "32", "wide"] {
+            let err = parse_args(&args(&[&base[..], &["--zstd-window-log", bits]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Invalid zstd window log: {}", bits));
+        }
+        let err = parse_args(&args(&[&base[..], &["--zstd-workers", "-1"]].concat()))
+            .err()
+            .unwrap();
+        assert_eq!(err, "Invalid zstd worker count: -1");
+    }
+
+    #[test]
+    fn test_low_memory_flag() {
+        let base = [
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--linux-x86_64",
+            "a",
+            "--low-memory",
+        ];
+        let config = parse_args(&args(&base)).unwrap();
+        assert!(config.low_memory && config.trailing_manifest);
+        assert!(!config.use_delta && !config.use_dict);
+
+        let err = parse_args(&args(&[&base[..], &["--layout", "standard"]].concat()))
+            .err()
+            .unwrap();
+        assert_eq!(err, "--low-memory writes the trailing layout");
+        let err = parse_args(&args(
+            &[
+                &base[..],
+                &["--compress", "max", "--compression-algo", "xz"],
+            ]
+            .concat(),
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err, "--low-memory can't compress with xz");
+        let err = parse_args(&args(&[&base[..], &["--dedup", "chunks"]].concat()))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            "--dedup chunks and segments can't stream with --low-memory"
+        );
+    }
+
+    #[test]
+    fn test_memory_budget_flag() {
+        let base = [
+            "--name",
+            "app",
+            "--output",
+            "app.pbin",
+            "--linux-x86_64",
+            "a",
+        ];
+        let config = parse_args(&args(&base)).unwrap();
+        assert_eq!(config.memory_budget, None);
+        for (size, bytes) in [("64K", 64 * 1024), ("512M", 512 << 20), ("2G", 2 << 30)] {
+            let config =
+                parse_args(&args(&[&base[..], &["--memory-budget", size]].concat())).unwrap();
+            assert_eq!(config.memory_budget, Some(bytes));
+        }
+        for size in ["0", "big", "G"] {
+            let err = parse_args(&args(&[&base[..], &["--memory-budget", size]].concat()))
+                .err()
+                .unwrap();
+            assert_eq!(err, format!("Invalid memory budget: {}", size));
+        }
+        for flags in [
+            &["--low-memory"][..],
+            &["--dedup", "chunks"],
+            &["--no-compress"],
+        ] {
+            let err = parse_args(&args(
+                &[&base[..], &["--memory-budget", "1M"], flags].concat(),
+            ))
+            .err()
+            .unwrap();
+            assert!(err.starts_with("--memory-budget can't bound"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,2495 @@
+//! Packing binaries and assets into a new PBIN file.
+//!
+//! The input readers here are shared with `pbin-pack add`.
+
+use crate::args::{Config, Dedup};
+use crate::report::{print_report, report_stats, Progress};
+use pbin_compress::bcj::BcjArch;
+use pbin_compress::dict::{self, TrainedDictionary, ZstdParams};
+use pbin_compress::{CompressionError, CompressionLevel, CompressionPipeline};
+use pbin_core::{
+    Checksum, ChecksumKey, Compression, Error, Os, PbinChunkStore, PbinDictionary, PbinEntry,
+    PbinHeader, PbinManifest, Target,
+};
+use pbin_stub::StubGenerator;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Reads an input file, naming it in any error.
+pub(crate) fn read_binary(path: &PathBuf) -> io::Result<Vec<u8>> {
+    let read = || {
+        let mut file = File::open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    };
+    read().map_err(|e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Dictionaries read for `--dict-in`.
+enum DictIn {
+    /// Saved by `--dict-out`, labelled by group.
+    Saved(Vec<(String, TrainedDictionary)>),
+    /// One dictionary for every binary, saved on its own or as zstd
+    /// writes it.
+    Supplied(TrainedDictionary),
+}
+
+/// Reads the dictionaries in the file at `path`, telling them apart by
+/// their magic bytes. A missing file is noted and nothing is reused, so the
+/// first run of a build script can pass the same `--dict-in` as later ones.
+fn read_dictionaries(path: &PathBuf) -> pbin_core::Result<DictIn> {
+    if !path.exists() {
+        println!(
+            "    No saved dictionaries at {}; training them",
+            path.display()
+        );
+        return Ok(DictIn::Saved(Vec::new()));
+    }
+    let data = read_binary(path)?;
+    let read = if data.starts_with(&dict::DICT_SET_MAGIC) {
+        dict::dictionaries_from_bytes(&data).map(DictIn::Saved)
+    } else if data.starts_with(&dict::DICT_MAGIC) {
+        TrainedDictionary::from_bytes(&data).map(DictIn::Supplied)
+    } else if data.starts_with(&dict::ZSTD_DICT_MAGIC) {
+        Ok(DictIn::Supplied(TrainedDictionary {
+            data,
+            sample_count: 0,
+            total_sample_size: 0,
+        }))
+    } else {
+        return Err(Error::InvalidInput(format!(
+            "{}: not saved dictionaries or a zstd dictionary",
+            path.display()
+        )));
+    };
+    read.map_err(|e| Error::InvalidInput(format!("{}: {}", path.display(), e)))
+}
+
+/// Reads the key for keyed checksums from the file at `path`.
+pub(crate) fn read_checksum_key(path: &PathBuf) -> pbin_core::Result<ChecksumKey> {
+    let material = read_binary(path)?;
+    if material.is_empty() {
+        return Err(Error::InvalidInput(format!(
+            "{}: checksum key file is empty",
+            path.display()
+        )));
+    }
+    Ok(ChecksumKey::derive(&material))
+}
+
+/// Returns the checksum recorded for an entry's original `data`, keyed when
+/// the file has a checksum key.
+pub(crate) fn entry_checksum(key: Option<&ChecksumKey>, data: &[u8]) -> Checksum {
+    match key {
+        Some(key) => Checksum::keyed(key, data),
+        None => Checksum::of(data),
+    }
+}
+
+/// Checks that a Windows x86_64 binary was given for the ABI it was built
+/// with, which `--windows-x86_64` and `--windows-x86_64-gnu` record.
+pub(crate) fn check_windows_abi(target: Target, data: &[u8]) -> pbin_core::Result<()> {
+    use pbin_compress::segment::WindowsAbi;
+
+    let abi = pbin_compress::segment::detect_windows_abi(data);
+    let expected = match abi {
+        Some(WindowsAbi::Msvc) if target == Target::WindowsX86_64Gnu => Target::WindowsX86_64,
+        Some(WindowsAbi::Gnu) if target == Target::WindowsX86_64 => Target::WindowsX86_64Gnu,
+        _ => return Ok(()),
+    };
+    Err(Error::InvalidInput(format!(
+        "{} binary was built with the {} toolchain; pass it as --{}",
+        target,
+        abi.map_or("", |abi| abi.as_str()),
+        expected
+    )))
+}
+
+/// Splits a universal macOS binary given for `target` into one binary per
+/// darwin target it has a slice for, with the slice's offset in the file;
+/// other inputs come back whole, at offset 0.
+///
+/// `given` lists the targets the same program has inputs for. Naming a
+/// target the universal binary also covers with a different file is an
+/// error.
+pub(crate) fn split_universal(
+    target: Target,
+    path: &Path,
+    data: Vec<u8>,
+    given: &[(Target, &Path)],
+) -> pbin_core::Result<Vec<(Target, u64, Vec<u8>)>> {
+    if target.os() != Os::Darwin {
+        return Ok(vec![(target, 0, data)]);
+    }
+    let Some(slices) = pbin_compress::segment::split_universal(&data)? else {
+        return Ok(vec![(target, 0, data)]);
+    };
+    let mut split: Vec<(Target, u64, Vec<u8>)> = Vec::new();
+    for slice in slices {
+        let Ok(slice_target) = format!("darwin-{}", slice.arch).parse::<Target>() else {
+            println!(
+                "    Skipping {} slice: no darwin target runs it",
+                slice.arch
+            );
+            continue;
+        };
+        if split.iter().any(|(t, _, _)| *t == slice_target) {
+            println!("    Skipping second {} slice", slice.arch);
+            continue;
+        }
+        if let Some((_, other)) = given
+            .iter()
+            .find(|(t, other)| *t == slice_target && *other != path)
+        {
+            return Err(Error::InvalidInput(format!(
+                "{} is a universal binary with a {} slice, but --{} also gives {}",
+                path.display(),
+                slice_target,
+                slice_target,
+                other.display()
+            )));
+        }
+        split.push((slice_target, slice.offset, slice.data.to_vec()));
+    }
+    if split.is_empty() {
+        return Err(Error::InvalidInput(format!(
+            "{} is a universal binary with no x86_64 or arm64 slice",
+            path.display()
+        )));
+    }
+    let targets: Vec<&str> = split.iter().map(|(t, _, _)| t.as_str()).collect();
+    println!("    Universal binary: split into {}", targets.join(", "));
+    Ok(split)
+}
+
+/// Returns the permission bits of the file at `path`, or `default` on
+/// platforms without unix modes.
+pub(crate) fn file_mode(path: &Path, default: u32) -> io::Result<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = default;
+        Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(default)
+    }
+}
+
+/// Details of an input binary recorded in its manifest entry.
+struct InputInfo {
+    /// Program the binary belongs to, or `None` for the main program.
+    program: Option<String>,
+    target: Target,
+    /// Input file the binary was read from.
+    path: PathBuf,
+    /// Offset of the binary in `path`: nonzero for a slice of a universal
+    /// binary.
+    offset: u64,
+    /// Size of the binary.
+    size: u64,
+    /// Checksum recorded for the binary, keyed if a key was given.
+    checksum: Checksum,
+    /// Name the binary is extracted as.
+    file_name: String,
+    /// Permission bits of the input file.
+    mode: u32,
+    /// Oldest OS version the binary runs on, if known.
+    min_os_version: Option<String>,
+    /// Shared libraries the binary loads at startup.
+    dynamic_deps: Vec<String>,
+}
+
+impl InputInfo {
+    /// Records these details on `entry`.
+    fn apply(&self, mut entry: PbinEntry) -> PbinEntry {
+        entry.target = self.target.into();
+        entry.program = self.program.clone();
+        entry.file_name = self.file_name.clone();
+        entry.mode = self.mode;
+        entry.min_os_version = self.min_os_version.clone();
+        entry.dynamic_deps = self.dynamic_deps.clone();
+        entry
+    }
+}
+
+/// Returns the key of the manifest entry for `program`'s `target` binary,
+/// matching [`PbinEntry::key`].
+fn entry_key(program: Option<&str>, target: Target) -> String {
+    match program {
+        Some(program) => format!("{}/{}", program, target),
+        None => target.as_str().to_string(),
+    }
+}
+
+/// A binary ready to be written, with the checksums its manifest entry needs.
+struct PackedEntry {
+    /// Key of the manifest entry (see [`entry_key`]).
+    key: String,
+    /// Bytes stored in the file, or nothing if they were streamed straight
+    /// into it.
+    data: Vec<u8>,
+    /// Number of bytes stored in the file.
+    stored_size: u64,
+    /// Size of the original binary.
+    uncompressed_size: u64,
+    /// Compression applied to `data`.
+    compression: Compression,
+    /// BCJ filter applied before compression, if any.
+    bcj: Option<BcjArch>,
+    /// Byte ranges `bcj` was applied to, as `[start, end)` pairs, when it
+    /// didn't cover the whole binary.
+    bcj_ranges: Option<Vec<[u64; 2]>>,
+    /// Key of the entry that `data` is a delta patch against, if any.
+    delta_reference: Option<String>,
+    /// Index of the dictionary `data` was compressed with, if any.
+    dict_index: Option<usize>,
+    /// Zstd window log `data` was compressed with, if long-distance
+    /// matching was used.
+    window_log: Option<u32>,
+    /// Chunks of the chunk store making up the binary, which then stores
+    /// no `data` of its own.
+    chunks: Option<Vec<u32>>,
+    /// BLAKE3 of the original binary.
+    checksum: Checksum,
+    /// BLAKE3 of `data`.
+    compressed_checksum: Checksum,
+    /// Keys of other entries whose input was identical, stored as aliases
+    /// of this entry's data.
+    aliases: Vec<String>,
+}
+
+/// Returns `ranges` as the manifest records them, `[start, end)` pairs.
+fn range_pairs(ranges: &[Range<usize>]) -> Vec<[u64; 2]> {
+    ranges
+        .iter()
+        .map(|range| [range.start as u64, range.end as u64])
+        .collect()
+}
+
+/// An asset ready to be written.
+struct PackedAsset {
+    /// Name the asset is extracted as.
+    name: String,
+    /// Permission bits of the input file.
+    mode: u32,
+    /// Bytes stored in the file.
+    data: Vec<u8>,
+    /// Size of the original file.
+    uncompressed_size: u64,
+    /// Compression applied to `data`.
+    compression: Compression,
+    /// Zstd window log `data` was compressed with, if long-distance
+    /// matching was used.
+    window_log: Option<u32>,
+    /// BLAKE3 of the original file.
+    checksum: Checksum,
+    /// BLAKE3 of `data`.
+    compressed_checksum: Checksum,
+}
+
+impl PackedEntry {
+    /// Returns true if the manifest entry with `key` points at `data`.
+    fn stores(&self, key: &str) -> bool {
+        self.key == key || self.aliases.iter().any(|a| a == key)
+    }
+}
+
+/// Builds the compression pipeline `config` asks for at `level`.
+fn configure_pipeline(config: &Config, level: CompressionLevel) -> CompressionPipeline {
+    let mut pipeline = CompressionPipeline::new(level)
+        .with_algorithm(config.compression_algo)
+        .with_brotli_window(config.brotli_window)
+        .with_zstd_params(ZstdParams {
+            window_log: config.zstd_window_log,
+            workers: config.zstd_workers,
+            ..Default::default()
+        })
+        .with_jobs(config.jobs)
+        .with_min_savings(config.min_savings);
+    if let Some(zstd_level) = config.zstd_level {
+        pipeline = pipeline.with_zstd_level(zstd_level);
+    }
+    if let Some(threshold) = config.delta_threshold {
+        pipeline = pipeline.with_delta_threshold(threshold);
+    }
+    if !config.use_bcj {
+        pipeline = pipeline.without_bcj();
+    }
+    if config.x86_jcc {
+        pipeline = pipeline.with_x86_jcc();
+    }
+    if !config.use_delta {
+        pipeline = pipeline.without_delta();
+    }
+    if config.delta_chains {
+        pipeline = pipeline.with_delta_chains();
+    }
+    if !config.use_dict {
+        pipeline = pipeline.without_dict();
+    }
+    if config.verify {
+        pipeline = pipeline.with_verify();
+    }
+    if config.reproducible {
+        pipeline = pipeline.with_deterministic();
+    }
+    if let Some(size) = config.dict_size {
+        pipeline = pipeline.with_dict_size(size);
+    }
+    if let Some(budget) = config.memory_budget {
+        pipeline = pipeline.with_memory_budget(budget);
+    }
+    pipeline
+}
+
+/// Binaries read for packing, shared by every compression mode.
+struct Inputs {
+    /// Binaries by manifest entry key, in the order they were read, without
+    /// those identical to an earlier one. With --low-memory only the key is
+    /// kept: each binary is read again as it is compressed.
+    binaries: Vec<(String, Vec<u8>)>,
+    /// Details of every input, aliases included, by entry key.
+    info: HashMap<String, InputInfo>,
+    /// Keys of the inputs identical to each binary kept, by its key.
+    aliases: HashMap<String, Vec<String>>,
+}
+
+impl Inputs {
+    /// Total size of the binaries to compress.
+    fn total_size(&self) -> u64 {
+        self.binaries
+            .iter()
+            .map(|(key, _)| self.info[key].size)
+            .sum()
+    }
+
+    /// Returns the entry for the binary with `key`, with its checksum and
+    /// aliases, storing no data; each mode fills in how it stored it.
+    fn entry(&mut self, key: String) -> PackedEntry {
+        let info = &self.info[&key];
+        PackedEntry {
+            aliases: self.aliases.remove(&key).unwrap_or_default(),
+            data: Vec::new(),
+            stored_size: 0,
+            uncompressed_size: info.size,
+            compression: Compression::None,
+            bcj: None,
+            bcj_ranges: None,
+            delta_reference: None,
+            dict_index: None,
+            window_log: None,
+            chunks: None,
+            checksum: info.checksum,
+            compressed_checksum: Checksum::of(&[]),
+            key,
+        }
+    }
+
+    /// Converts a compression error, naming the input it came from.
+    fn compression_error(&self, e: CompressionError) -> Error {
+        match e.target().and_then(|key| self.info.get(key)) {
+            Some(info) => Error::Compression(format!("{} (input {})", e, info.path.display())),
+            None => e.into(),
+        }
+    }
+}
+
+/// Binaries as a compression mode stored them.
+struct Payloads {
+    /// Compression recorded in the header.
+    compression: Compression,
+    entries: Vec<PackedEntry>,
+    /// Dictionaries the entries were compressed with, by group.
+    dictionaries: Vec<(String, Vec<u8>)>,
+    /// With --dedup chunks or sections, the chunk store and its stored
+    /// bytes.
+    chunk_store: Option<(PbinChunkStore, Vec<u8>)>,
+    /// With --low-memory, the output the entries were compressed straight
+    /// into, after the stub and a placeholder for the header.
+    streamed_output: Option<File>,
+}
+
+impl Payloads {
+    /// Returns payloads of `entries` alone, compressed with `compression`.
+    fn new(compression: Compression, entries: Vec<PackedEntry>) -> Self {
+        Self {
+            compression,
+            entries,
+            dictionaries: Vec::new(),
+            chunk_store: None,
+            streamed_output: None,
+        }
+    }
+}
+
+/// Reads the binaries `config` names, and sets aside those identical to an
+/// earlier one as its aliases.
+fn read_inputs(config: &Config, checksum_key: Option<&ChecksumKey>) -> pbin_core::Result<Inputs> {
+    let mut binaries: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut input_info: HashMap<String, InputInfo> = HashMap::new();
+    let mut content_hashes: HashMap<String, Checksum> = HashMap::new();
+
+    // Read in a fixed order so the output doesn't depend on hash iteration:
+    // the main program, then the others as given, each in canonical target
+    // order.
+    let mut inputs: Vec<(Option<&str>, &Target, &PathBuf)> = Vec::new();
+    let sections = std::iter::once((None, &config.binaries)).chain(
+        config
+            .programs
+            .iter()
+            .map(|(program, binaries)| (Some(program.as_str()), binaries)),
+    );
+    for (program, binaries) in sections {
+        let mut section: Vec<_> = binaries.iter().collect();
+        section.sort_by_key(|(target, _)| **target);
+        inputs.extend(
+            section
+                .into_iter()
+                .map(|(target, path)| (program, target, path)),
+        );
+    }
+    for &(program, target, path) in &inputs {
+        println!(
+            "  Reading {} from {}",
+            entry_key(program, *target),
+            path.display()
+        );
+
+        if !path.exists() {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Binary not found: {}", path.display()),
+            )));
+        }
+
+        let data = read_binary(path)?;
+        println!("    Size: {} bytes", data.len());
+        let given: Vec<(Target, &Path)> = inputs
+            .iter()
+            .filter(|(other, _, _)| *other == program)
+            .map(|(_, target, path)| (**target, path.as_path()))
+            .collect();
+        for (target, offset, data) in split_universal(*target, path, data, &given)? {
+            let key = entry_key(program, target);
+            if input_info.contains_key(&key) {
+                // Another input named the same universal binary.
+                continue;
+            }
+            check_windows_abi(target, &data)?;
+
+            let file_name = match (&config.exe_name, program) {
+                (Some(name), None) => name.clone(),
+                _ => path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| config.name.clone()),
+            };
+            pbin_core::validate_file_name(&file_name)?;
+            let min_os_version = config
+                .min_os_versions
+                .get(&target)
+                .cloned()
+                .or_else(|| pbin_compress::segment::detect_min_os_version(&data));
+            if let Some(version) = &min_os_version {
+                println!("    Requires {} >= {}", target.os_name(), version);
+            } else if matches!(target.os(), Os::Tvos | Os::Watchos) {
+                eprintln!(
+                    "\n  WARNING: {} binary declares no minimum OS version; pass \
+                     --min-os-version {}=<VERSION> to record one.\n",
+                    target, target
+                );
+            }
+            let dynamic_deps = pbin_compress::segment::detect_dynamic_deps(&data);
+            if !dynamic_deps.is_empty() {
+                println!("    Dynamic deps: {}", dynamic_deps.join(", "));
+                if target.os() == Os::Linux {
+                    if config.require_static {
+                        return Err(Error::InvalidInput(format!(
+                            "{} binary is dynamically linked ({}), but --require-static was given",
+                            target,
+                            dynamic_deps.join(", ")
+                        )));
+                    }
+                    eprintln!(
+                        "\n  WARNING: {} binary is dynamically linked against {}.\n  \
+                         It will fail on systems without these libraries; consider a static \
+                         (e.g., musl) build.\n",
+                        target,
+                        dynamic_deps.join(", ")
+                    );
+                }
+            }
+            input_info.insert(
+                key.clone(),
+                InputInfo {
+                    program: program.map(str::to_string),
+                    target,
+                    path: path.clone(),
+                    offset,
+                    size: data.len() as u64,
+                    checksum: entry_checksum(checksum_key, &data),
+                    file_name,
+                    mode: file_mode(path, 0o755)?,
+                    min_os_version,
+                    dynamic_deps,
+                },
+            );
+
+            content_hashes.insert(key.clone(), Checksum::of(&data));
+            binaries.push((key, if config.low_memory { Vec::new() } else { data }));
+        }
+    }
+
+    // Deduplicate identical inputs: the first entry keeps the data and the
+    // others become aliases pointing at it.
+    let mut aliases: HashMap<String, Vec<String>> = HashMap::new();
+    let mut primaries: HashMap<Checksum, String> = HashMap::new();
+    binaries.retain(|(key, _)| {
+        let hash = content_hashes[key];
+        match primaries.get(&hash) {
+            Some(primary) => {
+                aliases
+                    .entry(primary.clone())
+                    .or_default()
+                    .push(key.clone());
+                false
+            }
+            None => {
+                primaries.insert(hash, key.clone());
+                true
+            }
+        }
+    });
+
+    Ok(Inputs {
+        binaries,
+        info: input_info,
+        aliases,
+    })
+}
+
+/// Reads and compresses the assets `config` names. They are independent
+/// data files, so they skip BCJ, delta and dictionary processing.
+fn pack_assets(
+    config: &Config,
+    checksum_key: Option<&ChecksumKey>,
+) -> pbin_core::Result<Vec<PackedAsset>> {
+    let mut packed_assets = Vec::new();
+    for (name, path) in &config.assets {
+        println!("  Reading asset {} from {}", name, path.display());
+        let data = read_binary(path)?;
+        let checksum = entry_checksum(checksum_key, &data);
+        let (stored, compression, window_log) = match config.compression_level {
+            Some(level) => {
+                let pipeline = configure_pipeline(config, level);
+                let compressed = pipeline.compress(&data)?;
+                if pipeline.stores_raw(data.len(), compressed.len()) {
+                    println!("    Stored raw (compression saved too little)");
+                    (data.clone(), Compression::None, None)
+                } else {
+                    (compressed, config.compression_algo, pipeline.window_log())
+                }
+            }
+            None => (data.clone(), Compression::None, None),
+        };
+        packed_assets.push(PackedAsset {
+            name: name.clone(),
+            mode: file_mode(path, 0o644)?,
+            uncompressed_size: data.len() as u64,
+            compression,
+            window_log,
+            checksum,
+            compressed_checksum: Checksum::of(&stored),
+            data: stored,
+        });
+    }
+    Ok(packed_assets)
+}
+
+/// Builds the pipeline for the modes that also run without compression,
+/// where it only stores the binaries.
+fn storing_pipeline(config: &Config) -> CompressionPipeline {
+    match config.compression_level {
+        Some(level) => configure_pipeline(config, level),
+        None => CompressionPipeline::default()
+            .with_algorithm(Compression::None)
+            .without_bcj(),
+    }
+}
+
+/// Compresses the binaries with --low-memory: each is read from disk again
+/// and compressed straight into the output, after `stub` and a placeholder
+/// for the header.
+fn pack_streamed(config: &Config, inputs: &mut Inputs, stub: &[u8]) -> pbin_core::Result<Payloads> {
+    println!(
+        "\n  Streaming {} binaries from disk (bcj={})...",
+        inputs.binaries.len(),
+        config.use_bcj && config.compression_level.is_some()
+    );
+    let progress = Progress::new(inputs.total_size());
+    let pipeline = progress.attach(storing_pipeline(config));
+    let readers = inputs
+        .binaries
+        .iter()
+        .map(|(key, _)| {
+            let info = &inputs.info[key];
+            let mut file = File::open(&info.path)?;
+            file.seek(SeekFrom::Start(info.offset))?;
+            // A universal slice is followed by the next slice, so only
+            // whole files are checked for having grown since inspection.
+            let reader: Box<dyn Read> = if info.offset > 0 {
+                Box::new(io::BufReader::new(file).take(info.size))
+            } else {
+                Box::new(io::BufReader::new(file))
+            };
+            Ok((key.clone(), reader, info.size))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut output = File::create(&config.output)?;
+    output.write_all(stub)?;
+    output.write_all(&[0; pbin_core::HEADER_SIZE])?;
+    let result = pipeline.compress_streaming(readers, &mut output);
+    progress.finish();
+    let result = result.map_err(|e| inputs.compression_error(e))?;
+    report_stats(config, &result.stats)?;
+
+    let compression = if config.compression_level.is_some() {
+        config.compression_algo
+    } else {
+        Compression::None
+    };
+    let entries = result
+        .entries
+        .into_iter()
+        .map(|entry| PackedEntry {
+            stored_size: entry.compressed_size,
+            compression: entry.compression,
+            bcj: entry.bcj_arch(),
+            window_log: entry.window_log,
+            compressed_checksum: entry.compressed_checksum,
+            ..inputs.entry(entry.target)
+        })
+        .collect();
+    Ok(Payloads {
+        streamed_output: Some(output),
+        ..Payloads::new(compression, entries)
+    })
+}
+
+/// Compresses the binaries into a chunk store shared between them, split by
+/// content or by section as `config.dedup` asks.
+fn pack_deduped(config: &Config, inputs: &mut Inputs) -> pbin_core::Result<Payloads> {
+    println!(
+        "\n  Chunking {} binaries by {} (bcj={})...",
+        inputs.binaries.len(),
+        if config.dedup == Dedup::Chunks {
+            "content"
+        } else {
+            "shared section"
+        },
+        config.use_bcj && config.compression_level.is_some()
+    );
+    let progress = Progress::new(inputs.total_size());
+    let pipeline = progress.attach(storing_pipeline(config));
+    // Entries come back from the chunk store, so segments can have the
+    // binaries themselves.
+    let result = if config.dedup == Dedup::Chunks {
+        pipeline.compress_chunked(&inputs.binaries)
+    } else {
+        pipeline.compress_segments(std::mem::take(&mut inputs.binaries))
+    };
+    progress.finish();
+    let result = result.map_err(|e| inputs.compression_error(e))?;
+    report_stats(config, &result.stats)?;
+    println!(
+        "    Chunk store: {} chunks, {} bytes before compression",
+        result.chunk_sizes.len(),
+        result.store_size
+    );
+
+    let entries = result
+        .entries
+        .into_iter()
+        .map(|entry| PackedEntry {
+            compression: result.compression,
+            bcj: entry.bcj_arch(),
+            bcj_ranges: entry.bcj_ranges.as_deref().map(range_pairs),
+            chunks: Some(entry.chunks),
+            ..inputs.entry(entry.target)
+        })
+        .collect();
+    let chunk_store = PbinChunkStore {
+        offset: 0, // Placeholder
+        compressed_size: result.store.len() as u64,
+        uncompressed_size: result.store_size as u64,
+        checksum: result.store_checksum,
+        compression: Some(result.compression),
+        window_log: result.window_log,
+        chunk_sizes: result.chunk_sizes,
+    };
+    Ok(Payloads {
+        chunk_store: Some((chunk_store, result.store)),
+        ..Payloads::new(result.compression, entries)
+    })
+}
+
+/// Compresses each binary at `level`, with the BCJ filters, delta patches
+/// and dictionaries `config` enables.
+fn pack_compressed(
+    config: &Config,
+    inputs: &mut Inputs,
+    level: CompressionLevel,
+) -> pbin_core::Result<Payloads> {
+    println!(
+        "\n  Compressing with {} at {:?} level (bcj={}, delta={}, dict={})...",
+        config.compression_algo, level, config.use_bcj, config.use_delta, config.use_dict
+    );
+
+    let mut pipeline = configure_pipeline(config, level);
+    if let Some(path) = &config.dict_in {
+        pipeline = match read_dictionaries(path)? {
+            DictIn::Saved(dictionaries) => pipeline.with_saved_dictionaries(dictionaries),
+            DictIn::Supplied(dictionary) => pipeline.with_dictionary(dictionary),
+        };
+    }
+
+    // Compress all binaries. The pipeline identifies them by entry key,
+    // and only borrows them.
+    let binaries = std::mem::take(&mut inputs.binaries);
+    let progress = Progress::new(binaries.iter().map(|(_, d)| d.len() as u64).sum());
+    let mut pipeline = progress.attach(pipeline);
+    let result = pipeline.compress_all(&binaries);
+    progress.finish();
+    let result = result.map_err(|e| inputs.compression_error(e))?;
+
+    report_stats(config, &result.stats)?;
+    if let Some(path) = &config.dict_out {
+        std::fs::write(path, dict::dictionaries_to_bytes(pipeline.dictionaries()))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        println!(
+            "    Saved {} dictionaries to {}",
+            pipeline.dictionaries().len(),
+            path.display()
+        );
+    }
+
+    // Map compressed entries back to their inputs
+    let entries = binaries
+        .into_iter()
+        .map(|(key, _)| {
+            let entry = result
+                .entries
+                .iter()
+                .find(|e| e.target == key)
+                .expect("Missing compressed entry");
+            PackedEntry {
+                data: entry.data.clone(),
+                stored_size: entry.data.len() as u64,
+                compression: entry.compression,
+                bcj: entry.bcj_arch(),
+                bcj_ranges: entry.bcj_ranges.as_deref().map(range_pairs),
+                delta_reference: entry.delta_reference.clone(),
+                dict_index: entry.dict_index,
+                window_log: entry.window_log,
+                compressed_checksum: Checksum::of(&entry.data),
+                ..inputs.entry(key)
+            }
+        })
+        .collect();
+    Ok(Payloads {
+        dictionaries: result.dictionaries,
+        ..Payloads::new(config.compression_algo, entries)
+    })
+}
+
+/// Stores the binaries as they are.
+fn pack_uncompressed(inputs: &mut Inputs) -> Payloads {
+    println!("\n  Compression disabled");
+    let entries = std::mem::take(&mut inputs.binaries)
+        .into_iter()
+        .map(|(key, data)| PackedEntry {
+            stored_size: data.len() as u64,
+            compressed_checksum: Checksum::of(&data),
+            data,
+            ..inputs.entry(key)
+        })
+        .collect();
+    Payloads::new(Compression::None, entries)
+}
+
+pub(crate) fn pack(config: Config) -> pbin_core::Result<()> {
+    println!("Packing {} v{}", config.name, config.version);
+    for (label, value) in [
+        ("Description", &config.description),
+        ("License", &config.license),
+        ("Homepage", &config.homepage),
+    ] {
+        if let Some(value) = value {
+            println!("  {}: {}", label, value);
+        }
+    }
+    if !config.authors.is_empty() {
+        println!("  Authors: {}", config.authors.join(", "));
+    }
+
+    let mut manifest = PbinManifest::new(config.name.clone(), config.version.clone());
+    if let Err(e) = manifest.parsed_version() {
+        if config.strict {
+            return Err(Error::InvalidInput(format!("{} (required by --strict)", e)));
+        }
+        eprintln!(
+            "\n  WARNING: {}; tools can't compare it with other versions.\n",
+            e
+        );
+    }
+
+    let checksum_key = config
+        .hmac_key
+        .as_ref()
+        .map(read_checksum_key)
+        .transpose()?;
+    if let Some(key) = &checksum_key {
+        println!("  Checksum key fingerprint: {}", key.fingerprint());
+        manifest.key_fingerprint = Some(key.fingerprint());
+    }
+    let checksum_key = checksum_key.as_ref();
+
+    let mut inputs = read_inputs(&config, checksum_key)?;
+    let packed_assets = pack_assets(&config, checksum_key)?;
+
+    let stub = StubGenerator::generate();
+    let payloads = if config.low_memory {
+        pack_streamed(&config, &mut inputs, &stub)?
+    } else if config.dedup != Dedup::Files {
+        pack_deduped(&config, &mut inputs)?
+    } else if let Some(level) = config.compression_level {
+        pack_compressed(&config, &mut inputs, level)?
+    } else {
+        pack_uncompressed(&mut inputs)
+    };
+    let Payloads {
+        compression: compression_type,
+        entries: compressed_entries,
+        dictionaries,
+        chunk_store,
+        streamed_output,
+    } = payloads;
+    let input_info = inputs.info;
+
+    println!("\n  Stub size: {} bytes", stub.len());
+
+    // Fill in the manifest with placeholder offsets
+    manifest.description = config.description;
+    manifest.license = config.license;
+    manifest.homepage = config.homepage;
+    manifest.authors = config.authors;
+    manifest.created_at = config.created_at;
+    manifest.created_by = Some(format!("pbin-pack {}", env!("CARGO_PKG_VERSION")));
+    manifest.source_id = config.source_id;
+    manifest.dictionaries = dictionaries
+        .iter()
+        .map(|(group, data)| PbinDictionary::new(group.as_str(), data, 0)) // Placeholder offset
+        .collect();
+    manifest.chunk_store = chunk_store.as_ref().map(|(store, _)| store.clone());
+
+    for entry in &compressed_entries {
+        let info = &input_info[&entry.key];
+        let mut manifest_entry = info.apply(
+            PbinEntry::new(
+                info.target,
+                0, // Placeholder
+                entry.stored_size,
+                entry.uncompressed_size,
+                entry.checksum,
+                entry.compressed_checksum,
+            )
+            .with_compression(entry.compression),
+        );
+        if let Some(arch) = entry.bcj {
+            manifest_entry = manifest_entry.with_bcj(arch.as_str());
+        }
+        if let Some(ranges) = &entry.bcj_ranges {
+            manifest_entry = manifest_entry.with_bcj_ranges(ranges.clone());
+        }
+        if let Some(reference) = &entry.delta_reference {
+            manifest_entry = manifest_entry.with_delta_reference(reference.clone());
+        }
+        if let Some(window_log) = entry.window_log {
+            manifest_entry = manifest_entry.with_window_log(window_log);
+        }
+        if let Some(index) = entry.dict_index {
+            let dict_id = manifest.dictionaries[index].dict_id.clone();
+            manifest_entry = manifest_entry.with_dictionary(index, dict_id);
+        }
+        if let Some(chunks) = &entry.chunks {
+            manifest_entry = manifest_entry.with_chunks(chunks.clone());
+        }
+        let alias_entries: Vec<PbinEntry> = entry
+            .aliases
+            .iter()
+            .map(|alias| input_info[alias].apply(manifest_entry.clone()))
+            .collect();
+        manifest.try_add_entry(manifest_entry)?;
+        for alias_entry in alias_entries {
+            manifest.try_add_entry(alias_entry)?;
+        }
+    }
+
+    for asset in &packed_assets {
+        let mut asset_entry = PbinEntry::new_asset(
+            asset.name.as_str(),
+            0, // Placeholder
+            asset.data.len() as u64,
+            asset.uncompressed_size,
+            asset.checksum,
+            asset.compressed_checksum,
+        )
+        .with_compression(asset.compression)
+        .with_mode(asset.mode);
+        if let Some(window_log) = asset.window_log {
+            asset_entry = asset_entry.with_window_log(window_log);
+        }
+        manifest.try_add_entry(asset_entry)?;
+    }
+
+    // Offsets are relative to the start of the payload region, so they
+    // don't depend on the stub or on the manifest's own length.
+    let payload_size = assign_offsets(&mut manifest, &compressed_entries, &packed_assets);
+    manifest.validate()?;
+    let manifest_json = manifest.to_json()?;
+    let manifest_bytes = manifest_json.as_bytes();
+
+    // Create header
+    let header = PbinHeader::new(
+        compression_type,
+        manifest.entry_count()?,
+        manifest_bytes.len() as u32,
+    );
+    let header = if config.trailing_manifest {
+        header.with_trailing_manifest(payload_size)
+    } else {
+        header.with_relative_offsets()
+    };
+    let header = if manifest.chunk_store.is_some() {
+        header.with_chunks()
+    } else {
+        header
+    };
+
+    // Write output file
+    let mut output = match streamed_output {
+        // The entries are already written; fill in the header before them.
+        Some(mut output) => {
+            output.seek(SeekFrom::Start(stub.len() as u64))?;
+            output.write_all(&header.to_bytes())?;
+            output.seek(SeekFrom::End(0))?;
+            output
+        }
+        None => {
+            let mut output = File::create(&config.output)?;
+            output.write_all(&stub)?;
+            output.write_all(&header.to_bytes())?;
+            if !config.trailing_manifest {
+                output.write_all(manifest_bytes)?;
+            }
+            for (_, dictionary) in &dictionaries {
+                output.write_all(dictionary)?;
+            }
+            if let Some((_, store)) = &chunk_store {
+                output.write_all(store)?;
+            }
+            for entry in &compressed_entries {
+                output.write_all(&entry.data)?;
+            }
+            output
+        }
+    };
+    for asset in &packed_assets {
+        output.write_all(&asset.data)?;
+    }
+    if config.trailing_manifest {
+        output.write_all(manifest_bytes)?;
+    }
+    output.flush()?;
+
+    let summary = manifest.summary();
+    print_report(&manifest, &summary);
+
+    // Make executable on Unix
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = std::fs::metadata(&config.output)?.permissions();
+        perms.set_mode(0o755);
+        std::fs::set_permissions(&config.output, perms)?;
+    }
+
+    let total_size = std::fs::metadata(&config.output)?.len();
+    println!(
+        "\nCreated {} ({} bytes, {:.1}% of original)",
+        config.output.display(),
+        total_size,
+        (total_size as f64 / summary.total_uncompressed_size.max(1) as f64) * 100.0
+    );
+
+    Ok(())
+}
+
+/// Lays out the payload section after the manifest: the dictionaries first,
+/// then any chunk store, then each entry's data in order, shared by the
+/// entry's aliases, then the assets. Chunked entries store no data and keep
+/// offset 0. Offsets count from the start of the payload region. Returns the
+/// region's size.
+fn assign_offsets(
+    manifest: &mut PbinManifest,
+    entries: &[PackedEntry],
+    assets: &[PackedAsset],
+) -> u64 {
+    let mut offset = 0;
+    for dictionary in &mut manifest.dictionaries {
+        dictionary.offset = offset;
+        offset += dictionary.size;
+    }
+    if let Some(store) = &mut manifest.chunk_store {
+        store.offset = offset;
+        offset += store.compressed_size;
+    }
+    for entry in entries.iter().filter(|e| e.chunks.is_none()) {
+        for manifest_entry in &mut manifest.entries {
+            if !manifest_entry.is_asset() && entry.stores(&manifest_entry.key()) {
+                manifest_entry.offset = offset;
+            }
+        }
+        offset += entry.stored_size;
+    }
+    for asset in assets {
+        for manifest_entry in &mut manifest.entries {
+            if manifest_entry.is_asset() && manifest_entry.file_name == asset.name {
+                manifest_entry.offset = offset;
+            }
+        }
+        offset += asset.data.len() as u64;
+    }
+    offset
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use pbin_compress::pipeline::SUPPLIED_DICT_GROUP;
+    use pbin_core::{ErrorCategory, PbinFile, PAYLOAD_MARKER};
+    use std::process;
+
+    pub(crate) fn write_input(dir: &Path, name: &str, data: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    pub(crate) fn test_config(dir: &Path, binaries: HashMap<Target, PathBuf>) -> Config {
+        Config {
+            name: "test".to_string(),
+            version: "1.0.0".to_string(),
+            output: dir.join("test.pbin"),
+            description: None,
+            license: None,
+            homepage: None,
+            authors: Vec::new(),
+            source_id: None,
+            hmac_key: None,
+            created_at: None,
+            reproducible: false,
+            binaries,
+            programs: Vec::new(),
+            exe_name: None,
+            min_os_versions: HashMap::new(),
+            require_static: false,
+            strict: false,
+            assets: Vec::new(),
+            compression_level: None,
+            compression_algo: Compression::Zstd,
+            brotli_window: pbin_compress::brotli::DEFAULT_WINDOW,
+            zstd_level: None,
+            zstd_window_log: None,
+            zstd_workers: 0,
+            jobs: 1,
+            delta_threshold: None,
+            min_savings: 0.0,
+            delta_chains: false,
+            dedup: Dedup::Files,
+            use_bcj: true,
+            x86_jcc: false,
+            use_delta: true,
+            use_dict: true,
+            dict_size: None,
+            dict_in: None,
+            dict_out: None,
+            stats_json: None,
+            verify: false,
+            trailing_manifest: false,
+            low_memory: false,
+            memory_budget: None,
+        }
+    }
+
+    /// Deterministic binary-like data that compresses moderately well.
+    pub(crate) fn sample_binary(seed: u32) -> Vec<u8> {
+        let mut data = b"\x7FELF\x02\x01\x01\x00".to_vec();
+        let mut state = seed.wrapping_add(1);
+        for i in 0..16384u32 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            data.push(if i % 4 == 0 {
+                (state >> 16) as u8
+            } else {
+                (i % 64) as u8
+            });
+        }
+        data
+    }
+
+    /// Deterministic noise no compressor can shrink.
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
+    }
+
+    /// A shell script payload that prints the marker, so it both contains the
+    /// marker bytes and can be run by the stub on any Unix host.
+    fn marker_payload() -> Vec<u8> {
+        let mut payload = b"#!/bin/sh\necho \"ok ".to_vec();
+        payload.extend_from_slice(PAYLOAD_MARKER);
+        payload.extend_from_slice(b" $*\"\n");
+        payload
+    }
+
+    #[test]
+    fn test_missing_binary_is_io_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(
+            dir.path(),
+            HashMap::from([(Target::LinuxX86_64, dir.path().join("missing"))]),
+        );
+
+        let err = pack(config).unwrap_err();
+        assert_eq!(err.category(), ErrorCategory::Io);
+        assert!(err.to_string().contains("Binary not found"), "{}", err);
+    }
+
+    #[test]
+    fn test_unreadable_input_named_in_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = write_input(dir.path(), "good", &sample_binary(20));
+        let bad = dir.path().join("bad");
+        std::fs::create_dir(&bad).unwrap();
+        let config = test_config(
+            dir.path(),
+            HashMap::from([
+                (Target::LinuxX86_64, good.clone()),
+                (Target::LinuxAarch64, bad.clone()),
+                (Target::DarwinAarch64, good),
+            ]),
+        );
+
+        let err = pack(config).unwrap_err().to_string();
+        assert!(err.contains(&bad.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_payload_containing_marker_extracts() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = marker_payload();
+        let input = write_input(dir.path(), "payload", &payload);
+
+        let config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        assert_eq!(file.header_offset(), StubGenerator::stub_size());
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(file.entry_data(entry).unwrap(), payload.as_slice());
+    }
+
+    #[test]
+    fn test_stub_replaced_by_splice() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(9);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        // Swap the stub for a longer one without touching the manifest.
+        let packed = std::fs::read(&output).unwrap();
+        let header_offset = PbinFile::from_bytes(packed.clone())
+            .unwrap()
+            .header_offset();
+        let mut spliced = b"#!/bin/sh\necho a different stub\n".repeat(3);
+        spliced.extend_from_slice(PAYLOAD_MARKER);
+        spliced.extend_from_slice(&packed[header_offset..]);
+
+        let file = PbinFile::from_bytes(spliced).unwrap();
+        assert!(file.header().has_relative_offsets());
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(file.entry_data(entry).unwrap(), binary.as_slice());
+    }
+
+    #[test]
+    fn test_trailing_layout_extracts() {
+        let dir = tempfile::tempdir().unwrap();
+        let x86 = sample_binary(10);
+        let arm = sample_binary(11);
+        let binaries = HashMap::from([
+            (Target::LinuxX86_64, write_input(dir.path(), "x86", &x86)),
+            (Target::LinuxAarch64, write_input(dir.path(), "arm", &arm)),
+        ]);
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.trailing_manifest = true;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let header = file.header();
+        assert!(header.has_trailing_manifest());
+        assert_eq!(
+            file.manifest_start() + header.manifest_size as usize,
+            file.as_bytes().len()
+        );
+        for (target, binary) in [(Target::LinuxX86_64, &x86), (Target::LinuxAarch64, &arm)] {
+            let entry = file.manifest().find_entry(target).unwrap();
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, binary);
+        }
+    }
+
+    #[test]
+    fn test_trailing_layout_appends_without_moving_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let x86 = sample_binary(12);
+        let input = write_input(dir.path(), "app", &x86);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.trailing_manifest = true;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let header_offset = file.header_offset();
+        let manifest_start = file.manifest_start();
+
+        // Write the new entry where the manifest was, then a new manifest.
+        let riscv = sample_binary(13);
+        let appended_at = file.header().manifest_offset;
+        let mut manifest = file.manifest().clone();
+        manifest.add_entry(PbinEntry::new(
+            Target::LinuxRiscv64,
+            appended_at,
+            riscv.len() as u64,
+            riscv.len() as u64,
+            Checksum::of(&riscv),
+            Checksum::of(&riscv),
+        ));
+        let json = manifest.to_json().unwrap();
+        let header = PbinHeader::new(Compression::None, 2, json.len() as u32)
+            .with_trailing_manifest(appended_at + riscv.len() as u64);
+
+        let mut bytes = file.as_bytes()[..manifest_start].to_vec();
+        bytes[header_offset..header_offset + pbin_core::HEADER_SIZE]
+            .copy_from_slice(&header.to_bytes());
+        bytes.extend_from_slice(&riscv);
+        bytes.extend_from_slice(json.as_bytes());
+
+        let appended = PbinFile::from_bytes(bytes).unwrap();
+        let payload = header_offset + pbin_core::HEADER_SIZE..manifest_start;
+        assert_eq!(
+            appended.as_bytes()[payload.clone()],
+            file.as_bytes()[payload]
+        );
+        let old = appended.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let new = appended
+            .manifest()
+            .find_entry(Target::LinuxRiscv64)
+            .unwrap();
+        assert_eq!(appended.entry_data(old).unwrap(), x86.as_slice());
+        assert_eq!(appended.entry_data(new).unwrap(), riscv.as_slice());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_stub_runs_trailing_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "payload", &marker_payload());
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.trailing_manifest = true;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let result = process::Command::new("sh")
+            .arg(&output)
+            .arg("arg")
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        assert_eq!(
+            String::from_utf8_lossy(&result.stdout),
+            "ok __PBIN_PAYLOAD__ arg\n"
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_stub_runs_payload_containing_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "payload", &marker_payload());
+
+        let config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let result = process::Command::new("sh")
+            .arg(&output)
+            .arg("arg")
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        assert_eq!(
+            String::from_utf8_lossy(&result.stdout),
+            "ok __PBIN_PAYLOAD__ arg\n"
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_stub_refuses_chunked_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "payload", &marker_payload());
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.dedup = Dedup::Chunks;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let result = process::Command::new("sh").arg(&output).output().unwrap();
+        assert!(!result.status.success(), "{:?}", result);
+        assert_eq!(
+            String::from_utf8_lossy(&result.stderr),
+            "chunks needs native extraction\n"
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_stub_falls_back_to_musl() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "payload", &marker_payload());
+
+        let config = test_config(
+            dir.path(),
+            HashMap::from([(Target::LinuxX86_64Musl, input)]),
+        );
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let result = process::Command::new("sh")
+            .arg(&output)
+            .arg("arg")
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        assert_eq!(
+            String::from_utf8_lossy(&result.stdout),
+            "ok __PBIN_PAYLOAD__ arg\n"
+        );
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_stub_runs_large_window_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        // Bigger than the 2^27-byte window zstd decodes by default, so the
+        // frame records the larger window.
+        let mut payload = marker_payload();
+        payload.extend_from_slice(b"exit\n");
+        payload.resize((1 << 27) + (1 << 20), 0);
+        let input = write_input(dir.path(), "payload", &payload);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.zstd_window_log = Some(28);
+        config.use_bcj = false;
+        let output = config.output.clone();
+        pack(config).unwrap();
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(entry.window_log, Some(28));
+
+        let result = process::Command::new("sh")
+            .arg(&output)
+            .arg("arg")
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{:?}", result);
+        assert_eq!(
+            String::from_utf8_lossy(&result.stdout),
+            "ok __PBIN_PAYLOAD__ arg\n"
+        );
+    }
+
+    #[test]
+    fn test_checksums_verify_packed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(1);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.use_bcj = false;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let stored = file.entry_data(entry).unwrap();
+
+        // The stored bytes match the compressed checksum only.
+        assert!(entry.verify_compressed(stored).unwrap());
+        assert!(!entry.verify_checksum(stored).unwrap());
+
+        // The decompressed binary matches the uncompressed checksum only.
+        let decompressed = pbin_compress::dict::decompress(stored, None).unwrap();
+        assert_eq!(decompressed, binary);
+        assert!(entry.verify_checksum(&decompressed).unwrap());
+        assert!(!entry.verify_compressed(&decompressed).unwrap());
+    }
+
+    #[test]
+    fn test_hmac_key_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(1);
+        let input = write_input(dir.path(), "app", &binary);
+        let key_file = write_input(dir.path(), "key", b"secret key material");
+        let key = ChecksumKey::derive(b"secret key material");
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.hmac_key = Some(key_file.clone());
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        assert_eq!(manifest.key_fingerprint, Some(key.fingerprint()));
+        let entry = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let err = pbin_compress::extract::extract_entry(&file, entry).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                pbin_compress::CompressionError::Format(Error::ChecksumKeyRequired { .. })
+            ),
+            "{}",
+            err
+        );
+        let keyed = file.clone().with_checksum_key(key.clone());
+        let extracted = pbin_compress::extract::extract_entry(&keyed, entry).unwrap();
+        assert_eq!(extracted, binary);
+        assert!(manifest.verify_entry(entry, &extracted, Some(&key)).is_ok());
+        assert!(matches!(
+            manifest.verify_entry(entry, &extracted, None),
+            Err(Error::ChecksumKeyRequired { .. })
+        ));
+        assert!(entry
+            .verify_compressed(file.entry_data(entry).unwrap())
+            .unwrap());
+    }
+
+    #[test]
+    fn test_checksums_match_without_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(2);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        let stored = file.entry_data(entry).unwrap();
+
+        assert!(entry.verify_compressed(stored).unwrap());
+        assert!(entry.verify_checksum(stored).unwrap());
+    }
+
+    #[test]
+    fn test_uncompressed_size_matches_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(3);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Balanced);
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(entry.uncompressed_size, binary.len() as u64);
+        assert_eq!(
+            entry.compressed_size,
+            file.entry_data(entry).unwrap().len() as u64
+        );
+        assert!(entry.compressed_size < entry.uncompressed_size);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_bcj_filtered_elf_roundtrips() {
+        let dir = tempfile::tempdir().unwrap();
+        // The test binary itself is a real x86_64 ELF.
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let input = write_input(dir.path(), "app", &binary);
+
+        for (x86_jcc, bcj) in [(false, "x86v2"), (true, "x86v2-jcc")] {
+            let mut config = test_config(
+                dir.path(),
+                HashMap::from([(Target::LinuxX86_64, input.clone())]),
+            );
+            config.compression_level = Some(CompressionLevel::Fast);
+            config.x86_jcc = x86_jcc;
+            let output = config.output.clone();
+            pack(config).unwrap();
+
+            let file = PbinFile::open(&output).unwrap();
+            let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+            assert_eq!(entry.bcj.as_deref(), Some(bcj));
+
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert!(extracted == binary, "extracted binary differs from input");
+            assert!(entry.verify_checksum(&extracted).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_delta_entries_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let linux = sample_binary(4);
+        let mut darwin = linux.clone();
+        darwin[1000..1006].copy_from_slice(b"darwin");
+        let linux_input = write_input(dir.path(), "linux", &linux);
+        let darwin_input = write_input(dir.path(), "darwin", &darwin);
+
+        let mut config = test_config(
+            dir.path(),
+            HashMap::from([
+                (Target::LinuxX86_64, linux_input),
+                (Target::DarwinX86_64, darwin_input),
+            ]),
+        );
+        config.compression_level = Some(CompressionLevel::Balanced);
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        assert_eq!(
+            manifest
+                .entries
+                .iter()
+                .filter(|e| e.delta_reference.is_some())
+                .count(),
+            1
+        );
+
+        let linux_entry = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let darwin_entry = manifest.find_entry(Target::DarwinX86_64).unwrap();
+        // Linux comes first in canonical order, so it is the reference.
+        assert_eq!(
+            darwin_entry.delta_reference.as_deref(),
+            Some("linux-x86_64")
+        );
+        let extract = |entry| pbin_compress::extract::extract_entry(&file, entry).unwrap();
+        assert_eq!(extract(linux_entry), linux);
+        assert_eq!(extract(darwin_entry), darwin);
+    }
+
+    #[test]
+    fn test_delta_chain_entries_extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let targets = [
+            Target::LinuxX86_64,
+            Target::DarwinX86_64,
+            Target::WindowsX86_64,
+            Target::FreebsdX86_64,
+        ];
+        // Each build rewrites one more block of the one before it.
+        let mut data = sample_binary(30);
+        let mut builds = Vec::new();
+        let mut binaries = HashMap::new();
+        for (i, target) in targets.into_iter().enumerate() {
+            let block = i * 1000..(i + 1) * 1000;
+            data[block.clone()].copy_from_slice(&sample_binary(31 + i as u32)[block]);
+            binaries.insert(target, write_input(dir.path(), &target.to_string(), &data));
+            builds.push((target, data.clone()));
+        }
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.delta_chains = true;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        let reference_of = |key: &str| {
+            manifest
+                .entries
+                .iter()
+                .find(|e| e.key() == key)
+                .and_then(|e| e.delta_reference.clone())
+        };
+        // Some patch is made against an entry that is itself a patch.
+        assert!(manifest
+            .entries
+            .iter()
+            .filter_map(|e| e.delta_reference.as_deref())
+            .any(|reference| reference_of(reference).is_some()));
+        for (target, binary) in &builds {
+            let entry = manifest.find_entry(*target).unwrap();
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert!(extracted == *binary, "{} differs from its input", target);
+        }
+    }
+
+    #[test]
+    fn test_dictionary_embedded() {
+        let dir = tempfile::tempdir().unwrap();
+        let targets = [
+            Target::LinuxX86_64,
+            Target::LinuxAarch64,
+            Target::LinuxRiscv64,
+            Target::LinuxArmv7,
+            Target::DarwinX86_64,
+            Target::DarwinAarch64,
+            Target::WindowsX86_64,
+            Target::WindowsAarch64,
+        ];
+        // A table of noise every binary shares, which only a dictionary
+        // compresses, so the dictionary pays for itself.
+        let table = random_bytes(12_000, 0x2545_f491_4f6c_dd1d);
+        let mut binaries = HashMap::new();
+        let mut originals = Vec::new();
+        for (seed, target) in targets.into_iter().enumerate() {
+            // Shared structure gives the dictionary trainer something to learn.
+            let symbols = (0..256)
+                .flat_map(|i| format!("fn symbol_{}() -> {}\n", i, i * (seed + 1)).into_bytes());
+            let binary: Vec<u8> = symbols.chain(table.iter().copied()).collect();
+            binaries.insert(target, write_input(dir.path(), target.as_str(), &binary));
+            originals.push((target, binary));
+        }
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.use_delta = false;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let dictionaries = &file.manifest().dictionaries;
+        assert!(!dictionaries.is_empty());
+        for (target, original) in &originals {
+            let entry = file.manifest().find_entry(*target).unwrap();
+            let dictionary = &dictionaries[entry.dict_index.unwrap()];
+            assert_eq!(entry.dict_id.as_ref(), Some(&dictionary.dict_id));
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, original);
+        }
+    }
+
+    #[test]
+    fn test_saved_dictionaries_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let strings: Vec<u8> = (0..800)
+            .flat_map(|i| format!("error {}\n", i * 7919 % 10007).into_bytes())
+            .collect();
+        let mut binaries = HashMap::new();
+        let mut originals = Vec::new();
+        for target in [
+            Target::LinuxX86_64,
+            Target::LinuxAarch64,
+            Target::LinuxRiscv64,
+            Target::LinuxArmv7,
+            Target::DarwinX86_64,
+            Target::DarwinAarch64,
+            Target::WindowsX86_64,
+            Target::WindowsAarch64,
+        ] {
+            let binary = [sample_binary(originals.len() as u32), strings.clone()].concat();
+            binaries.insert(target, write_input(dir.path(), target.as_str(), &binary));
+            originals.push((target, binary));
+        }
+        let dict_path = dir.path().join("saved.dict");
+        let pack_with = |dict_in: Option<PathBuf>, dict_out: PathBuf| {
+            let mut config = test_config(dir.path(), binaries.clone());
+            config.compression_level = Some(CompressionLevel::Fast);
+            config.use_delta = false;
+            config.dict_in = dict_in;
+            config.dict_out = Some(dict_out);
+            let output = config.output.clone();
+            pack(config).map(|()| PbinFile::open(&output).unwrap())
+        };
+
+        // A missing --dict-in file means training from scratch.
+        pack_with(Some(dict_path.clone()), dict_path.clone()).unwrap();
+        let saved = std::fs::read(&dict_path).unwrap();
+        assert!(!dict::dictionaries_from_bytes(&saved).unwrap().is_empty());
+
+        let reused_path = dir.path().join("reused.dict");
+        let file = pack_with(Some(dict_path), reused_path.clone()).unwrap();
+        assert_eq!(std::fs::read(&reused_path).unwrap(), saved);
+        for (target, original) in &originals {
+            let entry = file.manifest().find_entry(*target).unwrap();
+            assert!(entry.dict_index.is_some());
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, original);
+        }
+
+        // A dictionary in zstd's format is used for every binary.
+        let samples: Vec<&[u8]> = originals.iter().map(|(_, b)| b.as_slice()).collect();
+        let curated = dict::train_dictionary(&samples, 16 * 1024).unwrap();
+        assert!(curated.starts_with(&dict::ZSTD_DICT_MAGIC));
+        let curated_path = write_input(dir.path(), "curated.dict", &curated);
+        let file = pack_with(Some(curated_path), reused_path.clone()).unwrap();
+        let dictionaries = &file.manifest().dictionaries;
+        assert_eq!(dictionaries.len(), 1);
+        assert_eq!(dictionaries[0].group, SUPPLIED_DICT_GROUP);
+        for (target, original) in &originals {
+            let entry = file.manifest().find_entry(*target).unwrap();
+            assert_eq!(entry.dict_index, Some(0));
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, original);
+        }
+
+        let junk_path = write_input(dir.path(), "junk.dict", b"not a dictionary");
+        let err = pack_with(Some(junk_path), reused_path).err().unwrap();
+        assert!(
+            err.to_string().contains("not saved dictionaries"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_identical_inputs_share_payload() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = sample_binary(5);
+        let other = sample_binary(6);
+        let shared_input = write_input(dir.path(), "shared", &shared);
+        let other_input = write_input(dir.path(), "other", &other);
+
+        let mut config = test_config(
+            dir.path(),
+            HashMap::from([
+                (Target::LinuxX86_64, shared_input.clone()),
+                (Target::FreebsdX86_64, shared_input),
+                (Target::LinuxAarch64, other_input),
+            ]),
+        );
+        config.compression_level = Some(CompressionLevel::Fast);
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        manifest.validate().unwrap();
+        assert_eq!(manifest.entries.len(), 3);
+
+        let linux = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let freebsd = manifest.find_entry(Target::FreebsdX86_64).unwrap();
+        assert_eq!(linux.offset, freebsd.offset);
+        assert_eq!(linux.compressed_size, freebsd.compressed_size);
+
+        let extract = |entry| pbin_compress::extract::extract_entry(&file, entry).unwrap();
+        assert_eq!(extract(linux), shared);
+        assert_eq!(extract(freebsd), shared);
+        let arm = manifest.find_entry(Target::LinuxAarch64).unwrap();
+        assert_eq!(extract(arm), other);
+
+        let summary = manifest.summary();
+        assert_eq!(summary.deduplicated, 1);
+        assert_eq!(summary.deduplicated_size, linux.compressed_size);
+        assert_eq!(
+            summary.total_compressed_size,
+            linux.compressed_size + arm.compressed_size
+        );
+        assert_eq!(
+            summary.total_uncompressed_size,
+            (2 * shared.len() + other.len()) as u64
+        );
+    }
+
+    #[test]
+    fn test_alternative_algorithms_pack_and_extract() {
+        for algo in [Compression::Lz4, Compression::Xz, Compression::Brotli] {
+            pack_and_extract_with(algo);
+        }
+    }
+
+    fn pack_and_extract_with(algo: Compression) {
+        let dir = tempfile::tempdir().unwrap();
+        let x86 = sample_binary(20);
+        let arm = sample_binary(21);
+        let readme = b"Read me, read me, read me.".repeat(10);
+        let binaries = HashMap::from([
+            (Target::LinuxX86_64, write_input(dir.path(), "x86", &x86)),
+            (Target::LinuxAarch64, write_input(dir.path(), "arm", &arm)),
+        ]);
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.compression_algo = algo;
+        config.assets = vec![(
+            "README".to_string(),
+            write_input(dir.path(), "readme-src", &readme),
+        )];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        assert_eq!(file.header().compression, algo);
+        assert!(file.manifest().dictionaries.is_empty());
+        let manifest = file.manifest();
+        let entries = [
+            (manifest.find_entry(Target::LinuxX86_64).unwrap(), &x86),
+            (manifest.find_entry(Target::LinuxAarch64).unwrap(), &arm),
+            (manifest.assets().next().unwrap(), &readme),
+        ];
+        for (entry, original) in entries {
+            assert_eq!(entry.compression, Some(algo));
+            assert!(entry.compressed_size < entry.uncompressed_size);
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, original);
+        }
+    }
+
+    #[test]
+    fn test_ultra_records_window_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(5);
+        let asset = b"README contents\n".repeat(64);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Ultra);
+        config.zstd_window_log = Some(24);
+        config.zstd_workers = 2;
+        config.assets = vec![(
+            "README".to_string(),
+            write_input(dir.path(), "readme-src", &asset),
+        )];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(entry.window_log, Some(24));
+        let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+        assert_eq!(extracted, binary);
+
+        let readme = file
+            .manifest()
+            .entries
+            .iter()
+            .find(|entry| entry.is_asset())
+            .unwrap();
+        assert_eq!(readme.window_log, Some(24));
+        let extracted = pbin_compress::extract::extract_entry(&file, readme).unwrap();
+        assert_eq!(extracted, asset);
+    }
+
+    #[test]
+    fn test_incompressible_input_stored_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = random_bytes(32 * 1024, 0x2545_f491_4f6c_dd1d);
+        let asset = random_bytes(4096, 0x9e37_79b9_7f4a_7c15);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.use_bcj = false;
+        config.assets = vec![(
+            "data.bin".to_string(),
+            write_input(dir.path(), "data-src", &asset),
+        )];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        for (entry, original) in [
+            (manifest.find_entry(Target::LinuxX86_64).unwrap(), &binary),
+            (manifest.assets().next().unwrap(), &asset),
+        ] {
+            assert_eq!(entry.compression, Some(Compression::None));
+            assert_eq!(entry.compressed_size, original.len() as u64);
+            assert_eq!(file.entry_data(entry).unwrap(), original.as_slice());
+        }
+        // Nothing follows the manifest but the two inputs.
+        let header = file.header();
+        assert_eq!(
+            file.as_bytes().len(),
+            file.manifest_start() + header.manifest_size as usize + binary.len() + asset.len()
+        );
+    }
+
+    #[test]
+    fn test_dedup_chunks_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let run = |seed: u32| -> Vec<u8> { (seed..seed + 8).flat_map(sample_binary).collect() };
+        let (first, second, third) = (run(40), run(50), run(60));
+        let inputs = [
+            (
+                Target::LinuxX86_64,
+                [sample_binary(70), first.clone(), second.clone()].concat(),
+            ),
+            (
+                Target::WindowsX86_64,
+                [second.clone(), sample_binary(71), first.clone()].concat(),
+            ),
+            (
+                Target::DarwinAarch64,
+                [first[..5_000].to_vec(), first, third.clone()].concat(),
+            ),
+            (
+                Target::LinuxRiscv64,
+                [third, sample_binary(72), second].concat(),
+            ),
+        ];
+        let binaries: HashMap<Target, PathBuf> = inputs
+            .iter()
+            .map(|(target, data)| (*target, write_input(dir.path(), target.as_str(), data)))
+            .collect();
+
+        let mut sizes = Vec::new();
+        for dedup_chunks in [false, true] {
+            let mut config = test_config(dir.path(), binaries.clone());
+            config.compression_level = Some(CompressionLevel::Balanced);
+            config.output = dir.path().join(format!("chunks-{}.pbin", dedup_chunks));
+            if dedup_chunks {
+                config.dedup = Dedup::Chunks;
+            }
+            config.use_delta = !dedup_chunks;
+            config.use_dict = !dedup_chunks;
+            let output = config.output.clone();
+            pack(config).unwrap();
+            sizes.push(std::fs::metadata(&output).unwrap().len());
+
+            let file = PbinFile::open(&output).unwrap();
+            let manifest = file.manifest();
+            assert_eq!(file.header().has_chunks(), dedup_chunks);
+            assert_eq!(manifest.chunk_store.is_some(), dedup_chunks);
+            for (target, data) in &inputs {
+                let entry = manifest.find_entry(*target).unwrap();
+                assert_eq!(entry.is_chunked(), dedup_chunks);
+                let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+                assert!(&extracted == data, "{} differs from its input", target);
+            }
+        }
+        // Each shared run is stored once rather than patched against a
+        // single reference per binary.
+        assert!(sizes[1] * 10 < sizes[0] * 7, "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_dedup_segments_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = [
+            (Target::LinuxX86_64, sample_binary(80)),
+            (Target::LinuxAarch64, sample_binary(81)),
+        ];
+        let binaries: HashMap<Target, PathBuf> = inputs
+            .iter()
+            .map(|(target, data)| (*target, write_input(dir.path(), target.as_str(), data)))
+            .collect();
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.dedup = Dedup::Segments;
+        config.use_delta = false;
+        config.use_dict = false;
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        // Inputs without sections goblin can read are stored whole, still
+        // through the chunk store.
+        let file = PbinFile::open(&output).unwrap();
+        assert!(file.header().has_chunks());
+        for (target, data) in &inputs {
+            let entry = file.manifest().find_entry(*target).unwrap();
+            assert!(entry.is_chunked());
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert!(&extracted == data, "{} differs from its input", target);
+        }
+    }
+
+    #[test]
+    fn test_low_memory_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let (x86, arm) = (sample_binary(20), sample_binary(21));
+        let (intel, apple) = (sample_binary(22), sample_binary(23));
+        let asset = b"README contents\n".repeat(64);
+        let binaries = HashMap::from([
+            (Target::LinuxX86_64, write_input(dir.path(), "x86", &x86)),
+            (Target::LinuxAarch64, write_input(dir.path(), "arm", &arm)),
+            (Target::FreebsdX86_64, write_input(dir.path(), "bsd", &x86)),
+            (
+                Target::DarwinAarch64,
+                write_input(dir.path(), "mac", &universal_binary(&intel, &apple)),
+            ),
+        ]);
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.low_memory = true;
+        config.trailing_manifest = true;
+        config.use_delta = false;
+        config.use_dict = false;
+        config.assets = vec![(
+            "README".to_string(),
+            write_input(dir.path(), "readme-src", &asset),
+        )];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        assert!(file.header().has_trailing_manifest());
+        let manifest = file.manifest();
+        assert!(manifest.dictionaries.is_empty());
+        for (target, binary) in [
+            (Target::LinuxX86_64, &x86),
+            (Target::LinuxAarch64, &arm),
+            (Target::FreebsdX86_64, &x86),
+            (Target::DarwinX86_64, &intel),
+            (Target::DarwinAarch64, &apple),
+        ] {
+            let entry = manifest.find_entry(target).unwrap();
+            assert!(entry.delta_reference.is_none());
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, binary);
+        }
+        let readme = manifest.assets().next().unwrap();
+        let extracted = pbin_compress::extract::extract_entry(&file, readme).unwrap();
+        assert_eq!(extracted, asset);
+    }
+
+    #[test]
+    fn test_memory_budget_pack() {
+        let dir = tempfile::tempdir().unwrap();
+        let (x86, arm) = (sample_binary(24), sample_binary(25));
+        let mut bsd = x86.clone();
+        bsd[100] ^= 0xFF;
+        let binaries = HashMap::from([
+            (Target::LinuxX86_64, write_input(dir.path(), "x86", &x86)),
+            (Target::LinuxAarch64, write_input(dir.path(), "arm", &arm)),
+            (Target::FreebsdX86_64, write_input(dir.path(), "bsd", &bsd)),
+        ]);
+
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Balanced);
+        config.memory_budget = Some(1024);
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        assert!(manifest.dictionaries.is_empty());
+        for (target, binary) in [
+            (Target::LinuxX86_64, &x86),
+            (Target::LinuxAarch64, &arm),
+            (Target::FreebsdX86_64, &bsd),
+        ] {
+            let entry = manifest.find_entry(target).unwrap();
+            assert!(entry.delta_reference.is_none());
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(&extracted, binary);
+        }
+    }
+
+    #[test]
+    fn test_zstd_level_override_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(22);
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut sizes = Vec::new();
+        for zstd_level in [None, Some(1), Some(15)] {
+            let mut config = test_config(
+                dir.path(),
+                HashMap::from([(Target::LinuxX86_64, input.clone())]),
+            );
+            config.compression_level = Some(CompressionLevel::Fast);
+            config.zstd_level = zstd_level;
+            let output = config.output.clone();
+            pack(config).unwrap();
+
+            let file = PbinFile::open(&output).unwrap();
+            let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+            let extracted = pbin_compress::extract::extract_entry(&file, entry).unwrap();
+            assert_eq!(extracted, binary);
+            sizes.push(entry.compressed_size);
+        }
+        // Level 1 stores more than the Fast preset's level 3, level 15 less.
+        assert!(sizes[1] > sizes[0] && sizes[2] < sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_assets_extracted_next_to_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = sample_binary(7);
+        let license = b"Permission is hereby granted, free of charge...".to_vec();
+        let config_toml = b"[server]\nport = 8080\n".to_vec();
+        let input = write_input(dir.path(), "app", &binary);
+
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.compression_level = Some(CompressionLevel::Fast);
+        config.assets = vec![
+            (
+                "LICENSE".to_string(),
+                write_input(dir.path(), "license-src", &license),
+            ),
+            (
+                "config.toml".to_string(),
+                write_input(dir.path(), "config-src", &config_toml),
+            ),
+        ];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        assert_eq!(file.manifest().assets().count(), 2);
+        let out_dir = dir.path().join("out");
+        std::fs::create_dir(&out_dir).unwrap();
+        let binary_path =
+            pbin_compress::extract::extract_to_dir(&file, Target::LinuxX86_64, &out_dir).unwrap();
+
+        assert_eq!(binary_path.parent(), Some(out_dir.as_path()));
+        assert_eq!(std::fs::read(&binary_path).unwrap(), binary);
+        for asset in file.manifest().assets() {
+            let name = &asset.file_name;
+            let extracted = std::fs::read(out_dir.join(name)).unwrap();
+            assert!(asset.verify_checksum(&extracted).unwrap(), "{}", name);
+        }
+        assert_eq!(std::fs::read(out_dir.join("LICENSE")).unwrap(), license);
+        assert_eq!(
+            std::fs::read(out_dir.join("config.toml")).unwrap(),
+            config_toml
+        );
+    }
+
+    #[test]
+    fn test_file_name_and_mode_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "hello-linux", &sample_binary(3));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&input, std::fs::Permissions::from_mode(0o750)).unwrap();
+        }
+        let windows = write_input(dir.path(), "hello-windows", &sample_binary(4));
+
+        let binaries = HashMap::from([
+            (Target::LinuxX86_64, input),
+            (Target::WindowsX86_64, windows),
+        ]);
+        let config = test_config(dir.path(), binaries.clone());
+        let output = config.output.clone();
+        pack(config).unwrap();
+        let file = PbinFile::open(&output).unwrap();
+        let linux = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert_eq!(linux.file_name, "hello-linux");
+        #[cfg(unix)]
+        assert_eq!(linux.mode, 0o750);
+
+        let mut config = test_config(dir.path(), binaries);
+        config.exe_name = Some("hello".to_string());
+        pack(config).unwrap();
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        let linux = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        let windows = manifest.find_entry(Target::WindowsX86_64).unwrap();
+        assert_eq!(linux.output_name(&manifest.name).unwrap(), "hello");
+        assert_eq!(windows.output_name(&manifest.name).unwrap(), "hello.exe");
+    }
+
+    #[test]
+    fn test_metadata_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "app", &sample_binary(5));
+        let mut config = test_config(dir.path(), HashMap::from([(Target::LinuxX86_64, input)]));
+        config.description = Some("Says hello".to_string());
+        config.license = Some("MIT".to_string());
+        config.homepage = Some("https://example.com/hello".to_string());
+        config.authors = vec!["Jane Doe".to_string(), "John Roe".to_string()];
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let manifest = PbinFile::open(&output).unwrap().manifest().clone();
+        assert_eq!(manifest.description.as_deref(), Some("Says hello"));
+        assert_eq!(manifest.license.as_deref(), Some("MIT"));
+        assert_eq!(
+            manifest.homepage.as_deref(),
+            Some("https://example.com/hello")
+        );
+        assert_eq!(manifest.authors, ["Jane Doe", "John Roe"]);
+    }
+
+    #[test]
+    fn test_reproducible_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let binaries: HashMap<Target, PathBuf> = Target::all()
+            .iter()
+            .take(8)
+            .enumerate()
+            .map(|(i, target)| {
+                let name = format!("app-{}", target);
+                (
+                    *target,
+                    write_input(dir.path(), &name, &sample_binary(i as u32)),
+                )
+            })
+            .collect();
+
+        let mut hashes = Vec::new();
+        for run in 0..2 {
+            let mut config = test_config(dir.path(), binaries.clone());
+            config.output = dir.path().join(format!("run{}.pbin", run));
+            config.compression_level = Some(CompressionLevel::Fast);
+            config.source_id = Some("0123abcd".to_string());
+            config.reproducible = true;
+            config.jobs = 1 + run * 3;
+            let output = config.output.clone();
+            pack(config).unwrap();
+            hashes.push(Checksum::of(&std::fs::read(output).unwrap()));
+        }
+
+        assert_eq!(hashes[0], hashes[1]);
+        let file = PbinFile::open(dir.path().join("run0.pbin")).unwrap();
+        assert!(file.manifest().created_at.is_none());
+        assert_eq!(file.manifest().source_id.as_deref(), Some("0123abcd"));
+    }
+
+    #[test]
+    fn test_min_os_version_override() {
+        let dir = tempfile::tempdir().unwrap();
+        let darwin = write_input(dir.path(), "darwin", &sample_binary(1));
+        let linux = write_input(dir.path(), "linux", &sample_binary(2));
+        let mut config = test_config(
+            dir.path(),
+            HashMap::from([
+                (Target::DarwinAarch64, darwin),
+                (Target::LinuxX86_64, linux),
+            ]),
+        );
+        config.min_os_versions = HashMap::from([(Target::DarwinAarch64, "13.0".to_string())]);
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        let darwin = manifest.find_entry(Target::DarwinAarch64).unwrap();
+        assert_eq!(darwin.min_os_version.as_deref(), Some("13.0"));
+        assert!(darwin.check_os_version("11.6").is_err());
+        let linux = manifest.find_entry(Target::LinuxX86_64).unwrap();
+        assert!(linux.min_os_version.is_none());
+    }
+
+    /// A universal macOS binary with an x86_64 and an arm64 slice.
+    fn universal_binary(x86_64: &[u8], arm64: &[u8]) -> Vec<u8> {
+        const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+        const CPU_TYPE_ARM64: u32 = 0x0100_000c;
+        let mut data = Vec::new();
+        let mut offset = 48;
+        for field in [0xcafe_babe, 2] {
+            data.extend_from_slice(&u32::to_be_bytes(field));
+        }
+        for (cputype, slice) in [(CPU_TYPE_X86_64, x86_64), (CPU_TYPE_ARM64, arm64)] {
+            for field in [cputype, 0, offset, slice.len() as u32, 0] {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            offset += slice.len() as u32;
+        }
+        data.extend_from_slice(x86_64);
+        data.extend_from_slice(arm64);
+        data
+    }
+
+    #[test]
+    fn test_universal_binary_split() {
+        let dir = tempfile::tempdir().unwrap();
+        let (intel, arm) = (sample_binary(1), sample_binary(2));
+        let universal = write_input(dir.path(), "app", &universal_binary(&intel, &arm));
+        let config = test_config(
+            dir.path(),
+            HashMap::from([(Target::DarwinAarch64, universal)]),
+        );
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        assert_eq!(
+            manifest.targets(),
+            [Target::DarwinX86_64, Target::DarwinAarch64]
+        );
+        for (target, slice) in [
+            (Target::DarwinX86_64, &intel),
+            (Target::DarwinAarch64, &arm),
+        ] {
+            let entry = manifest.find_entry(target).unwrap();
+            assert_eq!(entry.file_name, "app");
+            assert_eq!(file.entry_data(entry).unwrap(), slice.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_universal_binary_conflicts_with_explicit_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let universal = universal_binary(&sample_binary(1), &sample_binary(2));
+        let universal = write_input(dir.path(), "app", &universal);
+        let arm = write_input(dir.path(), "app-arm64", &sample_binary(3));
+        let config = test_config(
+            dir.path(),
+            HashMap::from([
+                (Target::DarwinX86_64, universal),
+                (Target::DarwinAarch64, arm),
+            ]),
+        );
+
+        let error = pack(config).unwrap_err();
+        assert!(matches!(error, Error::InvalidInput(_)));
+        assert!(error.to_string().contains("--darwin-aarch64"));
+    }
+
+    #[test]
+    fn test_strict_rejects_non_semver_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = write_input(dir.path(), "app", &sample_binary(20));
+        let binaries = HashMap::from([(Target::LinuxX86_64, input)]);
+
+        let mut config = test_config(dir.path(), binaries.clone());
+        config.version = "1.0..0".to_string();
+        let output = config.output.clone();
+        pack(config).unwrap();
+        assert_eq!(
+            PbinFile::open(&output).unwrap().manifest().version,
+            "1.0..0"
+        );
+
+        let mut config = test_config(dir.path(), binaries);
+        config.version = "1.0..0".to_string();
+        config.strict = true;
+        let err = pack(config).unwrap_err();
+        assert!(err.to_string().contains("invalid version"), "{}", err);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn test_dynamic_deps_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let input = write_input(dir.path(), "app", &binary);
+        let binaries = HashMap::from([(Target::LinuxX86_64, input)]);
+
+        let config = test_config(dir.path(), binaries.clone());
+        let output = config.output.clone();
+        pack(config).unwrap();
+        let file = PbinFile::open(&output).unwrap();
+        let entry = file.manifest().find_entry(Target::LinuxX86_64).unwrap();
+        assert!(entry
+            .dynamic_deps
+            .iter()
+            .any(|dep| dep.starts_with("libc.so")));
+
+        let mut config = test_config(dir.path(), binaries);
+        config.require_static = true;
+        let err = pack(config).unwrap_err();
+        assert!(err.to_string().contains("--require-static"), "{}", err);
+    }
+
+    #[test]
+    fn test_programs_extract_independently() {
+        let dir = tempfile::tempdir().unwrap();
+        let host = Target::detect_current().unwrap();
+        let script = |name: &str| format!("#!/bin/sh\necho \"{} $*\"\n", name).into_bytes();
+        let mut config = test_config(
+            dir.path(),
+            HashMap::from([(host, write_input(dir.path(), "main", &script("main")))]),
+        );
+        config.programs = ["tool-a", "tool-b"]
+            .iter()
+            .map(|program| {
+                let input = write_input(dir.path(), program, &script(program));
+                (program.to_string(), HashMap::from([(host, input)]))
+            })
+            .collect();
+        let output = config.output.clone();
+        pack(config).unwrap();
+
+        let file = PbinFile::open(&output).unwrap();
+        let manifest = file.manifest();
+        assert_eq!(manifest.programs(), ["test", "tool-a", "tool-b"]);
+        for program in ["test", "tool-a", "tool-b"] {
+            let entry = manifest.find_program_entry(program, host).unwrap();
+            let expected = script(if program == "test" { "main" } else { program });
+            assert_eq!(
+                pbin_compress::extract::extract_entry(&file, entry).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(
+            manifest
+                .find_entry(host)
+                .unwrap()
+                .program_name(&manifest.name),
+            "test"
+        );
+
+        // The stub dispatches on the name it was invoked as.
+        let links = dir.path().join("links");
+        std::fs::create_dir(&links).unwrap();
+        #[cfg(unix)]
+        for (invoked_as, expected) in [("tool-b", "tool-b x\n"), ("test.pbin", "main x\n")] {
+            let link = links.join(invoked_as);
+            std::os::unix::fs::symlink(&output, &link).unwrap();
+            let result = process::Command::new("sh")
+                .arg(&link)
+                .arg("x")
+                .output()
+                .unwrap();
+            assert!(result.status.success(), "{:?}", result);
+            assert_eq!(String::from_utf8_lossy(&result.stdout), expected);
+        }
+        let result = process::Command::new("sh")
+            .arg(&output)
+            .args(["--pbin-program", "tool-a", "y"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&result.stdout), "tool-a y\n");
+    }
+}
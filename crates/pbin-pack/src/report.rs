@@ -0,0 +1,307 @@
+//! Progress and statistics `pbin-pack` prints while packing.
+
+use crate::args::Config;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use pbin_compress::pipeline::{CompressionStats, ProgressEvent, SUPPLIED_DICT_GROUP};
+use pbin_compress::CompressionPipeline;
+use pbin_core::{PbinManifest, Summary};
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::sync::{Arc, Mutex};
+
+/// Reports a compression mode's stats, and writes them where `config`
+/// asks.
+pub(crate) fn report_stats(config: &Config, stats: &CompressionStats) -> pbin_core::Result<()> {
+    print_stats(stats);
+    write_stats_json(config, stats)
+}
+
+/// Shows a compression pipeline's progress on stderr: as a bar over the
+/// bytes to compress when stderr is a terminal, or a line per stage
+/// otherwise.
+pub(crate) struct Progress {
+    /// Bar over the bytes to compress; hidden unless stderr is a terminal.
+    bar: ProgressBar,
+    /// Whether to log a line as each stage starts instead.
+    log: bool,
+    /// Bytes of each target compressed so far.
+    done: Mutex<HashMap<String, u64>>,
+}
+
+impl Progress {
+    /// Creates a display for compressing `total` bytes.
+    pub(crate) fn new(total: u64) -> Arc<Self> {
+        let log = !io::stderr().is_terminal();
+        let target = if log {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr()
+        };
+        let bar = ProgressBar::with_draw_target(Some(total), target).with_style(
+            ProgressStyle::with_template("    [{bar:30}] {bytes}/{total_bytes} {msg}")
+                .expect("progress template is valid")
+                .progress_chars("=> "),
+        );
+        Arc::new(Self {
+            bar,
+            log,
+            done: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns `pipeline` reporting its progress here.
+    pub(crate) fn attach(self: &Arc<Self>, pipeline: CompressionPipeline) -> CompressionPipeline {
+        let progress = Arc::clone(self);
+        pipeline.with_progress(move |event| progress.update(event))
+    }
+
+    /// Shows `event`.
+    fn update(&self, event: ProgressEvent<'_>) {
+        match event {
+            ProgressEvent::StageStarted { target, stage } => {
+                let message = match target {
+                    Some(target) => format!("{} {}", stage, target),
+                    None => stage.to_string(),
+                };
+                if self.log {
+                    eprintln!("    [{}]", message);
+                }
+                self.bar.set_message(message);
+            }
+            ProgressEvent::BytesProcessed { target, done, .. } => {
+                let mut targets = self.done.lock().unwrap_or_else(|e| e.into_inner());
+                let last = targets.entry(target.to_string()).or_default();
+                self.bar.inc(done.saturating_sub(*last));
+                *last = done.max(*last);
+            }
+            ProgressEvent::StageFinished { .. } => {}
+        }
+    }
+
+    /// Removes the bar, once compression is done or has failed.
+    pub(crate) fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Writes `stats` as JSON to the `--stats-json` file, if one was given.
+fn write_stats_json(config: &Config, stats: &CompressionStats) -> pbin_core::Result<()> {
+    let Some(path) = &config.stats_json else {
+        return Ok(());
+    };
+    let json = serde_json::to_string_pretty(stats).map_err(io::Error::from)?;
+    std::fs::write(path, json)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    println!("    Wrote statistics to {}", path.display());
+    Ok(())
+}
+
+/// Prints what the compression pipeline did.
+fn print_stats(stats: &CompressionStats) {
+    println!("    Original: {} bytes", stats.original_size);
+    println!("    Compressed: {} bytes", stats.compressed_size);
+    println!(
+        "    Ratio: {:.1}% (saved {:.1}%)",
+        stats.ratio() * 100.0,
+        stats.savings_percent()
+    );
+    if stats.bcj_filtered > 0 {
+        println!("    BCJ filtered: {} binaries", stats.bcj_filtered);
+        for (key, arch) in &stats.bcj_filters {
+            println!("      {} with {}", key, arch.as_str());
+        }
+    }
+    if let (Some(limit), false) = (stats.dict_size_limit, stats.dictionaries.is_empty()) {
+        println!("    Dictionaries trained up to {} bytes", limit);
+    }
+    for decision in &stats.dict_decisions {
+        println!(
+            "    Dictionary {} {}: {} bytes with it against {} without ({:+} saved)",
+            decision.group,
+            if decision.kept { "kept" } else { "dropped" },
+            decision.with_dict,
+            decision.without_dict,
+            decision.savings()
+        );
+    }
+    for dict in &stats.dictionaries {
+        println!(
+            "    Dictionary {}: {} bytes for {} binaries, ratio {:.1}% against {:.1}% overall",
+            dict.group,
+            dict.dict_size,
+            dict.entries,
+            dict.ratio() * 100.0,
+            stats.ratio() * 100.0
+        );
+    }
+    for group in &stats.reused_dictionaries {
+        println!("    Reused saved dictionary for {}", group);
+    }
+    if let Some(id) = &stats.supplied_dictionary {
+        println!("    Compressed every binary with dictionary {}", id);
+    }
+    for group in &stats.stale_dictionaries {
+        if group == SUPPLIED_DICT_GROUP {
+            println!("    Supplied dictionary didn't help; trained dictionaries instead");
+        } else {
+            println!(
+                "    Saved dictionary for {} no longer helped; retrained",
+                group
+            );
+        }
+    }
+    if stats.delta_used > 0 {
+        println!("    Delta compressed: {} binaries", stats.delta_used);
+    }
+    if stats.delta_comparisons_skipped > 0 {
+        println!(
+            "    Delta comparisons settled by a quick probe: {}",
+            stats.delta_comparisons_skipped
+        );
+    }
+    if stats.stored_raw > 0 {
+        println!(
+            "    Stored raw: {} binaries (compression saved too little)",
+            stats.stored_raw
+        );
+    }
+    if !stats.entries.is_empty() {
+        println!(
+            "\n    {:<24} {:>12} {:>12} {:>7} {:<18} {:>10} {:>10} {:>10}",
+            "Entry", "Original", "Compressed", "Ratio", "Method", "BCJ", "Delta", "Compress"
+        );
+        for entry in &stats.entries {
+            println!(
+                "    {:<24} {:>12} {:>12} {:>6.1}% {:<18} {:>10.2?} {:>10.2?} {:>10.2?}",
+                entry.target,
+                entry.original_size,
+                entry.compressed_size,
+                entry.ratio() * 100.0,
+                entry.method(),
+                entry.times.bcj,
+                entry.times.delta_patch + entry.times.delta_probe,
+                entry.times.compress
+            );
+        }
+        println!();
+    } else {
+        for (key, time) in &stats.compress_times {
+            println!("    Compressed {} in {:.2?}", key, time);
+        }
+    }
+    let times = &stats.stage_times;
+    println!(
+        "    Stages: BCJ {:.2?}, dictionaries {:.2?}, delta grouping {:.2?}, compression {:.2?}",
+        times.bcj, times.dict_training, times.delta_grouping, times.compression
+    );
+    if let Some(time) = stats.verify_time {
+        println!("    Verified every entry in {:.2?}", time);
+    }
+    for degradation in &stats.degradations {
+        println!("    To fit the memory budget: {}", degradation);
+    }
+}
+
+/// Prints each entry's sizes and the payload totals, all taken from the
+/// manifest so they match what library consumers see.
+pub(crate) fn print_report(manifest: &PbinManifest, summary: &Summary) {
+    println!(
+        "\n  {:<20} {:>12} {:>12} {:>16}",
+        "Target", "Stored", "Original", "CompressionRatio"
+    );
+    for entry in &manifest.entries {
+        println!(
+            "  {:<20} {:>12} {:>12} {:>15.1}%",
+            entry.label(),
+            entry.compressed_size,
+            entry.uncompressed_size,
+            entry.compression_ratio() * 100.0
+        );
+    }
+    println!(
+        "  {:<20} {:>12} {:>12} {:>15.1}%",
+        "Total",
+        summary.total_compressed_size,
+        summary.total_uncompressed_size,
+        summary.overall_ratio * 100.0
+    );
+
+    if summary.deduplicated > 0 {
+        println!(
+            "\n  {} entries deduplicated, saved {} bytes",
+            summary.deduplicated, summary.deduplicated_size
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::pack;
+    use crate::pack::tests::{sample_binary, test_config, write_input};
+    use pbin_compress::CompressionLevel;
+    use pbin_core::Target;
+
+    #[test]
+    fn test_progress_counts_bytes_once() {
+        let progress = Progress::new(300);
+        let bytes = |target, done| ProgressEvent::BytesProcessed {
+            target,
+            done,
+            total: 200,
+        };
+        progress.update(bytes("linux-x86_64", 50));
+        progress.update(bytes("linux-aarch64", 100));
+        progress.update(bytes("linux-x86_64", 200));
+        progress.update(bytes("linux-x86_64", 200));
+        assert_eq!(progress.bar.position(), 300);
+        assert_eq!(progress.bar.length(), Some(300));
+        progress.finish();
+        assert!(progress.bar.is_finished());
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut binaries = HashMap::new();
+        for (seed, target) in [
+            Target::LinuxX86_64,
+            Target::LinuxAarch64,
+            Target::WindowsX86_64,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let binary = sample_binary(seed as u32);
+            binaries.insert(target, write_input(dir.path(), target.as_str(), &binary));
+        }
+        let mut config = test_config(dir.path(), binaries);
+        config.compression_level = Some(CompressionLevel::Fast);
+        let stats_path = dir.path().join("stats.json");
+        config.stats_json = Some(stats_path.clone());
+        pack(config).unwrap();
+
+        let stats: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&stats_path).unwrap()).unwrap();
+        let entries = stats["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        let sum = |key: &str| -> u64 { entries.iter().map(|e| e[key].as_u64().unwrap()).sum() };
+        let dictionaries: u64 = stats["dictionaries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["dict_size"].as_u64().unwrap())
+            .sum();
+        assert_eq!(
+            sum("original_size"),
+            stats["original_size"].as_u64().unwrap()
+        );
+        assert_eq!(
+            sum("compressed_size") + dictionaries,
+            stats["compressed_size"].as_u64().unwrap()
+        );
+        assert_eq!(entries[0]["compression"], "zstd");
+        assert!(entries[0]["times"]["compress"].is_object());
+        assert!(stats["stage_times"]["compression"].is_object());
+    }
+}
@@ -4,6 +4,9 @@
 /// This template works as both a POSIX shell script and a Windows batch file.
 pub const STUB_TEMPLATE: &str = include_str!("../../../stubs/polyglot.template");
 
+/// Placeholder in the template that is replaced with the header's byte offset.
+pub const HEADER_OFFSET_PLACEHOLDER: &str = "__PBIN_HEADER_OFFSET__";
+
 /// Generates polyglot stubs that work as both shell scripts and batch files.
 pub struct StubGenerator;
 
@@ -12,18 +15,39 @@ impl StubGenerator {
     ///
     /// The stub is a script that:
     /// 1. Detects the current OS and architecture
-    /// 2. Finds the payload marker in the file
+    /// 2. Checks the payload marker at its recorded header offset
     /// 3. Reads the PBIN header and manifest
     /// 4. Extracts the appropriate binary for the current platform
     /// 5. Executes it with all original arguments
     /// 6. Cleans up temporary files
+    ///
+    /// The header immediately follows the stub, so the recorded offset is the
+    /// stub's own length. Substituting the number changes that length, so the
+    /// offset is recomputed until it is stable.
     pub fn generate() -> Vec<u8> {
-        STUB_TEMPLATE.as_bytes().to_vec()
+        let mut header_offset = STUB_TEMPLATE.len();
+        loop {
+            let stub = Self::generate_for_offset(header_offset);
+            if stub.len() == header_offset {
+                return stub;
+            }
+            header_offset = stub.len();
+        }
+    }
+
+    /// Returns the stub with `header_offset` recorded as the header position.
+    ///
+    /// The runtime seeks straight to this offset instead of searching for the
+    /// payload marker, so payloads containing the marker bytes can't confuse it.
+    pub fn generate_for_offset(header_offset: usize) -> Vec<u8> {
+        STUB_TEMPLATE
+            .replace(HEADER_OFFSET_PLACEHOLDER, &header_offset.to_string())
+            .into_bytes()
     }
 
     /// Returns the stub size in bytes.
     pub fn stub_size() -> usize {
-        STUB_TEMPLATE.len()
+        Self::generate().len()
     }
 }
 
@@ -50,4 +74,13 @@ mod tests {
         // Stub should be under 4KB as per spec
         assert!(size < 4096, "Stub size {} exceeds 4KB limit", size);
     }
+
+    #[test]
+    fn test_header_offset_recorded() {
+        let stub = StubGenerator::generate();
+        let stub_str = String::from_utf8_lossy(&stub);
+
+        assert!(!stub_str.contains(HEADER_OFFSET_PLACEHOLDER));
+        assert!(stub_str.contains(&format!("H={}", stub.len())));
+    }
 }
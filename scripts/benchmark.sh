@@ -52,6 +52,14 @@ benchmark "balanced+bcj" --compress balanced
 benchmark "maximum" --compress maximum --no-bcj
 benchmark "maximum+bcj" --compress maximum
 
+echo ""
+echo "Algorithm Benchmarks (maximum level):"
+echo "------------------------"
+
+for algo in zstd lz4 xz brotli; do
+    benchmark "maximum+$algo" --compress maximum --compression-algo "$algo"
+done
+
 echo ""
 echo "Testing execution of compressed PBIN..."
 chmod +x "$OUTDIR/balanced.pbin"
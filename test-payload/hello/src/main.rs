@@ -31,12 +31,7 @@ fn main() {
         other => other,
     };
 
-    let arch_name = match ARCH {
-        "x86_64" => "x86_64",
-        "aarch64" => "aarch64",
-        "riscv64" => "riscv64",
-        other => other,
-    };
+    let arch_name = ARCH;
 
     // Print the detection message
     println!(
@@ -50,7 +45,10 @@ fn main() {
 
     let stdin = io::stdin();
     let mut input = String::new();
-    stdin.lock().read_line(&mut input).expect("Failed to read input");
+    stdin
+        .lock()
+        .read_line(&mut input)
+        .expect("Failed to read input");
 
     let response = input.trim().to_lowercase();
     if response == "yes" || response == "y" {